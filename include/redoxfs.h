@@ -0,0 +1,80 @@
+/* C interface to the Redox Filesystem, implemented in src/ffi.rs
+ *
+ * Link with the redoxfs cdylib (libredoxfs.so, libredoxfs.dylib or redoxfs.dll).
+ * Functions returning a number give a negative errno on failure. Nodes are named
+ * by the number of their block, the same number as in redoxfs_stat.ino.
+ * A panic in the library fails the call with -EIO, or NULL, after which the
+ * filesystem should be closed.
+ */
+
+#ifndef REDOXFS_H
+#define REDOXFS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct redoxfs redoxfs_t;
+
+/* Same layout as ExStat, without padding */
+#pragma pack(push, 1)
+struct redoxfs_stat {
+    uint64_t ino;
+    uint16_t mode;
+    uint32_t uid;
+    uint32_t gid;
+    uint32_t flags;
+    uint32_t generation;
+    uint64_t size;
+    uint64_t blocks;
+    uint32_t blksize;
+    uint64_t mtime;
+    uint32_t mtime_nsec;
+    uint64_t ctime;
+    uint32_t ctime_nsec;
+    uint64_t crtime;
+    uint32_t crtime_nsec;
+};
+#pragma pack(pop)
+
+/* Open the filesystem on a disk or image, returning NULL on failure */
+redoxfs_t *redoxfs_open(const char *path);
+/* Create an image of size bytes holding a new filesystem, returning NULL on failure */
+redoxfs_t *redoxfs_mkfs(const char *path, uint64_t size);
+/* Write everything to the disk and free the filesystem, which is freed even on failure */
+int redoxfs_close(redoxfs_t *fs);
+/* Write everything to the disk */
+int redoxfs_sync(redoxfs_t *fs);
+
+/* The root directory node */
+uint64_t redoxfs_root(const redoxfs_t *fs);
+/* Find the node at a path of names separated by slashes, starting at parent */
+int64_t redoxfs_lookup(redoxfs_t *fs, uint64_t parent, const char *path);
+/* Fill stat with the metadata of a node */
+int redoxfs_stat(redoxfs_t *fs, uint64_t node, struct redoxfs_stat *stat);
+
+/* Read up to len bytes at offset, returning the number read */
+int64_t redoxfs_read(redoxfs_t *fs, uint64_t node, uint64_t offset, uint8_t *buf, size_t len);
+/* Write len bytes at offset, returning the number written */
+int64_t redoxfs_write(redoxfs_t *fs, uint64_t node, uint64_t offset, const uint8_t *buf, size_t len);
+/* Change the length of a node */
+int redoxfs_truncate(redoxfs_t *fs, uint64_t node, uint64_t len);
+
+/* Copy the name of entry index of a directory to name, null terminated, returning its node.
+ * Returns 0 after the last entry, and -ERANGE if name_len is too short for the name. */
+int64_t redoxfs_readdir(redoxfs_t *fs, uint64_t dir, uint64_t index, char *name, size_t name_len);
+/* Create a node with a mode such as 0100644 or 040755 in a directory, returning it */
+int64_t redoxfs_create(redoxfs_t *fs, uint64_t parent, const char *name, uint16_t mode);
+/* Remove a file, symlink, or empty directory */
+int redoxfs_remove(redoxfs_t *fs, uint64_t parent, const char *name);
+/* Move a node to a new name and directory, replacing the node with that name */
+int redoxfs_rename(redoxfs_t *fs, uint64_t parent, const char *name, uint64_t new_parent, const char *new_name);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* REDOXFS_H */
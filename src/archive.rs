@@ -0,0 +1,803 @@
+use std::{cmp, fs, str};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use disk::Disk;
+use error::Error;
+use filesystem::FileSystem;
+use node::Node;
+use progress::Progress;
+
+/// Size of a tar header and the unit tar data is padded to
+const RECORD: usize = 512;
+
+/// Bytes of file data copied at a time
+const CHUNK: usize = 1024 * 1024;
+
+/// Largest long name or extended header that is read into memory
+const META_MAX: u64 = 1024 * 1024;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Copy everything from a reader to the end of a node
+fn copy_to_node<D: Disk, R: Read>(fs: &mut FileSystem<D>, block: u64, reader: &mut R, mtime: u64, mtime_nsec: u32, progress: &mut Progress) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK];
+    let mut offset = 0;
+    loop {
+        let count = reader.read(&mut buf)?;
+        if count == 0 {
+            return Ok(offset);
+        }
+        fs.write_node(block, offset, &buf[..count], mtime, mtime_nsec)?;
+        offset += count as u64;
+        progress.add(count as u64);
+    }
+}
+
+/// Copy the data of a node to a writer
+pub fn copy_from_node<D: Disk, W: Write>(fs: &mut FileSystem<D>, block: u64, writer: &mut W) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK];
+    let mut offset = 0;
+    loop {
+        let count = fs.read_node(block, offset, &mut buf)?;
+        if count == 0 {
+            return Ok(offset);
+        }
+        writer.write_all(&buf[..count])?;
+        offset += count as u64;
+    }
+}
+
+/// Read the whole data of a node, such as the target of a symlink
+pub fn read_all<D: Disk>(fs: &mut FileSystem<D>, block: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    copy_from_node(fs, block, &mut data)?;
+    Ok(data)
+}
+
+/// Set the permissions, owner and modification time of a node
+pub fn set_attr<D: Disk>(fs: &mut FileSystem<D>, block: u64, mode: u16, uid: u32, gid: u32, mtime: u64, mtime_nsec: u32) -> io::Result<()> {
+    let mut node = fs.node(block)?;
+    node.1.mode = (node.1.mode & Node::MODE_TYPE) | (mode & Node::MODE_PERM);
+    node.1.uid = uid;
+    node.1.gid = gid;
+    node.1.mtime = mtime;
+    node.1.mtime_nsec = mtime_nsec;
+    fs.write_at(node.0, &node.1)?;
+    Ok(())
+}
+
+/// Copy the contents of a directory into a directory node, recursively
+///
+/// Hard links are stored as separate copies, other special files are skipped.
+pub fn archive_at<D: Disk, P: AsRef<Path>>(fs: &mut FileSystem<D>, parent_path: P, parent_block: u64) -> io::Result<()> {
+    archive_dir(fs, parent_path, parent_block, &mut Progress::new(&mut |_, _| (), None))
+}
+
+/// Copy the contents of a directory like `archive_at`, calling progress with the bytes of file
+/// data copied and the bytes of all the files, which are added up first
+pub fn archive_at_progress<D: Disk, P: AsRef<Path>>(fs: &mut FileSystem<D>, parent_path: P, parent_block: u64, progress: &mut FnMut(u64, Option<u64>)) -> io::Result<()> {
+    let total = dir_size(parent_path.as_ref())?;
+    let mut progress = Progress::new(progress, Some(total));
+    archive_dir(fs, parent_path, parent_block, &mut progress)?;
+    progress.finish();
+    Ok(())
+}
+
+/// Bytes of the files in a directory, recursively
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+fn archive_dir<D: Disk, P: AsRef<Path>>(fs: &mut FileSystem<D>, parent_path: P, parent_block: u64, progress: &mut Progress) -> io::Result<()> {
+    let mut entries = fs::read_dir(parent_path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
+        let mode_type = if file_type.is_dir() {
+            Node::MODE_DIR
+        } else if file_type.is_symlink() {
+            Node::MODE_SYMLINK
+        } else if file_type.is_file() {
+            Node::MODE_FILE
+        } else {
+            continue;
+        };
+
+        let name = entry.file_name().into_string().or(Err(Error::InvalidName))?;
+        let mtime = metadata.mtime() as u64;
+        let mtime_nsec = metadata.mtime_nsec() as u32;
+        let node = fs.create_node(mode_type | (metadata.mode() as u16 & Node::MODE_PERM), &name, parent_block, metadata.ctime() as u64, metadata.ctime_nsec() as u32)?;
+
+        if file_type.is_dir() {
+            archive_dir(fs, &path, node.0, progress)?;
+        } else if file_type.is_symlink() {
+            let link = fs::read_link(&path)?;
+            fs.write_node(node.0, 0, link.as_os_str().as_bytes(), mtime, mtime_nsec)?;
+        } else {
+            let mut file = fs::File::open(&path)?;
+            copy_to_node(fs, node.0, &mut file, mtime, mtime_nsec, progress)?;
+        }
+
+        set_attr(fs, node.0, metadata.mode() as u16, metadata.uid(), metadata.gid(), mtime, mtime_nsec)?;
+    }
+
+    Ok(())
+}
+
+/// Children of a directory node, sorted by name
+pub fn sorted_children<D: Disk>(fs: &mut FileSystem<D>, parent_block: u64) -> io::Result<Vec<(u64, Node)>> {
+    let mut children = Vec::new();
+    fs.child_nodes(&mut children, parent_block)?;
+    children.sort_by(|a, b| a.1.name().unwrap_or("").cmp(b.1.name().unwrap_or("")));
+    Ok(children)
+}
+
+/// Copy the contents of a directory node into an existing directory, recursively
+///
+/// Owners and times are not restored.
+pub fn extract_at<D: Disk, P: AsRef<Path>>(fs: &mut FileSystem<D>, parent_block: u64, parent_path: P) -> io::Result<()> {
+    for child in sorted_children(fs, parent_block)? {
+        let name = child.1.name().or(Err(Error::InvalidName))?;
+        let path = parent_path.as_ref().join(name);
+        if child.1.is_dir() {
+            fs::create_dir(&path)?;
+            extract_at(fs, child.0, &path)?;
+        } else if child.1.is_symlink() {
+            let link = read_all(fs, child.0)?;
+            symlink(OsStr::from_bytes(&link), &path)?;
+            continue;
+        } else {
+            let mut file = fs::File::create(&path)?;
+            copy_from_node(fs, child.0, &mut file)?;
+        }
+
+        fs::set_permissions(&path, fs::Permissions::from_mode((child.1.mode & Node::MODE_PERM) as u32))?;
+    }
+
+    Ok(())
+}
+
+/// How a node and the file at the same path in a directory differ, see `compare_at`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Difference {
+    /// There is a node but no file
+    OnlyInImage,
+    /// There is a file but no node
+    OnlyInDir,
+    /// One is a directory, file, or symlink and the other is not, or the file is a special file
+    Type,
+    /// The permission bits of the node and of the file
+    Mode(u16, u16),
+    /// The size of the node and of the file
+    Size(u64, u64),
+    /// The node and the file are the same size, but their data differs
+    Data,
+    /// The target of the symlink node and of the symlink
+    Link(Vec<u8>, Vec<u8>),
+}
+
+/// The node type of a file, or 0 for special files, which cannot be archived
+fn mode_type(file_type: fs::FileType) -> u16 {
+    if file_type.is_dir() {
+        Node::MODE_DIR
+    } else if file_type.is_symlink() {
+        Node::MODE_SYMLINK
+    } else if file_type.is_file() {
+        Node::MODE_FILE
+    } else {
+        0
+    }
+}
+
+/// True if the data of a node is the same as what is left of a file, of the same size
+fn same_data<D: Disk>(fs: &mut FileSystem<D>, block: u64, file: &mut fs::File) -> io::Result<bool> {
+    let mut node_buf = vec![0; CHUNK];
+    let mut file_buf = vec![0; CHUNK];
+    let mut offset = 0;
+    loop {
+        let count = fs.read_node(block, offset, &mut node_buf)?;
+        if count == 0 {
+            return Ok(true);
+        }
+        match file.read_exact(&mut file_buf[..count]) {
+            Ok(()) => (),
+            // The file was truncated while it was compared
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err)
+        }
+        if node_buf[..count] != file_buf[..count] {
+            return Ok(false);
+        }
+        offset += count as u64;
+    }
+}
+
+/// Compare the contents of a directory node with a directory, recursively, without changing
+/// either, calling report with the path of every difference relative to them, and returning
+/// the number of differences
+///
+/// Types and permission bits are compared, and then the sizes and data of files and the
+/// targets of symlinks. Owners and times are not, as `extract_at` does not restore them.
+pub fn compare_at<D: Disk, P: AsRef<Path>>(fs: &mut FileSystem<D>, parent_block: u64, parent_path: P, report: &mut FnMut(&Path, &Difference)) -> io::Result<u64> {
+    compare_dir(fs, parent_block, parent_path.as_ref(), Path::new(""), report)
+}
+
+fn compare_dir<D: Disk>(fs: &mut FileSystem<D>, parent_block: u64, parent_path: &Path, relative: &Path, report: &mut FnMut(&Path, &Difference)) -> io::Result<u64> {
+    let mut nodes = BTreeMap::new();
+    for child in sorted_children(fs, parent_block)? {
+        nodes.insert(child.1.name().or(Err(Error::InvalidName))?.as_bytes().to_vec(), child);
+    }
+    let mut entries = BTreeMap::new();
+    for entry in fs::read_dir(parent_path)? {
+        let entry = entry?;
+        entries.insert(entry.file_name().as_bytes().to_vec(), entry);
+    }
+
+    let mut names: Vec<&Vec<u8>> = nodes.keys().chain(entries.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    let mut differences = 0;
+    for name in names {
+        let path = relative.join(OsStr::from_bytes(name));
+        let (node, entry) = match (nodes.get(name), entries.get(name)) {
+            (Some(node), Some(entry)) => (node, entry),
+            (Some(_), None) => {
+                report(&path, &Difference::OnlyInImage);
+                differences += 1;
+                continue;
+            },
+            (None, _) => {
+                report(&path, &Difference::OnlyInDir);
+                differences += 1;
+                continue;
+            }
+        };
+
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let mode = metadata.mode() as u16 & Node::MODE_PERM;
+        if node.1.mode & Node::MODE_TYPE != mode_type(metadata.file_type()) {
+            report(&path, &Difference::Type);
+            differences += 1;
+            continue;
+        }
+        if ! node.1.is_symlink() && node.1.mode & Node::MODE_PERM != mode {
+            report(&path, &Difference::Mode(node.1.mode & Node::MODE_PERM, mode));
+            differences += 1;
+        }
+
+        if node.1.is_dir() {
+            differences += compare_dir(fs, node.0, &entry.path(), &path, report)?;
+        } else if node.1.is_symlink() {
+            let node_link = read_all(fs, node.0)?;
+            let link = fs::read_link(entry.path())?.into_os_string().into_vec();
+            if node_link != link {
+                report(&path, &Difference::Link(node_link, link));
+                differences += 1;
+            }
+        } else {
+            let size = fs.node_len(node.0)?;
+            if size != metadata.len() {
+                report(&path, &Difference::Size(size, metadata.len()));
+                differences += 1;
+            } else if ! same_data(fs, node.0, &mut fs::File::open(entry.path())?)? {
+                report(&path, &Difference::Data);
+                differences += 1;
+            }
+        }
+    }
+
+    Ok(differences)
+}
+
+/// Fill a whole record, returning false if the stream ended before it
+fn read_record<R: Read>(reader: &mut R, record: &mut [u8; RECORD]) -> io::Result<bool> {
+    let mut i = 0;
+    while i < record.len() {
+        match reader.read(&mut record[i..]) {
+            Ok(0) => if i == 0 {
+                return Ok(false);
+            } else {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tar stream"));
+            },
+            Ok(count) => i += count,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(true)
+}
+
+/// Length of tar data, including the padding to a whole record
+fn padded(size: u64) -> u64 {
+    (size + RECORD as u64 - 1)/RECORD as u64 * RECORD as u64
+}
+
+/// Read the data of an entry that is kept in memory, such as a long name
+fn read_meta<R: Read>(reader: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    if size > META_MAX {
+        return Err(invalid("tar metadata entry too large"));
+    }
+    let mut data = vec![0; padded(size) as usize];
+    reader.read_exact(&mut data)?;
+    data.truncate(size as usize);
+    Ok(data)
+}
+
+/// Skip len bytes of the stream
+fn skip<R: Read>(reader: &mut R, len: u64) -> io::Result<()> {
+    if io::copy(&mut reader.by_ref().take(len), &mut io::sink())? < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tar stream"));
+    }
+    Ok(())
+}
+
+/// A text field, which ends at the first NUL
+fn text(field: &[u8]) -> &[u8] {
+    match field.iter().position(|&b| b == 0) {
+        Some(end) => &field[..end],
+        None => field
+    }
+}
+
+/// A numeric field, in octal text or in big endian base-256 if the high bit is set
+fn number(field: &[u8]) -> io::Result<u64> {
+    let mut value: u64 = 0;
+    if field[0] & 0x80 != 0 {
+        for (i, &b) in field.iter().enumerate() {
+            let b = if i == 0 { b & 0x7F } else { b };
+            if value >> 56 != 0 {
+                return Err(invalid("tar number too large"));
+            }
+            value = value << 8 | b as u64;
+        }
+        return Ok(value);
+    }
+
+    for &b in field.iter().skip_while(|&&b| b == b' ') {
+        if b == 0 || b == b' ' {
+            break;
+        } else if b >= b'0' && b <= b'7' {
+            if value >> 61 != 0 {
+                return Err(invalid("tar number too large"));
+            }
+            value = value << 3 | (b - b'0') as u64;
+        } else {
+            return Err(invalid("invalid number in tar header"));
+        }
+    }
+    Ok(value)
+}
+
+/// Sum of the header bytes, with the checksum field counted as spaces
+fn checksum(header: &[u8; RECORD]) -> u64 {
+    header.iter().enumerate().map(|(i, &b)| (if i >= 148 && i < 156 { b' ' } else { b }) as u64).sum()
+}
+
+/// Extended header records of the form "LENGTH KEY=VALUE\n"
+fn pax_records(data: &[u8]) -> io::Result<Vec<(&[u8], &[u8])>> {
+    let mut records = Vec::new();
+    let mut data = data;
+    while ! data.is_empty() {
+        let space = data.iter().position(|&b| b == b' ').ok_or_else(|| invalid("invalid pax record"))?;
+        let len = str::from_utf8(&data[..space]).ok().and_then(|len| len.parse::<usize>().ok()).ok_or_else(|| invalid("invalid pax record"))?;
+        if len <= space + 1 || len > data.len() || data[len - 1] != b'\n' {
+            return Err(invalid("invalid pax record"));
+        }
+
+        let record = &data[space + 1..len - 1];
+        let equals = record.iter().position(|&b| b == b'=').ok_or_else(|| invalid("invalid pax record"))?;
+        records.push((&record[..equals], &record[equals + 1..]));
+        data = &data[len..];
+    }
+    Ok(records)
+}
+
+/// Components of a path in the archive, relative to the directory it is imported into
+fn components(path: &[u8]) -> io::Result<Vec<&str>> {
+    let path = str::from_utf8(path).or(Err(Error::InvalidName))?;
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => return Err(invalid("tar path leaves the archive")),
+            _ => components.push(component)
+        }
+    }
+    Ok(components)
+}
+
+/// Find a directory, creating it if it does not exist
+fn tar_dir<D: Disk>(fs: &mut FileSystem<D>, name: &str, parent_block: u64, mtime: u64) -> io::Result<u64> {
+    match fs.find_node(name, parent_block) {
+        Ok(node) => if node.1.is_dir() {
+            Ok(node.0)
+        } else {
+            Err(Error::NotDir.into())
+        },
+        Err(Error::NotFound) => Ok(fs.create_node(Node::MODE_DIR | 0o755, name, parent_block, mtime, 0)?.0),
+        Err(err) => Err(err.into())
+    }
+}
+
+/// Find the node of an earlier entry, for a hard link
+fn tar_find<D: Disk>(fs: &mut FileSystem<D>, path: &[u8], root_block: u64) -> io::Result<u64> {
+    let mut block = root_block;
+    for name in components(path)? {
+        block = fs.find_node(name, block)?.0;
+    }
+    Ok(block)
+}
+
+/// Create a node for an entry, replacing a node of the same name unless both are directories
+fn tar_create<D: Disk>(fs: &mut FileSystem<D>, mode: u16, name: &str, parent_block: u64, mtime: u64) -> io::Result<u64> {
+    match fs.find_node(name, parent_block) {
+        Ok(node) => if node.1.is_dir() && mode & Node::MODE_TYPE == Node::MODE_DIR {
+            return Ok(node.0);
+        } else {
+            fs.remove_node(node.1.mode & Node::MODE_TYPE, name, parent_block)?;
+        },
+        Err(Error::NotFound) => (),
+        Err(err) => return Err(err.into())
+    }
+    Ok(fs.create_node(mode, name, parent_block, mtime, 0)?.0)
+}
+
+/// Populate a directory node from a tar stream
+///
+/// Hard links are stored as copies of the file they link to, other special files are skipped.
+/// GNU long names and pax extended headers are understood.
+pub fn archive_tar<D: Disk, R: Read>(fs: &mut FileSystem<D>, reader: &mut R, parent_block: u64) -> io::Result<()> {
+    tar_into(fs, reader, parent_block, &mut Progress::new(&mut |_, _| (), None))
+}
+
+/// Populate a directory node from a tar stream like `archive_tar`, calling progress with the
+/// bytes of file data copied, as the length of a stream is not known
+pub fn archive_tar_progress<D: Disk, R: Read>(fs: &mut FileSystem<D>, reader: &mut R, parent_block: u64, progress: &mut FnMut(u64, Option<u64>)) -> io::Result<()> {
+    let mut progress = Progress::new(progress, None);
+    tar_into(fs, reader, parent_block, &mut progress)?;
+    progress.finish();
+    Ok(())
+}
+
+fn tar_into<D: Disk, R: Read>(fs: &mut FileSystem<D>, reader: &mut R, parent_block: u64, progress: &mut Progress) -> io::Result<()> {
+    let mut long_name = None;
+    let mut long_link = None;
+    let mut pax_size = None;
+
+    let mut header = [0; RECORD];
+    while read_record(reader, &mut header)? {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if number(&header[148..156])? != checksum(&header) {
+            return Err(invalid("invalid tar header checksum"));
+        }
+
+        let kind = header[156];
+        let size = match pax_size.take() {
+            Some(size) => size,
+            None => number(&header[124..136])?
+        };
+
+        match kind {
+            b'L' => {
+                long_name = Some(text(&read_meta(reader, size)?).to_vec());
+                continue;
+            },
+            b'K' => {
+                long_link = Some(text(&read_meta(reader, size)?).to_vec());
+                continue;
+            },
+            b'x' => {
+                let data = read_meta(reader, size)?;
+                for (key, value) in pax_records(&data)? {
+                    match key {
+                        b"path" => long_name = Some(value.to_vec()),
+                        b"linkpath" => long_link = Some(value.to_vec()),
+                        b"size" => pax_size = Some(str::from_utf8(value).ok().and_then(|size| size.parse().ok()).ok_or_else(|| invalid("invalid pax size"))?),
+                        _ => ()
+                    }
+                }
+                continue;
+            },
+            b'g' => {
+                skip(reader, padded(size))?;
+                continue;
+            },
+            _ => ()
+        }
+
+        let path = match long_name.take() {
+            Some(path) => path,
+            None => {
+                let name = text(&header[..100]);
+                let prefix = text(&header[345..500]);
+                if &header[257..263] == b"ustar\0" && ! prefix.is_empty() {
+                    let mut path = prefix.to_vec();
+                    path.push(b'/');
+                    path.extend_from_slice(name);
+                    path
+                } else {
+                    name.to_vec()
+                }
+            }
+        };
+        let link = match long_link.take() {
+            Some(link) => link,
+            None => text(&header[157..257]).to_vec()
+        };
+
+        let mode = number(&header[100..108])? as u16 & Node::MODE_PERM;
+        let uid = number(&header[108..116])? as u32;
+        let gid = number(&header[116..124])? as u32;
+        let mtime = number(&header[136..148])?;
+
+        let mode_type = match kind {
+            b'0' | b'1' | b'7' | 0 => Node::MODE_FILE,
+            b'2' => Node::MODE_SYMLINK,
+            b'5' => Node::MODE_DIR,
+            _ => {
+                skip(reader, padded(size))?;
+                continue;
+            }
+        };
+
+        let mut names = components(&path)?;
+        let name = match names.pop() {
+            Some(name) => name,
+            None => {
+                // The archive root, such as "./", describes the directory being imported into
+                if mode_type == Node::MODE_DIR {
+                    set_attr(fs, parent_block, mode, uid, gid, mtime, 0)?;
+                }
+                skip(reader, padded(size))?;
+                continue;
+            }
+        };
+
+        let mut dir_block = parent_block;
+        for dir_name in names {
+            dir_block = tar_dir(fs, dir_name, dir_block, mtime)?;
+        }
+
+        let block = tar_create(fs, mode_type | mode, name, dir_block, mtime)?;
+        match kind {
+            b'1' => {
+                let target = tar_find(fs, &link, parent_block)?;
+                if fs.node(target)?.1.is_dir() {
+                    return Err(Error::IsDir.into());
+                }
+
+                let mut offset = 0;
+                let mut buf = vec![0; CHUNK];
+                loop {
+                    let count = fs.read_node(target, offset, &mut buf)?;
+                    if count == 0 {
+                        break;
+                    }
+                    fs.write_node(block, offset, &buf[..count], mtime, 0)?;
+                    offset += count as u64;
+                }
+                skip(reader, padded(size))?;
+            },
+            b'2' => {
+                fs.write_node(block, 0, &link, mtime, 0)?;
+                skip(reader, padded(size))?;
+            },
+            b'5' => {
+                skip(reader, padded(size))?;
+            },
+            _ => {
+                if copy_to_node(fs, block, &mut reader.by_ref().take(size), mtime, 0, progress)? < size {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tar stream"));
+                }
+                skip(reader, padded(size) - size)?;
+            }
+        }
+
+        set_attr(fs, block, mode, uid, gid, mtime, 0)?;
+    }
+
+    Ok(())
+}
+
+/// Store a numeric field in octal text, or in base-256 if it does not fit
+fn put_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if digits < 22 && value >> (digits * 3) != 0 {
+        for (i, b) in field.iter_mut().rev().enumerate() {
+            *b = if i < 8 { (value >> (i * 8)) as u8 } else { 0 };
+        }
+        field[0] |= 0x80;
+    } else {
+        let text = format!("{:01$o}", value, digits);
+        field[..digits].copy_from_slice(text.as_bytes());
+        field[digits] = 0;
+    }
+}
+
+/// A pax record, whose length includes the digits of the length itself
+fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while len != base + len.to_string().len() {
+        len = base + len.to_string().len();
+    }
+
+    records.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+fn write_header<W: Write>(writer: &mut W, path: &[u8], link: &[u8], kind: u8, mode: u16, uid: u32, gid: u32, size: u64, mtime: u64) -> io::Result<()> {
+    let mut header = [0; RECORD];
+    header[..cmp::min(path.len(), 100)].copy_from_slice(&path[..cmp::min(path.len(), 100)]);
+    put_number(&mut header[100..108], mode as u64);
+    put_number(&mut header[108..116], uid as u64);
+    put_number(&mut header[116..124], gid as u64);
+    put_number(&mut header[124..136], size);
+    put_number(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[157..157 + cmp::min(link.len(), 100)].copy_from_slice(&link[..cmp::min(link.len(), 100)]);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    let sum = checksum(&header);
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    writer.write_all(&header)
+}
+
+/// Write a header, preceded by a pax extended header if the names do not fit in it
+fn write_entry<W: Write>(writer: &mut W, path: &[u8], link: &[u8], kind: u8, node: &Node, size: u64) -> io::Result<()> {
+    let mut records = Vec::new();
+    if path.len() > 100 {
+        pax_record(&mut records, "path", path);
+    }
+    if link.len() > 100 {
+        pax_record(&mut records, "linkpath", link);
+    }
+    if ! records.is_empty() {
+        write_header(writer, b"././@PaxHeader", b"", b'x', 0o644, 0, 0, records.len() as u64, node.mtime)?;
+        writer.write_all(&records)?;
+        write_padding(writer, records.len() as u64)?;
+    }
+
+    write_header(writer, path, link, kind, node.mode & Node::MODE_PERM, node.uid, node.gid, size, node.mtime)
+}
+
+fn write_padding<W: Write>(writer: &mut W, size: u64) -> io::Result<()> {
+    writer.write_all(&[0; RECORD][..(padded(size) - size) as usize])
+}
+
+fn extract_tar_at<D: Disk, W: Write>(fs: &mut FileSystem<D>, parent_block: u64, prefix: &str, writer: &mut W) -> io::Result<()> {
+    for child in sorted_children(fs, parent_block)? {
+        let path = format!("{}{}", prefix, child.1.name().or(Err(Error::InvalidName))?);
+        if child.1.is_dir() {
+            let path = path + "/";
+            write_entry(writer, path.as_bytes(), b"", b'5', &child.1, 0)?;
+            extract_tar_at(fs, child.0, &path, writer)?;
+        } else if child.1.is_symlink() {
+            let link = read_all(fs, child.0)?;
+            write_entry(writer, path.as_bytes(), &link, b'2', &child.1, 0)?;
+        } else {
+            let size = fs.node_len(child.0)?;
+            write_entry(writer, path.as_bytes(), b"", b'0', &child.1, size)?;
+            if copy_from_node(fs, child.0, writer)? != size {
+                return Err(Error::Corrupt(child.0).into());
+            }
+            write_padding(writer, size)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the contents of a directory node as a tar stream
+pub fn extract_tar<D: Disk, W: Write>(fs: &mut FileSystem<D>, parent_block: u64, writer: &mut W) -> io::Result<()> {
+    extract_tar_at(fs, parent_block, "", writer)?;
+    writer.write_all(&[0; RECORD * 2])?;
+    writer.flush()
+}
+
+#[test]
+fn tar_test() {
+    use testing::memory_fs;
+
+    let mut filesystem = memory_fs(256);
+    let root = filesystem.header.1.root;
+
+    let long = "d".repeat(120);
+    let mut tar = Vec::new();
+    {
+        let dir = filesystem.create_node(Node::MODE_DIR | 0o750, &long, root, 0, 0).unwrap();
+        let file = filesystem.create_node(Node::MODE_FILE | 0o640, "file", dir.0, 0, 0).unwrap();
+        filesystem.write_node(file.0, 0, &[7; 1000], 0, 0).unwrap();
+        let link = filesystem.create_node(Node::MODE_SYMLINK | 0o777, "link", root, 0, 0).unwrap();
+        filesystem.write_node(link.0, 0, b"target", 0, 0).unwrap();
+        extract_tar(&mut filesystem, root, &mut tar).unwrap();
+    }
+    assert_eq!(tar.len() % RECORD, 0);
+
+    // A hard link to the file, inserted before the end of archive marker
+    let end = tar.len() - 2 * RECORD;
+    let mut hard = Vec::new();
+    let target = format!("{}/file", long);
+    write_entry(&mut hard, b"copy", target.as_bytes(), b'1', &Node::new(Node::MODE_FILE | 0o600, "copy", 0, 0, 0), 0).unwrap();
+    tar.splice(end..end, hard);
+
+    let dest = filesystem.create_node(Node::MODE_DIR | 0o755, "dest", root, 0, 0).unwrap();
+    archive_tar(&mut filesystem, &mut &tar[..], dest.0).unwrap();
+
+    let dir = filesystem.find_node(&long, dest.0).unwrap();
+    assert_eq!({ dir.1.mode }, Node::MODE_DIR | 0o750);
+    let file = filesystem.find_node("file", dir.0).unwrap();
+    assert_eq!({ file.1.mode }, Node::MODE_FILE | 0o640);
+    assert_eq!(read_all(&mut filesystem, file.0).unwrap(), vec![7; 1000]);
+    let link = filesystem.find_node("link", dest.0).unwrap();
+    assert!(link.1.is_symlink());
+    assert_eq!(read_all(&mut filesystem, link.0).unwrap(), b"target");
+    let copy = filesystem.find_node("copy", dest.0).unwrap();
+    assert_eq!({ copy.1.mode }, Node::MODE_FILE | 0o600);
+    assert_eq!(read_all(&mut filesystem, copy.0).unwrap(), vec![7; 1000]);
+}
+
+#[test]
+fn compare_test() {
+    use std::{env, process};
+    use testing::memory_fs;
+
+    let dir = env::temp_dir().join(format!("redoxfs_compare_test_{}", process::id()));
+    fs::create_dir(&dir).unwrap();
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/file"), &[1; 3000][..]).unwrap();
+    fs::write(dir.join("same"), b"same").unwrap();
+    fs::set_permissions(dir.join("same"), fs::Permissions::from_mode(0o644)).unwrap();
+    symlink("same", dir.join("link")).unwrap();
+
+    let mut filesystem = memory_fs(256);
+    let root = filesystem.header.1.root;
+    archive_at(&mut filesystem, &dir, root).unwrap();
+
+    let mut differences = Vec::new();
+    assert_eq!(compare_at(&mut filesystem, root, &dir, &mut |path, difference| differences.push((path.to_path_buf(), difference.clone()))).unwrap(), 0);
+
+    // Change the directory after archiving it
+    fs::write(dir.join("sub/file"), &[2; 3000][..]).unwrap();
+    fs::write(dir.join("same"), b"longer").unwrap();
+    fs::set_permissions(dir.join("same"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::remove_file(dir.join("link")).unwrap();
+    symlink("other", dir.join("link")).unwrap();
+    fs::write(dir.join("new"), b"").unwrap();
+
+    assert_eq!(compare_at(&mut filesystem, root, &dir, &mut |path, difference| differences.push((path.to_path_buf(), difference.clone()))).unwrap(), 5);
+    assert_eq!(differences, vec![
+        (Path::new("link").to_path_buf(), Difference::Link(b"same".to_vec(), b"other".to_vec())),
+        (Path::new("new").to_path_buf(), Difference::OnlyInDir),
+        (Path::new("same").to_path_buf(), Difference::Mode(0o644, 0o600)),
+        (Path::new("same").to_path_buf(), Difference::Size(4, 6)),
+        (Path::new("sub/file").to_path_buf(), Difference::Data),
+    ]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
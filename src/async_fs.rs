@@ -0,0 +1,227 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use disk::Disk;
+use error::{Error, Result};
+use ex_stat::ExStat;
+use filesystem::FileSystem;
+use node::Node;
+
+struct FutureState<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// The result of an operation of an `AsyncFileSystem`, ready once the worker thread has run it
+pub struct FsFuture<T> {
+    state: Arc<Mutex<FutureState<T>>>,
+}
+
+impl<T> Future for FsFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<T>> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Completes an `FsFuture`, with an I/O error if it is dropped without a result
+struct Completion<T> {
+    state: Arc<Mutex<FutureState<T>>>,
+    done: bool,
+}
+
+impl<T> Completion<T> {
+    fn complete(&mut self, result: Result<T>) {
+        let mut state = self.state.lock().unwrap();
+        state.result = Some(result);
+        self.done = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        // The worker thread panicked or stopped before running the operation
+        if ! self.done {
+            self.complete(Err(Error::Io(0)));
+        }
+    }
+}
+
+type Job<D> = Box<FnOnce(&mut FileSystem<D>) + Send>;
+
+/// A filesystem whose operations run on a worker thread, returning futures
+///
+/// Operations run one at a time in the order they were started, so executor threads never
+/// wait for the disk. The disk can be an `AsyncDisk` wrapped in a `DiskBlocking`.
+pub struct AsyncFileSystem<D: Disk + Send + 'static> {
+    sender: Option<mpsc::Sender<Job<D>>>,
+    worker: Option<thread::JoinHandle<FileSystem<D>>>,
+}
+
+impl<D: Disk + Send + 'static> AsyncFileSystem<D> {
+    pub fn new(mut fs: FileSystem<D>) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job<D>>();
+        let worker = thread::spawn(move || {
+            for job in receiver {
+                job(&mut fs);
+            }
+            fs
+        });
+        AsyncFileSystem {
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    /// Run a function on the filesystem in the worker thread
+    pub fn run<T, F>(&self, f: F) -> FsFuture<T> where T: Send + 'static, F: FnOnce(&mut FileSystem<D>) -> Result<T> + Send + 'static {
+        let state = Arc::new(Mutex::new(FutureState {
+            result: None,
+            waker: None,
+        }));
+        let mut completion = Completion {
+            state: state.clone(),
+            done: false,
+        };
+        if let Some(ref sender) = self.sender {
+            // If the worker is gone, dropping the job completes the future with an error
+            let _ = sender.send(Box::new(move |fs: &mut FileSystem<D>| {
+                let result = f(fs);
+                completion.complete(result);
+            }));
+        }
+        FsFuture {
+            state: state,
+        }
+    }
+
+    /// Wait for the operations that were started, then return the filesystem
+    pub fn into_inner(mut self) -> FileSystem<D> {
+        self.sender.take();
+        self.worker.take().unwrap().join().unwrap()
+    }
+
+    pub fn find_node(&self, name: &str, parent_block: u64) -> FsFuture<(u64, Node)> {
+        let name = name.to_string();
+        self.run(move |fs| fs.find_node(&name, parent_block))
+    }
+
+    pub fn child_nodes(&self, parent_block: u64) -> FsFuture<Vec<(u64, Node)>> {
+        self.run(move |fs| {
+            let mut children = Vec::new();
+            fs.child_nodes(&mut children, parent_block)?;
+            Ok(children)
+        })
+    }
+
+    pub fn create_node(&self, mode: u16, name: &str, parent_block: u64, ctime: u64, ctime_nsec: u32) -> FsFuture<(u64, Node)> {
+        let name = name.to_string();
+        self.run(move |fs| fs.create_node(mode, &name, parent_block, ctime, ctime_nsec))
+    }
+
+    pub fn remove_node(&self, mode: u16, name: &str, parent_block: u64) -> FsFuture<()> {
+        let name = name.to_string();
+        self.run(move |fs| fs.remove_node(mode, &name, parent_block))
+    }
+
+    /// Read up to len bytes at offset, fewer at the end of the node
+    pub fn read_node(&self, block: u64, offset: u64, len: usize) -> FsFuture<Vec<u8>> {
+        self.run(move |fs| {
+            let mut buf = vec![0; len];
+            let count = fs.read_node(block, offset, &mut buf)?;
+            buf.truncate(count);
+            Ok(buf)
+        })
+    }
+
+    pub fn write_node(&self, block: u64, offset: u64, buf: Vec<u8>, mtime: u64, mtime_nsec: u32) -> FsFuture<usize> {
+        self.run(move |fs| fs.write_node(block, offset, &buf, mtime, mtime_nsec))
+    }
+
+    pub fn node_len(&self, block: u64) -> FsFuture<u64> {
+        self.run(move |fs| fs.node_len(block))
+    }
+
+    pub fn node_set_len(&self, block: u64, length: u64) -> FsFuture<()> {
+        self.run(move |fs| fs.node_set_len(block, length))
+    }
+
+    pub fn node_stat(&self, block: u64) -> FsFuture<ExStat> {
+        self.run(move |fs| fs.node_stat(block))
+    }
+
+    pub fn sync_all(&self) -> FsFuture<()> {
+        self.run(|fs| fs.sync_all())
+    }
+}
+
+impl<D: Disk + Send + 'static> Drop for AsyncFileSystem<D> {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[test]
+fn async_test() {
+    use std::{cmp, future};
+    use BLOCK_SIZE;
+    use disk::{AsyncDisk, DiskBlocking, DiskFuture, block_on};
+
+    struct AsyncMemory(Vec<u8>);
+
+    impl AsyncDisk for AsyncMemory {
+        fn read_at(&mut self, block: u64, len: usize) -> DiskFuture<Vec<u8>> {
+            let start = cmp::min((block * BLOCK_SIZE) as usize, self.0.len());
+            let end = cmp::min(start + len, self.0.len());
+            Box::pin(future::ready(Ok(self.0[start..end].to_vec())))
+        }
+
+        fn write_at(&mut self, block: u64, buffer: Vec<u8>) -> DiskFuture<usize> {
+            let start = (block * BLOCK_SIZE) as usize;
+            let end = cmp::min(start + buffer.len(), self.0.len());
+            self.0[start..end].copy_from_slice(&buffer[..end - start]);
+            Box::pin(future::ready(Ok(end - start)))
+        }
+
+        fn size(&mut self) -> DiskFuture<u64> {
+            Box::pin(future::ready(Ok(self.0.len() as u64)))
+        }
+    }
+
+    let disk = DiskBlocking::new(AsyncMemory(vec![0; 64 * BLOCK_SIZE as usize]));
+    let fs = AsyncFileSystem::new(FileSystem::create(disk, 0, 0).unwrap());
+    let root = block_on(fs.run(|fs| Ok(fs.header.1.root))).unwrap();
+
+    // Operations started together complete in order
+    let create = fs.create_node(Node::MODE_FILE | 0o644, "file", root, 0, 0);
+    let write = fs.run(move |fs| {
+        let node = fs.find_node("file", root)?;
+        fs.write_node(node.0, 0, b"async", 0, 0)
+    });
+    let file = block_on(create).unwrap();
+    assert_eq!(block_on(write), Ok(5));
+    assert_eq!(block_on(fs.read_node(file.0, 1, 16)), Ok(b"sync".to_vec()));
+    assert_eq!(block_on(fs.node_len(file.0)), Ok(5));
+    assert_eq!(block_on(fs.find_node("missing", root)).err(), Some(Error::NotFound));
+    assert_eq!(block_on(fs.child_nodes(root)).unwrap().len(), 1);
+
+    let mut fs = fs.into_inner();
+    assert_eq!(fs.node_len(file.0), Ok(5));
+}
@@ -0,0 +1,122 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{cmp, env, fs, io, process};
+
+use redoxfs::{BLOCK_SIZE, DiskFile, DiskVerity, FileSystem, archive_at_progress, archive_fat, archive_tar_progress};
+
+fn usage() {
+    println!("redoxfs-archive [--tar | --fat FAT_IMAGE] [--verity] DISK [DIRECTORY]");
+    println!("    copies DIRECTORY, a tar stream on stdin with --tar, or a FAT32 image such as an");
+    println!("    EFI system partition with --fat, into the root of DISK");
+    println!("    --verity writes a hash tree of the result after the filesystem, for mounting with --verity");
+}
+
+/// Print how far a long operation got over the last line printed by it on stderr
+fn progress(what: &str, done: u64, total: Option<u64>) {
+    match total {
+        Some(total) if total > 0 => eprint!("\r{}: {}%, {} of {} MB", what, cmp::min(done * 100/total, 100), done/1000/1000, total/1000/1000),
+        _ => eprint!("\r{}: {} MB", what, done/1000/1000)
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let mut tar = false;
+    let mut fat_path_opt = None;
+    let mut verity = false;
+    let mut disk_path_opt = None;
+    let mut dir_path_opt = None;
+    while let Some(arg) = args.next() {
+        if arg == "--tar" {
+            tar = true;
+        } else if arg == "--fat" {
+            match args.next() {
+                Some(path) => fat_path_opt = Some(path),
+                None => {
+                    println!("redoxfs-archive: no FAT image provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--verity" {
+            verity = true;
+        } else if disk_path_opt.is_none() {
+            disk_path_opt = Some(arg);
+        } else if dir_path_opt.is_none() {
+            dir_path_opt = Some(arg);
+        } else {
+            println!("redoxfs-archive: unexpected argument '{}'", arg);
+            usage();
+            process::exit(1);
+        }
+    }
+
+    let disk_path = if let Some(path) = disk_path_opt {
+        path
+    } else {
+        println!("redoxfs-archive: no disk image provided");
+        usage();
+        process::exit(1);
+    };
+
+    if tar as usize + fat_path_opt.is_some() as usize + dir_path_opt.is_some() as usize != 1 {
+        println!("redoxfs-archive: provide one of a directory, --tar or --fat");
+        usage();
+        process::exit(1);
+    }
+
+    let disk = match DiskFile::open(&disk_path) {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("redoxfs-archive: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let mut fs = match FileSystem::open_notify(disk, Box::new(|notice| println!("redoxfs-archive: {}", notice))) {
+        Ok(fs) => fs,
+        Err(err) => {
+            println!("redoxfs-archive: failed to open filesystem on {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let root = fs.header.1.root;
+    let res = if let Some(ref dir_path) = dir_path_opt {
+        let res = archive_at_progress(&mut fs, dir_path, root, &mut |done, total| progress("redoxfs-archive: copying", done, total));
+        eprintln!();
+        res
+    } else if let Some(ref fat_path) = fat_path_opt {
+        fs::File::open(fat_path).and_then(|image| archive_fat(&mut fs, image, root))
+    } else {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        let res = archive_tar_progress(&mut fs, &mut stdin, root, &mut |done, total| progress("redoxfs-archive: copying", done, total));
+        eprintln!();
+        res
+    };
+
+    if let Err(err) = res.and_then(|_| fs.sync_all().map_err(io::Error::from)) {
+        println!("redoxfs-archive: failed to archive into {}: {}", disk_path, err);
+        process::exit(1);
+    }
+
+    if verity {
+        let data_blocks = fs.header.1.size/BLOCK_SIZE;
+        let res = DiskVerity::create_progress(&mut fs.disk, data_blocks, &mut |done, total| progress("redoxfs-archive: hashing", done, total));
+        eprintln!();
+        match res {
+            Ok(root) => {
+                let hex: String = root.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("redoxfs-archive: wrote hash tree to {}, root hash {}", disk_path, hex);
+            },
+            Err(err) => {
+                println!("redoxfs-archive: failed to write hash tree to {}: {}", disk_path, err);
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,352 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, fs, io, process};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use redoxfs::{DiskCache, DiskFile, FileSystem, Node};
+
+/// Name of the scratch directory the workloads run in
+const SCRATCH: &'static str = "redoxfs-bench";
+
+/// Bytes written or read at a time by the sequential workloads
+const CHUNK: usize = 1024 * 1024;
+
+/// Bytes written or read at a time by the random workloads
+const RANDOM: usize = 4096;
+
+fn usage() {
+    println!("redoxfs-bench [--size MB] [--ops COUNT] [--files COUNT] IMAGE | --dir DIRECTORY");
+    println!("    runs sequential, random 4K, and metadata workloads in a scratch directory of the filesystem");
+    println!("    on IMAGE, or of the mounted DIRECTORY, and prints their throughput and latency");
+    println!("    --size   MB written and read sequentially, 256 by default");
+    println!("    --ops    random 4K writes and reads, 4096 by default");
+    println!("    --files  files created and removed, 1000 by default");
+}
+
+/// Where the workloads run, with names relative to the scratch directory
+trait Target {
+    /// Create an empty file
+    fn create(&mut self, name: &str) -> io::Result<()>;
+    fn remove(&mut self, name: &str) -> io::Result<()>;
+    /// Choose the file the following reads and writes go to
+    fn open(&mut self, name: &str) -> io::Result<()>;
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+    /// Write everything to the disk
+    fn sync(&mut self) -> io::Result<()>;
+    /// Remove the scratch directory, which has to be empty
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// The filesystem on an image, used through the library like the mount frontends do
+struct Image {
+    fs: FileSystem<DiskCache<DiskFile>>,
+    dir: u64,
+    file: u64,
+}
+
+impl Image {
+    fn open(disk_path: &str) -> io::Result<Image> {
+        let disk = DiskFile::open(disk_path)?;
+        let mut fs = FileSystem::open(DiskCache::new(disk))?;
+        let (ctime, ctime_nsec) = now();
+        let root = fs.root();
+        let dir = fs.create_node(Node::MODE_DIR | 0o755, SCRATCH, root, ctime, ctime_nsec)?;
+        Ok(Image {
+            fs: fs,
+            dir: dir.0,
+            file: 0,
+        })
+    }
+}
+
+impl Target for Image {
+    fn create(&mut self, name: &str) -> io::Result<()> {
+        let (ctime, ctime_nsec) = now();
+        self.fs.create_node(Node::MODE_FILE | 0o644, name, self.dir, ctime, ctime_nsec)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> io::Result<()> {
+        self.fs.remove_node(Node::MODE_FILE, name, self.dir)?;
+        Ok(())
+    }
+
+    fn open(&mut self, name: &str) -> io::Result<()> {
+        self.file = self.fs.find_node(name, self.dir)?.0;
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let (mtime, mtime_nsec) = now();
+        self.fs.write_node(self.file, offset, buf, mtime, mtime_nsec)?;
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.fs.read_node(self.file, offset, buf)? < buf.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short read"));
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.fs.sync_all()?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let root = self.fs.root();
+        self.fs.remove_node(Node::MODE_DIR, SCRATCH, root)?;
+        self.fs.sync_all()?;
+        Ok(())
+    }
+}
+
+/// A mounted filesystem, used through the standard library like any program would
+struct Mounted {
+    dir: PathBuf,
+    file: Option<fs::File>,
+}
+
+impl Mounted {
+    fn open(dir_path: &str) -> io::Result<Mounted> {
+        let dir = PathBuf::from(dir_path).join(SCRATCH);
+        fs::create_dir(&dir)?;
+        Ok(Mounted {
+            dir: dir,
+            file: None,
+        })
+    }
+
+    fn file(&mut self) -> io::Result<&mut fs::File> {
+        self.file.as_mut().ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no file open"))
+    }
+}
+
+impl Target for Mounted {
+    fn create(&mut self, name: &str) -> io::Result<()> {
+        fs::OpenOptions::new().write(true).create_new(true).open(self.dir.join(name))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.dir.join(name))
+    }
+
+    fn open(&mut self, name: &str) -> io::Result<()> {
+        self.file = Some(fs::OpenOptions::new().read(true).write(true).open(self.dir.join(name))?);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(buf)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file()?.sync_all()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file = None;
+        fs::remove_dir(&self.dir)
+    }
+}
+
+fn now() -> (u64, u32) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    (time.as_secs(), time.subsec_nanos())
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1000000000.0
+}
+
+fn micros(duration: Duration) -> f64 {
+    secs(duration) * 1000000.0
+}
+
+/// A fixed xorshift sequence, so every run does the same operations
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Print the throughput of a sequential workload
+fn report_bytes(name: &str, bytes: u64, elapsed: Duration) {
+    println!("{:<16} {:>8} MB in {:>7.2} s, {:>9.1} MB/s", name, bytes/1000/1000, secs(elapsed), bytes as f64/1000.0/1000.0/secs(elapsed));
+}
+
+/// Print the rate and latencies of a workload whose operations were timed one at a time
+fn report_ops(name: &str, mut times: Vec<Duration>, elapsed: Duration) {
+    if times.is_empty() {
+        return;
+    }
+    times.sort();
+    let count = times.len();
+    println!("{:<16} {:>8} ops in {:>6.2} s, {:>9.1} ops/s, latency avg {:.1} us, p99 {:.1} us, max {:.1} us",
+             name, count, secs(elapsed), count as f64/secs(elapsed), micros(elapsed)/count as f64,
+             micros(times[count * 99/100]), micros(times[count - 1]));
+}
+
+/// Time each call of op, returning the times and the time of them all
+fn timed<F: FnMut(u64) -> io::Result<()>>(count: u64, mut op: F) -> io::Result<(Vec<Duration>, Duration)> {
+    let mut times = Vec::with_capacity(count as usize);
+    let start = Instant::now();
+    for i in 0..count {
+        let op_start = Instant::now();
+        op(i)?;
+        times.push(op_start.elapsed());
+    }
+    Ok((times, start.elapsed()))
+}
+
+fn run(target: &mut Target, size: u64, ops: u64, files: u64) -> io::Result<()> {
+    let chunks = (size + CHUNK as u64 - 1)/CHUNK as u64;
+    let size = chunks * CHUNK as u64;
+    let mut buf = vec![0; CHUNK];
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+
+    target.create("data")?;
+    target.open("data")?;
+
+    let start = Instant::now();
+    for i in 0..chunks {
+        target.write_at(i * CHUNK as u64, &buf)?;
+    }
+    target.sync()?;
+    report_bytes("sequential write", size, start.elapsed());
+
+    let start = Instant::now();
+    for i in 0..chunks {
+        target.read_at(i * CHUNK as u64, &mut buf)?;
+    }
+    report_bytes("sequential read", size, start.elapsed());
+
+    // Random operations are aligned, and stay within the data written above
+    let slots = size/RANDOM as u64;
+    let mut random = Random(0x9E3779B97F4A7C15);
+    let (times, elapsed) = timed(ops, |_| {
+        let offset = random.next() % slots * RANDOM as u64;
+        target.write_at(offset, &buf[..RANDOM])
+    })?;
+    target.sync()?;
+    report_ops("random 4K write", times, elapsed);
+
+    let (times, elapsed) = timed(ops, |_| {
+        let offset = random.next() % slots * RANDOM as u64;
+        target.read_at(offset, &mut buf[..RANDOM])
+    })?;
+    report_ops("random 4K read", times, elapsed);
+
+    target.remove("data")?;
+
+    let (times, elapsed) = timed(files, |i| target.create(&format!("file{}", i)))?;
+    report_ops("create", times, elapsed);
+
+    let (times, elapsed) = timed(files, |i| target.remove(&format!("file{}", i)))?;
+    report_ops("remove", times, elapsed);
+
+    Ok(())
+}
+
+fn parse_count(args: &mut Iterator<Item=String>, name: &str) -> u64 {
+    match args.next().map(|arg| arg.parse::<u64>()) {
+        Some(Ok(count)) if count > 0 => count,
+        Some(Ok(_)) => {
+            println!("redoxfs-bench: {} must be at least 1", name);
+            process::exit(1);
+        },
+        Some(Err(err)) => {
+            println!("redoxfs-bench: invalid {}: {}", name, err);
+            usage();
+            process::exit(1);
+        },
+        None => {
+            println!("redoxfs-bench: no {} provided", name);
+            usage();
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let mut size = 256 * 1000 * 1000;
+    let mut ops = 4096;
+    let mut files = 1000;
+    let mut dir_path_opt = None;
+    let mut disk_path_opt = None;
+    while let Some(arg) = args.next() {
+        if arg == "--size" {
+            size = parse_count(&mut args, "size") * 1000 * 1000;
+        } else if arg == "--ops" {
+            ops = parse_count(&mut args, "ops");
+        } else if arg == "--files" {
+            files = parse_count(&mut args, "files");
+        } else if arg == "--dir" {
+            match args.next() {
+                Some(path) => dir_path_opt = Some(path),
+                None => {
+                    println!("redoxfs-bench: no directory provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if disk_path_opt.is_none() {
+            disk_path_opt = Some(arg);
+        } else {
+            println!("redoxfs-bench: unexpected argument '{}'", arg);
+            usage();
+            process::exit(1);
+        }
+    }
+
+    let res = match (dir_path_opt, disk_path_opt) {
+        (Some(dir_path), None) => Mounted::open(&dir_path).map(|target| (dir_path, Box::new(target) as Box<Target>)),
+        (None, Some(disk_path)) => Image::open(&disk_path).map(|target| (disk_path, Box::new(target) as Box<Target>)),
+        _ => {
+            println!("redoxfs-bench: provide one of an image or --dir");
+            usage();
+            process::exit(1);
+        }
+    };
+    let (path, mut target) = match res {
+        Ok(res) => res,
+        Err(err) => {
+            println!("redoxfs-bench: failed to create a scratch directory: {}", err);
+            process::exit(1);
+        }
+    };
+
+    println!("redoxfs-bench: running in {}", path);
+    if let Err(err) = run(&mut *target, size, ops, files) {
+        println!("redoxfs-bench: failed to run the workloads, the scratch directory {} may be left behind: {}", SCRATCH, err);
+        process::exit(1);
+    }
+    if let Err(err) = target.finish() {
+        println!("redoxfs-bench: failed to remove the scratch directory {}: {}", SCRATCH, err);
+        process::exit(1);
+    }
+}
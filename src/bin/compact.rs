@@ -0,0 +1,121 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{cmp, env, fs, process};
+
+use redoxfs::{BLOCK_SIZE, DiskFile, FileSystem, compact_progress};
+
+fn usage() {
+    println!("redoxfs-compact [--report] IMAGE");
+    println!("    moves everything in use to the start of the image IMAGE and truncates it after the last used block");
+    println!("    --report  only prints the size IMAGE could be truncated to, without changing it");
+}
+
+fn filesystem_open(disk_path: &str) -> FileSystem<DiskFile> {
+    let disk = match DiskFile::open(disk_path) {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("redoxfs-compact: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    match FileSystem::open_notify(disk, Box::new(|notice| println!("redoxfs-compact: {}", notice))) {
+        Ok(fs) => fs,
+        Err(err) => {
+            println!("redoxfs-compact: failed to open filesystem on {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Print how far a long operation got over the last line printed by it on stderr
+fn progress(what: &str, done: u64, total: Option<u64>) {
+    match total {
+        Some(total) if total > 0 => eprint!("\r{}: {}%, {} of {} MB", what, cmp::min(done * 100/total, 100), done/1000/1000, total/1000/1000),
+        _ => eprint!("\r{}: {} MB", what, done/1000/1000)
+    }
+}
+
+/// Copy the filesystem into a sparse file next to the image, truncate it, and replace the image
+/// with it, returning the new size
+fn compact_image(filesystem: &mut FileSystem<DiskFile>, disk_path: &str, len: u64) -> Result<u64, String> {
+    let compact_path = format!("{}.compact", disk_path);
+    fs::File::create(&compact_path).and_then(|file| file.set_len(len)).map_err(|err| {
+        format!("failed to create {}: {}", compact_path, err)
+    })?;
+
+    let res = DiskFile::open(&compact_path).map_err(|err| {
+        format!("failed to open {}: {}", compact_path, err)
+    }).and_then(|disk| {
+        let res = compact_progress(filesystem, disk, &mut |done, total| progress("redoxfs-compact: copying", done, total));
+        eprintln!();
+        let mut new = res.map_err(|err| format!("failed to copy {}: {}", disk_path, err))?;
+        new.sync_all().map_err(|err| format!("failed to sync {}: {}", compact_path, err))?;
+        Ok(new.header.1.size)
+    }).and_then(|size| {
+        fs::OpenOptions::new().write(true).open(&compact_path).and_then(|file| file.set_len(size))
+            .and_then(|_| fs::rename(&compact_path, disk_path))
+            .map(|_| size)
+            .map_err(|err| format!("failed to replace {} with {}: {}", disk_path, compact_path, err))
+    });
+
+    if res.is_err() {
+        let _ = fs::remove_file(&compact_path);
+    }
+    res
+}
+
+fn main() {
+    let mut report = false;
+    let mut disk_path_opt = None;
+    for arg in env::args().skip(1) {
+        if arg == "--report" {
+            report = true;
+        } else if disk_path_opt.is_none() && ! arg.starts_with('-') {
+            disk_path_opt = Some(arg);
+        } else {
+            usage();
+            process::exit(1);
+        }
+    }
+    let disk_path = match disk_path_opt {
+        Some(disk_path) => disk_path,
+        None => {
+            usage();
+            process::exit(1);
+        }
+    };
+
+    let len = match fs::metadata(&disk_path) {
+        Ok(ref metadata) if metadata.is_file() => metadata.len(),
+        Ok(_) => {
+            println!("redoxfs-compact: {} is not an image file", disk_path);
+            process::exit(1);
+        },
+        Err(err) => {
+            println!("redoxfs-compact: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let mut filesystem = filesystem_open(&disk_path);
+
+    if report {
+        // Compacting needs about the blocks in use, the reserved data, and the header
+        let total = filesystem.header.1.size/BLOCK_SIZE;
+        let compacted = (total - filesystem.free_blocks()) * BLOCK_SIZE;
+        println!("redoxfs-compact: {} is {} bytes, its last used block ends at {} bytes, and it compacts to about {} bytes",
+                 disk_path, len, filesystem.used_size(), compacted);
+        return;
+    }
+
+    match compact_image(&mut filesystem, &disk_path, len) {
+        Ok(size) => println!("redoxfs-compact: compacted {} from {} to {} bytes", disk_path, len, size),
+        Err(err) => {
+            println!("redoxfs-compact: {}", err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+extern crate uuid;
+
+use std::{env, fs, process, time};
+
+use redoxfs::{DiskFile, FileSystem, convert_ext2};
+use uuid::Uuid;
+
+fn usage() {
+    println!("redoxfs-convert EXT2_IMAGE DISK");
+    println!("    creates a filesystem on DISK holding the files of an ext2 or ext3 image");
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        usage();
+        process::exit(1);
+    }
+    let (ext2_path, disk_path) = (&args[0], &args[1]);
+
+    let image = match fs::File::open(ext2_path) {
+        Ok(image) => image,
+        Err(err) => {
+            println!("redoxfs-convert: failed to open ext2 image {}: {}", ext2_path, err);
+            process::exit(1);
+        }
+    };
+
+    let disk = match DiskFile::open(disk_path) {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("redoxfs-convert: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let ctime = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap();
+    let mut filesystem = match FileSystem::create(disk, ctime.as_secs(), ctime.subsec_nanos()) {
+        Ok(filesystem) => filesystem,
+        Err(err) => {
+            println!("redoxfs-convert: failed to create filesystem on {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let root = filesystem.header.1.root;
+    if let Err(err) = convert_ext2(image, &mut filesystem, root) {
+        println!("redoxfs-convert: failed to convert {}: {}", ext2_path, err);
+        process::exit(1);
+    }
+
+    if let Err(err) = filesystem.sync_all() {
+        println!("redoxfs-convert: failed to sync {}: {}", disk_path, err);
+        process::exit(1);
+    }
+
+    let uuid = Uuid::from_bytes(&filesystem.header.1.uuid).unwrap();
+    println!("redoxfs-convert: converted {} to {}, uuid {}", ext2_path, disk_path, uuid.hyphenated());
+}
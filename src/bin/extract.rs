@@ -0,0 +1,147 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, fs, io, process};
+use std::path::Path;
+
+use redoxfs::{Difference, Disk, DiskFile, DiskQcow2, FileSystem, compare_at, extract_at, extract_fat, extract_tar};
+
+// Messages go to stderr, as stdout may carry the tar stream
+fn usage() {
+    eprintln!("redoxfs-extract [--tar | --fat FAT_IMAGE | --compare] DISK [DIRECTORY]");
+    eprintln!("    copies the root of DISK into DIRECTORY, to a tar stream on stdout with --tar, or with");
+    eprintln!("    --fat to a new FAT32 filesystem filling FAT_IMAGE, such as an EFI system partition");
+    eprintln!("    --compare changes neither, but prints how the root of DISK and DIRECTORY differ, and");
+    eprintln!("    fails if they do");
+    eprintln!("    DISK may be a raw or qcow2 image");
+}
+
+/// Print a difference found by --compare
+fn print_difference(path: &Path, difference: &Difference) {
+    let path = path.display();
+    match *difference {
+        Difference::OnlyInImage => println!("only in image: {}", path),
+        Difference::OnlyInDir => println!("only in directory: {}", path),
+        Difference::Type => println!("type differs: {}", path),
+        Difference::Mode(image, dir) => println!("mode differs: {}: {:o} in image, {:o} in directory", path, image, dir),
+        Difference::Size(image, dir) => println!("size differs: {}: {} bytes in image, {} in directory", path, image, dir),
+        Difference::Data => println!("data differs: {}", path),
+        Difference::Link(ref image, ref dir) => println!("symlink target differs: {}: {} in image, {} in directory", path,
+                                                         String::from_utf8_lossy(image), String::from_utf8_lossy(dir)),
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let mut tar = false;
+    let mut compare = false;
+    let mut fat_path_opt = None;
+    let mut disk_path_opt = None;
+    let mut dir_path_opt = None;
+    while let Some(arg) = args.next() {
+        if arg == "--tar" {
+            tar = true;
+        } else if arg == "--compare" {
+            compare = true;
+        } else if arg == "--fat" {
+            match args.next() {
+                Some(path) => fat_path_opt = Some(path),
+                None => {
+                    eprintln!("redoxfs-extract: no FAT image provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if disk_path_opt.is_none() {
+            disk_path_opt = Some(arg);
+        } else if dir_path_opt.is_none() {
+            dir_path_opt = Some(arg);
+        } else {
+            eprintln!("redoxfs-extract: unexpected argument '{}'", arg);
+            usage();
+            process::exit(1);
+        }
+    }
+
+    let disk_path = if let Some(path) = disk_path_opt {
+        path
+    } else {
+        eprintln!("redoxfs-extract: no disk image provided");
+        usage();
+        process::exit(1);
+    };
+
+    if tar as usize + fat_path_opt.is_some() as usize + dir_path_opt.is_some() as usize != 1 {
+        eprintln!("redoxfs-extract: provide one of a directory, --tar or --fat");
+        usage();
+        process::exit(1);
+    }
+    if compare && dir_path_opt.is_none() {
+        eprintln!("redoxfs-extract: --compare needs a directory");
+        usage();
+        process::exit(1);
+    }
+
+    // Comparing shares the image with read-only mounts, as it changes nothing
+    let disk_res = if DiskQcow2::probe(&disk_path).unwrap_or(false) {
+        if compare {
+            DiskQcow2::open_read_only(&disk_path)
+        } else {
+            DiskQcow2::open(&disk_path)
+        }.map(|disk| -> Box<Disk> { Box::new(disk) })
+    } else {
+        if compare {
+            DiskFile::open_read_only(&disk_path)
+        } else {
+            DiskFile::open(&disk_path)
+        }.map(|disk| -> Box<Disk> { Box::new(disk) })
+    };
+    let disk = match disk_res {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("redoxfs-extract: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let mut fs = match FileSystem::open_notify(disk, Box::new(|notice| eprintln!("redoxfs-extract: {}", notice))) {
+        Ok(fs) => fs,
+        Err(err) => {
+            eprintln!("redoxfs-extract: failed to open filesystem on {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let root = fs.header.1.root;
+    if compare {
+        let dir_path = dir_path_opt.unwrap();
+        match compare_at(&mut fs, root, &dir_path, &mut print_difference) {
+            Ok(0) => return,
+            Ok(count) => {
+                eprintln!("redoxfs-extract: {} and {} have {} differences", disk_path, dir_path, count);
+                process::exit(1);
+            },
+            Err(err) => {
+                eprintln!("redoxfs-extract: failed to compare {} with {}: {}", disk_path, dir_path, err);
+                process::exit(2);
+            }
+        }
+    }
+
+    let res = if let Some(ref dir_path) = dir_path_opt {
+        extract_at(&mut fs, root, dir_path)
+    } else if let Some(ref fat_path) = fat_path_opt {
+        fs::OpenOptions::new().write(true).open(fat_path).and_then(|image| extract_fat(&mut fs, root, image))
+    } else {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        extract_tar(&mut fs, root, &mut stdout)
+    };
+
+    if let Err(err) = res {
+        eprintln!("redoxfs-extract: failed to extract from {}: {}", disk_path, err);
+        process::exit(1);
+    }
+}
@@ -3,32 +3,206 @@
 extern crate redoxfs;
 extern crate uuid;
 
-use std::{env, fs, process, time};
+use std::{cmp, env, fs, process, time};
 use std::io::Read;
 
-use redoxfs::{FileSystem, DiskFile};
+use redoxfs::{BLOCK_SIZE, Disk, FileSystem, DiskFile, DiskMirror, DiskPartition, DiskSpan, DiskVerity, Header, verity_data_blocks};
 use uuid::Uuid;
 
+fn usage() {
+    println!("redoxfs-mkfs [--mirror DISK | --span DISK]... [--block-size BYTES] [--case-insensitive] [--root-reserve PERCENT] [--subvol NAME | --default-subvol NAME]... [--verity] DISK [BOOTLOADER]");
+}
+
+fn disk_open(disk_path: &str) -> DiskFile {
+    match DiskFile::open(disk_path) {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("redoxfs-mkfs: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Print how far a long operation got over the last line printed by it on stderr
+fn progress(what: &str, done: u64, total: Option<u64>) {
+    match total {
+        Some(total) if total > 0 => eprint!("\r{}: {}%, {} of {} MB", what, cmp::min(done * 100/total, 100), done/1000/1000, total/1000/1000),
+        _ => eprint!("\r{}: {} MB", what, done/1000/1000)
+    }
+}
+
+/// Write a hash tree of the first data_blocks blocks of the disk after them, for mounting
+/// with --verity
+fn verity<D: Disk>(disk: &mut D, data_blocks: u64, disk_path: &str) {
+    let res = DiskVerity::create_progress(disk, data_blocks, &mut |done, total| progress("redoxfs-mkfs: hashing", done, total));
+    eprintln!();
+    match res {
+        Ok(root) => {
+            let hex: String = root.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("redoxfs-mkfs: wrote hash tree to {}, root hash {}", disk_path, hex);
+        },
+        Err(err) => {
+            println!("redoxfs-mkfs: failed to write hash tree to {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn create<D: Disk>(mut disk: D, disk_path: &str, bootloader: &[u8], block_size: u64, flags: u64, root_reserve: u64, subvols: &[(String, bool)]) -> FileSystem<D> {
+    let (logical, physical) = disk.sector_size();
+    if logical > BLOCK_SIZE {
+        println!("redoxfs-mkfs: logical sector size {} of {} is larger than {}", logical, disk_path, BLOCK_SIZE);
+        process::exit(1);
+    }
+    if physical > block_size {
+        println!("redoxfs-mkfs: physical sector size {} of {} is larger than block size {}, use --block-size {}", physical, disk_path, block_size, physical);
+        process::exit(1);
+    }
+
+    let ctime = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap();
+    let res = FileSystem::create_block_size(disk, bootloader, block_size, ctime.as_secs(), ctime.subsec_nanos()).and_then(|mut filesystem| {
+        if flags != 0 {
+            filesystem.header.1.flags = flags;
+            filesystem.write_header()?;
+        }
+        if root_reserve != 0 {
+            let blocks = filesystem.header.1.size/BLOCK_SIZE * root_reserve/100;
+            filesystem.set_root_reserved(blocks)?;
+        }
+        for &(ref name, default) in subvols.iter() {
+            filesystem.create_subvolume(name, ctime.as_secs(), ctime.subsec_nanos())?;
+            if default {
+                filesystem.set_default_subvolume(Some(name))?;
+            }
+        }
+        Ok(filesystem)
+    });
+    match res {
+        Ok(filesystem) => {
+            let uuid = Uuid::from_bytes(&filesystem.header.1.uuid).unwrap();
+            println!("redoxfs-mkfs: created filesystem on {}, reserved {} blocks, size {} MB, block size {}, uuid {}", disk_path, filesystem.block, filesystem.header.1.size/1000/1000, block_size, uuid.hyphenated());
+            filesystem
+        },
+        Err(err) => {
+            println!("redoxfs-mkfs: failed to create filesystem on {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
     let mut args = env::args().skip(1);
 
-    let disk_path = if let Some(path) = args.next() {
+    let mut disk_path_opt = None;
+    let mut bootloader_path_opt = None;
+    let mut mirror_paths = Vec::new();
+    let mut span_paths = Vec::new();
+    let mut block_size = BLOCK_SIZE;
+    let mut flags = 0;
+    let mut root_reserve = 0;
+    let mut subvols = Vec::new();
+    let mut verity_opt = false;
+    while let Some(arg) = args.next() {
+        if arg == "--mirror" {
+            match args.next() {
+                Some(path) => mirror_paths.push(path),
+                None => {
+                    println!("redoxfs-mkfs: no mirror provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--span" {
+            match args.next() {
+                Some(path) => span_paths.push(path),
+                None => {
+                    println!("redoxfs-mkfs: no span disk provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--block-size" {
+            match args.next().map(|arg| arg.parse::<u64>()) {
+                Some(Ok(size)) => block_size = size,
+                Some(Err(err)) => {
+                    println!("redoxfs-mkfs: invalid block size: {}", err);
+                    usage();
+                    process::exit(1);
+                },
+                None => {
+                    println!("redoxfs-mkfs: no block size provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--case-insensitive" {
+            flags |= Header::FLAG_CASE_INSENSITIVE;
+        } else if arg == "--root-reserve" {
+            match args.next().map(|arg| arg.parse::<u64>()) {
+                Some(Ok(percent)) if percent <= 100 => root_reserve = percent,
+                Some(Ok(percent)) => {
+                    println!("redoxfs-mkfs: invalid root reserve: {} is more than 100 percent", percent);
+                    usage();
+                    process::exit(1);
+                },
+                Some(Err(err)) => {
+                    println!("redoxfs-mkfs: invalid root reserve: {}", err);
+                    usage();
+                    process::exit(1);
+                },
+                None => {
+                    println!("redoxfs-mkfs: no root reserve provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--subvol" || arg == "--default-subvol" {
+            match args.next() {
+                Some(name) => subvols.push((name, arg == "--default-subvol")),
+                None => {
+                    println!("redoxfs-mkfs: no subvolume provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--verity" {
+            verity_opt = true;
+        } else if disk_path_opt.is_none() {
+            disk_path_opt = Some(arg);
+        } else if bootloader_path_opt.is_none() {
+            bootloader_path_opt = Some(arg);
+        } else {
+            println!("redoxfs-mkfs: unexpected argument '{}'", arg);
+            usage();
+            process::exit(1);
+        }
+    }
+
+    let disk_path = if let Some(path) = disk_path_opt {
         path
     } else {
         println!("redoxfs-mkfs: no disk image provided");
-        println!("redoxfs-mkfs DISK [BOOTLOADER]");
+        usage();
         process::exit(1);
     };
 
-    let bootloader_path_opt = args.next();
+    if block_size < BLOCK_SIZE || ! block_size.is_power_of_two() {
+        println!("redoxfs-mkfs: block size must be a power of two of at least {} bytes", BLOCK_SIZE);
+        usage();
+        process::exit(1);
+    }
 
-    let disk = match DiskFile::open(&disk_path) {
-        Ok(disk) => disk,
-        Err(err) => {
-            println!("redoxfs-mkfs: failed to open image {}: {}", disk_path, err);
-            process::exit(1);
-        }
-    };
+    if ! mirror_paths.is_empty() && ! span_paths.is_empty() {
+        println!("redoxfs-mkfs: cannot both mirror and span a filesystem");
+        usage();
+        process::exit(1);
+    }
+
+    if verity_opt && ! (span_paths.is_empty() && mirror_paths.is_empty()) {
+        println!("redoxfs-mkfs: cannot write a hash tree for a spanned or mirrored filesystem");
+        usage();
+        process::exit(1);
+    }
 
     let mut bootloader = vec![];
     if let Some(bootloader_path) = bootloader_path_opt {
@@ -47,15 +221,66 @@ fn main() {
         }
     };
 
-    let ctime = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap();
-    match FileSystem::create_reserved(disk, &bootloader, ctime.as_secs(), ctime.subsec_nanos()) {
-        Ok(filesystem) => {
-            let uuid = Uuid::from_bytes(&filesystem.header.1.uuid).unwrap();
-            println!("redoxfs-mkfs: created filesystem on {}, reserved {} blocks, size {} MB, uuid {}", disk_path, filesystem.block, filesystem.header.1.size/1000/1000, uuid.hyphenated());
-        },
-        Err(err) => {
-            println!("redoxfs-mkfs: failed to create filesystem on {}: {}", disk_path, err);
-            process::exit(1);
+    if ! mirror_paths.is_empty() {
+        let mut disks = vec![disk_open(&disk_path)];
+        for mirror_path in mirror_paths.iter() {
+            disks.push(disk_open(mirror_path));
+        }
+
+        let mirror = match DiskMirror::create(disks) {
+            Ok(mirror) => mirror,
+            Err(err) => {
+                println!("redoxfs-mkfs: failed to mirror disks: {}", err);
+                process::exit(1);
+            }
+        };
+
+        create(mirror, &disk_path, &bootloader, block_size, flags, root_reserve, &subvols);
+        for mirror_path in mirror_paths.iter() {
+            println!("redoxfs-mkfs: mirrored filesystem to {}", mirror_path);
+        }
+    } else if span_paths.is_empty() {
+        let mut disk = disk_open(&disk_path);
+        if verity_opt {
+            // The filesystem leaves room at the end of the disk for the hash tree
+            let data_blocks = match disk.size() {
+                Ok(size) => verity_data_blocks(size/BLOCK_SIZE),
+                Err(err) => {
+                    println!("redoxfs-mkfs: failed to get size of {}: {}", disk_path, err);
+                    process::exit(1);
+                }
+            };
+            let mut filesystem = create(DiskPartition::new(disk, 0, data_blocks), &disk_path, &bootloader, block_size, flags, root_reserve, &subvols);
+            verity(filesystem.disk.inner(), data_blocks, &disk_path);
+        } else {
+            create(disk, &disk_path, &bootloader, block_size, flags, root_reserve, &subvols);
+        }
+    } else {
+        let mut disks = vec![disk_open(&disk_path)];
+        for span_path in span_paths.iter() {
+            disks.push(disk_open(span_path));
+        }
+
+        let span = match DiskSpan::with_members(disks) {
+            Ok(span) => span,
+            Err(err) => {
+                println!("redoxfs-mkfs: failed to span disks: {}", err);
+                process::exit(1);
+            }
+        };
+
+        let mut filesystem = create(span, &disk_path, &bootloader, block_size, flags, root_reserve, &subvols);
+        let res = filesystem.disk.write_labels(&mut filesystem.header.1).and_then(|_| {
+            filesystem.write_header()
+        });
+        match res {
+            Ok(_) => for (i, span_path) in span_paths.iter().enumerate() {
+                println!("redoxfs-mkfs: spanned filesystem to {} as disk {}", span_path, i + 1);
+            },
+            Err(err) => {
+                println!("redoxfs-mkfs: failed to write span labels: {}", err);
+                process::exit(1);
+            }
         }
     }
 }
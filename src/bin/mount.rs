@@ -12,11 +12,15 @@ extern crate uuid;
 
 use std::env;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::os::unix::io::FromRawFd;
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use redoxfs::{DiskCache, DiskFile, mount};
+use redoxfs::{BLOCK_SIZE, CachePool, Disk, DiskCache, DiskFile, DiskHttp, DiskMirror, DiskNbd, DiskPartition, DiskQcow2, DiskS3, DiskSpan, DiskVerity, FileSystem, Header, NamePolicy, Notice, mount, mount_all};
+#[cfg(unix)]
+use redoxfs::DiskMmap;
 use uuid::Uuid;
 
 #[cfg(unix)]
@@ -39,13 +43,409 @@ fn pipe(pipes: &mut [usize; 2]) -> isize {
     syscall::Error::mux(syscall::pipe2(pipes, 0)) as isize
 }
 
+#[cfg(feature = "nfc")]
+fn nfc_policy() -> Result<NamePolicy, String> {
+    Ok(NamePolicy::Nfc)
+}
+
+#[cfg(not(feature = "nfc"))]
+fn nfc_policy() -> Result<NamePolicy, String> {
+    Err("built without the nfc feature".to_string())
+}
+
+/// Table of filesystems mounted by --all
+const FSTAB: &'static str = "/etc/redoxfs.fstab";
+
 fn usage() {
-    println!("redoxfs [--uuid] [disk or uuid] [mountpoint]");
+    println!("redoxfs --list [disk]...");
+    println!("redoxfs --all [--fstab file]");
+    println!("redoxfs [-f | --foreground] [--wait[=seconds]] [--mirror disk | --member disk]... [--direct | --mmap] [--case-insensitive] [--nfc] [--secure-delete] [--force] [--read-only] [--permissions] [--subvol name] [-o ro,uid=uid,gid=gid,umask=umask,permissions,autorepair,root_reserve=percent,low_space=percent[:percent]...,subvol=name,...] [--verity root_hash] [--uuid | --partuuid] [disk, qcow2 image, nbd://host[:port][/export], http://host[:port]/path, s3://host[:port]/bucket/prefix, uuid, or partition uuid] [mountpoint] [[--uuid | --partuuid] disk mountpoint]...");
+}
+
+/// How long to wait for a disk to appear
+#[derive(Clone, Copy)]
+enum Wait {
+    Never,
+    Forever,
+    Seconds(u64),
+}
+
+/// Options controlling how disks are found and opened
+#[derive(Clone)]
+struct Options {
+    mirrors: Vec<String>,
+    members: Vec<String>,
+    /// Bypass the host page cache and the disk cache
+    direct: bool,
+    /// Map disk images into memory instead of using the disk cache
+    mmap: bool,
+    /// Keep looking for the disk until it appears
+    wait: Wait,
+    /// Number of blocks to cache, shared by the filesystems of one daemon
+    cache_size: usize,
+    /// Look up names ignoring case, even if the filesystem was not created that way
+    case_insensitive: bool,
+    name_policy: NamePolicy,
+    /// Overwrite freed data with zeros
+    secure_delete: bool,
+    /// Check every block against the hash tree with this root hash, and mount read-only
+    verity: Option<[u8; 32]>,
+    /// Open disk files even if another process has locked them, such as a mount that hung
+    force: bool,
+    /// Open disk files for reading only, sharing them with other read-only mounts
+    read_only: bool,
+    /// Owner and group to report for every node
+    uid: Option<u32>,
+    gid: Option<u32>,
+    /// Permission bits to clear from every node
+    umask: u16,
+    /// Check the caller's permissions for every operation, as the Redox scheme always does
+    permissions: bool,
+    /// Percentage of blocks to reserve for root, stored in the header
+    root_reserve: Option<u64>,
+    /// Name of the subvolume to mount instead of the default one
+    subvol: Option<String>,
+    /// Remove bad extents from the free list if the check on opening finds any
+    autorepair: bool,
+    /// Percentages of blocks that free space is warned about dropping below
+    low_space: Vec<u64>,
+}
+
+impl Options {
+    fn new() -> Options {
+        Options {
+            mirrors: Vec::new(),
+            members: Vec::new(),
+            direct: false,
+            mmap: false,
+            wait: Wait::Never,
+            cache_size: 65536,
+            case_insensitive: false,
+            name_policy: NamePolicy::Preserve,
+            secure_delete: false,
+            verity: None,
+            force: false,
+            read_only: false,
+            uid: None,
+            gid: None,
+            umask: 0,
+            permissions: false,
+            root_reserve: None,
+            subvol: None,
+            autorepair: false,
+            low_space: Vec::new(),
+        }
+    }
+
+    /// Apply a comma separated list of options from a table entry
+    fn parse(&mut self, list: &str) -> Result<(), String> {
+        for option in list.split(',') {
+            if option == "defaults" || option.is_empty() {
+                continue;
+            } else if option == "direct" {
+                self.direct = true;
+            } else if option == "mmap" {
+                self.mmap = true;
+            } else if option == "case_insensitive" {
+                self.case_insensitive = true;
+            } else if option == "secure_delete" {
+                self.secure_delete = true;
+            } else if option == "force" {
+                self.force = true;
+            } else if option == "ro" {
+                self.read_only = true;
+            } else if option == "rw" {
+                self.read_only = false;
+            } else if option == "permissions" {
+                self.permissions = true;
+            } else if option == "autorepair" {
+                self.autorepair = true;
+            } else if option == "nfc" {
+                self.name_policy = nfc_policy()?;
+            } else if option == "wait" {
+                self.wait = Wait::Forever;
+            } else if option.starts_with("wait=") {
+                match option[5..].parse::<u64>() {
+                    Ok(seconds) => self.wait = Wait::Seconds(seconds),
+                    Err(err) => return Err(format!("invalid wait time '{}': {}", &option[5..], err))
+                }
+            } else if option.starts_with("uid=") {
+                match option[4..].parse::<u32>() {
+                    Ok(uid) => self.uid = Some(uid),
+                    Err(err) => return Err(format!("invalid uid '{}': {}", &option[4..], err))
+                }
+            } else if option.starts_with("gid=") {
+                match option[4..].parse::<u32>() {
+                    Ok(gid) => self.gid = Some(gid),
+                    Err(err) => return Err(format!("invalid gid '{}': {}", &option[4..], err))
+                }
+            } else if option.starts_with("umask=") {
+                match u16::from_str_radix(&option[6..], 8) {
+                    Ok(umask) if umask <= 0o7777 => self.umask = umask,
+                    Ok(_) => return Err(format!("invalid umask '{}'", &option[6..])),
+                    Err(err) => return Err(format!("invalid umask '{}': {}", &option[6..], err))
+                }
+            } else if option.starts_with("root_reserve=") {
+                match option[13..].parse::<u64>() {
+                    Ok(percent) if percent <= 100 => self.root_reserve = Some(percent),
+                    Ok(_) => return Err(format!("invalid root reserve '{}'", &option[13..])),
+                    Err(err) => return Err(format!("invalid root reserve '{}': {}", &option[13..], err))
+                }
+            } else if option.starts_with("low_space=") {
+                self.low_space.clear();
+                for percent in option[10..].split(':') {
+                    match percent.parse::<u64>() {
+                        Ok(percent) if percent <= 100 => self.low_space.push(percent),
+                        Ok(_) => return Err(format!("invalid low space threshold '{}'", percent)),
+                        Err(err) => return Err(format!("invalid low space threshold '{}': {}", percent, err))
+                    }
+                }
+            } else if option.starts_with("subvol=") {
+                self.subvol = Some(option[7..].to_string());
+            } else if option.starts_with("verity=") {
+                self.verity = Some(parse_hash(&option[7..])?);
+            } else if option.starts_with("mirror=") {
+                self.mirrors.push(option[7..].to_string());
+            } else if option.starts_with("member=") {
+                self.members.push(option[7..].to_string());
+            } else {
+                return Err(format!("unknown option '{}'", option));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse the default mount options stored in a header, which can only be ones that apply to a
+/// filesystem once it is open, not to the disks it is opened from, or root_reserve, which is
+/// stored in the header already
+fn stored_options(list: &str) -> Result<Options, String> {
+    const UNSTORED: [&'static str; 11] = ["direct", "mmap", "force", "ro", "rw", "wait", "wait=", "verity=", "mirror=", "member=", "root_reserve="];
+
+    for option in list.split(',') {
+        if UNSTORED.iter().any(|unstored| option == *unstored || (unstored.ends_with('=') && option.starts_with(unstored))) {
+            return Err(format!("option '{}' cannot be stored in the filesystem", option));
+        }
+    }
+    let mut options = Options::new();
+    options.parse(list)?;
+    Ok(options)
+}
+
+/// Parse a root hash written as 64 hexadecimal digits
+fn parse_hash(hex: &str) -> Result<[u8; 32], String> {
+    let mut hash = [0; 32];
+    if hex.len() != 64 || ! hex.bytes().all(|b| (b as char).is_digit(16)) {
+        return Err(format!("invalid root hash '{}'", hex));
+    }
+    for i in 0..32 {
+        hash[i] = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|err| format!("invalid root hash '{}': {}", hex, err))?;
+    }
+    Ok(hash)
+}
+
+#[cfg(unix)]
+fn mmap_open(path: &str) -> io::Result<Box<Disk + Send>> {
+    DiskMmap::open(path).map(|disk| -> Box<Disk + Send> { Box::new(disk) })
+}
+
+#[cfg(not(unix))]
+fn mmap_open(_path: &str) -> io::Result<Box<Disk + Send>> {
+    Err(io::Error::new(io::ErrorKind::Other, "memory mapped disks are not supported on this platform"))
+}
+
+fn disk_open(path: &str, options: &Options) -> io::Result<Box<Disk + Send>> {
+    if path.starts_with("http://") {
+        // DiskHttp keeps its own chunk cache
+        DiskHttp::open(path).map(|disk| -> Box<Disk + Send> { Box::new(disk) })
+    } else if path.starts_with("s3://") {
+        DiskS3::open(path).map(|disk| -> Box<Disk + Send> { Box::new(disk) })
+    } else if path.starts_with("nbd://") {
+        DiskNbd::open(path).map(|disk| -> Box<Disk + Send> { Box::new(disk) })
+    } else if DiskQcow2::probe(path).unwrap_or(false) {
+        if options.direct || options.mmap {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "qcow2 images cannot be opened with --direct or --mmap"))
+        } else if options.read_only {
+            DiskQcow2::open_read_only(path)
+        } else {
+            DiskQcow2::open(path)
+        }.map(|disk| -> Box<Disk + Send> { Box::new(disk) })
+    } else if options.read_only {
+        if options.direct || options.mmap {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "read-only disks cannot be opened with --direct or --mmap"))
+        } else if options.force {
+            DiskFile::open_read_only_unlocked(path)
+        } else {
+            DiskFile::open_read_only(path)
+        }.map(|disk| -> Box<Disk + Send> { Box::new(disk) })
+    } else if options.mmap {
+        mmap_open(path)
+    } else if options.direct {
+        if options.force {
+            DiskFile::open_direct_unlocked(path)
+        } else {
+            DiskFile::open_direct(path)
+        }.map(|disk| -> Box<Disk + Send> { Box::new(disk) })
+    } else {
+        if options.force {
+            DiskFile::open_unlocked(path)
+        } else {
+            DiskFile::open(path)
+        }.map(|disk| -> Box<Disk + Send> { Box::new(disk) })
+    }
+}
+
+/// Find the partition with a matching GUID on a disk
+fn partition_open(mut disk: Box<Disk + Send>, partuuid: &Uuid) -> io::Result<Box<Disk + Send>> {
+    for entry in DiskPartition::gpt_entries(&mut disk)?.iter() {
+        if &entry.guid == partuuid {
+            println!("redoxfs: found partition {} at offset {}", partuuid.hyphenated(), entry.start);
+            let partition = DiskPartition::from_entry(disk, entry)?;
+            return Ok(Box::new(partition));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, format!("no partition {}", partuuid.hyphenated())))
+}
+
+fn disks_open(path: &str, partuuid_opt: Option<&Uuid>, options: &Options, pool: &CachePool) -> io::Result<Box<Disk + Send>> {
+    let mut disk = disk_open(path, options)?;
+    if let Some(partuuid) = partuuid_opt {
+        disk = partition_open(disk, partuuid)?;
+    }
+    if ! options.mirrors.is_empty() {
+        let mut disks = vec![disk];
+        for mirror in options.mirrors.iter() {
+            println!("redoxfs: opening mirror {}", mirror);
+            disks.push(disk_open(mirror, options)?);
+        }
+        let mirror = DiskMirror::open(disks).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("failed to open mirror: {}", err))
+        })?;
+        disk = Box::new(mirror);
+    }
+
+    let mut disk = span_open(disk, path, options)?;
+    if let Some(root) = options.verity {
+        disk = verity_open(disk, root)?;
+    }
+    if options.direct || options.mmap || path.starts_with("http://") {
+        Ok(disk)
+    } else {
+        Ok(Box::new(DiskCache::with_pool(disk, pool)))
+    }
+}
+
+/// Check the blocks of the filesystem against the hash tree that follows it
+fn verity_open(mut disk: Box<Disk + Send>, root: [u8; 32]) -> io::Result<Box<Disk + Send>> {
+    let (_, header) = FileSystem::probe(&mut disk)?;
+    let data_blocks = header.size/BLOCK_SIZE;
+    let verity = DiskVerity::new(disk, data_blocks, root)?;
+    println!("redoxfs: verifying {} blocks against the hash tree", data_blocks);
+    Ok(Box::new(verity))
+}
+
+/// If the disk holds the first disk of a span, find the other members by uuid
+fn span_open(mut disk: Box<Disk + Send>, path: &str, options: &Options) -> io::Result<Box<Disk + Send>> {
+    let header = match FileSystem::probe(&mut disk) {
+        Ok((_, header)) => header,
+        // Let FileSystem::open report the error
+        Err(_) => return Ok(disk)
+    };
+
+    if ! header.spanned() {
+        return Ok(disk);
+    }
+
+    let index = header.span_index;
+    if index != 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("disk {} of a span, not the first disk", index)));
+    }
+
+    let count = header.span_count as usize;
+    if count > Header::SPAN_MAX {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("span of {} disks, more than {}", count, Header::SPAN_MAX)));
+    }
+    let mut found = vec![false; count];
+    found[0] = true;
+    let mut disks = vec![disk];
+
+    let mut candidates = options.members.clone();
+    disk_paths(&mut candidates);
+    for candidate in candidates.iter() {
+        if candidate == path {
+            continue;
+        }
+
+        if let Ok(mut member) = disk_open(candidate, options) {
+            if let Ok((_, label)) = FileSystem::probe(&mut member) {
+                let index = label.span_index as usize;
+                if label.uuid == header.uuid && label.spanned() && index < count && ! found[index] {
+                    println!("redoxfs: found disk {} of span on {}", index, candidate);
+                    found[index] = true;
+                    disks.push(member);
+                }
+            }
+        }
+    }
+
+    if let Some(i) = found.iter().position(|&found| ! found) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("missing disk {} of span", i)));
+    }
+
+    // DiskSpan puts the members in the order of their labels
+    match DiskSpan::open(&header, disks) {
+        Ok(span) => Ok(Box::new(span)),
+        Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("failed to open span: {}", err)))
+    }
 }
 
 enum DiskId {
     Path(String),
     Uuid(Uuid),
+    PartUuid(Uuid),
+}
+
+impl DiskId {
+    /// Parse a path, UUID=uuid, or PARTUUID=uuid
+    fn parse(spec: &str) -> Result<DiskId, String> {
+        if spec.starts_with("UUID=") {
+            Uuid::parse_str(&spec[5..]).map(DiskId::Uuid).map_err(|err| format!("invalid uuid '{}': {}", &spec[5..], err))
+        } else if spec.starts_with("PARTUUID=") {
+            Uuid::parse_str(&spec[9..]).map(DiskId::PartUuid).map_err(|err| format!("invalid uuid '{}': {}", &spec[9..], err))
+        } else {
+            Ok(DiskId::Path(spec.to_string()))
+        }
+    }
+}
+
+/// Read a table of disks, mountpoints, and options, one filesystem per line
+fn fstab(path: &str, defaults: &Options) -> Result<Vec<(DiskId, Options, String)>, String> {
+    let mut data = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut data)).map_err(|err| format!("failed to read {}: {}", path, err))?;
+
+    let mut entries = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line
+        };
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        if fields.len() < 2 || fields.len() > 3 {
+            return Err(format!("{}:{}: expected disk, mountpoint, and options", path, i + 1));
+        }
+
+        let disk_id = DiskId::parse(fields[0]).map_err(|err| format!("{}:{}: {}", path, i + 1, err))?;
+        let mut options = defaults.clone();
+        if let Some(list) = fields.get(2) {
+            options.parse(list).map_err(|err| format!("{}:{}: {}", path, i + 1, err))?;
+        }
+        entries.push((disk_id, options, fields[1].to_string()));
+    }
+    Ok(entries)
 }
 
 #[cfg(not(target_os = "redox"))]
@@ -90,57 +490,233 @@ fn disk_paths(paths: &mut Vec<String>) {
     }
 }
 
-fn daemon(disk_id: &DiskId, mountpoint: &str, mut write: File) -> ! {
-    let mut paths = vec![];
-    let mut uuid_opt = None;
+/// Print the filesystem on a disk in the style of blkid, returning true if one was found
+fn list_disk(path: &str, partuuid_opt: Option<&Uuid>, mut disk: Box<Disk + Send>) -> bool {
+    let partuuid = match partuuid_opt {
+        Some(partuuid) => format!(" PARTUUID=\"{}\"", partuuid.hyphenated()),
+        None => String::new()
+    };
 
-    match *disk_id {
-        DiskId::Path(ref path) => {
-            paths.push(path.clone());
+    match FileSystem::probe(&mut disk) {
+        Ok((_, header)) => {
+            let version = header.version;
+            let size = header.size;
+            let label = match header.label() {
+                Ok(label) if ! label.is_empty() => format!(" LABEL=\"{}\"", label),
+                _ => String::new()
+            };
+            println!("{}:{} UUID=\"{}\" VERSION=\"{}\" SIZE=\"{}\"{} TYPE=\"redoxfs\"", path, label,
+                     Uuid::from_bytes(&header.uuid).unwrap().hyphenated(), version, size, partuuid);
+            true
         },
-        DiskId::Uuid(ref uuid) => {
-            disk_paths(&mut paths);
-            uuid_opt = Some(uuid.clone());
+        Err(redoxfs::Error::Version(version)) => {
+            println!("{}: VERSION=\"{}\"{} TYPE=\"redoxfs\"", path, version, partuuid);
+            true
         },
+        Err(_) => false
+    }
+}
+
+/// Print every filesystem found on the given disks, or on all disks if none are given
+fn list(mut paths: Vec<String>, options: &Options) -> ! {
+    if paths.is_empty() {
+        disk_paths(&mut paths);
+    }
+
+    // Only headers are read, so disks that are mounted can be listed too
+    let options = &Options {
+        force: true,
+        ..options.clone()
+    };
+
+    let mut found = false;
+    for path in paths.iter() {
+        let mut disk = match disk_open(path, options) {
+            Ok(disk) => disk,
+            Err(err) => {
+                println!("redoxfs: failed to open image {}: {}", path, err);
+                continue;
+            }
+        };
+
+        // The header probe would also find filesystems inside partitions
+        let entries = DiskPartition::gpt_entries(&mut disk).unwrap_or(Vec::new());
+        if entries.is_empty() && list_disk(path, None, disk) {
+            found = true;
+        }
+
+        for entry in entries.iter() {
+            if let Ok(disk) = disk_open(path, options) {
+                if let Ok(partition) = DiskPartition::from_entry(disk, entry) {
+                    if list_disk(path, Some(&entry.guid), Box::new(partition)) {
+                        found = true;
+                    }
+                }
+            }
+        }
     }
 
-    for path in paths {
-        println!("redoxfs: opening {}", path);
-        match DiskFile::open(&path).map(|image| DiskCache::new(image)) {
-            Ok(disk) => match redoxfs::FileSystem::open(disk) {
-                Ok(filesystem) => {
-                    println!("redoxfs: opened filesystem on {} with uuid {}", path,
-                             Uuid::from_bytes(&filesystem.header.1.uuid).unwrap().hyphenated());
+    process::exit(if found { 0 } else { 2 });
+}
+
+/// Fork a daemon to mount the filesystems, returning its status once it has mounted or failed
+fn spawn(targets: &[(DiskId, String)], options: &Options) -> i32 {
+    let mut pipes = [0; 2];
+    if pipe(&mut pipes) == 0 {
+        let mut read = unsafe { File::from_raw_fd(pipes[0]) };
+        let write = unsafe { File::from_raw_fd(pipes[1]) };
+
+        let pid = fork();
+        if pid == 0 {
+            drop(read);
 
-                    let matches = if let Some(uuid) = uuid_opt {
-                        if &filesystem.header.1.uuid == uuid.as_bytes() {
+            daemon(targets, options, Some(write));
+        } else if pid > 0 {
+            drop(write);
+
+            let mut res = [1];
+            let _ = read.read(&mut res);
+
+            res[0] as i32
+        } else {
+            panic!("redoxfs: failed to fork");
+        }
+    } else {
+        panic!("redoxfs: failed to create pipe");
+    }
+}
+
+/// Log the notices of the filesystem on path
+fn notices(path: &str) -> Box<FnMut(Notice) + Send> {
+    let path = path.to_string();
+    Box::new(move |notice| println!("redoxfs: filesystem on {}: {}", path, notice))
+}
+
+/// Find and open the filesystem identified by disk_id, waiting for it to appear if requested
+fn filesystem_open(disk_id: &DiskId, options: &Options, pool: &CachePool) -> Option<(String, FileSystem<Box<Disk + Send>>)> {
+    let deadline = match options.wait {
+        Wait::Never => Some(Instant::now()),
+        Wait::Forever => None,
+        Wait::Seconds(seconds) => Some(Instant::now() + Duration::from_secs(seconds)),
+    };
+
+    loop {
+        let mut paths = vec![];
+        let mut uuid_opt = None;
+        let mut partuuid_opt = None;
+
+        match *disk_id {
+            DiskId::Path(ref path) => {
+                paths.push(path.clone());
+            },
+            DiskId::Uuid(ref uuid) => {
+                disk_paths(&mut paths);
+                uuid_opt = Some(uuid.clone());
+            },
+            DiskId::PartUuid(ref partuuid) => {
+                disk_paths(&mut paths);
+                partuuid_opt = Some(partuuid);
+            },
+        }
+
+        for path in paths {
+            println!("redoxfs: opening {}", path);
+            match disks_open(&path, partuuid_opt, options, pool) {
+                Ok(mut disk) => {
+                    // The header is checked first, so that filesystems that are not the one asked
+                    // for are not written to by opening them
+                    let header = match FileSystem::probe(&mut disk) {
+                        Ok((_, header)) => header,
+                        Err(err) => {
+                            println!("redoxfs: failed to open filesystem {}: {}", path, err);
+                            continue;
+                        }
+                    };
+                    println!("redoxfs: found filesystem on {} with uuid {}", path,
+                             Uuid::from_bytes(&header.uuid).unwrap().hyphenated());
+
+                    if let Some(uuid) = uuid_opt {
+                        if &header.uuid == uuid.as_bytes() {
                             println!("redoxfs: filesystem on {} matches uuid {}", path, uuid.hyphenated());
-                            true
                         } else {
                             println!("redoxfs: filesystem on {} does not match uuid {}", path, uuid.hyphenated());
-                            false
+                            continue;
                         }
-                    } else {
-                        true
-                    };
+                    }
+
+                    // Disks cannot be locked on Redox, so one marked as mounted may be in use
+                    if cfg!(target_os = "redox") && ! options.force && header.mounted() {
+                        println!("redoxfs: filesystem on {} is marked as mounted, it may already be mounted or was not cleanly unmounted, --force mounts it anyway", path);
+                        continue;
+                    }
 
-                    if matches {
-                        match mount(filesystem, &mountpoint, || {
-                            println!("redoxfs: mounted filesystem on {} to {}", path, mountpoint);
-                            let _ = write.write(&[0]);
-                        }) {
-                            Ok(()) => {
-                                process::exit(0);
-                            },
-                            Err(err) => {
-                                println!("redoxfs: failed to mount {} to {}: {}", path, mountpoint, err);
+                    match FileSystem::open_notify(disk, notices(&path)) {
+                        Ok(mut filesystem) => {
+                            if filesystem.header.1.mounted() {
+                                println!("redoxfs: filesystem on {} was not cleanly unmounted", path);
                             }
-                        }
+                            match filesystem.quick_check(options.autorepair) {
+                                Ok(0) => (),
+                                Ok(count) => println!("redoxfs: removed {} bad extents from the free list of {}", count, path),
+                                Err(err) => {
+                                    println!("redoxfs: filesystem on {} failed its check, -o autorepair repairs the free list: {}", path, err);
+                                    continue;
+                                }
+                            }
+                            // Options given to the mount take the place of the stored ones
+                            let stored = match filesystem.header.1.mount_options() {
+                                Ok(list) => stored_options(list),
+                                Err(err) => Err(format!("{}", err))
+                            };
+                            let stored = match stored {
+                                Ok(stored) => stored,
+                                Err(err) => {
+                                    println!("redoxfs: ignoring default mount options of {}: {}", path, err);
+                                    Options::new()
+                                }
+                            };
+                            if options.case_insensitive || stored.case_insensitive {
+                                filesystem.set_case_insensitive(true);
+                            }
+                            if options.name_policy != NamePolicy::Preserve {
+                                filesystem.set_name_policy(options.name_policy);
+                            } else {
+                                filesystem.set_name_policy(stored.name_policy);
+                            }
+                            filesystem.set_secure_delete(options.secure_delete || stored.secure_delete);
+                            let umask = if options.umask != 0 { options.umask } else { stored.umask };
+                            filesystem.set_ownership(options.uid.or(stored.uid), options.gid.or(stored.gid), umask);
+                            filesystem.set_check_permissions(options.permissions || stored.permissions);
+                            if let Some(name) = options.subvol.as_ref().or(stored.subvol.as_ref()) {
+                                if let Err(err) = filesystem.set_subvolume(Some(name)) {
+                                    println!("redoxfs: failed to find subvolume {} on {}: {}", name, path, err);
+                                    continue;
+                                }
+                            }
+                            let low_space = if options.low_space.is_empty() { &stored.low_space } else { &options.low_space };
+                            let blocks = filesystem.header.1.size/BLOCK_SIZE;
+                            filesystem.set_low_space(low_space.iter().map(|percent| blocks * percent/100).collect());
+                            if let Some(percent) = options.root_reserve {
+                                let blocks = filesystem.header.1.size/BLOCK_SIZE * percent/100;
+                                if let Err(err) = filesystem.set_root_reserved(blocks) {
+                                    println!("redoxfs: failed to set root reserve on {}: {}", path, err);
+                                }
+                            }
+                            return Some((path, filesystem));
+                        },
+                        Err(err) => println!("redoxfs: failed to open filesystem {}: {}", path, err)
                     }
                 },
-                Err(err) => println!("redoxfs: failed to open filesystem {}: {}", path, err)
-            },
-            Err(err) => println!("redoxfs: failed to open image {}: {}", path, err)
+                Err(err) => println!("redoxfs: failed to open image {}: {}", path, err)
+            }
+        }
+
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => break,
+            _ => {
+                println!("redoxfs: waiting for disk to appear");
+                thread::sleep(Duration::from_secs(1));
+            }
         }
     }
 
@@ -151,17 +727,88 @@ fn daemon(disk_id: &DiskId, mountpoint: &str, mut write: File) -> ! {
         DiskId::Uuid(ref uuid) => {
             println!("redoxfs: not able to mount uuid {}", uuid.hyphenated());
         },
+        DiskId::PartUuid(ref partuuid) => {
+            println!("redoxfs: not able to mount partition uuid {}", partuuid.hyphenated());
+        },
+    }
+
+    None
+}
+
+/// Mount the filesystems, reporting success or failure to the parent process over write if it was forked
+fn daemon(targets: &[(DiskId, String)], options: &Options, mut write: Option<File>) -> ! {
+    let mut paths = Vec::new();
+    let mut filesystems = Vec::new();
+    let pool = CachePool::new(options.cache_size);
+    for &(ref disk_id, ref mountpoint) in targets.iter() {
+        match filesystem_open(disk_id, options, &pool) {
+            Some((path, filesystem)) => {
+                paths.push(path);
+                filesystems.push((filesystem, mountpoint.clone()));
+            },
+            None => {
+                if let Some(ref mut write) = write {
+                    let _ = write.write(&[1]);
+                }
+                process::exit(1);
+            }
+        }
     }
 
-    let _ = write.write(&[1]);
-    process::exit(1);
+    let count = filesystems.len();
+    let mut mounted = |i: usize| {
+        println!("redoxfs: mounted filesystem on {} to {}", paths[i], targets[i].1);
+        if i + 1 == count {
+            if let Some(ref mut write) = write {
+                let _ = write.write(&[0]);
+            }
+        }
+    };
+
+    let res = if count == 1 {
+        let (filesystem, mountpoint) = filesystems.pop().unwrap();
+        mount(filesystem, &mountpoint, || mounted(0))
+    } else {
+        mount_all(filesystems, &mut mounted)
+    };
+
+    match res {
+        Ok(()) => {
+            process::exit(0);
+        },
+        Err(err) => {
+            println!("redoxfs: failed to mount: {}", err);
+            process::exit(1);
+        }
+    }
 }
 
 fn main() {
     let mut args = env::args().skip(1);
 
-    let disk_id = match args.next() {
-        Some(arg) => if arg == "--uuid" {
+    let mut foreground = false;
+    let mut list_opt = None;
+    let mut all = false;
+    let mut fstab_path = FSTAB.to_string();
+    let mut targets: Vec<(DiskId, Option<String>)> = Vec::new();
+    let mut options = Options::new();
+    while let Some(arg) = args.next() {
+        if arg == "--list" {
+            list_opt = Some(Vec::new());
+        } else if arg == "--all" {
+            all = true;
+        } else if arg == "--fstab" {
+            match args.next() {
+                Some(arg) => fstab_path = arg,
+                None => {
+                    println!("redoxfs: no fstab provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "-f" || arg == "--foreground" {
+            foreground = true;
+        } else if arg == "--uuid" || arg == "--partuuid" {
             let uuid = match args.next() {
                 Some(arg) => match Uuid::parse_str(&arg) {
                     Ok(uuid) => uuid,
@@ -178,47 +825,153 @@ fn main() {
                 }
             };
 
-            DiskId::Uuid(uuid)
+            targets.push((if arg == "--partuuid" {
+                DiskId::PartUuid(uuid)
+            } else {
+                DiskId::Uuid(uuid)
+            }, None));
+        } else if arg == "--mirror" {
+            match args.next() {
+                Some(arg) => options.mirrors.push(arg),
+                None => {
+                    println!("redoxfs: no mirror provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--member" {
+            match args.next() {
+                Some(arg) => options.members.push(arg),
+                None => {
+                    println!("redoxfs: no member provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--direct" {
+            options.direct = true;
+        } else if arg == "--wait" {
+            options.wait = Wait::Forever;
+        } else if arg.starts_with("--wait=") {
+            options.wait = match arg[7..].parse::<u64>() {
+                Ok(seconds) => Wait::Seconds(seconds),
+                Err(err) => {
+                    println!("redoxfs: invalid wait time '{}': {}", &arg[7..], err);
+                    usage();
+                    process::exit(1);
+                }
+            };
+        } else if arg == "--mmap" {
+            options.mmap = true;
+        } else if arg == "--case-insensitive" {
+            options.case_insensitive = true;
+        } else if arg == "--secure-delete" {
+            options.secure_delete = true;
+        } else if arg == "--force" {
+            options.force = true;
+        } else if arg == "--read-only" {
+            options.read_only = true;
+        } else if arg == "--permissions" {
+            options.permissions = true;
+        } else if arg == "--subvol" {
+            match args.next() {
+                Some(arg) => options.subvol = Some(arg),
+                None => {
+                    println!("redoxfs: no subvolume provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "-o" {
+            let res = match args.next() {
+                Some(list) => options.parse(&list),
+                None => Err("no options provided".to_string())
+            };
+            if let Err(err) = res {
+                println!("redoxfs: {}", err);
+                usage();
+                process::exit(1);
+            }
+        } else if arg == "--nfc" {
+            options.name_policy = match nfc_policy() {
+                Ok(name_policy) => name_policy,
+                Err(err) => {
+                    println!("redoxfs: cannot normalize names: {}", err);
+                    process::exit(1);
+                }
+            };
+        } else if arg == "--verity" {
+            options.verity = match args.next().map(|arg| parse_hash(&arg)) {
+                Some(Ok(root)) => Some(root),
+                Some(Err(err)) => {
+                    println!("redoxfs: {}", err);
+                    usage();
+                    process::exit(1);
+                },
+                None => {
+                    println!("redoxfs: no root hash provided");
+                    usage();
+                    process::exit(1);
+                }
+            };
+        } else if let Some(ref mut list_paths) = list_opt {
+            list_paths.push(arg);
+        } else if targets.last().map_or(false, |target| target.1.is_none()) {
+            targets.last_mut().unwrap().1 = Some(arg);
         } else {
-            DiskId::Path(arg)
-        },
-        None => {
-            println!("redoxfs: no disk provided");
-            usage();
-            process::exit(1);
+            targets.push((DiskId::Path(arg), None));
         }
-    };
+    }
+
+    if let Some(list_paths) = list_opt {
+        list(list_paths, &options);
+    }
 
-    let mountpoint = match args.next() {
-        Some(arg) => arg,
-        None => {
-            println!("redoxfs: no mountpoint provided");
+    if all {
+        if foreground || ! targets.is_empty() {
+            println!("redoxfs: --all cannot be combined with a disk or --foreground");
             usage();
             process::exit(1);
         }
-    };
 
-    let mut pipes = [0; 2];
-    if pipe(&mut pipes) == 0 {
-        let mut read = unsafe { File::from_raw_fd(pipes[0]) };
-        let write = unsafe { File::from_raw_fd(pipes[1]) };
-
-        let pid = fork();
-        if pid == 0 {
-            drop(read);
+        let entries = match fstab(&fstab_path, &options) {
+            Ok(entries) => entries,
+            Err(err) => {
+                println!("redoxfs: {}", err);
+                process::exit(1);
+            }
+        };
 
-            daemon(&disk_id, &mountpoint, write);
-        } else if pid > 0 {
-            drop(write);
+        let mut status = 0;
+        for (disk_id, options, mountpoint) in entries {
+            if spawn(&[(disk_id, mountpoint)], &options) != 0 {
+                status = 1;
+            }
+        }
+        process::exit(status);
+    }
 
-            let mut res = [0];
-            read.read(&mut res).unwrap();
+    if targets.is_empty() {
+        println!("redoxfs: no disk provided");
+        usage();
+        process::exit(1);
+    }
 
-            process::exit(res[0] as i32);
-        } else {
-            panic!("redoxfs: failed to fork");
+    let mut mounts = Vec::new();
+    for (disk_id, mountpoint_opt) in targets {
+        match mountpoint_opt {
+            Some(mountpoint) => mounts.push((disk_id, mountpoint)),
+            None => {
+                println!("redoxfs: no mountpoint provided");
+                usage();
+                process::exit(1);
+            }
         }
-    } else {
-        panic!("redoxfs: failed to create pipe");
     }
+
+    if foreground {
+        daemon(&mounts, &options, None);
+    }
+
+    process::exit(spawn(&mounts, &options));
 }
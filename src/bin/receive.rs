@@ -0,0 +1,44 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, io, process};
+
+use redoxfs::{DiskFile, FileSystem, receive};
+
+fn usage() {
+    println!("redoxfs-receive DISK");
+    println!("    applies a stream from redoxfs-send on stdin to DISK, which must hold the old image");
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 1 {
+        usage();
+        process::exit(1);
+    }
+    let disk_path = &args[0];
+
+    let disk = match DiskFile::open(disk_path) {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("redoxfs-receive: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let mut fs = match FileSystem::open_notify(disk, Box::new(|notice| println!("redoxfs-receive: {}", notice))) {
+        Ok(fs) => fs,
+        Err(err) => {
+            println!("redoxfs-receive: failed to open filesystem on {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    if let Err(err) = receive(&mut fs, &mut stdin).and_then(|_| fs.sync_all().map_err(io::Error::from)) {
+        println!("redoxfs-receive: failed to receive into {}: {}", disk_path, err);
+        process::exit(1);
+    }
+}
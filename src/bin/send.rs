@@ -0,0 +1,49 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, io, process};
+
+use redoxfs::{DiskFile, FileSystem, send};
+
+// Messages go to stderr, as stdout carries the stream
+fn usage() {
+    eprintln!("redoxfs-send OLD NEW");
+    eprintln!("    writes the changes from the image OLD to the image NEW to stdout, for redoxfs-receive");
+}
+
+fn filesystem_open(disk_path: &str) -> FileSystem<DiskFile> {
+    let disk = match DiskFile::open(disk_path) {
+        Ok(disk) => disk,
+        Err(err) => {
+            eprintln!("redoxfs-send: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    match FileSystem::open_notify(disk, Box::new(|notice| eprintln!("redoxfs-send: {}", notice))) {
+        Ok(fs) => fs,
+        Err(err) => {
+            eprintln!("redoxfs-send: failed to open filesystem on {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        usage();
+        process::exit(1);
+    }
+
+    let mut old = filesystem_open(&args[0]);
+    let mut new = filesystem_open(&args[1]);
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if let Err(err) = send(&mut old, &mut new, &mut stdout) {
+        eprintln!("redoxfs-send: failed to send changes from {} to {}: {}", args[0], args[1], err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,187 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+extern crate uuid;
+
+use std::{env, process};
+
+use redoxfs::{BLOCK_SIZE, DiskFile, FileSystem, Header};
+use uuid::Uuid;
+
+fn usage() {
+    println!("redoxfs-tune [--uuid random | --uuid UUID] [--label LABEL] [--root-reserve PERCENT] [--case-insensitive | --case-sensitive] [--mount-options LIST] [--force] IMAGE");
+    println!("    changes the settings in the header of the filesystem on IMAGE, which must not be mounted, and prints them");
+    println!("    --uuid           sets a new random uuid, such as after cloning IMAGE, or the given one");
+    println!("    --label          names the filesystem, or removes its name if LABEL is empty");
+    println!("    --root-reserve   reserves PERCENT of the blocks for root");
+    println!("    --case-insensitive, --case-sensitive");
+    println!("                     looks up names ignoring case or not, which fails if names would collide");
+    println!("    --mount-options  stores a comma separated LIST of options, such as for redoxfs -o, that");
+    println!("                     mounts apply before their own");
+    println!("    --force          changes IMAGE even if it was not cleanly unmounted");
+}
+
+/// Get the value of an option, or exit if there is none
+fn value(args: &mut Iterator<Item=String>, name: &str) -> String {
+    match args.next() {
+        Some(value) => value,
+        None => {
+            println!("redoxfs-tune: no {} provided", name);
+            usage();
+            process::exit(1);
+        }
+    }
+}
+
+/// Changes to make to the header, with None for the settings that stay the same
+struct Changes {
+    uuid: Option<Uuid>,
+    label: Option<String>,
+    root_reserve: Option<u64>,
+    case_insensitive: Option<bool>,
+    mount_options: Option<String>,
+}
+
+fn tune(filesystem: &mut FileSystem<DiskFile>, changes: &Changes) -> Result<(), String> {
+    if let Some(ref uuid) = changes.uuid {
+        filesystem.header.1.uuid = *uuid.as_bytes();
+    }
+    if let Some(ref label) = changes.label {
+        filesystem.set_label(label).map_err(|err| format!("failed to set label '{}': {}", label, err))?;
+    }
+    if let Some(percent) = changes.root_reserve {
+        let blocks = filesystem.header.1.size/BLOCK_SIZE * percent/100;
+        filesystem.set_root_reserved(blocks).map_err(|err| format!("failed to set root reserve: {}", err))?;
+    }
+    if let Some(case_insensitive) = changes.case_insensitive {
+        let flags = if case_insensitive {
+            filesystem.header.1.flags | Header::FLAG_CASE_INSENSITIVE
+        } else {
+            filesystem.header.1.flags & ! Header::FLAG_CASE_INSENSITIVE
+        };
+        filesystem.set_flags(flags).map_err(|err| match err {
+            redoxfs::Error::Exists => "failed to make names case insensitive: a directory has names that only differ in case".to_string(),
+            err => format!("failed to set flags: {}", err)
+        })?;
+    }
+    if let Some(ref mount_options) = changes.mount_options {
+        filesystem.set_mount_options(mount_options).map_err(|err| format!("failed to set mount options '{}': {}", mount_options, err))?;
+    }
+    filesystem.sync_all().map_err(|err| format!("failed to write header: {}", err))
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let mut changes = Changes {
+        uuid: None,
+        label: None,
+        root_reserve: None,
+        case_insensitive: None,
+        mount_options: None,
+    };
+    let mut force = false;
+    let mut disk_path_opt = None;
+    while let Some(arg) = args.next() {
+        if arg == "--uuid" {
+            let uuid = value(&mut args, "uuid");
+            if uuid == "random" {
+                changes.uuid = Some(Uuid::new_v4());
+            } else {
+                match Uuid::parse_str(&uuid) {
+                    Ok(uuid) => changes.uuid = Some(uuid),
+                    Err(err) => {
+                        println!("redoxfs-tune: invalid uuid '{}': {}", uuid, err);
+                        usage();
+                        process::exit(1);
+                    }
+                }
+            }
+        } else if arg == "--label" {
+            changes.label = Some(value(&mut args, "label"));
+        } else if arg == "--root-reserve" {
+            match value(&mut args, "root reserve").parse::<u64>() {
+                Ok(percent) if percent <= 100 => changes.root_reserve = Some(percent),
+                Ok(percent) => {
+                    println!("redoxfs-tune: invalid root reserve: {} is more than 100 percent", percent);
+                    usage();
+                    process::exit(1);
+                },
+                Err(err) => {
+                    println!("redoxfs-tune: invalid root reserve: {}", err);
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--case-insensitive" {
+            changes.case_insensitive = Some(true);
+        } else if arg == "--case-sensitive" {
+            changes.case_insensitive = Some(false);
+        } else if arg == "--mount-options" {
+            changes.mount_options = Some(value(&mut args, "mount options"));
+        } else if arg == "--force" {
+            force = true;
+        } else if disk_path_opt.is_none() && ! arg.starts_with('-') {
+            disk_path_opt = Some(arg);
+        } else {
+            println!("redoxfs-tune: unexpected argument '{}'", arg);
+            usage();
+            process::exit(1);
+        }
+    }
+
+    let disk_path = if let Some(path) = disk_path_opt {
+        path
+    } else {
+        println!("redoxfs-tune: no disk image provided");
+        usage();
+        process::exit(1);
+    };
+
+    // The disk is locked while it is open, so this fails if it is mounted from this system
+    let mut disk = match DiskFile::open(&disk_path) {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("redoxfs-tune: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    // Opening the filesystem can already write to it, so the header is checked before
+    let header = match FileSystem::probe(&mut disk) {
+        Ok((_, header)) => header,
+        Err(err) => {
+            println!("redoxfs-tune: failed to open filesystem on {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+    if header.spanned() {
+        println!("redoxfs-tune: {} spans several disks, which would all need to be changed", disk_path);
+        process::exit(1);
+    }
+    if header.mounted() && ! force {
+        println!("redoxfs-tune: {} is mounted or was not cleanly unmounted, use --force to change it anyway", disk_path);
+        process::exit(1);
+    }
+
+    let mut filesystem = match FileSystem::open_notify(disk, Box::new(|notice| println!("redoxfs-tune: {}", notice))) {
+        Ok(filesystem) => filesystem,
+        Err(err) => {
+            println!("redoxfs-tune: failed to open filesystem on {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = tune(&mut filesystem, &changes) {
+        println!("redoxfs-tune: {}", err);
+        process::exit(1);
+    }
+
+    let header = &filesystem.header.1;
+    println!("redoxfs-tune: {}", disk_path);
+    println!("    uuid           {}", Uuid::from_bytes(&header.uuid).unwrap().hyphenated());
+    println!("    label          {}", header.label().unwrap_or("(invalid)"));
+    println!("    root reserve   {} blocks", { header.root_reserved });
+    println!("    flags          {:#x}{}", { header.flags }, if filesystem.case_insensitive() { ", case insensitive" } else { "" });
+    println!("    mount options  {}", header.mount_options().unwrap_or("(invalid)"));
+}
@@ -0,0 +1,184 @@
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+use filesystem::FileSystem;
+use progress::Progress;
+
+/// Bytes of file data copied at a time
+const CHUNK: usize = 1024 * 1024;
+
+/// Copy the data of a node
+fn copy_data<D: Disk, E: Disk>(old: &mut FileSystem<D>, old_block: u64, new: &mut FileSystem<E>, new_block: u64, progress: &mut Progress) -> Result<()> {
+    let mut buf = vec![0; CHUNK];
+    let mut offset = 0;
+    loop {
+        let count = old.read_node(old_block, offset, &mut buf)?;
+        if count == 0 {
+            return Ok(());
+        }
+        new.write_node(new_block, offset, &buf[..count], 0, 0)?;
+        offset += count as u64;
+        progress.add(count as u64);
+    }
+}
+
+/// Copy the extended attributes, mode, owner, times, and flags of a node, which is done after
+/// its data and children so they do not change the times, and flags such as immutable do not
+/// stop the copy
+fn copy_attr<D: Disk, E: Disk>(old: &mut FileSystem<D>, old_block: u64, new: &mut FileSystem<E>, new_block: u64) -> Result<()> {
+    for (name, value) in old.node_xattrs(old_block)? {
+        new.set_xattr(new_block, &name, &value, false, false)?;
+    }
+
+    let old_node = old.node(old_block)?;
+    let mut node = new.node(new_block)?;
+    node.1.mode = old_node.1.mode;
+    node.1.uid = old_node.1.uid;
+    node.1.gid = old_node.1.gid;
+    node.1.ctime = old_node.1.ctime;
+    node.1.ctime_nsec = old_node.1.ctime_nsec;
+    node.1.mtime = old_node.1.mtime;
+    node.1.mtime_nsec = old_node.1.mtime_nsec;
+    node.1.crtime = old_node.1.crtime;
+    node.1.crtime_nsec = old_node.1.crtime_nsec;
+    node.1.flags = old_node.1.flags;
+    node.1.generation = old_node.1.generation;
+    new.write_at(node.0, &node.1)?;
+    Ok(())
+}
+
+/// Copy the children of a directory node, recursively
+fn copy_dir<D: Disk, E: Disk>(old: &mut FileSystem<D>, old_parent: u64, new: &mut FileSystem<E>, new_parent: u64, progress: &mut Progress) -> Result<()> {
+    let mut children = Vec::new();
+    old.child_nodes(&mut children, old_parent)?;
+
+    for child in children {
+        let name = child.1.name().or(Err(Error::InvalidName))?;
+        let node = new.create_node(child.1.mode, name, new_parent, child.1.ctime, child.1.ctime_nsec)?;
+        progress.add(BLOCK_SIZE);
+        if child.1.is_dir() {
+            copy_dir(old, child.0, new, node.0, progress)?;
+        } else {
+            copy_data(old, child.0, new, node.0, progress)?;
+        }
+        copy_attr(old, child.0, new, node.0)?;
+    }
+
+    Ok(())
+}
+
+/// Copy a filesystem to a new one on disk, with the same reserved data, block size, uuid, and
+/// settings, and everything in use packed at its start
+///
+/// The new filesystem is shrunk to the blocks it uses, see `FileSystem::shrink`, so the disk can
+/// be truncated to the size in its header. Nodes that are open or orphaned are not copied.
+pub fn compact<D: Disk, E: Disk>(fs: &mut FileSystem<D>, disk: E) -> Result<FileSystem<E>> {
+    compact_into(fs, disk, &mut Progress::new(&mut |_, _| (), None))
+}
+
+/// Copy a filesystem like `compact`, calling progress with the bytes of nodes and data copied,
+/// and the bytes in use, which they come close to
+pub fn compact_progress<D: Disk, E: Disk>(fs: &mut FileSystem<D>, disk: E, progress: &mut FnMut(u64, Option<u64>)) -> Result<FileSystem<E>> {
+    let total = (fs.header.1.size/BLOCK_SIZE - fs.block - fs.free_blocks()) * BLOCK_SIZE;
+    let mut progress = Progress::new(progress, Some(total));
+    let new = compact_into(fs, disk, &mut progress)?;
+    progress.finish();
+    Ok(new)
+}
+
+fn compact_into<D: Disk, E: Disk>(fs: &mut FileSystem<D>, disk: E, progress: &mut Progress) -> Result<FileSystem<E>> {
+    if fs.header.1.spanned() {
+        return Err(Error::Unsupported);
+    }
+
+    let mut reserved = vec![0; (fs.block * BLOCK_SIZE) as usize];
+    fs.disk.read_at(0, &mut reserved)?;
+
+    let old_root = fs.header.1.root;
+    let root = fs.node(old_root)?;
+    let mut new = FileSystem::create_block_size(disk, &reserved, fs.header.1.block_size(), root.1.ctime, root.1.ctime_nsec)?;
+    new.header.1.uuid = fs.header.1.uuid;
+    new.header.1.flags = fs.header.1.flags;
+    new.header.1.label = fs.header.1.label;
+    new.header.1.label_len = fs.header.1.label_len;
+    new.header.1.mount_options = fs.header.1.mount_options;
+    new.header.1.mount_options_len = fs.header.1.mount_options_len;
+    new.set_case_insensitive(fs.case_insensitive());
+    new.set_root_reserved(fs.header.1.root_reserved)?;
+
+    let new_root = new.header.1.root;
+    copy_dir(fs, root.0, &mut new, new_root, progress)?;
+    copy_attr(fs, root.0, &mut new, new_root)?;
+
+    for subvolume in fs.subvolumes()? {
+        let name = subvolume.1.name().or(Err(Error::InvalidName))?;
+        let new_subvolume = new.create_subvolume(name, subvolume.1.ctime, subvolume.1.ctime_nsec)?;
+        copy_dir(fs, subvolume.0, &mut new, new_subvolume.0, progress)?;
+        copy_attr(fs, subvolume.0, &mut new, new_subvolume.0)?;
+        if subvolume.0 == fs.header.1.default_subvolume {
+            new.set_default_subvolume(Some(name))?;
+        }
+    }
+
+    new.shrink()?;
+    Ok(new)
+}
+
+#[test]
+fn compact_test() {
+    use testing::{DiskMemory, memory_fs};
+    use node::Node;
+
+    let mut fs = memory_fs(256);
+    let root = fs.header.1.root;
+    let dir = fs.create_node(Node::MODE_DIR | 0o750, "dir", root, 1, 0).unwrap();
+    let file = fs.create_node(Node::MODE_FILE | 0o640, "file", dir.0, 2, 0).unwrap();
+    fs.write_node(file.0, 0, &[1; 3 * BLOCK_SIZE as usize], 3, 0).unwrap();
+    fs.set_xattr(file.0, "user.a", b"b", true, false).unwrap();
+    fs.node_set_flags(file.0, Node::FLAG_IMMUTABLE).unwrap();
+
+    // A large file at the end of the image that is removed leaves the used blocks behind it
+    let temp = fs.create_node(Node::MODE_FILE | 0o644, "temp", root, 0, 0).unwrap();
+    fs.write_node(temp.0, 0, &vec![2; 200 * BLOCK_SIZE as usize], 0, 0).unwrap();
+    let last = fs.create_node(Node::MODE_FILE | 0o644, "last", root, 0, 0).unwrap();
+    fs.write_node(last.0, 0, b"last", 4, 0).unwrap();
+    fs.remove_node(Node::MODE_FILE, "temp", root).unwrap();
+    fs.create_subvolume("sub", 0, 0).and_then(|sub| fs.create_node(Node::MODE_FILE | 0o644, "file", sub.0, 0, 0)).unwrap();
+    fs.set_default_subvolume(Some("sub")).unwrap();
+    fs.set_label("label").unwrap();
+    fs.set_mount_options("uid=1000,low_space=10").unwrap();
+
+    let used_size = fs.used_size();
+    assert!(used_size > 200 * BLOCK_SIZE);
+
+    let mut new = compact(&mut fs, DiskMemory::new(256)).unwrap();
+    let size = new.header.1.size;
+    assert!(size < 20 * BLOCK_SIZE);
+    assert_eq!(new.used_size(), size);
+    assert_eq!(new.free_blocks(), 0);
+    assert_eq!({ new.header.1.uuid }, { fs.header.1.uuid });
+    assert_eq!(new.header.1.label(), Ok("label"));
+    assert_eq!(new.header.1.mount_options(), Ok("uid=1000,low_space=10"));
+
+    let new_root = new.header.1.root;
+    let new_dir = new.find_node("dir", new_root).unwrap();
+    assert_eq!({ new_dir.1.mode }, Node::MODE_DIR | 0o750);
+    let new_file = new.find_node("file", new_dir.0).unwrap();
+    assert_eq!({ new_file.1.flags }, Node::FLAG_IMMUTABLE);
+    assert_eq!({ new_file.1.mtime }, 3);
+    assert_eq!(new.get_xattr(new_file.0, "user.a").unwrap(), b"b".to_vec());
+    let mut data = [0; 3 * BLOCK_SIZE as usize];
+    assert_eq!(new.read_node(new_file.0, 0, &mut data).unwrap(), data.len());
+    assert!(data.iter().all(|&b| b == 1));
+    assert!(new.find_node("temp", new_root).is_err());
+    let new_sub = new.find_subvolume("sub").unwrap();
+    assert_eq!({ new.header.1.default_subvolume }, new_sub.0);
+    new.find_node("file", new_sub.0).unwrap();
+
+    // Once shrunk, the filesystem opens on a disk of just its size
+    let mut disk = new.disk;
+    disk.0.truncate(size as usize);
+    let mut fs = FileSystem::open(disk).unwrap();
+    assert_eq!({ fs.header.1.size }, size);
+    assert!(fs.allocate(1).is_err());
+}
@@ -0,0 +1,85 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::{cmp, future, thread};
+
+use disk::Disk;
+use error::Result;
+
+/// The result of an operation of an `AsyncDisk`
+pub type DiskFuture<T> = Pin<Box<Future<Output = Result<T>> + Send>>;
+
+/// A disk whose operations complete in the background, such as one on a network or behind an async runtime
+pub trait AsyncDisk: Send {
+    /// Read up to len bytes starting at block, fewer at the end of the disk
+    fn read_at(&mut self, block: u64, len: usize) -> DiskFuture<Vec<u8>>;
+    fn write_at(&mut self, block: u64, buffer: Vec<u8>) -> DiskFuture<usize>;
+    fn size(&mut self) -> DiskFuture<u64>;
+
+    /// Wait until everything written so far is on stable storage
+    fn sync(&mut self) -> DiskFuture<()> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run a future to completion on the current thread, parking it while the future is pending
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+/// A `Disk` that waits for an `AsyncDisk`, for use on a thread that is allowed to block
+///
+/// `AsyncFileSystem` runs the filesystem on such a thread of its own.
+pub struct DiskBlocking<T> {
+    inner: T,
+}
+
+impl<T: AsyncDisk> DiskBlocking<T> {
+    pub fn new(inner: T) -> Self {
+        DiskBlocking {
+            inner: inner,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncDisk> Disk for DiskBlocking<T> {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let data = block_on(self.inner.read_at(block, buffer.len()))?;
+        let count = cmp::min(data.len(), buffer.len());
+        buffer[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        block_on(self.inner.write_at(block, buffer.to_vec()))
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        block_on(self.inner.size())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        block_on(self.inner.sync())
+    }
+}
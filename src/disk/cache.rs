@@ -1,6 +1,7 @@
 use std::{cmp, ptr};
 use std::collections::{HashMap, VecDeque};
-use syscall::error::Result;
+use std::sync::{Arc, Mutex};
+use error::Result;
 
 use BLOCK_SIZE;
 use disk::Disk;
@@ -11,40 +12,40 @@ fn copy_memory(src: &[u8], dest: &mut [u8]) -> usize {
     len
 }
 
-pub struct DiskCache<T> {
-    inner: T,
-    cache: HashMap<u64, [u8; BLOCK_SIZE as usize]>,
-    order: VecDeque<u64>,
+/// Reads of at least this many bytes missing the cache are file data streamed past it, rather
+/// than copied into it, where they would push out the nodes
+const STREAM: usize = 256 * 1024;
+
+/// Cached blocks, keyed by the cache they belong to and their number
+struct Blocks {
+    blocks: HashMap<(usize, u64), [u8; BLOCK_SIZE as usize]>,
+    /// Keys of the blocks, oldest first, which may still hold the keys of a dropped cache
+    order: VecDeque<(usize, u64)>,
     size: usize,
+    /// Id of the next cache made with the pool
+    next_id: usize,
 }
 
-impl<T: Disk> DiskCache<T> {
-    pub fn new(inner: T) -> Self {
-        DiskCache {
-            inner: inner,
-            cache: HashMap::new(),
-            order: VecDeque::new(),
-            size: 65536, // 256 MB cache
-        }
-    }
+impl Blocks {
+    /// The cached copy of block i of cache id, making room for it if it is not cached yet
+    fn slot(&mut self, id: usize, i: u64) -> &mut [u8; BLOCK_SIZE as usize] {
+        if ! self.blocks.contains_key(&(id, i)) {
+            while self.blocks.len() >= self.size {
+                match self.order.pop_front() {
+                    Some(removed) => { self.blocks.remove(&removed); },
+                    None => break
+                }
+            }
 
-    fn insert(&mut self, i: u64, data: [u8; BLOCK_SIZE as usize]) {
-        while self.order.len() >= self.size {
-            let removed = self.order.pop_front().unwrap();
-            self.cache.remove(&removed);
+            self.blocks.insert((id, i), [0; BLOCK_SIZE as usize]);
+            self.order.push_back((id, i));
         }
-
-        self.cache.insert(i, data);
-        self.order.push_back(i);
+        self.blocks.get_mut(&(id, i)).unwrap()
     }
-}
-
-impl<T: Disk> Disk for DiskCache<T> {
-    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        // println!("Cache read at {}", block);
 
+    /// Copy the blocks of cache id starting at block into buffer, if all of them are cached
+    fn read_cached(&mut self, id: usize, block: u64, buffer: &mut [u8]) -> Option<usize> {
         let mut read = 0;
-        let mut failed = false;
         for i in 0..(buffer.len() + BLOCK_SIZE as usize - 1)/(BLOCK_SIZE as usize) {
             let block_i = block + i as u64;
 
@@ -52,56 +53,216 @@ impl<T: Disk> Disk for DiskCache<T> {
             let buffer_j = cmp::min(buffer_i + BLOCK_SIZE as usize, buffer.len());
             let buffer_slice = &mut buffer[buffer_i .. buffer_j];
 
-            if let Some(cache_buf) = self.cache.get_mut(&block_i) {
+            if let Some(cache_buf) = self.blocks.get_mut(&(id, block_i)) {
                 read += copy_memory(cache_buf, buffer_slice);
             }else{
-                failed = true;
-                break;
+                return None;
             }
         }
+        Some(read)
+    }
 
-        if failed {
-            self.inner.read_at(block, buffer)?;
+    /// Cache the blocks of buffer for cache id, which start at block
+    fn insert_buffer(&mut self, id: usize, block: u64, buffer: &[u8]) -> usize {
+        let mut count = 0;
+        for i in 0..(buffer.len() + BLOCK_SIZE as usize - 1)/(BLOCK_SIZE as usize) {
+            let block_i = block + i as u64;
 
-            read = 0;
-            for i in 0..(buffer.len() + BLOCK_SIZE as usize - 1)/(BLOCK_SIZE as usize) {
-                let block_i = block + i as u64;
+            let buffer_i = i * BLOCK_SIZE as usize;
+            let buffer_j = cmp::min(buffer_i + BLOCK_SIZE as usize, buffer.len());
+            let buffer_slice = &buffer[buffer_i .. buffer_j];
 
-                let buffer_i = i * BLOCK_SIZE as usize;
-                let buffer_j = cmp::min(buffer_i + BLOCK_SIZE as usize, buffer.len());
-                let buffer_slice = &buffer[buffer_i .. buffer_j];
+            count += copy_memory(buffer_slice, self.slot(id, block_i));
+        }
+        count
+    }
+}
 
-                let mut cache_buf = [0; BLOCK_SIZE as usize];
-                read += copy_memory(buffer_slice, &mut cache_buf);
-                self.insert(block_i, cache_buf);
-            }
+/// A number of blocks that several caches share, so that filesystems mounted together use one
+/// budget, and the busiest of them get the most of it
+#[derive(Clone)]
+pub struct CachePool(Arc<Mutex<Blocks>>);
+
+impl CachePool {
+    /// Create a pool holding up to size blocks
+    pub fn new(size: usize) -> CachePool {
+        CachePool(Arc::new(Mutex::new(Blocks {
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+            size: size,
+            next_id: 0,
+        })))
+    }
+}
+
+pub struct DiskCache<T> {
+    inner: T,
+    pool: CachePool,
+    /// Id of this cache in the pool
+    id: usize,
+}
+
+impl<T: Disk> DiskCache<T> {
+    pub fn new(inner: T) -> Self {
+        Self::with_size(inner, 65536) // 256 MB cache
+    }
+
+    /// Create a cache holding up to size blocks
+    pub fn with_size(inner: T, size: usize) -> Self {
+        Self::with_pool(inner, &CachePool::new(size))
+    }
+
+    /// Create a cache holding its blocks in pool, along with the other caches of the pool
+    pub fn with_pool(inner: T, pool: &CachePool) -> Self {
+        let id = {
+            let mut blocks = pool.0.lock().unwrap();
+            blocks.next_id += 1;
+            blocks.next_id - 1
+        };
+        DiskCache {
+            inner: inner,
+            pool: pool.clone(),
+            id: id,
         }
+    }
 
-        Ok(read)
+    fn read_cached(&mut self, block: u64, buffer: &mut [u8]) -> Option<usize> {
+        self.pool.0.lock().unwrap().read_cached(self.id, block, buffer)
+    }
+
+    fn insert_buffer(&mut self, block: u64, buffer: &[u8]) -> usize {
+        self.pool.0.lock().unwrap().insert_buffer(self.id, block, buffer)
+    }
+}
+
+impl<T> Drop for DiskCache<T> {
+    /// Give the blocks of this cache back to the pool
+    fn drop(&mut self) {
+        if let Ok(mut blocks) = self.pool.0.lock() {
+            let id = self.id;
+            blocks.blocks.retain(|key, _| key.0 != id);
+            blocks.order.retain(|key| key.0 != id);
+        }
+    }
+}
+
+/// Blocks taken up by a buffer
+fn blocks(buffer: &[u8]) -> u64 {
+    (buffer.len() as u64 + BLOCK_SIZE - 1)/BLOCK_SIZE
+}
+
+impl<T: Disk> Disk for DiskCache<T> {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        // println!("Cache read at {}", block);
+
+        if let Some(read) = self.read_cached(block, buffer) {
+            return Ok(read);
+        }
+
+        let count = self.inner.read_at(block, buffer)?;
+        if buffer.len() >= STREAM {
+            return Ok(count);
+        }
+        Ok(self.insert_buffer(block, buffer))
     }
 
     fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
         // println!("Cache write at {}", block);
 
         self.inner.write_at(block, buffer)?;
+        Ok(self.insert_buffer(block, buffer))
+    }
 
-        let mut written = 0;
-        for i in 0..(buffer.len() + BLOCK_SIZE as usize - 1)/(BLOCK_SIZE as usize) {
-            let block_i = block + i as u64;
+    /// Read the buffers from the cache if all of their blocks are in it, and otherwise all of them
+    /// from the disk with one request
+    fn read_vectored_at(&mut self, block: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        let mut read = 0;
+        let mut block_i = block;
+        let mut cached = true;
+        for buffer in buffers.iter_mut() {
+            match self.read_cached(block_i, buffer) {
+                Some(count) => read += count,
+                None => {
+                    cached = false;
+                    break;
+                }
+            }
+            block_i += blocks(buffer);
+        }
+        if cached {
+            return Ok(read);
+        }
 
-            let buffer_i = i * BLOCK_SIZE as usize;
-            let buffer_j = cmp::min(buffer_i + BLOCK_SIZE as usize, buffer.len());
-            let buffer_slice = &buffer[buffer_i .. buffer_j];
+        self.inner.read_vectored_at(block, buffers)?;
 
-            let mut cache_buf = [0; BLOCK_SIZE as usize];
-            written += copy_memory(buffer_slice, &mut cache_buf);
-            self.insert(block_i, cache_buf);
+        read = 0;
+        block_i = block;
+        for buffer in buffers.iter() {
+            if buffer.len() >= STREAM {
+                read += buffer.len();
+            } else {
+                read += self.insert_buffer(block_i, buffer);
+            }
+            block_i += blocks(buffer);
         }
+        Ok(read)
+    }
+
+    fn write_vectored_at(&mut self, block: u64, buffers: &[&[u8]]) -> Result<usize> {
+        self.inner.write_vectored_at(block, buffers)?;
 
+        let mut written = 0;
+        let mut block_i = block;
+        for buffer in buffers.iter() {
+            written += self.insert_buffer(block_i, buffer);
+            block_i += blocks(buffer);
+        }
         Ok(written)
     }
 
     fn size(&mut self) -> Result<u64> {
         self.inner.size()
     }
+
+    fn sector_size(&mut self) -> (u64, u64) {
+        self.inner.sector_size()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn barrier(&mut self) -> Result<()> {
+        self.inner.barrier()
+    }
+}
+
+#[test]
+fn pool_test() {
+    use disk::DiskFaulty;
+    use error::Error;
+    use testing::DiskMemory;
+
+    let pool = CachePool::new(4);
+    let mut first = DiskCache::with_pool(DiskFaulty::new(DiskMemory::new(16)), &pool);
+    let mut second = DiskCache::with_pool(DiskFaulty::new(DiskMemory::new(16)), &pool);
+    let mut buf = [0; BLOCK_SIZE as usize];
+
+    // Blocks of both caches are told apart, and come from the cache once the disk fails
+    assert_eq!(first.write_at(0, &[1; BLOCK_SIZE as usize]), Ok(buf.len()));
+    assert_eq!(second.write_at(0, &[2; BLOCK_SIZE as usize]), Ok(buf.len()));
+    first.inner.fail_read(0);
+    assert_eq!(first.read_at(0, &mut buf), Ok(buf.len()));
+    assert_eq!(buf[0], 1);
+    assert_eq!(second.read_at(0, &mut buf), Ok(buf.len()));
+    assert_eq!(buf[0], 2);
+
+    // One busy cache can take the whole pool from the other
+    assert_eq!(second.read_at(1, &mut [0; 3 * BLOCK_SIZE as usize]), Ok(3 * BLOCK_SIZE as usize));
+    assert_eq!(first.read_at(0, &mut buf), Err(Error::Io(0)));
+    assert_eq!(pool.0.lock().unwrap().blocks.len(), 4);
+
+    // Dropping a cache gives its blocks back
+    drop(second);
+    assert_eq!(pool.0.lock().unwrap().blocks.len(), 0);
 }
@@ -0,0 +1,146 @@
+use std::cmp;
+use std::collections::BTreeSet;
+
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+
+/// A disk wrapper that injects faults, for exercising error handling paths
+pub struct DiskFaulty<T> {
+    inner: T,
+    read_faults: BTreeSet<u64>,
+    write_faults: BTreeSet<u64>,
+    short_write: Option<usize>,
+    power_loss: Option<u64>,
+    writes: u64,
+}
+
+impl<T: Disk> DiskFaulty<T> {
+    pub fn new(inner: T) -> Self {
+        DiskFaulty {
+            inner: inner,
+            read_faults: BTreeSet::new(),
+            write_faults: BTreeSet::new(),
+            short_write: None,
+            power_loss: None,
+            writes: 0,
+        }
+    }
+
+    /// Fail any read that touches the given block
+    pub fn fail_read(&mut self, block: u64) {
+        self.read_faults.insert(block);
+    }
+
+    /// Fail any write that touches the given block
+    pub fn fail_write(&mut self, block: u64) {
+        self.write_faults.insert(block);
+    }
+
+    /// Write at most `len` bytes per call, reporting the short count
+    pub fn short_write(&mut self, len: usize) {
+        self.short_write = Some(len);
+    }
+
+    /// Simulate a power loss after `count` more successful writes.
+    /// Every write after that fails without reaching the inner disk
+    pub fn power_loss_after(&mut self, count: u64) {
+        self.power_loss = Some(self.writes + count);
+    }
+
+    /// Remove all configured faults
+    pub fn clear(&mut self) {
+        self.read_faults.clear();
+        self.write_faults.clear();
+        self.short_write = None;
+        self.power_loss = None;
+    }
+
+    /// Number of writes that reached the inner disk
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    pub fn inner(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn fault(faults: &BTreeSet<u64>, block: u64, len: usize) -> Option<u64> {
+        let blocks = (len as u64 + BLOCK_SIZE - 1)/BLOCK_SIZE;
+        faults.range(block..block + cmp::max(blocks, 1)).next().cloned()
+    }
+}
+
+impl<T: Disk> Disk for DiskFaulty<T> {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        if let Some(fault) = Self::fault(&self.read_faults, block, buffer.len()) {
+            return Err(Error::Io(fault));
+        }
+
+        self.inner.read_at(block, buffer)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        if let Some(power_loss) = self.power_loss {
+            if self.writes >= power_loss {
+                return Err(Error::Io(block));
+            }
+        }
+
+        if let Some(fault) = Self::fault(&self.write_faults, block, buffer.len()) {
+            return Err(Error::Io(fault));
+        }
+
+        let len = match self.short_write {
+            Some(short) => cmp::min(short, buffer.len()),
+            None => buffer.len()
+        };
+
+        let count = self.inner.write_at(block, &buffer[..len])?;
+        self.writes += 1;
+        Ok(count)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        self.inner.size()
+    }
+
+    fn sector_size(&mut self) -> (u64, u64) {
+        self.inner.sector_size()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn barrier(&mut self) -> Result<()> {
+        self.inner.barrier()
+    }
+}
+
+#[test]
+fn faulty_test() {
+    use testing::DiskMemory;
+
+    let mut disk = DiskFaulty::new(DiskMemory::new(16));
+    let mut buf = [0; BLOCK_SIZE as usize * 2];
+
+    disk.fail_read(3);
+    assert_eq!(disk.read_at(2, &mut buf), Err(Error::Io(3)));
+    assert_eq!(disk.read_at(4, &mut buf), Ok(buf.len()));
+
+    disk.short_write(100);
+    assert_eq!(disk.write_at(0, &buf), Ok(100));
+
+    disk.power_loss_after(1);
+    assert_eq!(disk.write_at(0, &buf), Ok(100));
+    assert_eq!(disk.write_at(0, &buf), Err(Error::Io(0)));
+    assert_eq!(disk.writes(), 2);
+
+    disk.clear();
+    assert_eq!(disk.write_at(0, &buf), Ok(buf.len()));
+}
@@ -1,56 +1,530 @@
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+extern crate libc;
+
+use std::cmp;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom};
-use syscall::error::{Error, Result, EIO};
+use std::io::{self, Read, Write, Seek, SeekFrom};
 
 use BLOCK_SIZE;
 use disk::Disk;
+use error::{Error, Result};
 
 macro_rules! try_disk {
-    ($expr:expr) => (match $expr {
+    ($expr:expr, $block:expr) => (match $expr {
         Ok(val) => val,
         Err(err) => {
-            eprintln!("Disk I/O Error: {}", err);
-            return Err(Error::new(EIO));
+            eprintln!("Disk I/O Error at block {}: {}", $block, err);
+            return Err(Error::Io($block));
         }
     })
 }
 
 pub struct DiskFile {
-    file: File
+    file: File,
+    /// Bounce buffer for unbuffered I/O, which must be sector aligned in memory
+    direct: Option<Vec<u8>>,
+    /// Logical and physical sector size
+    sector_size: (u64, u64),
+    /// Opened for reading only, so writes fail with `Error::ReadOnly`
+    read_only: bool,
+}
+
+/// Ask Linux for the sector sizes of a block device
+#[cfg(target_os = "linux")]
+fn query_sector_size(file: &File) -> (u64, u64) {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+
+    const BLKSSZGET: u64 = 0x1268;
+    const BLKPBSZGET: u64 = 0x127b;
+
+    match file.metadata() {
+        Ok(ref metadata) if metadata.file_type().is_block_device() => (),
+        _ => return (512, 512)
+    }
+
+    let mut logical: libc::c_int = 0;
+    let mut physical: libc::c_uint = 0;
+    unsafe {
+        if libc::ioctl(file.as_raw_fd(), BLKSSZGET as _, &mut logical) < 0 {
+            logical = 512;
+        }
+        if libc::ioctl(file.as_raw_fd(), BLKPBSZGET as _, &mut physical) < 0 {
+            physical = logical as libc::c_uint;
+        }
+    }
+    (logical as u64, cmp::max(physical, logical as libc::c_uint) as u64)
+}
+
+/// Ask the disk scheme for its sector size
+#[cfg(target_os = "redox")]
+fn query_sector_size(file: &File) -> (u64, u64) {
+    use std::os::unix::io::AsRawFd;
+    use syscall;
+
+    let mut stat = syscall::Stat::default();
+    match syscall::fstat(file.as_raw_fd() as usize, &mut stat) {
+        Ok(_) if stat.st_blksize > 0 => (stat.st_blksize as u64, stat.st_blksize as u64),
+        _ => (512, 512)
+    }
+}
+
+/// Ask macOS for the sector sizes of a disk, which is a character device for raw access
+#[cfg(target_os = "macos")]
+fn query_sector_size(file: &File) -> (u64, u64) {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+
+    const DKIOCGETBLOCKSIZE: u64 = 0x40046418;
+    const DKIOCGETPHYSICALBLOCKSIZE: u64 = 0x4004644d;
+
+    match file.metadata() {
+        Ok(ref metadata) if metadata.file_type().is_block_device() || metadata.file_type().is_char_device() => (),
+        _ => return (512, 512)
+    }
+
+    let mut logical: u32 = 0;
+    let mut physical: u32 = 0;
+    unsafe {
+        if libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKSIZE as _, &mut logical) < 0 || logical == 0 {
+            logical = 512;
+        }
+        if libc::ioctl(file.as_raw_fd(), DKIOCGETPHYSICALBLOCKSIZE as _, &mut physical) < 0 {
+            physical = logical;
+        }
+    }
+    (logical as u64, cmp::max(physical, logical) as u64)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "redox")))]
+fn query_sector_size(_file: &File) -> (u64, u64) {
+    (512, 512)
+}
+
+/// Ask macOS for the size of a disk, as seeking to the end of a disk device gives 0
+#[cfg(target_os = "macos")]
+fn query_disk_size(file: &File) -> Option<u64> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+
+    const DKIOCGETBLOCKCOUNT: u64 = 0x40086419;
+
+    match file.metadata() {
+        Ok(ref metadata) if metadata.file_type().is_block_device() || metadata.file_type().is_char_device() => (),
+        _ => return None
+    }
+
+    let mut count: u64 = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKCOUNT as _, &mut count) } < 0 {
+        return None;
+    }
+    Some(count * query_sector_size(file).0)
+}
+
+#[cfg(not(target_os = "macos"))]
+fn query_disk_size(_file: &File) -> Option<u64> {
+    None
 }
 
 impl DiskFile {
-    pub fn open(path: &str) -> Result<DiskFile> {
-        let file = try_disk!(OpenOptions::new().read(true).write(true).open(path));
+    /// Open a disk, taking an exclusive lock on it that fails if another process has one
+    pub fn open(path: &str) -> io::Result<DiskFile> {
+        let disk = DiskFile::open_unlocked(path)?;
+        disk.lock()?;
+        Ok(disk)
+    }
+
+    /// Open a disk bypassing the host page cache, taking an exclusive lock on it like `open`
+    pub fn open_direct(path: &str) -> io::Result<DiskFile> {
+        let disk = DiskFile::open_direct_unlocked(path)?;
+        disk.lock()?;
+        Ok(disk)
+    }
+
+    /// Open a disk for reading only, taking a shared lock on it that other read-only opens can
+    /// also take, but that fails if another process has an exclusive one
+    pub fn open_read_only(path: &str) -> io::Result<DiskFile> {
+        let disk = DiskFile::open_read_only_unlocked(path)?;
+        disk.lock_shared()?;
+        Ok(disk)
+    }
+
+    /// Open a disk for reading only without locking it
+    pub fn open_read_only_unlocked(path: &str) -> io::Result<DiskFile> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let sector_size = query_sector_size(&file);
+        Ok(DiskFile {
+            file: file,
+            direct: None,
+            sector_size: sector_size,
+            read_only: true,
+        })
+    }
+
+    /// Open a disk without locking it, even if another process is using it
+    pub fn open_unlocked(path: &str) -> io::Result<DiskFile> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let sector_size = query_sector_size(&file);
         Ok(DiskFile {
-            file: file
+            file: file,
+            direct: None,
+            sector_size: sector_size,
+            read_only: false,
         })
     }
 
-    pub fn create(path: &str, size: u64) -> Result<DiskFile> {
-        let file = try_disk!(OpenOptions::new().read(true).write(true).create(true).open(path));
-        try_disk!(file.set_len(size));
+    /// Open a disk without locking it, bypassing the host page cache with `O_DIRECT`
+    #[cfg(target_os = "linux")]
+    pub fn open_direct_unlocked(path: &str) -> io::Result<DiskFile> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = OpenOptions::new().read(true).write(true).custom_flags(libc::O_DIRECT).open(path)?;
+        let sector_size = query_sector_size(&file);
+        if sector_size.0 > BLOCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("logical sector size {} is larger than {}", sector_size.0, BLOCK_SIZE)));
+        }
         Ok(DiskFile {
-            file: file
+            file: file,
+            direct: Some(Vec::new()),
+            sector_size: sector_size,
+            read_only: false,
         })
     }
+
+    /// Open a disk without locking it, bypassing the host page cache with `F_NOCACHE`, the macOS take on `O_DIRECT`
+    #[cfg(target_os = "macos")]
+    pub fn open_direct_unlocked(path: &str) -> io::Result<DiskFile> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sector_size = query_sector_size(&file);
+        if sector_size.0 > BLOCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("logical sector size {} is larger than {}", sector_size.0, BLOCK_SIZE)));
+        }
+        Ok(DiskFile {
+            file: file,
+            direct: Some(Vec::new()),
+            sector_size: sector_size,
+            read_only: false,
+        })
+    }
+
+    /// Open a disk without locking it, bypassing the host page cache with `O_DIRECT`
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn open_direct_unlocked(_path: &str) -> io::Result<DiskFile> {
+        Err(io::Error::new(io::ErrorKind::Other, "O_DIRECT is not supported on this platform"))
+    }
+
+    /// Create an image of size bytes, or resize an existing one, locking it like `open`
+    pub fn create(path: &str, size: u64) -> io::Result<DiskFile> {
+        let file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        let disk = DiskFile {
+            file: file,
+            direct: None,
+            sector_size: (512, 512),
+            read_only: false,
+        };
+        disk.lock()?;
+        disk.file.set_len(size)?;
+        Ok(disk)
+    }
+
+    /// The open file, for image formats such as qcow2 that access it at byte offsets
+    pub fn file_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Take an exclusive lock on the disk until it is closed, failing if another process has one
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn lock(&self) -> io::Result<()> {
+        self.flock(libc::LOCK_EX, "disk is in use, it may already be mounted")
+    }
+
+    /// Take a shared lock on the disk until it is closed, failing if another process has an
+    /// exclusive one
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn lock_shared(&self) -> io::Result<()> {
+        self.flock(libc::LOCK_SH, "disk is in use, it may already be mounted writable")
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn flock(&self, operation: libc::c_int, busy: &str) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        if unsafe { libc::flock(self.file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            Err(io::Error::new(io::ErrorKind::Other, busy))
+        } else {
+            Err(err)
+        }
+    }
+
+    /// Read into or write from all of the buffers described by iovecs at block, with as few
+    /// calls of readv or writev as short counts allow, until they are done or the end of the file
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn vectored(&mut self, block: u64, mut iovecs: Vec<libc::iovec>, write: bool) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+
+        // At most this many buffers can be passed to one call
+        const IOV_MAX: usize = 1024;
+
+        self.file.seek(SeekFrom::Start(block * BLOCK_SIZE))?;
+        let mut total = 0;
+        let mut start = 0;
+        while start < iovecs.len() {
+            let count = cmp::min(iovecs.len() - start, IOV_MAX) as libc::c_int;
+            let res = unsafe {
+                if write {
+                    libc::writev(self.file.as_raw_fd(), iovecs[start..].as_ptr(), count)
+                } else {
+                    libc::readv(self.file.as_raw_fd(), iovecs[start..].as_ptr(), count)
+                }
+            };
+            if res < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if res == 0 {
+                break;
+            }
+
+            // Skip the buffers that are done, and the done part of the one that is not
+            let mut done = res as usize;
+            total += done;
+            while start < iovecs.len() && done >= iovecs[start].iov_len {
+                done -= iovecs[start].iov_len;
+                start += 1;
+            }
+            if done > 0 {
+                iovecs[start].iov_base = (iovecs[start].iov_base as usize + done) as *mut libc::c_void;
+                iovecs[start].iov_len -= done;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Take an exclusive lock on the disk, which is not supported on this platform and always
+    /// succeeds. On Redox, `redoxfs` refuses filesystems marked as mounted in their header
+    /// instead, unless it is forced.
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn lock(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Take a shared lock on the disk, which is not supported on this platform and always succeeds
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn lock_shared(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Get a block aligned slice of at least `len` bytes, rounded up to a whole block
+fn bounce(buffer: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    let len = ((len + BLOCK_SIZE as usize - 1)/BLOCK_SIZE as usize) * BLOCK_SIZE as usize;
+    if buffer.len() < len + BLOCK_SIZE as usize {
+        buffer.resize(len + BLOCK_SIZE as usize, 0);
+    }
+    let offset = (BLOCK_SIZE as usize - (buffer.as_ptr() as usize % BLOCK_SIZE as usize)) % BLOCK_SIZE as usize;
+    &mut buffer[offset..offset + len]
 }
 
 impl Disk for DiskFile {
     fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        try_disk!(self.file.seek(SeekFrom::Start(block * BLOCK_SIZE)));
-        let count = try_disk!(self.file.read(buffer));
-        Ok(count)
+        try_disk!(self.file.seek(SeekFrom::Start(block * BLOCK_SIZE)), block);
+        if let Some(ref mut direct) = self.direct {
+            let aligned = bounce(direct, buffer.len());
+            let count = cmp::min(try_disk!(self.file.read(aligned), block), buffer.len());
+            buffer[..count].copy_from_slice(&aligned[..count]);
+            Ok(count)
+        } else {
+            let count = try_disk!(self.file.read(buffer), block);
+            Ok(count)
+        }
     }
 
     fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
-        try_disk!(self.file.seek(SeekFrom::Start(block * BLOCK_SIZE)));
-        let count = try_disk!(self.file.write(buffer));
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if let Some(ref mut direct) = self.direct {
+            let aligned = bounce(direct, buffer.len());
+            if buffer.len() < aligned.len() {
+                // Preserve the rest of the last block
+                let last = aligned.len() - BLOCK_SIZE as usize;
+                let last_block = block + last as u64/BLOCK_SIZE;
+                try_disk!(self.file.seek(SeekFrom::Start(last_block * BLOCK_SIZE)), last_block);
+                try_disk!(self.file.read(&mut aligned[last..]), last_block);
+            }
+            aligned[..buffer.len()].copy_from_slice(buffer);
+
+            try_disk!(self.file.seek(SeekFrom::Start(block * BLOCK_SIZE)), block);
+            let count = cmp::min(try_disk!(self.file.write(aligned), block), buffer.len());
+            Ok(count)
+        } else {
+            try_disk!(self.file.seek(SeekFrom::Start(block * BLOCK_SIZE)), block);
+            let count = try_disk!(self.file.write(buffer), block);
+            Ok(count)
+        }
+    }
+
+    /// Read all of the buffers with one request, straight into them with readv where it is
+    /// available, or else copying them out of a buffer just as large
+    fn read_vectored_at(&mut self, block: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        if buffers.len() == 1 {
+            return self.read_at(block, buffers[0]);
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            if self.direct.is_none() {
+                let iovecs = buffers.iter_mut().map(|buffer| libc::iovec {
+                    iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: buffer.len(),
+                }).collect();
+                let count = try_disk!(self.vectored(block, iovecs, false), block);
+                return Ok(count);
+            }
+        }
+
+        let mut data = vec![0; buffers.iter().map(|buffer| buffer.len()).sum()];
+        let count = self.read_at(block, &mut data)?;
+        let mut i = 0;
+        for buffer in buffers.iter_mut() {
+            if i >= count {
+                break;
+            }
+            let len = cmp::min(buffer.len(), count - i);
+            buffer[..len].copy_from_slice(&data[i..i + len]);
+            i += buffer.len();
+        }
         Ok(count)
     }
 
+    /// Write all of the buffers with one request, straight from them with writev where it is
+    /// available, or else after copying them into a buffer just as large
+    fn write_vectored_at(&mut self, block: u64, buffers: &[&[u8]]) -> Result<usize> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if buffers.len() == 1 {
+            return self.write_at(block, buffers[0]);
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            if self.direct.is_none() {
+                let iovecs = buffers.iter().map(|buffer| libc::iovec {
+                    iov_base: buffer.as_ptr() as *mut libc::c_void,
+                    iov_len: buffer.len(),
+                }).collect();
+                let count = try_disk!(self.vectored(block, iovecs, true), block);
+                return Ok(count);
+            }
+        }
+
+        let mut data = Vec::with_capacity(buffers.iter().map(|buffer| buffer.len()).sum());
+        for buffer in buffers.iter() {
+            data.extend_from_slice(buffer);
+        }
+        self.write_at(block, &data)
+    }
+
     fn size(&mut self) -> Result<u64> {
-        let size = try_disk!(self.file.seek(SeekFrom::End(0)));
+        if let Some(size) = query_disk_size(&self.file) {
+            return Ok(size);
+        }
+        let size = try_disk!(self.file.seek(SeekFrom::End(0)), 0);
         Ok(size)
     }
+
+    fn sector_size(&mut self) -> (u64, u64) {
+        self.sector_size
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        // On a block device this also flushes the drive's write cache
+        try_disk!(self.file.sync_all(), 0);
+        Ok(())
+    }
+
+    fn barrier(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        // Later writes only depend on the data and the length of the image, not its timestamps
+        try_disk!(self.file.sync_data(), 0);
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn lock_test() {
+    use std::{env, fs};
+
+    let path = env::temp_dir().join(format!("redoxfs_lock_test_{}.img", ::std::process::id()));
+    let path = path.to_str().unwrap();
+    let disk = DiskFile::create(path, 4 * BLOCK_SIZE).unwrap();
+
+    // The lock belongs to the open file, so even this process cannot take it again
+    assert!(DiskFile::open(path).is_err());
+    assert!(DiskFile::open_unlocked(path).is_ok());
+    drop(disk);
+    DiskFile::open(path).unwrap();
+
+    // Read-only opens share the lock, which keeps writable ones out
+    let reader = DiskFile::open_read_only(path).unwrap();
+    let mut other = DiskFile::open_read_only(path).unwrap();
+    assert!(DiskFile::open(path).is_err());
+    assert_eq!(other.write_at(0, &[0; BLOCK_SIZE as usize]), Err(Error::ReadOnly));
+    drop(other);
+    drop(reader);
+
+    let disk = DiskFile::open(path).unwrap();
+    assert!(DiskFile::open_read_only(path).is_err());
+    drop(disk);
+
+    fs::remove_file(path).unwrap();
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+#[test]
+fn vectored_test() {
+    use std::{env, fs};
+
+    let path = env::temp_dir().join(format!("redoxfs_vectored_test_{}.img", ::std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut disk = DiskFile::create(path, 4 * BLOCK_SIZE).unwrap();
+
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE as usize).map(|i| i as u8).collect();
+    let (a, b) = data.split_at(BLOCK_SIZE as usize);
+    assert_eq!(disk.write_vectored_at(1, &[a, b]).unwrap(), data.len());
+
+    // Buffers split differently than they were written are filled in order
+    let mut c = vec![0; 2 * BLOCK_SIZE as usize];
+    let mut d = vec![0; 1 * BLOCK_SIZE as usize];
+    assert_eq!(disk.read_vectored_at(1, &mut [&mut c[..], &mut d[..]]).unwrap(), data.len());
+    assert_eq!(&c[..], &data[..2 * BLOCK_SIZE as usize]);
+    assert_eq!(&d[..], &data[2 * BLOCK_SIZE as usize..]);
+
+    // Reads past the end stop at it
+    let mut e = vec![0; 2 * BLOCK_SIZE as usize];
+    let mut f = vec![0; 2 * BLOCK_SIZE as usize];
+    assert_eq!(disk.read_vectored_at(2, &mut [&mut e[..], &mut f[..]]).unwrap(), 2 * BLOCK_SIZE as usize);
+    assert_eq!(&e[..], &data[BLOCK_SIZE as usize..]);
+
+    drop(disk);
+    fs::remove_file(path).unwrap();
 }
@@ -0,0 +1,312 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+
+/// Number of blocks fetched per request
+const CHUNK_BLOCKS: u64 = 64;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A read-only disk fetched from an HTTP server with range requests
+pub struct DiskHttp {
+    host: String,
+    path: String,
+    stream: Option<BufReader<TcpStream>>,
+    size: u64,
+    cache: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+    cache_size: usize,
+}
+
+impl DiskHttp {
+    /// Open a disk from a URL of the form `http://host[:port]/path`
+    pub fn open(url: &str) -> io::Result<DiskHttp> {
+        let rest = if url.starts_with("http://") {
+            &url[7..]
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "url does not start with http://"));
+        };
+
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/")
+        };
+
+        let mut disk = DiskHttp {
+            host: host.to_string(),
+            path: path.to_string(),
+            stream: None,
+            size: 0,
+            cache: HashMap::new(),
+            order: VecDeque::new(),
+            cache_size: 1024, // 256 MB cache
+        };
+
+        let (status, length, _) = disk.request("HEAD", None)?;
+        if status != 200 {
+            return Err(invalid(&format!("http: HEAD {} returned status {}", url, status)));
+        }
+        disk.size = length.ok_or(invalid("http: server did not report content length"))?;
+
+        Ok(disk)
+    }
+
+    fn connect(&mut self) -> io::Result<&mut BufReader<TcpStream>> {
+        if self.stream.is_none() {
+            let addr = if self.host.contains(':') {
+                self.host.clone()
+            } else {
+                format!("{}:80", self.host)
+            };
+            let stream = TcpStream::connect(&addr)?;
+            stream.set_nodelay(true)?;
+            self.stream = Some(BufReader::new(stream));
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    /// Send a request, returning the status, content length, and body
+    fn request(&mut self, method: &str, range: Option<(u64, u64)>) -> io::Result<(u32, Option<u64>, Vec<u8>)> {
+        // Retry once, as a kept-alive connection may have been closed by the server
+        match self.request_inner(method, range) {
+            Ok(ok) => Ok(ok),
+            Err(_) => {
+                self.stream = None;
+                let res = self.request_inner(method, range);
+                if res.is_err() {
+                    // Whatever is left of a failed response must not be read as the next one
+                    self.stream = None;
+                }
+                res
+            }
+        }
+    }
+
+    fn request_inner(&mut self, method: &str, range: Option<(u64, u64)>) -> io::Result<(u32, Option<u64>, Vec<u8>)> {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: redoxfs\r\n", method, self.path, self.host);
+        if let Some((start, end)) = range {
+            request.push_str(&format!("Range: bytes={}-{}\r\n", start, end - 1));
+        }
+        request.push_str("\r\n");
+
+        // A body is never allowed to be longer than the range asked for
+        let limit = range.map_or(self.size, |(start, end)| end - start);
+
+        let stream = self.connect()?;
+        stream.get_mut().write_all(request.as_bytes())?;
+
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        let status = line.split_whitespace().nth(1)
+            .and_then(|status| status.parse::<u32>().ok())
+            .ok_or(invalid("http: invalid status line"))?;
+
+        let mut length = None;
+        let mut close = false;
+        loop {
+            line.clear();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(invalid("http: unexpected end of headers"));
+            }
+
+            let header = line.trim_matches(|c| c == '\r' || c == '\n');
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some(i) = header.find(':') {
+                let name = header[..i].trim().to_lowercase();
+                let value = header[i + 1..].trim();
+                if name == "content-length" {
+                    length = value.parse::<u64>().ok();
+                } else if name == "connection" && value.eq_ignore_ascii_case("close") {
+                    close = true;
+                } else if name == "transfer-encoding" && ! value.eq_ignore_ascii_case("identity") {
+                    return Err(invalid("http: unsupported transfer encoding"));
+                }
+            }
+        }
+
+        let mut body = Vec::new();
+        if method != "HEAD" {
+            match length {
+                Some(length) if length > limit => {
+                    return Err(invalid("http: response is longer than the range requested"));
+                },
+                Some(length) => {
+                    body.resize(length as usize, 0);
+                    stream.read_exact(&mut body)?;
+                },
+                None => {
+                    stream.by_ref().take(limit + 1).read_to_end(&mut body)?;
+                    if body.len() as u64 > limit {
+                        return Err(invalid("http: response is longer than the range requested"));
+                    }
+                    close = true;
+                }
+            }
+        }
+
+        if close {
+            self.stream = None;
+        }
+
+        Ok((status, length, body))
+    }
+
+    fn chunk(&mut self, chunk: u64) -> io::Result<&Vec<u8>> {
+        if ! self.cache.contains_key(&chunk) {
+            let start = chunk * CHUNK_BLOCKS * BLOCK_SIZE;
+            let end = cmp::min(start + CHUNK_BLOCKS * BLOCK_SIZE, self.size);
+            if start >= end {
+                return Err(invalid("http: read past end of image"));
+            }
+
+            let (status, _, data) = self.request("GET", Some((start, end)))?;
+            if status != 206 {
+                return Err(invalid(&format!("http: range request returned status {}", status)));
+            }
+            if data.len() as u64 != end - start {
+                return Err(invalid("http: range request returned wrong length"));
+            }
+
+            while self.order.len() >= self.cache_size {
+                let removed = self.order.pop_front().unwrap();
+                self.cache.remove(&removed);
+            }
+
+            self.cache.insert(chunk, data);
+            self.order.push_back(chunk);
+        }
+
+        Ok(&self.cache[&chunk])
+    }
+}
+
+impl Disk for DiskHttp {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buffer.len() {
+            let offset = block * BLOCK_SIZE + i as u64;
+            if offset >= self.size {
+                break;
+            }
+
+            let chunk_offset = (offset % (CHUNK_BLOCKS * BLOCK_SIZE)) as usize;
+            let data = match self.chunk(offset / (CHUNK_BLOCKS * BLOCK_SIZE)) {
+                Ok(data) => data,
+                Err(err) => {
+                    eprintln!("HTTP read error at block {}: {}", block, err);
+                    return Err(Error::Io(block));
+                }
+            };
+
+            let count = cmp::min(buffer.len() - i, data.len() - chunk_offset);
+            buffer[i..i + count].copy_from_slice(&data[chunk_offset..chunk_offset + count]);
+            i += count;
+        }
+
+        Ok(i)
+    }
+
+    fn write_at(&mut self, _block: u64, _buffer: &[u8]) -> Result<usize> {
+        Err(Error::ReadOnly)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.size)
+    }
+}
+
+#[test]
+fn http_test() {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    // Serves image at a URL, counting range requests, and sizing their bodies with len
+    fn serve(image: Vec<u8>, len: fn(usize) -> usize, gets: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/image", listener.local_addr().unwrap());
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = BufReader::new(stream.unwrap());
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let head = line.starts_with("HEAD ");
+
+                let mut range = (0, image.len());
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if line.starts_with("Range: bytes=") {
+                        let mut bounds = line[13..].trim().split('-').map(|n| n.parse::<usize>().unwrap());
+                        range = (bounds.next().unwrap(), bounds.next().unwrap() + 1);
+                    }
+                }
+
+                let response = if head {
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", image.len()).into_bytes()
+                } else {
+                    gets.fetch_add(1, Ordering::SeqCst);
+                    let end = cmp::min(range.0 + len(range.1 - range.0), image.len());
+                    let body = &image[range.0..end];
+                    let mut response = format!("HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+                    response.extend_from_slice(body);
+                    response
+                };
+                stream.get_mut().write_all(&response).unwrap();
+            }
+        });
+        url
+    }
+
+    // Two whole chunks and part of a third
+    let image: Vec<u8> = (0..(2 * CHUNK_BLOCKS + 3) * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE) as u8).collect();
+    let gets = Arc::new(AtomicUsize::new(0));
+    let mut disk = DiskHttp::open(&serve(image.clone(), |n| n, gets.clone())).unwrap();
+    assert_eq!(disk.size(), Ok(image.len() as u64));
+    assert_eq!(disk.write_at(0, &[0; BLOCK_SIZE as usize]), Err(Error::ReadOnly));
+
+    // A range read of one block
+    let mut buf = vec![0; BLOCK_SIZE as usize];
+    assert_eq!(disk.read_at(5, &mut buf), Ok(buf.len()));
+    assert!(buf.iter().all(|&b| b == 5));
+    assert_eq!(gets.load(Ordering::SeqCst), 1);
+
+    // A read across a chunk boundary fetches the next chunk, and rereads come from the cache
+    let mut buf = vec![0; 2 * BLOCK_SIZE as usize];
+    for _ in 0..2 {
+        assert_eq!(disk.read_at(CHUNK_BLOCKS - 1, &mut buf), Ok(buf.len()));
+        assert!(buf[..BLOCK_SIZE as usize].iter().all(|&b| b == (CHUNK_BLOCKS - 1) as u8));
+        assert!(buf[BLOCK_SIZE as usize..].iter().all(|&b| b == CHUNK_BLOCKS as u8));
+    }
+    assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+    // Reads stop at the end of the image
+    let mut buf = vec![0; 4 * BLOCK_SIZE as usize];
+    assert_eq!(disk.read_at(2 * CHUNK_BLOCKS + 1, &mut buf), Ok(2 * BLOCK_SIZE as usize));
+    assert!(buf[..2 * BLOCK_SIZE as usize] == image[(2 * CHUNK_BLOCKS + 1) as usize * BLOCK_SIZE as usize..]);
+
+    // A server that returns less than the range asked for fails the read
+    let mut disk = DiskHttp::open(&serve(image.clone(), |n| n / 2, Arc::new(AtomicUsize::new(0)))).unwrap();
+    let mut buf = vec![0; BLOCK_SIZE as usize];
+    assert_eq!(disk.read_at(5, &mut buf), Err(Error::Io(5)));
+
+    // So does one that returns more
+    let mut disk = DiskHttp::open(&serve(image, |n| n * 2, Arc::new(AtomicUsize::new(0)))).unwrap();
+    assert_eq!(disk.read_at(5, &mut buf), Err(Error::Io(5)));
+}
@@ -0,0 +1,309 @@
+use std::cmp;
+
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+
+/// Signature of the label in the last block of every member
+const LABEL_SIGNATURE: &'static [u8; 8] = b"RedoxMir";
+/// Most members a label can mark as failed
+const MEMBERS_MAX: usize = 64;
+
+fn read_le(data: &[u8], offset: usize) -> u64 {
+    data[offset..offset + 8].iter().rev().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn write_le(data: &mut [u8], offset: usize, value: u64) {
+    for (i, b) in data[offset..offset + 8].iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+}
+
+/// A set of disks holding identical copies of the filesystem (RAID1).
+///
+/// Writes go to every healthy member. Reads are served by the first healthy
+/// member, falling back to the others when it returns an error or a short
+/// read. There are no checksums, so a member that returns wrong data without
+/// an error is not noticed; use `DiskVerity` on top to catch that.
+///
+/// The last block of every member holds a label with its number in the
+/// mirror, an event count, and the members that have failed. A member that
+/// fails a write is marked as failed in the labels of the others, and the
+/// count goes up, so when the mirror is opened again a failed member is known
+/// from the labels, or from its own label being behind, and is not used until
+/// the mirror is created again.
+pub struct DiskMirror<T> {
+    members: Vec<T>,
+    failed: Vec<bool>,
+    /// Number of each member in the mirror when it was created, which the labels refer to it by
+    numbers: Vec<u64>,
+    events: u64,
+    /// Bytes of each member that hold data, before the label
+    size: u64,
+}
+
+impl<T: Disk> DiskMirror<T> {
+    /// Set members up as a new mirror, writing a fresh label to each
+    pub fn create(members: Vec<T>) -> Result<Self> {
+        let mut mirror = DiskMirror::with_members(members)?;
+        for i in 0..mirror.members.len() {
+            mirror.write_label(i)?;
+        }
+        Ok(mirror)
+    }
+
+    /// Open the members of a mirror made with `create`, leaving out the ones its labels show
+    /// have failed
+    pub fn open(members: Vec<T>) -> Result<Self> {
+        let mut mirror = DiskMirror::with_members(members)?;
+
+        let mut labels = Vec::new();
+        for member in mirror.members.iter_mut() {
+            labels.push(read_label(member, mirror.size));
+        }
+        let newest = match labels.iter().filter_map(|label| *label).max_by_key(|label| label.1) {
+            Some(newest) => newest,
+            None => return Err(Error::Corrupt(mirror.size/BLOCK_SIZE))
+        };
+        mirror.events = newest.1;
+        // Members are found by their numbers, so they can be given in any order
+        for (i, label) in labels.iter().enumerate() {
+            let failed = match *label {
+                Some((number, events, _)) => {
+                    mirror.numbers[i] = number;
+                    events < newest.1 || newest.2 & 1 << number != 0
+                },
+                None => true
+            };
+            if failed {
+                eprintln!("Mirror member {} failed before it was opened, not using it", i);
+                mirror.failed[i] = true;
+            }
+        }
+        Ok(mirror)
+    }
+
+    fn with_members(mut members: Vec<T>) -> Result<Self> {
+        if members.is_empty() || members.len() > MEMBERS_MAX {
+            return Err(Error::Unsupported);
+        }
+
+        let mut size_opt: Option<u64> = None;
+        for member in members.iter_mut() {
+            let size = member.size()?;
+            size_opt = Some(cmp::min(size_opt.unwrap_or(size), size));
+        }
+        let blocks = size_opt.unwrap_or(0)/BLOCK_SIZE;
+        if blocks < 2 {
+            return Err(Error::NoSpace);
+        }
+
+        let failed = vec![false; members.len()];
+        let numbers = (0..members.len() as u64).collect();
+        Ok(DiskMirror {
+            members: members,
+            failed: failed,
+            numbers: numbers,
+            events: 0,
+            size: (blocks - 1) * BLOCK_SIZE,
+        })
+    }
+
+    /// Number of members that have not failed
+    pub fn healthy(&self) -> usize {
+        self.failed.iter().filter(|&&failed| ! failed).count()
+    }
+
+    pub fn members(&mut self) -> &mut [T] {
+        &mut self.members
+    }
+
+    /// Write the label of member i, with the members that have failed so far
+    fn write_label(&mut self, i: usize) -> Result<()> {
+        let mut failed = 0u64;
+        for (j, &member_failed) in self.failed.iter().enumerate() {
+            if member_failed {
+                failed |= 1 << self.numbers[j];
+            }
+        }
+
+        let mut label = [0; BLOCK_SIZE as usize];
+        label[..8].copy_from_slice(LABEL_SIGNATURE);
+        write_le(&mut label, 8, self.numbers[i]);
+        write_le(&mut label, 16, self.events);
+        write_le(&mut label, 24, failed);
+        self.members[i].write_at(self.size/BLOCK_SIZE, &label)?;
+        Ok(())
+    }
+
+    /// Mark member i as failed, recording it in the labels of the members still healthy
+    fn fail(&mut self, i: usize) {
+        self.failed[i] = true;
+        self.events += 1;
+        let mut j = 0;
+        while j < self.members.len() {
+            if ! self.failed[j] {
+                if let Err(err) = self.write_label(j) {
+                    eprintln!("Mirror member {} label write error, marking failed: {}", j, err);
+                    self.failed[j] = true;
+                    self.events += 1;
+                    // The labels written so far miss this member
+                    j = 0;
+                    continue;
+                }
+            }
+            j += 1;
+        }
+    }
+
+    /// Sync every healthy member, or only order their writes if barrier is true, marking
+    /// the members that fail as failed
+    fn flush(&mut self, barrier: bool) -> Result<()> {
+        let mut res = Err(Error::Io(0));
+        for i in 0..self.members.len() {
+            if self.failed[i] {
+                continue;
+            }
+
+            let member_res = if barrier { self.members[i].barrier() } else { self.members[i].sync() };
+            match member_res {
+                Ok(()) => res = Ok(()),
+                Err(err) => {
+                    eprintln!("Mirror member {} {} error, marking failed: {}", i, if barrier { "barrier" } else { "sync" }, err);
+                    self.fail(i);
+                }
+            }
+        }
+        res
+    }
+
+    /// Bytes of a request at block that lie before the label, at most len
+    fn clamp(&self, block: u64, len: usize) -> usize {
+        cmp::min(len as u64, self.size.saturating_sub(block * BLOCK_SIZE)) as usize
+    }
+}
+
+/// The number, event count and failed members in the label of a member, if it has one
+fn read_label<T: Disk>(member: &mut T, size: u64) -> Option<(u64, u64, u64)> {
+    let mut label = [0; BLOCK_SIZE as usize];
+    match member.read_at(size/BLOCK_SIZE, &mut label) {
+        Ok(count) if count == label.len() && &label[..8] == LABEL_SIGNATURE => {
+            let number = read_le(&label, 8);
+            if number < MEMBERS_MAX as u64 {
+                Some((number, read_le(&label, 16), read_le(&label, 24)))
+            } else {
+                None
+            }
+        },
+        _ => None
+    }
+}
+
+impl<T: Disk> Disk for DiskMirror<T> {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let len = self.clamp(block, buffer.len());
+        let buffer = &mut buffer[..len];
+        let mut res = Err(Error::Io(block));
+        for (i, member) in self.members.iter_mut().enumerate() {
+            if self.failed[i] {
+                continue;
+            }
+
+            match member.read_at(block, buffer) {
+                Ok(count) if count == len => return Ok(count),
+                Ok(count) => {
+                    eprintln!("Mirror member {} short read at block {}, {} of {} bytes", i, block, count, len);
+                    if res.as_ref().map_or(true, |&best| count > best) {
+                        res = Ok(count);
+                    }
+                },
+                Err(err) => eprintln!("Mirror member {} read error at block {}: {}", i, block, err)
+            }
+        }
+        res
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let buffer = &buffer[..self.clamp(block, buffer.len())];
+        let mut res = Err(Error::Io(block));
+        for i in 0..self.members.len() {
+            if self.failed[i] {
+                continue;
+            }
+
+            match self.members[i].write_at(block, buffer) {
+                Ok(count) => if res.is_err() {
+                    res = Ok(count);
+                },
+                // Read-only members have not failed, and no more can be written to the others
+                Err(Error::ReadOnly) => return Err(Error::ReadOnly),
+                Err(err) => {
+                    eprintln!("Mirror member {} write error at block {}, marking failed: {}", i, block, err);
+                    self.fail(i);
+                }
+            }
+        }
+        res
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn sector_size(&mut self) -> (u64, u64) {
+        self.members.iter_mut().fold((512, 512), |(logical, physical), member| {
+            let (member_logical, member_physical) = member.sector_size();
+            (cmp::max(logical, member_logical), cmp::max(physical, member_physical))
+        })
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.flush(false)
+    }
+
+    fn barrier(&mut self) -> Result<()> {
+        self.flush(true)
+    }
+}
+
+#[test]
+fn mirror_test() {
+    use disk::DiskFaulty;
+    use testing::DiskMemory;
+
+    let members = vec![
+        DiskFaulty::new(DiskMemory::new(9)),
+        DiskFaulty::new(DiskMemory::new(10)),
+    ];
+    assert!(DiskMirror::open(members).is_err());
+
+    let members = vec![
+        DiskFaulty::new(DiskMemory::new(9)),
+        DiskFaulty::new(DiskMemory::new(10)),
+    ];
+    let mut mirror = DiskMirror::create(members).unwrap();
+    assert_eq!(mirror.size(), Ok(8 * BLOCK_SIZE));
+    assert_eq!(mirror.write_at(1, &[1; BLOCK_SIZE as usize]), Ok(BLOCK_SIZE as usize));
+    // The label is not part of the data
+    assert_eq!(mirror.write_at(7, &[2; 2 * BLOCK_SIZE as usize]), Ok(BLOCK_SIZE as usize));
+
+    // A member that fails a write is left out, here and once the mirror is opened again
+    mirror.members()[1].fail_write(3);
+    assert_eq!(mirror.write_at(3, &[3; BLOCK_SIZE as usize]), Ok(BLOCK_SIZE as usize));
+    assert_eq!(mirror.healthy(), 1);
+    let mut members = mirror.members.drain(..).map(|member| member.into_inner()).collect::<Vec<_>>();
+    members.reverse();
+    let mut mirror = DiskMirror::open(members).unwrap();
+    assert_eq!(mirror.healthy(), 1);
+    let mut buf = [0; BLOCK_SIZE as usize];
+    assert_eq!(mirror.read_at(3, &mut buf), Ok(buf.len()));
+    assert_eq!(&buf[..], &[3; BLOCK_SIZE as usize][..]);
+
+    // Short reads fall back to the next member
+    let members = vec![DiskMemory::new(8), DiskMemory::new(8)];
+    let mut mirror = DiskMirror::create(members).unwrap();
+    mirror.write_at(5, &[5; BLOCK_SIZE as usize]).unwrap();
+    mirror.members()[0].0.truncate(5 * BLOCK_SIZE as usize + 100);
+    assert_eq!(mirror.read_at(5, &mut buf), Ok(buf.len()));
+    assert_eq!(&buf[..], &[5; BLOCK_SIZE as usize][..]);
+}
@@ -0,0 +1,124 @@
+extern crate libc;
+
+use std::{cmp, ptr, slice};
+use std::fs::OpenOptions;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+
+/// A disk image mapped into memory, with reads and writes served from the mapping
+pub struct DiskMmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is owned by the DiskMmap and only accessed through &mut self
+unsafe impl Send for DiskMmap {}
+
+impl DiskMmap {
+    pub fn open(path: &str) -> io::Result<DiskMmap> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.seek(SeekFrom::End(0))? as usize;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot map an empty image"));
+        }
+
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(DiskMmap {
+            ptr: ptr as *mut u8,
+            len: len,
+        })
+    }
+
+    /// Write dirty pages of the mapping back to the image
+    pub fn flush(&mut self) -> io::Result<()> {
+        if unsafe { libc::msync(self.ptr as *mut libc::c_void, self.len, libc::MS_SYNC) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn as_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for DiskMmap {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            eprintln!("DiskMmap: failed to flush: {}", err);
+        }
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+impl Disk for DiskMmap {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let offset = block * BLOCK_SIZE;
+        if offset >= self.len as u64 {
+            return Ok(0);
+        }
+
+        let data = &self.as_slice()[offset as usize..];
+        let count = cmp::min(data.len(), buffer.len());
+        buffer[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let offset = block * BLOCK_SIZE;
+        if offset >= self.len as u64 {
+            return Err(Error::Io(block));
+        }
+
+        let data = &mut self.as_slice()[offset as usize..];
+        let count = cmp::min(data.len(), buffer.len());
+        data[..count].copy_from_slice(&buffer[..count]);
+        Ok(count)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.len as u64)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.flush().map_err(|err| {
+            eprintln!("DiskMmap: failed to sync: {}", err);
+            Error::Io(0)
+        })
+    }
+}
+
+#[test]
+fn mmap_test() {
+    use std::{env, fs};
+    use std::io::Write;
+
+    let path = env::temp_dir().join(format!("redoxfs_mmap_test_{}", ::std::process::id()));
+    fs::File::create(&path).unwrap().write_all(&[0; 4 * BLOCK_SIZE as usize]).unwrap();
+
+    {
+        let mut disk = DiskMmap::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(disk.size(), Ok(4 * BLOCK_SIZE));
+        assert_eq!(disk.write_at(3, &[1; BLOCK_SIZE as usize]), Ok(BLOCK_SIZE as usize));
+        assert_eq!(disk.write_at(4, &[1; BLOCK_SIZE as usize]), Err(Error::Io(4)));
+
+        let mut buffer = [0; 2 * BLOCK_SIZE as usize];
+        assert_eq!(disk.read_at(3, &mut buffer), Ok(BLOCK_SIZE as usize));
+        assert_eq!(&buffer[..BLOCK_SIZE as usize], &[1; BLOCK_SIZE as usize][..]);
+    }
+
+    let data = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(&data[3 * BLOCK_SIZE as usize..], &[1; BLOCK_SIZE as usize][..]);
+}
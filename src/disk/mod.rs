@@ -1,14 +1,127 @@
-use syscall::error::Result;
+use BLOCK_SIZE;
+use error::Result;
 
-pub use self::cache::DiskCache;
+#[cfg(feature = "async")]
+pub use self::blocking::{AsyncDisk, DiskBlocking, DiskFuture, block_on};
+pub use self::cache::{CachePool, DiskCache};
+pub use self::faulty::DiskFaulty;
 pub use self::file::DiskFile;
+pub use self::http::DiskHttp;
+pub use self::mirror::DiskMirror;
+#[cfg(unix)]
+pub use self::mmap::DiskMmap;
+pub use self::nbd::DiskNbd;
+pub use self::partition::{DiskPartition, GptEntry};
+pub use self::qcow2::DiskQcow2;
+pub use self::s3::DiskS3;
+pub use self::span::DiskSpan;
+pub use self::verity::{DiskVerity, verity_data_blocks};
 
+#[cfg(feature = "async")]
+mod blocking;
 mod cache;
+mod faulty;
 mod file;
+mod http;
+mod mirror;
+#[cfg(unix)]
+mod mmap;
+mod nbd;
+mod partition;
+mod qcow2;
+mod s3;
+mod span;
+mod verity;
 
 /// A disk
 pub trait Disk {
     fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize>;
     fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize>;
     fn size(&mut self) -> Result<u64>;
+
+    /// Read consecutive blocks, starting at block, into several buffers that are each a whole
+    /// number of blocks long but for the last, which disks may do with a single request
+    fn read_vectored_at(&mut self, block: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        read_each(self, block, buffers)
+    }
+
+    /// Write several buffers to consecutive blocks, starting at block, like `read_vectored_at`
+    fn write_vectored_at(&mut self, block: u64, buffers: &[&[u8]]) -> Result<usize> {
+        write_each(self, block, buffers)
+    }
+
+    /// Logical and physical sector size of the underlying device, in bytes
+    fn sector_size(&mut self) -> (u64, u64) {
+        (512, 512)
+    }
+
+    /// Wait until everything written so far is on stable storage
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Make sure everything written so far reaches the disk before anything written after,
+    /// which disks that cannot order writes more cheaply do by syncing
+    fn barrier(&mut self) -> Result<()> {
+        self.sync()
+    }
+}
+
+impl<T: Disk + ?Sized> Disk for Box<T> {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        (**self).read_at(block, buffer)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        (**self).write_at(block, buffer)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        (**self).size()
+    }
+
+    fn read_vectored_at(&mut self, block: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        (**self).read_vectored_at(block, buffers)
+    }
+
+    fn write_vectored_at(&mut self, block: u64, buffers: &[&[u8]]) -> Result<usize> {
+        (**self).write_vectored_at(block, buffers)
+    }
+
+    fn sector_size(&mut self) -> (u64, u64) {
+        (**self).sector_size()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
+
+    fn barrier(&mut self) -> Result<()> {
+        (**self).barrier()
+    }
+}
+
+/// Blocks taken up by a buffer of len bytes
+fn blocks(len: usize) -> u64 {
+    (len as u64 + BLOCK_SIZE - 1)/BLOCK_SIZE
+}
+
+/// Read the buffers of `Disk::read_vectored_at` one at a time, for disks that cannot do better
+pub fn read_each<T: Disk + ?Sized>(disk: &mut T, mut block: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+    let mut count = 0;
+    for buffer in buffers.iter_mut() {
+        count += disk.read_at(block, buffer)?;
+        block += blocks(buffer.len());
+    }
+    Ok(count)
+}
+
+/// Write the buffers of `Disk::write_vectored_at` one at a time, for disks that cannot do better
+pub fn write_each<T: Disk + ?Sized>(disk: &mut T, mut block: u64, buffers: &[&[u8]]) -> Result<usize> {
+    let mut count = 0;
+    for buffer in buffers.iter() {
+        count += disk.write_at(block, buffer)?;
+        block += blocks(buffer.len());
+    }
+    Ok(count)
 }
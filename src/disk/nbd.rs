@@ -0,0 +1,345 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+
+const NBD_MAGIC: u64 = 0x4e42444d41474943; // "NBDMAGIC"
+const NBD_OPTS_MAGIC: u64 = 0x49484156454f5054; // "IHAVEOPT"
+const NBD_OLDSTYLE_MAGIC: u64 = 0x00420281861253;
+const NBD_REQUEST_MAGIC: u32 = 0x25609513;
+const NBD_REPLY_MAGIC: u32 = 0x67446698;
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+
+pub const NBD_DEFAULT_PORT: u16 = 10809;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16(stream: &mut TcpStream) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf)?;
+    Ok((buf[0] as u16) << 8 | buf[1] as u16)
+}
+
+fn read_u32(stream: &mut TcpStream) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(buf.iter().fold(0, |n, &b| n << 8 | b as u32))
+}
+
+fn read_u64(stream: &mut TcpStream) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(buf.iter().fold(0, |n, &b| n << 8 | b as u64))
+}
+
+fn push_be(buf: &mut Vec<u8>, value: u64, bytes: usize) {
+    for i in (0..bytes).rev() {
+        buf.push((value >> (i * 8)) as u8);
+    }
+}
+
+/// A disk served by a network block device server
+pub struct DiskNbd {
+    stream: TcpStream,
+    size: u64,
+    flags: u16,
+    handle: u64,
+}
+
+impl DiskNbd {
+    /// Open a disk from a URL of the form `nbd://host[:port][/export]`
+    pub fn open(url: &str) -> io::Result<DiskNbd> {
+        let rest = if url.starts_with("nbd://") {
+            &url[6..]
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "url does not start with nbd://"));
+        };
+
+        let (host, export) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, "")
+        };
+
+        if host.contains(':') {
+            DiskNbd::connect(host, export)
+        } else {
+            DiskNbd::connect(&format!("{}:{}", host, NBD_DEFAULT_PORT), export)
+        }
+    }
+
+    /// Connect to `addr` and negotiate the given export
+    pub fn connect(addr: &str, export: &str) -> io::Result<DiskNbd> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        if read_u64(&mut stream)? != NBD_MAGIC {
+            return Err(invalid("nbd: invalid server magic"));
+        }
+
+        let (size, flags) = match read_u64(&mut stream)? {
+            NBD_OPTS_MAGIC => {
+                let server_flags = read_u16(&mut stream)?;
+                if server_flags & NBD_FLAG_FIXED_NEWSTYLE != NBD_FLAG_FIXED_NEWSTYLE {
+                    return Err(invalid("nbd: server does not support fixed newstyle negotiation"));
+                }
+
+                let no_zeroes = server_flags & NBD_FLAG_NO_ZEROES == NBD_FLAG_NO_ZEROES;
+                let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+                if no_zeroes {
+                    client_flags |= NBD_FLAG_C_NO_ZEROES;
+                }
+
+                let mut buf = Vec::new();
+                push_be(&mut buf, client_flags as u64, 4);
+                push_be(&mut buf, NBD_OPTS_MAGIC, 8);
+                push_be(&mut buf, NBD_OPT_EXPORT_NAME as u64, 4);
+                push_be(&mut buf, export.len() as u64, 4);
+                buf.extend_from_slice(export.as_bytes());
+                stream.write_all(&buf)?;
+
+                let size = read_u64(&mut stream)?;
+                let flags = read_u16(&mut stream)?;
+                if ! no_zeroes {
+                    let mut zeroes = [0; 124];
+                    stream.read_exact(&mut zeroes)?;
+                }
+
+                (size, flags)
+            },
+            NBD_OLDSTYLE_MAGIC => {
+                let size = read_u64(&mut stream)?;
+                let flags = read_u32(&mut stream)? as u16;
+                let mut zeroes = [0; 124];
+                stream.read_exact(&mut zeroes)?;
+
+                (size, flags)
+            },
+            _ => return Err(invalid("nbd: invalid negotiation magic"))
+        };
+
+        Ok(DiskNbd {
+            stream: stream,
+            size: size,
+            flags: flags,
+            handle: 0,
+        })
+    }
+
+    /// True if the server only allows reads
+    pub fn read_only(&self) -> bool {
+        self.flags & NBD_FLAG_READ_ONLY == NBD_FLAG_READ_ONLY
+    }
+
+    fn request(&mut self, kind: u16, offset: u64, length: usize, data: &[u8]) -> io::Result<()> {
+        self.handle += 1;
+
+        let mut buf = Vec::with_capacity(28 + data.len());
+        push_be(&mut buf, NBD_REQUEST_MAGIC as u64, 4);
+        push_be(&mut buf, 0, 2);
+        push_be(&mut buf, kind as u64, 2);
+        push_be(&mut buf, self.handle, 8);
+        push_be(&mut buf, offset, 8);
+        push_be(&mut buf, length as u64, 4);
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf)
+    }
+
+    fn reply(&mut self) -> io::Result<()> {
+        if read_u32(&mut self.stream)? != NBD_REPLY_MAGIC {
+            return Err(invalid("nbd: invalid reply magic"));
+        }
+
+        let error = read_u32(&mut self.stream)?;
+        let handle = read_u64(&mut self.stream)?;
+        if handle != self.handle {
+            return Err(invalid("nbd: reply for unexpected handle"));
+        }
+
+        if error != 0 {
+            Err(io::Error::from_raw_os_error(error as i32))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Disk for DiskNbd {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let res = self.request(NBD_CMD_READ, block * BLOCK_SIZE, buffer.len(), &[])
+            .and_then(|_| self.reply())
+            .and_then(|_| self.stream.read_exact(buffer));
+        match res {
+            Ok(()) => Ok(buffer.len()),
+            Err(err) => {
+                eprintln!("NBD read error at block {}: {}", block, err);
+                Err(Error::Io(block))
+            }
+        }
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let res = self.request(NBD_CMD_WRITE, block * BLOCK_SIZE, buffer.len(), buffer)
+            .and_then(|_| self.reply());
+        match res {
+            Ok(()) => Ok(buffer.len()),
+            Err(err) => {
+                eprintln!("NBD write error at block {}: {}", block, err);
+                Err(Error::Io(block))
+            }
+        }
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        // Servers without flush support write through, so there is nothing to wait for
+        if self.flags & NBD_FLAG_SEND_FLUSH != NBD_FLAG_SEND_FLUSH {
+            return Ok(());
+        }
+
+        let res = self.request(NBD_CMD_FLUSH, 0, 0, &[])
+            .and_then(|_| self.reply());
+        match res {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                eprintln!("NBD flush error: {}", err);
+                Err(Error::Io(0))
+            }
+        }
+    }
+}
+
+impl Drop for DiskNbd {
+    fn drop(&mut self) {
+        let _ = self.request(NBD_CMD_DISC, 0, 0, &[]);
+    }
+}
+
+#[test]
+fn nbd_test() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn be(buf: &[u8]) -> u64 {
+        buf.iter().fold(0, |n, &b| n << 8 | b as u64)
+    }
+
+    // Serves a disk of 16 blocks over fixed newstyle negotiation, or oldstyle as read-only,
+    // returning the disk once the client disconnects
+    fn serve(listener: TcpListener, oldstyle: bool) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut data = vec![0; 16 * BLOCK_SIZE as usize];
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut buf = Vec::new();
+            push_be(&mut buf, NBD_MAGIC, 8);
+            if oldstyle {
+                push_be(&mut buf, NBD_OLDSTYLE_MAGIC, 8);
+                push_be(&mut buf, data.len() as u64, 8);
+                push_be(&mut buf, NBD_FLAG_READ_ONLY as u64, 4);
+                buf.extend_from_slice(&[0; 124]);
+                stream.write_all(&buf).unwrap();
+            } else {
+                push_be(&mut buf, NBD_OPTS_MAGIC, 8);
+                push_be(&mut buf, (NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES) as u64, 2);
+                stream.write_all(&buf).unwrap();
+
+                let mut option = [0; 20];
+                stream.read_exact(&mut option).unwrap();
+                assert_eq!(be(&option[..4]), (NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES) as u64);
+                assert_eq!(be(&option[4..12]), NBD_OPTS_MAGIC);
+                assert_eq!(be(&option[12..16]), NBD_OPT_EXPORT_NAME as u64);
+                let mut name = vec![0; be(&option[16..20]) as usize];
+                stream.read_exact(&mut name).unwrap();
+                assert_eq!(&name[..], b"disk");
+
+                let mut buf = Vec::new();
+                push_be(&mut buf, data.len() as u64, 8);
+                push_be(&mut buf, NBD_FLAG_SEND_FLUSH as u64, 2);
+                stream.write_all(&buf).unwrap();
+            }
+
+            loop {
+                let mut request = [0; 28];
+                stream.read_exact(&mut request).unwrap();
+                assert_eq!(be(&request[..4]), NBD_REQUEST_MAGIC as u64);
+                let kind = be(&request[6..8]) as u16;
+                let offset = be(&request[16..24]) as usize;
+                let length = be(&request[24..28]) as usize;
+                if kind == NBD_CMD_DISC {
+                    return data;
+                }
+
+                let mut payload = vec![0; if kind == NBD_CMD_WRITE { length } else { 0 }];
+                stream.read_exact(&mut payload).unwrap();
+                // Requests past the end fail with EIO
+                let error = if offset + length > data.len() { 5 } else { 0 };
+                if error == 0 && kind == NBD_CMD_WRITE {
+                    data[offset..offset + length].copy_from_slice(&payload);
+                }
+
+                let mut reply = Vec::new();
+                push_be(&mut reply, NBD_REPLY_MAGIC as u64, 4);
+                push_be(&mut reply, error, 4);
+                reply.extend_from_slice(&request[8..16]);
+                if error == 0 && kind == NBD_CMD_READ {
+                    reply.extend_from_slice(&data[offset..offset + length]);
+                }
+                stream.write_all(&reply).unwrap();
+            }
+        })
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = serve(listener, false);
+    {
+        let mut disk = DiskNbd::connect(&addr, "disk").unwrap();
+        assert_eq!(disk.size(), Ok(16 * BLOCK_SIZE));
+        assert!(! disk.read_only());
+
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE as usize).map(|i| i as u8).collect();
+        assert_eq!(disk.write_at(3, &data), Ok(data.len()));
+        assert_eq!(disk.sync(), Ok(()));
+        let mut buf = vec![0; data.len()];
+        assert_eq!(disk.read_at(3, &mut buf), Ok(buf.len()));
+        assert!(buf == data);
+
+        // An error reply fails the request, and the next one still works
+        assert_eq!(disk.read_at(15, &mut buf), Err(Error::Io(15)));
+        assert_eq!(disk.read_at(4, &mut buf[..BLOCK_SIZE as usize]), Ok(BLOCK_SIZE as usize));
+        assert!(&buf[..BLOCK_SIZE as usize] == &data[BLOCK_SIZE as usize..]);
+    }
+    let data = server.join().unwrap();
+    assert_eq!(data[3 * BLOCK_SIZE as usize + 1], 1);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = serve(listener, true);
+    {
+        let mut disk = DiskNbd::connect(&addr, "").unwrap();
+        assert_eq!(disk.size(), Ok(16 * BLOCK_SIZE));
+        assert!(disk.read_only());
+    }
+    server.join().unwrap();
+}
@@ -0,0 +1,222 @@
+use std::cmp;
+
+use uuid::Uuid;
+
+use BLOCK_SIZE;
+use disk::{self, Disk};
+use error::{Error, Result};
+
+/// A partition found in a GUID partition table
+#[derive(Clone, Copy, Debug)]
+pub struct GptEntry {
+    /// Unique partition GUID
+    pub guid: Uuid,
+    /// Offset of the first byte of the partition
+    pub start: u64,
+    /// Size of the partition in bytes
+    pub size: u64,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut value = 0;
+    for i in 0..4 {
+        value |= (data[offset + i] as u32) << (i * 8);
+    }
+    value
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut value = 0;
+    for i in 0..8 {
+        value |= (data[offset + i] as u64) << (i * 8);
+    }
+    value
+}
+
+/// Convert a GUID stored in mixed endian order to UUID byte order
+fn read_guid(data: &[u8], offset: usize) -> Uuid {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&data[offset..offset + 16]);
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Uuid::from_bytes(&bytes).unwrap()
+}
+
+/// Read len bytes starting at a byte offset
+fn read_bytes<T: Disk>(disk: &mut T, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let block = offset/BLOCK_SIZE;
+    let skip = (offset % BLOCK_SIZE) as usize;
+    let blocks = (skip + len + BLOCK_SIZE as usize - 1)/BLOCK_SIZE as usize;
+
+    let mut data = vec![0; blocks * BLOCK_SIZE as usize];
+    let count = disk.read_at(block, &mut data)?;
+    if count < skip + len {
+        return Err(Error::Io(block));
+    }
+    Ok(data[skip..skip + len].to_vec())
+}
+
+/// A region of a disk, such as a partition
+pub struct DiskPartition<T> {
+    inner: T,
+    start: u64,
+    blocks: u64,
+}
+
+impl<T: Disk> DiskPartition<T> {
+    /// Create a partition of blocks blocks, starting at block start
+    pub fn new(inner: T, start: u64, blocks: u64) -> Self {
+        DiskPartition {
+            inner: inner,
+            start: start,
+            blocks: blocks,
+        }
+    }
+
+    /// Create a partition from a GPT entry, which must start on a block boundary
+    pub fn from_entry(inner: T, entry: &GptEntry) -> Result<Self> {
+        if entry.start % BLOCK_SIZE != 0 {
+            return Err(Error::BlockSize(entry.start));
+        }
+        Ok(DiskPartition::new(inner, entry.start/BLOCK_SIZE, entry.size/BLOCK_SIZE))
+    }
+
+    /// Read the GUID partition table of a disk, returning no entries if there is none
+    pub fn gpt_entries(disk: &mut T) -> Result<Vec<GptEntry>> {
+        let mut entries = Vec::new();
+
+        // The GPT header is in the second sector, which depends on the sector size
+        for &sector in [512, 4096].iter() {
+            let header = read_bytes(disk, sector, 92)?;
+            if &header[..8] != b"EFI PART" {
+                continue;
+            }
+
+            let entries_lba = read_u64(&header, 72);
+            let count = read_u32(&header, 80) as usize;
+            let entry_size = read_u32(&header, 84) as usize;
+            if entry_size < 128 || entry_size > 4096 || count > 4096 {
+                return Err(Error::Corrupt(sector/BLOCK_SIZE));
+            }
+
+            // The header fields are whatever is on disk, so none of the sizes are trusted
+            let corrupt = Error::Corrupt(sector/BLOCK_SIZE);
+            let table_offset = entries_lba.checked_mul(sector).ok_or(corrupt)?;
+            let table_len = count.checked_mul(entry_size).ok_or(corrupt)?;
+            let table = read_bytes(disk, table_offset, table_len)?;
+            for i in 0..count {
+                let entry = &table[i * entry_size..(i + 1) * entry_size];
+                if entry[..16].iter().all(|&b| b == 0) {
+                    continue;
+                }
+
+                let first = read_u64(entry, 32);
+                let last = read_u64(entry, 40);
+                if last >= first {
+                    entries.push(GptEntry {
+                        guid: read_guid(entry, 16),
+                        start: first.checked_mul(sector).ok_or(corrupt)?,
+                        size: (last - first + 1).checked_mul(sector).ok_or(corrupt)?,
+                    });
+                }
+            }
+
+            break;
+        }
+
+        Ok(entries)
+    }
+
+    pub fn inner(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Disk> Disk for DiskPartition<T> {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        if block >= self.blocks {
+            return Ok(0);
+        }
+        let len = cmp::min(buffer.len() as u64, (self.blocks - block) * BLOCK_SIZE) as usize;
+        self.inner.read_at(self.start + block, &mut buffer[..len])
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        if block >= self.blocks {
+            return Err(Error::Io(block));
+        }
+        let len = cmp::min(buffer.len() as u64, (self.blocks - block) * BLOCK_SIZE) as usize;
+        self.inner.write_at(self.start + block, &buffer[..len])
+    }
+
+    fn read_vectored_at(&mut self, block: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        let len: usize = buffers.iter().map(|buffer| buffer.len()).sum();
+        if block + (len as u64 + BLOCK_SIZE - 1)/BLOCK_SIZE <= self.blocks {
+            self.inner.read_vectored_at(self.start + block, buffers)
+        } else {
+            // The end is cut off one buffer at a time
+            disk::read_each(self, block, buffers)
+        }
+    }
+
+    fn write_vectored_at(&mut self, block: u64, buffers: &[&[u8]]) -> Result<usize> {
+        let len: usize = buffers.iter().map(|buffer| buffer.len()).sum();
+        if block + (len as u64 + BLOCK_SIZE - 1)/BLOCK_SIZE <= self.blocks {
+            self.inner.write_vectored_at(self.start + block, buffers)
+        } else {
+            disk::write_each(self, block, buffers)
+        }
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.blocks * BLOCK_SIZE)
+    }
+
+    fn sector_size(&mut self) -> (u64, u64) {
+        self.inner.sector_size()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn barrier(&mut self) -> Result<()> {
+        self.inner.barrier()
+    }
+}
+
+#[test]
+fn gpt_test() {
+    use testing::DiskMemory;
+
+    fn write_u64(data: &mut [u8], offset: usize, value: u64) {
+        for i in 0..8 {
+            data[offset + i] = (value >> (i * 8)) as u8;
+        }
+    }
+
+    // A table of 512 byte sectors, with one partition from sector 34 to 99
+    let mut disk = DiskMemory::new(4);
+    disk.0[512..520].copy_from_slice(b"EFI PART");
+    write_u64(&mut disk.0, 512 + 72, 2);
+    write_u64(&mut disk.0, 512 + 80, 1 | 128 << 32);
+    disk.0[1024] = 1;
+    write_u64(&mut disk.0, 1024 + 32, 34);
+    write_u64(&mut disk.0, 1024 + 40, 99);
+    let entries = DiskPartition::gpt_entries(&mut disk).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!((entries[0].start, entries[0].size), (34 * 512, 66 * 512));
+
+    // Sizes that overflow are corrupt rather than wrapped
+    write_u64(&mut disk.0, 1024 + 40, u64::max_value());
+    assert_eq!(DiskPartition::gpt_entries(&mut disk).err(), Some(Error::Corrupt(0)));
+    write_u64(&mut disk.0, 1024 + 32, u64::max_value() / 256);
+    assert_eq!(DiskPartition::gpt_entries(&mut disk).err(), Some(Error::Corrupt(0)));
+    write_u64(&mut disk.0, 512 + 72, u64::max_value() / 256);
+    assert_eq!(DiskPartition::gpt_entries(&mut disk).err(), Some(Error::Corrupt(0)));
+
+    // As are entries too large to be real
+    write_u64(&mut disk.0, 512 + 80, 1 | 8192 << 32);
+    assert_eq!(DiskPartition::gpt_entries(&mut disk).err(), Some(Error::Corrupt(0)));
+}
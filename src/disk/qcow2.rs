@@ -0,0 +1,623 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::Path;
+
+use BLOCK_SIZE;
+use disk::{Disk, DiskFile};
+use error::{Error, Result};
+
+/// `QFI\xfb`, at the start of every qcow2 image
+const MAGIC: u64 = 0x5146_49fb;
+
+/// Bits of the offset in L1, L2, and refcount table entries
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The cluster or table has a reference count of one, so it can be written in place
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeros, since version 3
+const ZERO: u64 = 1;
+
+/// Incompatible features, of which the image is only written with neither set
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+/// Incompatible feature naming the compression type, which only matters for compressed clusters
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+
+/// Header extension naming the format of the backing file
+const EXT_BACKING_FORMAT: u64 = 0xE279_2ACA;
+
+/// Cluster size of created images, 64 KiB like qemu-img
+const CLUSTER_BITS: u32 = 16;
+
+/// Number of L2 tables kept in memory
+const L2_CACHE: usize = 64;
+
+/// Most backing files in a chain, which stops chains that loop
+const BACKING_DEPTH: usize = 16;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn unsupported(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+fn read_be(data: &[u8], offset: usize, bytes: usize) -> u64 {
+    data[offset..offset + bytes].iter().fold(0, |n, &b| n << 8 | b as u64)
+}
+
+fn push_be(buf: &mut Vec<u8>, value: u64, bytes: usize) {
+    for i in (0..bytes).rev() {
+        buf.push((value >> (i * 8)) as u8);
+    }
+}
+
+/// Read from the image file at offset, with zeros past its end, where the last cluster may not
+/// have been written out to its full length
+fn read_host(file: &mut DiskFile, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let file = file.file_mut();
+    file.seek(SeekFrom::Start(offset))?;
+    let mut i = 0;
+    while i < buf.len() {
+        match file.read(&mut buf[i..]) {
+            Ok(0) => break,
+            Ok(count) => i += count,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
+        }
+    }
+    for b in buf[i..].iter_mut() {
+        *b = 0;
+    }
+    Ok(())
+}
+
+fn write_host(file: &mut DiskFile, offset: u64, buf: &[u8]) -> io::Result<()> {
+    let file = file.file_mut();
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+/// The image that unallocated clusters are read from
+enum Backing {
+    Raw(DiskFile),
+    Qcow2(Box<DiskQcow2>),
+}
+
+impl Backing {
+    fn open(path: &Path, depth: usize) -> io::Result<Backing> {
+        let path = path.to_str().ok_or(invalid("qcow2: backing file name is not valid UTF-8"))?;
+        let disk = DiskFile::open_read_only(path)?;
+        if DiskQcow2::probe(path)? {
+            DiskQcow2::open_disk(disk, path, true, depth).map(|disk| Backing::Qcow2(Box::new(disk)))
+        } else {
+            Ok(Backing::Raw(disk))
+        }
+    }
+
+    /// Read at offset in the backing image, which reads as zeros past its end
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match *self {
+            Backing::Raw(ref mut file) => read_host(file, offset, buf),
+            Backing::Qcow2(ref mut disk) => disk.read_bytes(offset, buf),
+        }
+    }
+}
+
+/// A disk stored in a qcow2 image, the format used by QEMU, read through its chain of backing
+/// files
+///
+/// Clusters that were never written are read from the backing file, or as zeros, and are
+/// allocated at the end of the image when written, as qemu does. Images with internal
+/// snapshots, or that were not closed cleanly, can only be opened read-only, and compressed or
+/// encrypted clusters cannot be read.
+pub struct DiskQcow2 {
+    file: DiskFile,
+    read_only: bool,
+    version: u64,
+    cluster_bits: u32,
+    /// Size of the virtual disk in bytes
+    size: u64,
+    l1_offset: u64,
+    l1: Vec<u64>,
+    refcount_offset: u64,
+    /// The refcount table, which is only read for writable images
+    refcounts: Vec<u64>,
+    /// End of the last cluster of the image file, where clusters are allocated
+    end: u64,
+    /// L2 tables in memory by their offset
+    l2_cache: HashMap<u64, Vec<u64>>,
+    l2_order: VecDeque<u64>,
+    backing: Option<Backing>,
+}
+
+impl DiskQcow2 {
+    /// Whether the file at path is a qcow2 image
+    pub fn probe(path: &str) -> io::Result<bool> {
+        let mut magic = [0; 4];
+        match File::open(path)?.read_exact(&mut magic) {
+            Ok(()) => Ok(read_be(&magic, 0, 4) == MAGIC),
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Open a qcow2 image, locking it like `DiskFile::open`, and its backing files read-only
+    pub fn open(path: &str) -> io::Result<DiskQcow2> {
+        DiskQcow2::open_disk(DiskFile::open(path)?, path, false, 0)
+    }
+
+    /// Open a qcow2 image for reading only, locking it like `DiskFile::open_read_only`
+    pub fn open_read_only(path: &str) -> io::Result<DiskQcow2> {
+        DiskQcow2::open_disk(DiskFile::open_read_only(path)?, path, true, 0)
+    }
+
+    /// Create a qcow2 image of size bytes, or replace an existing one, with no clusters
+    /// allocated, so it reads as the backing file if there is one, or as zeros
+    ///
+    /// A relative backing file is found next to the image, and is probed for its format, which
+    /// is stored in the image like `qemu-img create -b` does.
+    pub fn create(path: &str, size: u64, backing: Option<&str>) -> io::Result<DiskQcow2> {
+        let cluster_size = 1 << CLUSTER_BITS;
+        let l2_size = cluster_size * cluster_size/8;
+        let l1_size = (size + l2_size - 1)/l2_size;
+        let l1_clusters = cmp::max((l1_size * 8 + cluster_size - 1)/cluster_size, 1);
+        // The header, refcount table, refcount block, and L1 table come first
+        let clusters = 3 + l1_clusters;
+        if clusters > cluster_size/2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "qcow2: image is too large"));
+        }
+
+        let mut header = Vec::new();
+        push_be(&mut header, MAGIC, 4);
+        push_be(&mut header, 3, 4);
+        let (backing_name, backing_format) = match backing {
+            Some(name) => {
+                if name.len() > 1023 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "qcow2: backing file name is too long"));
+                }
+                let backing_path = Path::new(path).parent().unwrap_or(Path::new("")).join(name);
+                let qcow2 = DiskQcow2::probe(backing_path.to_str().unwrap_or(name))?;
+                (name.as_bytes(), if qcow2 { &b"qcow2"[..] } else { &b"raw"[..] })
+            },
+            None => (&b""[..], &b""[..])
+        };
+        let extensions = if backing.is_some() { 8 + 8 } else { 0 };
+        push_be(&mut header, if backing.is_some() { 104 + extensions + 8 } else { 0 }, 8);
+        push_be(&mut header, backing_name.len() as u64, 4);
+        push_be(&mut header, CLUSTER_BITS as u64, 4);
+        push_be(&mut header, size, 8);
+        push_be(&mut header, 0, 4); // no encryption
+        push_be(&mut header, l1_size, 4);
+        push_be(&mut header, 3 * cluster_size, 8);
+        push_be(&mut header, cluster_size, 8);
+        push_be(&mut header, 1, 4); // refcount table clusters
+        push_be(&mut header, 0, 4); // snapshots
+        push_be(&mut header, 0, 8);
+        push_be(&mut header, 0, 8); // incompatible features
+        push_be(&mut header, 0, 8); // compatible features
+        push_be(&mut header, 0, 8); // autoclear features
+        push_be(&mut header, 4, 4); // 16 bit refcounts
+        push_be(&mut header, 104, 4);
+        if backing.is_some() {
+            push_be(&mut header, EXT_BACKING_FORMAT, 4);
+            push_be(&mut header, backing_format.len() as u64, 4);
+            header.extend_from_slice(backing_format);
+            header.resize(104 + extensions as usize, 0);
+        }
+        push_be(&mut header, 0, 8); // end of extensions
+        header.extend_from_slice(backing_name);
+
+        let mut refcount_table = Vec::new();
+        push_be(&mut refcount_table, 2 * cluster_size, 8);
+        let mut refcount_block = Vec::new();
+        for _ in 0..clusters {
+            push_be(&mut refcount_block, 1, 2);
+        }
+
+        let mut disk = DiskFile::create(path, 0)?;
+        disk.file_mut().set_len(clusters * cluster_size)?;
+        write_host(&mut disk, 0, &header)?;
+        write_host(&mut disk, cluster_size, &refcount_table)?;
+        write_host(&mut disk, 2 * cluster_size, &refcount_block)?;
+        DiskQcow2::open_disk(disk, path, false, 0)
+    }
+
+    fn open_disk(mut file: DiskFile, path: &str, read_only: bool, depth: usize) -> io::Result<DiskQcow2> {
+        let mut header = [0; 104];
+        read_host(&mut file, 0, &mut header)?;
+        if read_be(&header, 0, 4) != MAGIC {
+            return Err(invalid("qcow2: not a qcow2 image"));
+        }
+
+        let version = read_be(&header, 4, 4);
+        let (incompatible, autoclear, refcount_order) = match version {
+            2 => (0, 0, 4),
+            3 => (read_be(&header, 72, 8), read_be(&header, 88, 8), read_be(&header, 96, 4)),
+            _ => return Err(unsupported(&format!("qcow2: version {} is not supported", version)))
+        };
+        let cluster_bits = read_be(&header, 20, 4) as u32;
+        if cluster_bits < 9 || cluster_bits > 21 {
+            return Err(invalid("qcow2: invalid cluster size"));
+        }
+        if read_be(&header, 32, 4) != 0 {
+            return Err(unsupported("qcow2: encrypted images are not supported"));
+        }
+        if incompatible & ! (INCOMPAT_DIRTY | INCOMPAT_CORRUPT | INCOMPAT_COMPRESSION_TYPE) != 0 {
+            return Err(unsupported(&format!("qcow2: image uses incompatible features {:#x} that are not supported", incompatible)));
+        }
+
+        let snapshots = read_be(&header, 60, 4);
+        if ! read_only {
+            if incompatible & INCOMPAT_CORRUPT != 0 {
+                return Err(invalid("qcow2: image is marked corrupt, it can only be opened read-only"));
+            }
+            if incompatible & INCOMPAT_DIRTY != 0 {
+                return Err(invalid("qcow2: image was not closed cleanly, repair it with qemu-img check -r all or open it read-only"));
+            }
+            if snapshots > 0 {
+                return Err(unsupported("qcow2: images with snapshots can only be opened read-only"));
+            }
+            if refcount_order != 4 {
+                return Err(unsupported("qcow2: images with refcounts other than 16 bits can only be opened read-only"));
+            }
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let size = read_be(&header, 24, 8);
+        let l1_size = read_be(&header, 36, 4);
+        let l2_size = cluster_size * cluster_size/8;
+        if l1_size < (size + l2_size - 1)/l2_size || l1_size > 1 << 25 {
+            return Err(invalid("qcow2: L1 table does not match the image size"));
+        }
+        let l1_offset = read_be(&header, 40, 8);
+        let mut l1_data = vec![0; l1_size as usize * 8];
+        read_host(&mut file, l1_offset, &mut l1_data)?;
+        let l1 = (0..l1_size as usize).map(|i| read_be(&l1_data, i * 8, 8)).collect();
+
+        let refcount_offset = read_be(&header, 48, 8);
+        let mut refcounts = Vec::new();
+        if ! read_only {
+            let refcount_clusters = read_be(&header, 56, 4);
+            if refcount_clusters << cluster_bits > 1 << 28 {
+                return Err(invalid("qcow2: refcount table is too large"));
+            }
+            let mut data = vec![0; (refcount_clusters << cluster_bits) as usize];
+            read_host(&mut file, refcount_offset, &mut data)?;
+            refcounts = (0..data.len()/8).map(|i| read_be(&data, i * 8, 8)).collect();
+        }
+
+        let len = file.file_mut().seek(SeekFrom::End(0))?;
+        let end = (len + cluster_size - 1)/cluster_size * cluster_size;
+
+        let backing_offset = read_be(&header, 8, 8);
+        let backing = if backing_offset != 0 {
+            if depth >= BACKING_DEPTH {
+                return Err(invalid("qcow2: chain of backing files is too long"));
+            }
+            let mut name = vec![0; read_be(&header, 16, 4) as usize];
+            if name.len() > 1023 {
+                return Err(invalid("qcow2: backing file name is too long"));
+            }
+            read_host(&mut file, backing_offset, &mut name)?;
+            let name = String::from_utf8(name).map_err(|_| invalid("qcow2: backing file name is not valid UTF-8"))?;
+            let backing_path = Path::new(path).parent().unwrap_or(Path::new("")).join(&name);
+            Some(Backing::open(&backing_path, depth + 1).map_err(|err| {
+                io::Error::new(err.kind(), format!("qcow2: failed to open backing file {}: {}", name, err))
+            })?)
+        } else {
+            None
+        };
+
+        // Unknown autoclear features have to be cleared by anything that writes the image
+        if ! read_only && autoclear != 0 {
+            write_host(&mut file, 88, &[0; 8])?;
+        }
+
+        Ok(DiskQcow2 {
+            file: file,
+            read_only: read_only,
+            version: version,
+            cluster_bits: cluster_bits,
+            size: size,
+            l1_offset: l1_offset,
+            l1: l1,
+            refcount_offset: refcount_offset,
+            refcounts: refcounts,
+            end: end,
+            l2_cache: HashMap::new(),
+            l2_order: VecDeque::new(),
+            backing: backing,
+        })
+    }
+
+    /// Indexes into the L1 and L2 tables of the cluster at offset
+    fn indexes(&self, offset: u64) -> (usize, usize) {
+        let l2_bits = self.cluster_bits - 3;
+        ((offset >> (self.cluster_bits + l2_bits)) as usize, ((offset >> self.cluster_bits) & ((1 << l2_bits) - 1)) as usize)
+    }
+
+    /// The L2 table at offset in the image file
+    fn l2_table(&mut self, offset: u64) -> io::Result<&mut Vec<u64>> {
+        if ! self.l2_cache.contains_key(&offset) {
+            let mut data = vec![0; 1 << self.cluster_bits];
+            read_host(&mut self.file, offset, &mut data)?;
+            let table = (0..data.len()/8).map(|i| read_be(&data, i * 8, 8)).collect();
+
+            while self.l2_order.len() >= L2_CACHE {
+                let removed = self.l2_order.pop_front().unwrap();
+                self.l2_cache.remove(&removed);
+            }
+
+            self.l2_cache.insert(offset, table);
+            self.l2_order.push_back(offset);
+        }
+
+        Ok(self.l2_cache.get_mut(&offset).unwrap())
+    }
+
+    /// The L2 entry of the cluster at offset, which is 0 if it was never written
+    fn l2_entry(&mut self, offset: u64) -> io::Result<u64> {
+        let (l1_index, l2_index) = self.indexes(offset);
+        let l2_offset = self.l1[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.l2_table(l2_offset)?[l2_index])
+    }
+
+    /// Read from the cluster at offset with the given L2 entry, buf not going past the cluster
+    fn read_cluster(&mut self, entry: u64, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let host = entry & OFFSET_MASK;
+        if entry & COMPRESSED != 0 {
+            Err(unsupported("qcow2: compressed clusters are not supported"))
+        } else if self.version >= 3 && entry & ZERO != 0 {
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+            Ok(())
+        } else if host != 0 {
+            read_host(&mut self.file, host + (offset & ((1 << self.cluster_bits) - 1)), buf)
+        } else if let Some(ref mut backing) = self.backing {
+            backing.read(offset, buf)
+        } else {
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+            Ok(())
+        }
+    }
+
+    /// Read the virtual disk at offset, with zeros past its end
+    fn read_bytes(&mut self, mut offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let cluster_size = 1 << self.cluster_bits;
+        let mut i = 0;
+        while i < buf.len() {
+            let count = cmp::min(buf.len() - i, (cluster_size - offset % cluster_size) as usize);
+            if offset >= self.size {
+                for b in buf[i..i + count].iter_mut() {
+                    *b = 0;
+                }
+            } else {
+                let entry = self.l2_entry(offset)?;
+                self.read_cluster(entry, offset, &mut buf[i..i + count])?;
+            }
+            i += count;
+            offset += count as u64;
+        }
+        Ok(())
+    }
+
+    /// Set the reference count of the cluster at index, adding a refcount block if it has none
+    fn set_refcount(&mut self, cluster: u64, count: u16) -> io::Result<()> {
+        let entry_bits = self.cluster_bits - 1;
+        let index = (cluster >> entry_bits) as usize;
+        if index >= self.refcounts.len() {
+            return Err(io::Error::new(io::ErrorKind::Other, "qcow2: refcount table is full"));
+        }
+
+        if self.refcounts[index] & ! 0x1ff == 0 {
+            let block = self.end;
+            self.end += 1 << self.cluster_bits;
+            write_host(&mut self.file, block, &vec![0; 1 << self.cluster_bits])?;
+            let mut entry = Vec::new();
+            push_be(&mut entry, block, 8);
+            write_host(&mut self.file, self.refcount_offset + index as u64 * 8, &entry)?;
+            self.refcounts[index] = block;
+            self.set_refcount(block >> self.cluster_bits, 1)?;
+        }
+
+        let mut value = Vec::new();
+        push_be(&mut value, count as u64, 2);
+        let offset = (self.refcounts[index] & ! 0x1ff) + (cluster & ((1 << entry_bits) - 1)) * 2;
+        write_host(&mut self.file, offset, &value)
+    }
+
+    /// Allocate a cluster at the end of the image file, returning its offset
+    fn allocate(&mut self) -> io::Result<u64> {
+        let offset = self.end;
+        self.end += 1 << self.cluster_bits;
+        self.set_refcount(offset >> self.cluster_bits, 1)?;
+        Ok(offset)
+    }
+
+    /// The offset of the L2 table for the L1 entry at index, allocating it if it has none
+    fn l2_offset_for_write(&mut self, index: usize) -> io::Result<u64> {
+        let entry = self.l1[index];
+        if entry & OFFSET_MASK != 0 {
+            if entry & COPIED == 0 {
+                return Err(unsupported("qcow2: L2 table is shared with a snapshot"));
+            }
+            return Ok(entry & OFFSET_MASK);
+        }
+
+        let offset = self.allocate()?;
+        write_host(&mut self.file, offset, &vec![0; 1 << self.cluster_bits])?;
+        let mut value = Vec::new();
+        push_be(&mut value, offset | COPIED, 8);
+        write_host(&mut self.file, self.l1_offset + index as u64 * 8, &value)?;
+        self.l1[index] = offset | COPIED;
+        Ok(offset)
+    }
+
+    /// Write the cluster at offset, buf not going past the cluster or the end of the disk
+    fn write_cluster(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let cluster_size = 1 << self.cluster_bits;
+        let in_cluster = (offset % cluster_size) as usize;
+        let (l1_index, l2_index) = self.indexes(offset);
+        let l2_offset = self.l2_offset_for_write(l1_index)?;
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        let host = entry & OFFSET_MASK;
+        let zero = self.version >= 3 && entry & ZERO != 0;
+        let in_place = entry & COPIED != 0 && entry & COMPRESSED == 0 && host != 0;
+        if in_place && ! zero {
+            return write_host(&mut self.file, host + in_cluster as u64, buf);
+        }
+
+        // The whole cluster is written, with what it read as before around buf
+        let start = offset - in_cluster as u64;
+        let mut data = vec![0; cluster_size as usize];
+        let len = cmp::min(cluster_size, self.size - start) as usize;
+        self.read_cluster(entry, start, &mut data[..len])?;
+        data[in_cluster..in_cluster + buf.len()].copy_from_slice(buf);
+
+        // A zeroed cluster that is still allocated is reused
+        let host = if in_place {
+            host
+        } else {
+            self.allocate()?
+        };
+        write_host(&mut self.file, host, &data)?;
+
+        let mut value = Vec::new();
+        push_be(&mut value, host | COPIED, 8);
+        write_host(&mut self.file, l2_offset + l2_index as u64 * 8, &value)?;
+        self.l2_table(l2_offset)?[l2_index] = host | COPIED;
+        Ok(())
+    }
+
+    /// Write the virtual disk at offset, buf not going past its end
+    fn write_bytes(&mut self, mut offset: u64, buf: &[u8]) -> io::Result<()> {
+        let cluster_size = 1 << self.cluster_bits;
+        let mut i = 0;
+        while i < buf.len() {
+            let count = cmp::min(buf.len() - i, (cluster_size - offset % cluster_size) as usize);
+            self.write_cluster(offset, &buf[i..i + count])?;
+            i += count;
+            offset += count as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Disk for DiskQcow2 {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let offset = block * BLOCK_SIZE;
+        let count = cmp::min(buffer.len() as u64, self.size.saturating_sub(offset)) as usize;
+        if let Err(err) = self.read_bytes(offset, &mut buffer[..count]) {
+            eprintln!("QCOW2 read error at block {}: {}", block, err);
+            return Err(Error::Io(block));
+        }
+        Ok(count)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let offset = block * BLOCK_SIZE;
+        let count = cmp::min(buffer.len() as u64, self.size.saturating_sub(offset)) as usize;
+        if let Err(err) = self.write_bytes(offset, &buffer[..count]) {
+            eprintln!("QCOW2 write error at block {}: {}", block, err);
+            return Err(Error::Io(block));
+        }
+        Ok(count)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync()
+    }
+
+    fn barrier(&mut self) -> Result<()> {
+        self.file.barrier()
+    }
+}
+
+#[test]
+fn qcow2_test() {
+    use std::{env, fs};
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let dir = env::temp_dir();
+    let name = |image: &str| format!("redoxfs_qcow2_test_{}_{}", ::std::process::id(), image);
+    let base_path = dir.join(name("base.qcow2"));
+    let base_path = base_path.to_str().unwrap();
+    let overlay_path = dir.join(name("overlay.qcow2"));
+    let overlay_path = overlay_path.to_str().unwrap();
+    let raw_path = dir.join(name("raw.img"));
+    let raw_path = raw_path.to_str().unwrap();
+
+    {
+        let disk = DiskQcow2::create(base_path, 256 * BLOCK_SIZE, None).unwrap();
+        let mut fs = FileSystem::create(disk, 0, 0).unwrap();
+        let root = fs.header.1.root;
+        let file = fs.create_node(Node::MODE_FILE | 0o644, "base", root, 0, 0).unwrap();
+        fs.write_node(file.0, 0, &[1; 100 * BLOCK_SIZE as usize], 0, 0).unwrap();
+        fs.sync_all().unwrap();
+    }
+
+    // Writes to an overlay leave its backing file as it was
+    {
+        let disk = DiskQcow2::create(overlay_path, 256 * BLOCK_SIZE, Some(name("base.qcow2").as_str())).unwrap();
+        let mut fs = FileSystem::open(disk).unwrap();
+        let root = fs.header.1.root;
+        let file = fs.find_node("base", root).unwrap();
+        fs.write_node(file.0, BLOCK_SIZE, &[2; BLOCK_SIZE as usize], 0, 0).unwrap();
+        fs.create_node(Node::MODE_FILE | 0o644, "overlay", root, 0, 0).unwrap();
+        fs.sync_all().unwrap();
+    }
+    {
+        let mut fs = FileSystem::open(DiskQcow2::open_read_only(overlay_path).unwrap()).unwrap();
+        let root = fs.header.1.root;
+        fs.find_node("overlay", root).unwrap();
+        let file = fs.find_node("base", root).unwrap();
+        let mut data = vec![0; 100 * BLOCK_SIZE as usize];
+        assert_eq!(fs.read_node(file.0, 0, &mut data).unwrap(), data.len());
+        assert!(data[..BLOCK_SIZE as usize].iter().all(|&b| b == 1));
+        assert!(data[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize].iter().all(|&b| b == 2));
+        assert!(data[2 * BLOCK_SIZE as usize..].iter().all(|&b| b == 1));
+    }
+    {
+        let mut fs = FileSystem::open(DiskQcow2::open(base_path).unwrap()).unwrap();
+        let root = fs.header.1.root;
+        assert!(fs.find_node("overlay", root).is_err());
+        let file = fs.find_node("base", root).unwrap();
+        let mut data = vec![0; BLOCK_SIZE as usize];
+        fs.read_node(file.0, BLOCK_SIZE, &mut data).unwrap();
+        assert!(data.iter().all(|&b| b == 1));
+    }
+
+    // A raw backing file reads as zeros past its end
+    fs::write(raw_path, &[3; 3 * BLOCK_SIZE as usize]).unwrap();
+    let mut disk = DiskQcow2::create(overlay_path, 8 * BLOCK_SIZE, Some(name("raw.img").as_str())).unwrap();
+    assert_eq!(disk.write_at(1, &[4; BLOCK_SIZE as usize]).unwrap(), BLOCK_SIZE as usize);
+    let mut data = vec![0; 9 * BLOCK_SIZE as usize];
+    assert_eq!(disk.read_at(0, &mut data).unwrap(), 8 * BLOCK_SIZE as usize);
+    assert!(data[..BLOCK_SIZE as usize].iter().all(|&b| b == 3));
+    assert!(data[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize].iter().all(|&b| b == 4));
+    assert!(data[2 * BLOCK_SIZE as usize..3 * BLOCK_SIZE as usize].iter().all(|&b| b == 3));
+    assert!(data[3 * BLOCK_SIZE as usize..].iter().all(|&b| b == 0));
+    drop(disk);
+
+    for path in &[base_path, overlay_path, raw_path] {
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,388 @@
+use std::{cmp, env};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+use sha256::{hmac_sha256, sha256};
+
+/// Number of blocks stored in each object
+const CHUNK_BLOCKS: u64 = 1024;
+
+/// Name of the object holding the size of the image in bytes, next to the chunks
+const SIZE_OBJECT: &'static str = "size";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The UTC date and time of a Unix time, formatted as `YYYYMMDD` and `YYYYMMDDTHHMMSSZ`
+fn amz_date(time: u64) -> (String, String) {
+    // The civil date of a day since 1970-01-01, by Howard Hinnant's algorithm
+    let z = time/86400 + 719468;
+    let era = z/146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096)/365;
+    let doy = doe - (365 * yoe + yoe/4 - yoe/100);
+    let mp = (5 * doy + 2)/153;
+    let day = doy - (153 * mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let secs = time % 86400;
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let date_time = format!("{}T{:02}{:02}{:02}Z", date, secs/3600, secs/60 % 60, secs % 60);
+    (date, date_time)
+}
+
+/// The key AWS Signature Version 4 signs the requests of a day with
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> [u8; 32] {
+    let key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// A disk stored in an S3 compatible object store, as objects of `CHUNK_BLOCKS` blocks each,
+/// which are kept in memory once read and written back when the disk is synced
+///
+/// The objects of an image at `s3://host/bucket/prefix` are called `prefix/size`, holding the
+/// size of the image in bytes, and `prefix/` followed by the number of each chunk in 16
+/// hexadecimal digits. Chunks that do not exist read as zeros, so an image only stores the
+/// chunks that were written. Requests are made over plain HTTP, to object stores on the local
+/// network such as test infrastructure runs, and are signed with AWS Signature Version 4.
+pub struct DiskS3 {
+    host: String,
+    /// Path of the objects, `/bucket/prefix`
+    path: String,
+    access_key: String,
+    secret_key: String,
+    region: String,
+    stream: Option<BufReader<TcpStream>>,
+    size: u64,
+    /// Chunks in memory, and whether each was written since it was stored
+    cache: HashMap<u64, (Vec<u8>, bool)>,
+    order: VecDeque<u64>,
+    cache_size: usize,
+}
+
+impl DiskS3 {
+    /// Open a disk from a URL of the form `s3://host[:port]/bucket/prefix`, with the credentials
+    /// in the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment variables and the
+    /// region in `AWS_REGION`, or `us-east-1` if it is not set
+    pub fn open(url: &str) -> io::Result<DiskS3> {
+        let mut disk = DiskS3::new(url)?;
+        let (status, body) = disk.request("GET", SIZE_OBJECT, &[])?;
+        if status != 200 {
+            return Err(invalid(&format!("s3: GET {}/{} returned status {}", url, SIZE_OBJECT, status)));
+        }
+        disk.size = String::from_utf8_lossy(&body).trim().parse::<u64>().map_err(|_| invalid("s3: invalid image size"))?;
+        Ok(disk)
+    }
+
+    /// Create a disk of size bytes like `open`, replacing the size of any image there, whose
+    /// chunks are kept
+    pub fn create(url: &str, size: u64) -> io::Result<DiskS3> {
+        let mut disk = DiskS3::new(url)?;
+        let (status, _) = disk.request("PUT", SIZE_OBJECT, size.to_string().as_bytes())?;
+        if status != 200 {
+            return Err(invalid(&format!("s3: PUT {}/{} returned status {}", url, SIZE_OBJECT, status)));
+        }
+        disk.size = size;
+        Ok(disk)
+    }
+
+    fn new(url: &str) -> io::Result<DiskS3> {
+        let rest = if url.starts_with("s3://") {
+            &url[5..]
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "url does not start with s3://"));
+        };
+
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, "")
+        };
+        if path.len() < 2 || path[1..].find('/').is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "s3: url has no bucket and prefix"));
+        }
+        // Paths are signed as they are, so they must not need encoding
+        if ! path.bytes().all(|b| (b as char).is_ascii_alphanumeric() || b"/-_.~".contains(&b)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "s3: bucket and prefix may only use letters, digits, and -_.~"));
+        }
+
+        let var = |name: &str| env::var(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("s3: {} is not set", name)));
+        Ok(DiskS3 {
+            host: host.to_string(),
+            path: path.to_string(),
+            access_key: var("AWS_ACCESS_KEY_ID")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+            region: env::var("AWS_REGION").unwrap_or("us-east-1".to_string()),
+            stream: None,
+            size: 0,
+            cache: HashMap::new(),
+            order: VecDeque::new(),
+            cache_size: 64, // 256 MB cache
+        })
+    }
+
+    fn connect(&mut self) -> io::Result<&mut BufReader<TcpStream>> {
+        if self.stream.is_none() {
+            let addr = if self.host.contains(':') {
+                self.host.clone()
+            } else {
+                format!("{}:80", self.host)
+            };
+            let stream = TcpStream::connect(&addr)?;
+            stream.set_nodelay(true)?;
+            self.stream = Some(BufReader::new(stream));
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    /// Send a request for the object called name, returning the status and body
+    fn request(&mut self, method: &str, name: &str, body: &[u8]) -> io::Result<(u32, Vec<u8>)> {
+        // Retry once, as a kept-alive connection may have been closed by the server
+        match self.request_inner(method, name, body) {
+            Ok(ok) => Ok(ok),
+            Err(_) => {
+                self.stream = None;
+                self.request_inner(method, name, body)
+            }
+        }
+    }
+
+    fn request_inner(&mut self, method: &str, name: &str, body: &[u8]) -> io::Result<(u32, Vec<u8>)> {
+        let path = format!("{}/{}", self.path, name);
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let (date, date_time) = amz_date(time);
+        let payload_hash = hex(&sha256(body));
+
+        let canonical = format!("{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+                                method, path, self.host, payload_hash, date_time, payload_hash);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", date_time, scope, hex(&sha256(canonical.as_bytes())));
+        let signature = hex(&hmac_sha256(&signing_key(&self.secret_key, &date, &self.region, "s3"), string_to_sign.as_bytes()));
+
+        let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: redoxfs\r\nx-amz-content-sha256: {}\r\nx-amz-date: {}\r\n\
+                               Authorization: AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}\r\n\
+                               Content-Length: {}\r\n\r\n",
+                              method, path, self.host, payload_hash, date_time, self.access_key, scope, signature, body.len());
+
+        let stream = self.connect()?;
+        stream.get_mut().write_all(request.as_bytes())?;
+        stream.get_mut().write_all(body)?;
+
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        let status = line.split_whitespace().nth(1)
+            .and_then(|status| status.parse::<u32>().ok())
+            .ok_or(invalid("s3: invalid status line"))?;
+
+        let mut length = None;
+        let mut close = false;
+        loop {
+            line.clear();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(invalid("s3: unexpected end of headers"));
+            }
+
+            let header = line.trim_matches(|c| c == '\r' || c == '\n');
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some(i) = header.find(':') {
+                let name = header[..i].trim().to_lowercase();
+                let value = header[i + 1..].trim();
+                if name == "content-length" {
+                    length = value.parse::<u64>().ok();
+                } else if name == "connection" && value.eq_ignore_ascii_case("close") {
+                    close = true;
+                } else if name == "transfer-encoding" && ! value.eq_ignore_ascii_case("identity") {
+                    return Err(invalid("s3: unsupported transfer encoding"));
+                }
+            }
+        }
+
+        let mut response = Vec::new();
+        match length {
+            Some(length) => {
+                response.resize(length as usize, 0);
+                stream.read_exact(&mut response)?;
+            },
+            None => {
+                stream.read_to_end(&mut response)?;
+                close = true;
+            }
+        }
+
+        if close {
+            self.stream = None;
+        }
+
+        Ok((status, response))
+    }
+
+    /// Name of the object of a chunk
+    fn chunk_name(chunk: u64) -> String {
+        format!("{:016x}", chunk)
+    }
+
+    /// Bytes of the image in a chunk, which are fewer than a whole chunk for the last one
+    fn chunk_len(&self, chunk: u64) -> u64 {
+        let start = chunk * CHUNK_BLOCKS * BLOCK_SIZE;
+        cmp::min(start + CHUNK_BLOCKS * BLOCK_SIZE, self.size) - start
+    }
+
+    /// Write a chunk back to its object
+    fn store(&mut self, chunk: u64, data: &[u8]) -> io::Result<()> {
+        let (status, _) = self.request("PUT", &DiskS3::chunk_name(chunk), data)?;
+        if status != 200 {
+            return Err(invalid(&format!("s3: PUT of chunk {} returned status {}", chunk, status)));
+        }
+        Ok(())
+    }
+
+    /// Write every chunk that changed back to its object
+    fn store_all(&mut self) -> io::Result<()> {
+        let mut dirty: Vec<u64> = self.cache.iter().filter(|&(_, entry)| entry.1).map(|(&chunk, _)| chunk).collect();
+        dirty.sort();
+        for chunk in dirty {
+            let data = self.cache[&chunk].0.clone();
+            self.store(chunk, &data)?;
+            self.cache.get_mut(&chunk).unwrap().1 = false;
+        }
+        Ok(())
+    }
+
+    /// The chunk in memory, reading it unless it will be overwritten whole
+    fn chunk(&mut self, chunk: u64, overwrite: bool) -> io::Result<&mut (Vec<u8>, bool)> {
+        if ! self.cache.contains_key(&chunk) {
+            let len = self.chunk_len(chunk) as usize;
+            let data = if overwrite {
+                vec![0; len]
+            } else {
+                let (status, mut data) = self.request("GET", &DiskS3::chunk_name(chunk), &[])?;
+                match status {
+                    200 if data.len() <= len => data.resize(len, 0),
+                    200 => return Err(invalid(&format!("s3: chunk {} is longer than the image", chunk))),
+                    404 => data = vec![0; len],
+                    _ => return Err(invalid(&format!("s3: GET of chunk {} returned status {}", chunk, status)))
+                }
+                data
+            };
+
+            while self.order.len() >= self.cache_size {
+                let removed = self.order.pop_front().unwrap();
+                let (removed_data, dirty) = self.cache.remove(&removed).unwrap();
+                if dirty {
+                    if let Err(err) = self.store(removed, &removed_data) {
+                        self.cache.insert(removed, (removed_data, dirty));
+                        self.order.push_front(removed);
+                        return Err(err);
+                    }
+                }
+            }
+
+            self.cache.insert(chunk, (data, false));
+            self.order.push_back(chunk);
+        }
+
+        Ok(self.cache.get_mut(&chunk).unwrap())
+    }
+}
+
+impl Disk for DiskS3 {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buffer.len() {
+            let offset = block * BLOCK_SIZE + i as u64;
+            if offset >= self.size {
+                break;
+            }
+
+            let chunk_offset = (offset % (CHUNK_BLOCKS * BLOCK_SIZE)) as usize;
+            let data = match self.chunk(offset / (CHUNK_BLOCKS * BLOCK_SIZE), false) {
+                Ok(entry) => &entry.0,
+                Err(err) => {
+                    eprintln!("S3 read error at block {}: {}", block, err);
+                    return Err(Error::Io(block));
+                }
+            };
+
+            let count = cmp::min(buffer.len() - i, data.len() - chunk_offset);
+            buffer[i..i + count].copy_from_slice(&data[chunk_offset..chunk_offset + count]);
+            i += count;
+        }
+
+        Ok(i)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buffer.len() {
+            let offset = block * BLOCK_SIZE + i as u64;
+            if offset >= self.size {
+                break;
+            }
+
+            let chunk = offset / (CHUNK_BLOCKS * BLOCK_SIZE);
+            let chunk_offset = (offset % (CHUNK_BLOCKS * BLOCK_SIZE)) as usize;
+            let overwrite = chunk_offset == 0 && (buffer.len() - i) as u64 >= self.chunk_len(chunk);
+            let entry = match self.chunk(chunk, overwrite) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("S3 write error at block {}: {}", block, err);
+                    return Err(Error::Io(block));
+                }
+            };
+
+            let count = cmp::min(buffer.len() - i, entry.0.len() - chunk_offset);
+            entry.0[chunk_offset..chunk_offset + count].copy_from_slice(&buffer[i..i + count]);
+            entry.1 = true;
+            i += count;
+        }
+
+        Ok(i)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    /// Write every chunk that changed back to the object store
+    fn sync(&mut self) -> Result<()> {
+        if let Err(err) = self.store_all() {
+            eprintln!("S3 write error while syncing: {}", err);
+            return Err(Error::Io(0));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskS3 {
+    fn drop(&mut self) {
+        if let Err(err) = self.store_all() {
+            eprintln!("S3 write error while closing, changes since the last sync are lost: {}", err);
+        }
+    }
+}
+
+#[test]
+fn sign_test() {
+    assert_eq!(amz_date(0), ("19700101".to_string(), "19700101T000000Z".to_string()));
+    assert_eq!(amz_date(1440938160), ("20150830".to_string(), "20150830T123600Z".to_string()));
+    assert_eq!(amz_date(951825600).0, "20000229");
+
+    // The example of deriving a signing key in the AWS documentation
+    assert_eq!(hex(&signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam")),
+               "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+}
@@ -0,0 +1,235 @@
+use std::cmp;
+
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+use header::Header;
+
+/// Several disks concatenated into one.
+///
+/// Member 0 contributes its first `sizes[0]` blocks. Every other member holds
+/// a label (a copy of the header) in its first block and contributes the
+/// `sizes[i]` blocks after it.
+pub struct DiskSpan<T> {
+    members: Vec<T>,
+    sizes: Vec<u64>,
+}
+
+impl<T: Disk> DiskSpan<T> {
+    fn new(members: Vec<T>, sizes: Vec<u64>) -> Self {
+        DiskSpan {
+            members: members,
+            sizes: sizes,
+        }
+    }
+
+    /// Create a span using all of the space on each member
+    ///
+    /// Nothing is written until `write_labels`, but a span of more than `Header::SPAN_MAX`
+    /// members fails here, before a filesystem is created on it.
+    pub fn with_members(mut members: Vec<T>) -> Result<Self> {
+        if members.len() > Header::SPAN_MAX {
+            return Err(Error::Unsupported);
+        }
+
+        let mut sizes = Vec::new();
+        for (i, member) in members.iter_mut().enumerate() {
+            let blocks = member.size()?/BLOCK_SIZE;
+            if i == 0 {
+                sizes.push(blocks);
+            } else if blocks > 1 {
+                sizes.push(blocks - 1);
+            } else {
+                return Err(Error::NoSpace);
+            }
+        }
+        Ok(DiskSpan::new(members, sizes))
+    }
+
+    /// Open a span from the header of its first disk, the first member, and the other members
+    /// in any order, which are put in order by their labels
+    ///
+    /// Fails with `Error::Incomplete` if a member is missing, and with `Error::InvalidValue` if
+    /// a member has no label of this span, or the same label as another member.
+    pub fn open(header: &Header, members: Vec<T>) -> Result<Self> {
+        let count = header.span_count as usize;
+        if count > Header::SPAN_MAX {
+            return Err(Error::Unsupported);
+        }
+        if members.len() < count {
+            return Err(Error::Incomplete);
+        }
+
+        let mut found: Vec<Option<T>> = (0..count).map(|_| None).collect();
+        for (i, mut member) in members.into_iter().enumerate() {
+            let index = if i == 0 {
+                0
+            } else {
+                let mut label = Header::default();
+                member.read_at(0, &mut label)?;
+                if ! label.valid() || label.uuid != header.uuid || label.span_index == 0 {
+                    return Err(Error::InvalidValue);
+                }
+                label.span_index as usize
+            };
+            match found.get_mut(index) {
+                Some(slot) if slot.is_none() => *slot = Some(member),
+                _ => return Err(Error::InvalidValue)
+            }
+        }
+
+        let members = found.into_iter().map(|member| member.unwrap()).collect();
+        let sizes = header.span_sizes;
+        Ok(DiskSpan::new(members, sizes[..count].to_vec()))
+    }
+
+    /// Number of blocks each member contributes
+    pub fn sizes(&self) -> &[u64] {
+        &self.sizes
+    }
+
+    /// Write the span table to the header, and a label to every member after the first
+    pub fn write_labels(&mut self, header: &mut Header) -> Result<()> {
+        header.span_index = 0;
+        header.span_count = self.members.len() as u64;
+        let mut sizes = [0; Header::SPAN_MAX];
+        for (i, size) in sizes.iter_mut().enumerate() {
+            *size = self.sizes.get(i).cloned().unwrap_or(0);
+        }
+        header.span_sizes = sizes;
+
+        for i in 1..self.members.len() {
+            let mut label = Header::default();
+            label.copy_from_slice(header);
+            label.span_index = i as u64;
+            self.members[i].write_at(0, &label)?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the member, physical block, and remaining blocks for a logical block
+    fn map(&self, block: u64) -> Option<(usize, u64, u64)> {
+        let mut start = 0;
+        for (i, &size) in self.sizes.iter().enumerate() {
+            if block < start + size {
+                let offset = if i == 0 { 0 } else { 1 };
+                return Some((i, offset + block - start, start + size - block));
+            }
+            start += size;
+        }
+        None
+    }
+}
+
+impl<T: Disk> Disk for DiskSpan<T> {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buffer.len() {
+            let logical = block + (i as u64)/BLOCK_SIZE;
+            let (member, physical, remaining) = match self.map(logical) {
+                Some(map) => map,
+                None => break
+            };
+
+            let len = cmp::min(buffer.len() - i, (remaining * BLOCK_SIZE) as usize);
+            let count = self.members[member].read_at(physical, &mut buffer[i..i + len])?;
+            i += count;
+            if count < len {
+                break;
+            }
+        }
+        Ok(i)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let mut i = 0;
+        while i < buffer.len() {
+            let logical = block + (i as u64)/BLOCK_SIZE;
+            let (member, physical, remaining) = match self.map(logical) {
+                Some(map) => map,
+                None => return Err(Error::Io(logical))
+            };
+
+            let len = cmp::min(buffer.len() - i, (remaining * BLOCK_SIZE) as usize);
+            let count = self.members[member].write_at(physical, &buffer[i..i + len])?;
+            i += count;
+            if count < len {
+                break;
+            }
+        }
+        Ok(i)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.sizes.iter().fold(0, |total, size| total + size) * BLOCK_SIZE)
+    }
+
+    fn sector_size(&mut self) -> (u64, u64) {
+        self.members.iter_mut().fold((512, 512), |(logical, physical), member| {
+            let (member_logical, member_physical) = member.sector_size();
+            (cmp::max(logical, member_logical), cmp::max(physical, member_physical))
+        })
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        for member in self.members.iter_mut() {
+            member.sync()?;
+        }
+        Ok(())
+    }
+
+    fn barrier(&mut self) -> Result<()> {
+        for member in self.members.iter_mut() {
+            member.barrier()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn span_test() {
+    use filesystem::FileSystem;
+    use testing::DiskMemory;
+    use node::Node;
+
+    let members = (0..Header::SPAN_MAX + 1).map(|_| DiskMemory::new(4)).collect();
+    assert_eq!(DiskSpan::with_members(members).err(), Some(Error::Unsupported));
+
+    let members = vec![
+        DiskMemory::new(16),
+        DiskMemory::new(9),
+        DiskMemory::new(9),
+    ];
+    let span = DiskSpan::with_members(members).unwrap();
+    assert_eq!(span.sizes(), &[16, 8, 8]);
+    let mut fs = FileSystem::create(span, 0, 0).unwrap();
+    fs.disk.write_labels(&mut fs.header.1).unwrap();
+    fs.write_header().unwrap();
+    let root = fs.header.1.root;
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    let data: Vec<u8> = (0..20 * BLOCK_SIZE as usize).map(|i| (i / 4096 + i) as u8).collect();
+    assert_eq!(fs.write_node(file.0, 0, &data, 0, 0), Ok(data.len()));
+    fs.sync_all().unwrap();
+    let mut members = fs.disk.members;
+    // The data went past the first member
+    assert!(members[2].0[BLOCK_SIZE as usize..].iter().any(|&b| b != 0));
+
+    let (_, header) = FileSystem::probe(&mut members[0]).unwrap();
+    assert_eq!({ header.span_count }, 3);
+    let copy = |members: &[DiskMemory], order: &[usize]| -> Vec<DiskMemory> {
+        order.iter().map(|&i| DiskMemory(members[i].0.clone())).collect()
+    };
+    assert_eq!(DiskSpan::open(&header, copy(&members, &[0, 1])).err(), Some(Error::Incomplete));
+    assert_eq!(DiskSpan::open(&header, copy(&members, &[0, 1, 1])).err(), Some(Error::InvalidValue));
+    assert_eq!(DiskSpan::open(&header, copy(&members, &[0, 1, 0])).err(), Some(Error::InvalidValue));
+
+    // The other members are put in order by their labels
+    let span = DiskSpan::open(&header, copy(&members, &[0, 2, 1])).unwrap();
+    assert_eq!(span.sizes(), &[16, 8, 8]);
+    let mut fs = FileSystem::open(span).unwrap();
+    let file = fs.find_node("file", fs.header.1.root).unwrap();
+    let mut buf = vec![0; data.len()];
+    assert_eq!(fs.read_node(file.0, 0, &mut buf), Ok(data.len()));
+    assert!(buf == data);
+}
@@ -0,0 +1,277 @@
+use std::cmp;
+use std::collections::HashMap;
+
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+use progress::Progress;
+use sha256::sha256;
+
+/// Signature of the block in front of the hash tree
+pub const VERITY_SIGNATURE: &'static [u8; 8] = b"RedoxFSV";
+
+/// Number of hashes stored in one block of the tree
+const HASHES: u64 = BLOCK_SIZE / 32;
+
+/// Number of blocks in each level of the tree, starting with the hashes of the data blocks
+fn level_sizes(data_blocks: u64) -> Vec<u64> {
+    let mut sizes = Vec::new();
+    let mut count = data_blocks;
+    loop {
+        count = (count + HASHES - 1)/HASHES;
+        sizes.push(count);
+        if count <= 1 {
+            return sizes;
+        }
+    }
+}
+
+/// Number of data blocks that fit into blocks blocks together with their hash tree, which
+/// `DiskVerity::create` writes after them
+pub fn verity_data_blocks(blocks: u64) -> u64 {
+    let tree_blocks = |data_blocks| 1 + level_sizes(data_blocks).iter().sum::<u64>();
+    let mut data_blocks = blocks.saturating_sub(tree_blocks(blocks));
+    while data_blocks + 1 + tree_blocks(data_blocks + 1) <= blocks {
+        data_blocks += 1;
+    }
+    data_blocks
+}
+
+/// The root hash covers the top of the tree and the number of data blocks
+fn root_hash(top: &[u8], data_blocks: u64) -> [u8; 32] {
+    let mut data = top.to_vec();
+    for i in 0..8 {
+        data.push((data_blocks >> (i * 8)) as u8);
+    }
+    sha256(&data)
+}
+
+/// A read-only disk whose blocks are checked against a hash tree before they are returned
+///
+/// The tree follows the data blocks: a block with the signature, the number of data blocks
+/// and the root hash, then the levels of the tree from the hashes of the data blocks up to
+/// a single block. Only a root hash from a trusted source, rather than the stored one,
+/// protects against tampering.
+pub struct DiskVerity<T> {
+    inner: T,
+    data_blocks: u64,
+    /// First block of each level
+    levels: Vec<u64>,
+    root: [u8; 32],
+    /// Blocks of the tree that have already been checked
+    verified: HashMap<u64, Vec<u8>>,
+}
+
+impl<T: Disk> DiskVerity<T> {
+    /// Write the hash tree of the first data_blocks blocks after them, returning the root hash
+    ///
+    /// The disk has to have room for the tree, see `verity_data_blocks`, or this fails with
+    /// `Error::Io`.
+    pub fn create(disk: &mut T, data_blocks: u64) -> Result<[u8; 32]> {
+        DiskVerity::create_progress(disk, data_blocks, &mut |_, _| ())
+    }
+
+    /// Write the hash tree like `create`, calling progress with the bytes of data hashed and the
+    /// bytes of all the data blocks
+    pub fn create_progress(disk: &mut T, data_blocks: u64, progress: &mut FnMut(u64, Option<u64>)) -> Result<[u8; 32]> {
+        let mut progress = Progress::new(progress, Some(data_blocks * BLOCK_SIZE));
+        let mut level = Vec::new();
+        let mut data = vec![0; BLOCK_SIZE as usize];
+        for block in 0..data_blocks {
+            if disk.read_at(block, &mut data)? < data.len() {
+                return Err(Error::Io(block));
+            }
+            level.extend_from_slice(&sha256(&data));
+            progress.add(BLOCK_SIZE);
+        }
+
+        let mut block = data_blocks + 1;
+        loop {
+            let len = cmp::max((level.len() as u64 + BLOCK_SIZE - 1)/BLOCK_SIZE, 1) * BLOCK_SIZE;
+            level.resize(len as usize, 0);
+            if disk.write_at(block, &level)? < level.len() {
+                return Err(Error::Io(block));
+            }
+            block += level.len() as u64/BLOCK_SIZE;
+
+            if level.len() as u64 == BLOCK_SIZE {
+                break;
+            }
+
+            let mut next = Vec::new();
+            for chunk in level.chunks(BLOCK_SIZE as usize) {
+                next.extend_from_slice(&sha256(chunk));
+            }
+            level = next;
+        }
+
+        let root = root_hash(&level, data_blocks);
+
+        let mut superblock = [0; BLOCK_SIZE as usize];
+        superblock[..8].copy_from_slice(VERITY_SIGNATURE);
+        for i in 0..8 {
+            superblock[8 + i] = (data_blocks >> (i * 8)) as u8;
+        }
+        superblock[16..48].copy_from_slice(&root);
+        if disk.write_at(data_blocks, &superblock)? < superblock.len() {
+            return Err(Error::Io(data_blocks));
+        }
+
+        progress.finish();
+        Ok(root)
+    }
+
+    /// Read the root hash stored with the hash tree that follows data_blocks blocks
+    pub fn stored_root(disk: &mut T, data_blocks: u64) -> Result<[u8; 32]> {
+        let mut superblock = [0; BLOCK_SIZE as usize];
+        if disk.read_at(data_blocks, &mut superblock)? < superblock.len() || &superblock[..8] != VERITY_SIGNATURE {
+            return Err(Error::Corrupt(data_blocks));
+        }
+
+        let mut stored_blocks = 0;
+        for i in 0..8 {
+            stored_blocks |= (superblock[8 + i] as u64) << (i * 8);
+        }
+        if stored_blocks != data_blocks {
+            return Err(Error::Corrupt(data_blocks));
+        }
+
+        let mut root = [0; 32];
+        root.copy_from_slice(&superblock[16..48]);
+        Ok(root)
+    }
+
+    /// Verify the first data_blocks blocks of inner against the tree that follows them
+    pub fn new(inner: T, data_blocks: u64, root: [u8; 32]) -> Result<Self> {
+        let mut levels = Vec::new();
+        let mut block = data_blocks + 1;
+        for size in level_sizes(data_blocks) {
+            levels.push(block);
+            block += size;
+        }
+
+        let mut disk = DiskVerity {
+            inner: inner,
+            data_blocks: data_blocks,
+            levels: levels,
+            root: root,
+            verified: HashMap::new(),
+        };
+
+        // Fail early if the tree does not match the root hash at all
+        let top = disk.levels.len() - 1;
+        disk.tree_block(top, 0)?;
+        Ok(disk)
+    }
+
+    /// A verified block of the tree
+    fn tree_block(&mut self, level: usize, index: u64) -> Result<&[u8]> {
+        let block = self.levels[level] + index;
+        if ! self.verified.contains_key(&block) {
+            let mut data = vec![0; BLOCK_SIZE as usize];
+            if self.inner.read_at(block, &mut data)? < data.len() {
+                return Err(Error::Io(block));
+            }
+
+            let valid = if level + 1 == self.levels.len() {
+                index == 0 && root_hash(&data, self.data_blocks) == self.root
+            } else {
+                self.hash(level + 1, index)? == sha256(&data)
+            };
+            if ! valid {
+                return Err(Error::Corrupt(block));
+            }
+
+            self.verified.insert(block, data);
+        }
+        Ok(&self.verified[&block])
+    }
+
+    /// The expected hash of item index of the level below level, or of data block index for level 0
+    fn hash(&mut self, level: usize, index: u64) -> Result<[u8; 32]> {
+        let offset = ((index % HASHES) * 32) as usize;
+        let data = self.tree_block(level, index / HASHES)?;
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&data[offset..offset + 32]);
+        Ok(hash)
+    }
+}
+
+impl<T: Disk> Disk for DiskVerity<T> {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        if block >= self.data_blocks {
+            return Ok(0);
+        }
+
+        let blocks = cmp::min((buffer.len() as u64 + BLOCK_SIZE - 1)/BLOCK_SIZE, self.data_blocks - block);
+        let mut data = vec![0; (blocks * BLOCK_SIZE) as usize];
+        if self.inner.read_at(block, &mut data)? < data.len() {
+            return Err(Error::Io(block));
+        }
+
+        for (i, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            let block_i = block + i as u64;
+            if self.hash(0, block_i)? != sha256(chunk) {
+                return Err(Error::Corrupt(block_i));
+            }
+        }
+
+        let count = cmp::min(buffer.len(), data.len());
+        buffer[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+
+    fn write_at(&mut self, _block: u64, _buffer: &[u8]) -> Result<usize> {
+        Err(Error::ReadOnly)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.data_blocks * BLOCK_SIZE)
+    }
+
+    fn sector_size(&mut self) -> (u64, u64) {
+        self.inner.sector_size()
+    }
+}
+
+#[test]
+fn verity_test() {
+    use testing::DiskMemory;
+
+    // Enough data blocks for two levels
+    let data_blocks = HASHES + 3;
+    // The tree takes a block for its signature, two for the hashes of the data, and one for the
+    // hashes of those
+    let tree_blocks = 1 + 2 + 1;
+    assert_eq!(verity_data_blocks(data_blocks + tree_blocks), data_blocks);
+    assert_eq!(verity_data_blocks(data_blocks + tree_blocks - 1), data_blocks - 1);
+
+    // A disk without room for the tree fails instead of losing it
+    let mut disk = DiskMemory::new(data_blocks + 2);
+    assert_eq!(DiskVerity::create(&mut disk, data_blocks), Err(Error::Io(data_blocks + 1)));
+
+    let mut disk = DiskMemory::new(data_blocks + tree_blocks);
+    for (i, b) in disk.0[..(data_blocks * BLOCK_SIZE) as usize].iter_mut().enumerate() {
+        *b = (i as u64 / BLOCK_SIZE) as u8;
+    }
+    let root = DiskVerity::create(&mut disk, data_blocks).unwrap();
+    assert_eq!(DiskVerity::stored_root(&mut disk, data_blocks), Ok(root));
+    let last = ((data_blocks + tree_blocks - 1) * BLOCK_SIZE) as usize;
+    assert!(disk.0[last..].iter().any(|&b| b != 0));
+
+    let mut verity = DiskVerity::new(disk, data_blocks, root).unwrap();
+    let mut buffer = vec![0; 2 * BLOCK_SIZE as usize];
+    assert_eq!(verity.read_at(HASHES, &mut buffer), Ok(2 * BLOCK_SIZE as usize));
+    assert_eq!(buffer[BLOCK_SIZE as usize], (HASHES + 1) as u8);
+    assert_eq!(verity.write_at(0, &buffer), Err(Error::ReadOnly));
+
+    // A changed data block is rejected, other blocks can still be read
+    verity.inner.0[5 * BLOCK_SIZE as usize] ^= 1;
+    assert_eq!(verity.read_at(5, &mut buffer), Err(Error::Corrupt(5)));
+    assert_eq!(verity.read_at(6, &mut buffer[..BLOCK_SIZE as usize]), Ok(BLOCK_SIZE as usize));
+
+    // A wrong root hash is rejected when opening
+    let mut wrong = root;
+    wrong[0] ^= 1;
+    assert!(DiskVerity::new(verity.inner, data_blocks, wrong).is_err());
+}
@@ -0,0 +1,159 @@
+use std::{error, fmt, io, result};
+
+use syscall;
+use syscall::error::{EEXIST, EFBIG, EILSEQ, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODATA, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, ENXIO, EOPNOTSUPP, EPERM, EROFS};
+
+/// A filesystem result
+pub type Result<T> = result::Result<T, Error>;
+
+/// A filesystem error
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No block with a valid header signature was found
+    NoHeader,
+    /// The header has a version that is not supported
+    Version(u64),
+    /// The structure at the given block is corrupt
+    Corrupt(u64),
+    /// There is not enough free space
+    NoSpace,
+    /// The node does not exist
+    NotFound,
+    /// The node is not a directory
+    NotDir,
+    /// The node is a directory
+    IsDir,
+    /// The directory is not empty
+    NotEmpty,
+    /// A node with the same name already exists
+    Exists,
+    /// The disk failed to read or write the given block
+    Io(u64),
+    /// The disk cannot be written
+    ReadOnly,
+    /// The filesystem spans several disks, and not all of them are present
+    Incomplete,
+    /// The block size is not a power of two multiple of BLOCK_SIZE
+    BlockSize(u64),
+    /// The name is not valid UTF-8, or contains a `/` or NUL byte
+    InvalidName,
+    /// The name does not fit in a node
+    NameTooLong,
+    /// The flags of the node do not allow this operation
+    NotPermitted,
+    /// A directory cannot be moved below itself
+    InvalidMove,
+    /// The node would be longer than `Node::LEN_MAX`
+    TooLarge,
+    /// The node has no extended attribute with this name
+    NoAttribute,
+    /// The value of the extended attribute is not valid for its name
+    InvalidValue,
+    /// The extended attribute namespace is not supported
+    Unsupported,
+}
+
+impl Error {
+    /// The errno that best describes this error
+    pub fn errno(&self) -> i32 {
+        match *self {
+            Error::NoHeader => ENOENT,
+            Error::Version(_) => EIO,
+            Error::Corrupt(_) => EIO,
+            Error::NoSpace => ENOSPC,
+            Error::NotFound => ENOENT,
+            Error::NotDir => ENOTDIR,
+            Error::IsDir => EISDIR,
+            Error::NotEmpty => ENOTEMPTY,
+            Error::Exists => EEXIST,
+            Error::Io(_) => EIO,
+            Error::ReadOnly => EROFS,
+            Error::Incomplete => ENXIO,
+            Error::BlockSize(_) => EINVAL,
+            Error::InvalidName => EILSEQ,
+            Error::NameTooLong => ENAMETOOLONG,
+            Error::NotPermitted => EPERM,
+            Error::InvalidMove => EINVAL,
+            Error::TooLarge => EFBIG,
+            Error::NoAttribute => ENODATA,
+            Error::InvalidValue => EINVAL,
+            Error::Unsupported => EOPNOTSUPP,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoHeader => write!(f, "no filesystem header found"),
+            Error::Version(version) => write!(f, "unsupported filesystem version {}", version),
+            Error::Corrupt(block) => write!(f, "corrupt structure at block {}", block),
+            Error::NoSpace => write!(f, "no space left on filesystem"),
+            Error::NotFound => write!(f, "no such node"),
+            Error::NotDir => write!(f, "not a directory"),
+            Error::IsDir => write!(f, "is a directory"),
+            Error::NotEmpty => write!(f, "directory not empty"),
+            Error::Exists => write!(f, "node already exists"),
+            Error::Io(block) => write!(f, "disk I/O error at block {}", block),
+            Error::ReadOnly => write!(f, "read-only disk"),
+            Error::Incomplete => write!(f, "filesystem is missing disks of its span"),
+            Error::BlockSize(block_size) => write!(f, "unsupported block size {}", block_size),
+            Error::InvalidName => write!(f, "invalid file name"),
+            Error::NameTooLong => write!(f, "file name too long"),
+            Error::NotPermitted => write!(f, "operation not permitted"),
+            Error::InvalidMove => write!(f, "cannot move a directory below itself"),
+            Error::TooLarge => write!(f, "file too large"),
+            Error::NoAttribute => write!(f, "no such attribute"),
+            Error::InvalidValue => write!(f, "invalid attribute value"),
+            Error::Unsupported => write!(f, "operation not supported"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::NoHeader => "no filesystem header found",
+            Error::Version(_) => "unsupported filesystem version",
+            Error::Corrupt(_) => "corrupt structure",
+            Error::NoSpace => "no space left on filesystem",
+            Error::NotFound => "no such node",
+            Error::NotDir => "not a directory",
+            Error::IsDir => "is a directory",
+            Error::NotEmpty => "directory not empty",
+            Error::Exists => "node already exists",
+            Error::Io(_) => "disk I/O error",
+            Error::ReadOnly => "read-only disk",
+            Error::Incomplete => "filesystem is missing disks of its span",
+            Error::BlockSize(_) => "unsupported block size",
+            Error::InvalidName => "invalid file name",
+            Error::NameTooLong => "file name too long",
+            Error::NotPermitted => "operation not permitted",
+            Error::InvalidMove => "cannot move a directory below itself",
+            Error::TooLarge => "file too large",
+            Error::NoAttribute => "no such attribute",
+            Error::InvalidValue => "invalid attribute value",
+            Error::Unsupported => "operation not supported",
+        }
+    }
+}
+
+impl From<Error> for syscall::Error {
+    fn from(err: Error) -> syscall::Error {
+        syscall::Error::new(err.errno())
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match err {
+            Error::NotFound => io::ErrorKind::NotFound,
+            Error::Exists => io::ErrorKind::AlreadyExists,
+            Error::NoHeader | Error::Version(_) | Error::Corrupt(_) => io::ErrorKind::InvalidData,
+            Error::BlockSize(_) | Error::InvalidName | Error::NameTooLong | Error::InvalidMove | Error::TooLarge => io::ErrorKind::InvalidInput,
+            Error::NotPermitted => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,66 @@
+use std::{fmt, mem, ops, slice};
+
+/// Extended metadata of a node, with everything `Stat` has no room for
+#[derive(Clone, Copy, Default)]
+#[repr(packed)]
+pub struct ExStat {
+    pub ino: u64,
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    /// Attribute flags, see `Node::FLAG_*`
+    pub flags: u32,
+    /// Changes when the block of the node is reused by a new node
+    pub generation: u32,
+    /// Length in bytes
+    pub size: u64,
+    /// Allocated space, including the node itself, in 512 byte units
+    pub blocks: u64,
+    /// Allocation block size in bytes
+    pub blksize: u32,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+    pub ctime: u64,
+    pub ctime_nsec: u32,
+    pub crtime: u64,
+    pub crtime_nsec: u32,
+}
+
+impl fmt::Debug for ExStat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExStat")
+            .field("ino", &self.ino)
+            .field("mode", &self.mode)
+            .field("uid", &self.uid)
+            .field("gid", &self.gid)
+            .field("flags", &self.flags)
+            .field("generation", &self.generation)
+            .field("size", &self.size)
+            .field("blocks", &self.blocks)
+            .field("blksize", &self.blksize)
+            .field("mtime", &self.mtime)
+            .field("mtime_nsec", &self.mtime_nsec)
+            .field("ctime", &self.ctime)
+            .field("ctime_nsec", &self.ctime_nsec)
+            .field("crtime", &self.crtime)
+            .field("crtime_nsec", &self.crtime_nsec)
+            .finish()
+    }
+}
+
+impl ops::Deref for ExStat {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self as *const ExStat as *const u8, mem::size_of::<ExStat>()) as &[u8]
+        }
+    }
+}
+
+impl ops::DerefMut for ExStat {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self as *mut ExStat as *mut u8, mem::size_of::<ExStat>()) as &mut [u8]
+        }
+    }
+}
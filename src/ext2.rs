@@ -0,0 +1,434 @@
+use std::cmp;
+use std::collections::BTreeSet;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use archive::set_attr;
+use disk::Disk;
+use error::Error;
+use filesystem::FileSystem;
+use node::Node;
+
+const EXT2_MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+
+/// Directory entries carry a file type, which changes the width of the name length
+const INCOMPAT_FILETYPE: u32 = 0x2;
+/// An ext3 journal has to be replayed before the image is consistent
+const INCOMPAT_RECOVER: u32 = 0x4;
+
+/// Inode mode types, which match the node mode types
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u16_at(data, offset) as u32 | (u16_at(data, offset + 2) as u32) << 16
+}
+
+struct Inode {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    ctime: u32,
+    mtime: u32,
+    /// Number of 512 byte sectors, including indirect blocks and extended attributes
+    sectors: u32,
+    block: [u32; 15],
+    /// The raw block pointers, which hold the target of short symlinks
+    block_bytes: [u8; 60],
+}
+
+/// A reader for ext2 and ext3 images
+struct Ext2<R> {
+    image: R,
+    /// Length of the image in bytes, which nothing read from it can be larger than
+    len: u64,
+    block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// Inode table of each block group
+    inode_tables: Vec<u32>,
+    filetype: bool,
+}
+
+impl<R: Read + Seek> Ext2<R> {
+    fn open(mut image: R) -> io::Result<Self> {
+        let len = image.seek(SeekFrom::End(0))?;
+        let mut superblock = [0; 1024];
+        image.seek(SeekFrom::Start(1024))?;
+        image.read_exact(&mut superblock)?;
+
+        if u16_at(&superblock, 56) != EXT2_MAGIC {
+            return Err(invalid("not an ext2 or ext3 image"));
+        }
+
+        let incompat = u32_at(&superblock, 96);
+        if incompat & INCOMPAT_RECOVER != 0 {
+            return Err(invalid("the ext3 journal needs to be recovered first, run e2fsck"));
+        }
+        if incompat & ! INCOMPAT_FILETYPE != 0 {
+            return Err(invalid("unsupported ext2 features, such as those of ext4"));
+        }
+
+        let log_block_size = u32_at(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(invalid("invalid ext2 block size"));
+        }
+        let block_size = 1024 << log_block_size;
+
+        let blocks = u32_at(&superblock, 4) as u64;
+        let first_data_block = u32_at(&superblock, 20) as u64;
+        let blocks_per_group = u32_at(&superblock, 32) as u64;
+        let inodes_per_group = u32_at(&superblock, 40);
+        if blocks_per_group == 0 || inodes_per_group == 0 || blocks <= first_data_block {
+            return Err(invalid("invalid ext2 superblock"));
+        }
+
+        let inode_size = if u32_at(&superblock, 76) == 0 {
+            128
+        } else {
+            u16_at(&superblock, 88) as u64
+        };
+        if inode_size < 128 {
+            return Err(invalid("invalid ext2 inode size"));
+        }
+
+        let groups = (blocks - first_data_block + blocks_per_group - 1)/blocks_per_group;
+        if groups * 32 > len {
+            return Err(invalid("ext2 block group descriptors are larger than the image"));
+        }
+        let mut descriptors = vec![0; (groups * 32) as usize];
+        image.seek(SeekFrom::Start((first_data_block + 1) * block_size))?;
+        image.read_exact(&mut descriptors)?;
+
+        let inode_tables = descriptors.chunks(32).map(|descriptor| u32_at(descriptor, 8)).collect();
+
+        Ok(Ext2 {
+            image: image,
+            len: len,
+            block_size: block_size,
+            inodes_per_group: inodes_per_group,
+            inode_size: inode_size,
+            inode_tables: inode_tables,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+        })
+    }
+
+    fn read_block(&mut self, block: u32, buf: &mut [u8]) -> io::Result<()> {
+        if block == 0 {
+            // A hole in a sparse file
+            for b in buf.iter_mut() {
+                *b = 0;
+            }
+            Ok(())
+        } else {
+            self.image.seek(SeekFrom::Start(block as u64 * self.block_size))?;
+            self.image.read_exact(buf)
+        }
+    }
+
+    fn inode(&mut self, ino: u32) -> io::Result<Inode> {
+        let group = ((ino - 1)/self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let table = *self.inode_tables.get(group).ok_or_else(|| invalid("inode outside of the inode tables"))?;
+
+        let mut data = [0; 128];
+        self.image.seek(SeekFrom::Start(table as u64 * self.block_size + index * self.inode_size))?;
+        self.image.read_exact(&mut data)?;
+
+        let mode = u16_at(&data, 0);
+        let mut size = u32_at(&data, 4) as u64;
+        if mode & S_IFMT == S_IFREG {
+            size |= (u32_at(&data, 108) as u64) << 32;
+        }
+
+        let mut block = [0; 15];
+        for i in 0..15 {
+            block[i] = u32_at(&data, 40 + i * 4);
+        }
+        let mut block_bytes = [0; 60];
+        block_bytes.copy_from_slice(&data[40..100]);
+
+        Ok(Inode {
+            mode: mode,
+            uid: u16_at(&data, 2) as u32 | (u16_at(&data, 120) as u32) << 16,
+            gid: u16_at(&data, 24) as u32 | (u16_at(&data, 122) as u32) << 16,
+            size: size,
+            ctime: u32_at(&data, 12),
+            mtime: u32_at(&data, 16),
+            sectors: u32_at(&data, 28),
+            block: block,
+            block_bytes: block_bytes,
+        })
+    }
+
+    /// Call f with each of the data blocks below an indirect block of the given depth, until
+    /// count is 0
+    fn indirect_blocks<F>(&mut self, block: u32, depth: u32, count: &mut u64, f: &mut F) -> io::Result<()>
+        where F: FnMut(&mut Self, u32) -> io::Result<()>
+    {
+        let per_block = self.block_size/4;
+        if block == 0 {
+            let span = cmp::min(per_block.pow(depth), *count);
+            for _ in 0..span {
+                f(self, 0)?;
+            }
+            *count -= span;
+            return Ok(());
+        }
+
+        let mut data = vec![0; self.block_size as usize];
+        self.read_block(block, &mut data)?;
+        for i in 0..per_block as usize {
+            if *count == 0 {
+                break;
+            }
+            let pointer = u32_at(&data, i * 4);
+            if depth == 1 {
+                f(self, pointer)?;
+                *count -= 1;
+            } else {
+                self.indirect_blocks(pointer, depth - 1, count, f)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Call f with each of the blocks holding the data of an inode in order, with 0 for holes
+    ///
+    /// The blocks are not collected, as a sparse file can have many more than the image does.
+    fn data_blocks<F>(&mut self, inode: &Inode, mut f: F) -> io::Result<()>
+        where F: FnMut(&mut Self, u32) -> io::Result<()>
+    {
+        let mut count = (inode.size + self.block_size - 1)/self.block_size;
+        for i in 0..12 {
+            if count == 0 {
+                break;
+            }
+            f(self, inode.block[i])?;
+            count -= 1;
+        }
+        for depth in 1..4 {
+            if count == 0 {
+                break;
+            }
+            self.indirect_blocks(inode.block[11 + depth], depth as u32, &mut count, &mut f)?;
+        }
+        Ok(())
+    }
+
+    /// Read the whole data of an inode, such as a directory or the target of a symlink, which
+    /// has to fit in the image
+    fn read_data(&mut self, inode: &Inode) -> io::Result<Vec<u8>> {
+        if inode.size > self.len {
+            return Err(invalid("ext2 directory or symlink is larger than the image"));
+        }
+
+        let block_size = self.block_size as usize;
+        let mut data = vec![0; ((inode.size + self.block_size - 1)/self.block_size * self.block_size) as usize];
+        let mut i = 0;
+        self.data_blocks(inode, |ext2, block| {
+            ext2.read_block(block, &mut data[i * block_size..(i + 1) * block_size])?;
+            i += 1;
+            Ok(())
+        })?;
+        data.truncate(inode.size as usize);
+        Ok(data)
+    }
+
+    /// The entries of a directory inode, without "." and ".."
+    fn entries(&mut self, inode: &Inode) -> io::Result<Vec<(u32, String)>> {
+        let data = self.read_data(inode)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let ino = u32_at(&data, offset);
+            let rec_len = u16_at(&data, offset + 4) as usize;
+            let name_len = if self.filetype {
+                data[offset + 6] as usize
+            } else {
+                u16_at(&data, offset + 6) as usize
+            };
+            if rec_len < 8 || offset + rec_len > data.len() || 8 + name_len > rec_len {
+                return Err(invalid("corrupt ext2 directory"));
+            }
+
+            let name = &data[offset + 8..offset + 8 + name_len];
+            if ino != 0 && name != b"." && name != b".." {
+                let name = String::from_utf8(name.to_vec()).or(Err(Error::InvalidName))?;
+                entries.push((ino, name));
+            }
+            offset += rec_len;
+        }
+        Ok(entries)
+    }
+
+    /// Copy the tree below the root directory inode into a directory node
+    ///
+    /// The tree is walked with a stack rather than by recursion, and an image with a directory
+    /// linked more than once, which could otherwise loop forever, is refused.
+    fn convert_tree<D: Disk>(&mut self, root: Inode, fs: &mut FileSystem<D>, parent_block: u64) -> io::Result<()> {
+        let mut visited = BTreeSet::new();
+        visited.insert(ROOT_INODE);
+        let mut dirs = vec![(root, parent_block)];
+        // The attributes of directories are set once nothing more is created in them
+        let mut attrs = Vec::new();
+        while let Some((dir, dir_block)) = dirs.pop() {
+            for (ino, name) in self.entries(&dir)? {
+                let inode = self.inode(ino)?;
+                let mode_type = inode.mode & S_IFMT;
+                if mode_type != S_IFREG && mode_type != S_IFDIR && mode_type != S_IFLNK {
+                    continue;
+                }
+                if mode_type == S_IFDIR && ! visited.insert(ino) {
+                    return Err(invalid("ext2 directory is linked more than once"));
+                }
+
+                let node = fs.create_node(inode.mode & (Node::MODE_TYPE | Node::MODE_PERM), &name, dir_block, inode.ctime as u64, 0)?;
+                match mode_type {
+                    S_IFDIR => (),
+                    S_IFLNK => {
+                        // Short targets are stored in place of the block pointers
+                        let link = if inode.sectors == 0 && inode.size < 60 {
+                            inode.block_bytes[..inode.size as usize].to_vec()
+                        } else {
+                            self.read_data(&inode)?
+                        };
+                        fs.write_node(node.0, 0, &link, inode.mtime as u64, 0)?;
+                    },
+                    _ => {
+                        let mut data = vec![0; self.block_size as usize];
+                        let mut offset = 0;
+                        self.data_blocks(&inode, |ext2, block| {
+                            let len = cmp::min(inode.size - offset, ext2.block_size) as usize;
+                            ext2.read_block(block, &mut data)?;
+                            fs.write_node(node.0, offset, &data[..len], inode.mtime as u64, 0)?;
+                            offset += len as u64;
+                            Ok(())
+                        })?;
+                    }
+                }
+
+                if mode_type == S_IFDIR {
+                    attrs.push((node.0, inode.mode, inode.uid, inode.gid, inode.mtime));
+                    dirs.push((inode, node.0));
+                } else {
+                    set_attr(fs, node.0, inode.mode, inode.uid, inode.gid, inode.mtime as u64, 0)?;
+                }
+            }
+        }
+
+        for &(block, mode, uid, gid, mtime) in attrs.iter().rev() {
+            set_attr(fs, block, mode, uid, gid, mtime as u64, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy the files, directories and symlinks of an ext2 or ext3 image into a directory node
+///
+/// Hard links are stored as separate copies, other special files are skipped.
+pub fn convert_ext2<R: Read + Seek, D: Disk>(image: R, fs: &mut FileSystem<D>, parent_block: u64) -> io::Result<()> {
+    let mut ext2 = Ext2::open(image)?;
+    let root = ext2.inode(ROOT_INODE)?;
+    if root.mode & S_IFMT != S_IFDIR {
+        return Err(invalid("ext2 root is not a directory"));
+    }
+    let (mode, uid, gid, mtime) = (root.mode, root.uid, root.gid, root.mtime);
+    ext2.convert_tree(root, fs, parent_block)?;
+    set_attr(fs, parent_block, mode, uid, gid, mtime as u64, 0)
+}
+
+#[test]
+fn ext2_test() {
+    use std::io::Cursor;
+    use archive::read_all;
+    use testing::memory_fs;
+
+    fn put16(image: &mut [u8], offset: usize, value: u16) {
+        image[offset] = value as u8;
+        image[offset + 1] = (value >> 8) as u8;
+    }
+
+    fn put32(image: &mut [u8], offset: usize, value: u32) {
+        put16(image, offset, value as u16);
+        put16(image, offset + 2, (value >> 16) as u16);
+    }
+
+    // Inodes in the table at block 3, with a first data block
+    fn inode(image: &mut [u8], ino: u32, mode: u16, size: u32, block: u32) {
+        let offset = 3 * 1024 + (ino as usize - 1) * 128;
+        put16(image, offset, mode);
+        put32(image, offset + 4, size);
+        put32(image, offset + 16, 1000);
+        put32(image, offset + 40, block);
+    }
+
+    // Directory entries filling a block, the last one taking up the rest of it
+    fn dir(image: &mut [u8], block: usize, entries: &[(u32, &str)]) {
+        let mut offset = block * 1024;
+        for (i, &(ino, name)) in entries.iter().enumerate() {
+            let rec_len = if i + 1 == entries.len() { (block + 1) * 1024 - offset } else { (8 + name.len() + 3)/4 * 4 };
+            put32(image, offset, ino);
+            put16(image, offset + 4, rec_len as u16);
+            image[offset + 6] = name.len() as u8;
+            image[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+            offset += rec_len;
+        }
+    }
+
+    // A 1 KiB block image with one block group and sixteen inodes, of which 2 is the root
+    fn image(sub: &[(u32, &str)], file_size: u32) -> Vec<u8> {
+        let mut image = vec![0; 64 * 1024];
+        put32(&mut image, 1024 + 4, 64);
+        put32(&mut image, 1024 + 20, 1);
+        put32(&mut image, 1024 + 32, 8192);
+        put32(&mut image, 1024 + 40, 16);
+        put16(&mut image, 1024 + 56, EXT2_MAGIC);
+        put32(&mut image, 1024 + 96, INCOMPAT_FILETYPE);
+        put32(&mut image, 2048 + 8, 3);
+
+        inode(&mut image, ROOT_INODE, S_IFDIR | 0o755, 1024, 5);
+        dir(&mut image, 5, &[(2, "."), (2, ".."), (12, "hello"), (13, "sub")]);
+        inode(&mut image, 12, S_IFREG | 0o644, file_size, 6);
+        image[6 * 1024..6 * 1024 + 13].copy_from_slice(b"Hello, world\n");
+        inode(&mut image, 13, S_IFDIR | 0o700, 1024, 7);
+        dir(&mut image, 7, sub);
+        image
+    }
+
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+    let dest = fs.create_node(Node::MODE_DIR | 0o755, "dest", root, 0, 0).unwrap();
+    convert_ext2(Cursor::new(image(&[(13, "."), (2, ".."), (12, "link")], 13)), &mut fs, dest.0).unwrap();
+
+    let hello = fs.find_node("hello", dest.0).unwrap();
+    assert_eq!(read_all(&mut fs, hello.0).unwrap(), b"Hello, world\n");
+    assert_eq!({ hello.1.mtime }, 1000);
+    let sub = fs.find_node("sub", dest.0).unwrap();
+    assert_eq!({ sub.1.mode }, Node::MODE_DIR | 0o700);
+    let link = fs.find_node("link", sub.0).unwrap();
+    assert_eq!(read_all(&mut fs, link.0).unwrap(), b"Hello, world\n");
+
+    // A directory that links back to the root would be copied forever
+    let looped = fs.create_node(Node::MODE_DIR | 0o755, "looped", root, 0, 0).unwrap();
+    assert!(convert_ext2(Cursor::new(image(&[(13, "."), (2, ".."), (2, "root")], 13)), &mut fs, looped.0).is_err());
+
+    // Sizes larger than the image are refused before anything is allocated for them
+    let mut large = image(&[(13, "."), (2, ".."), (12, "link")], 13);
+    put32(&mut large, 3 * 1024 + 12 * 128 + 4, 0xFFFF_FFFF);
+    put16(&mut large, 3 * 1024 + 12 * 128, S_IFLNK | 0o777);
+    put32(&mut large, 3 * 1024 + 12 * 128 + 28, 2);
+    let refused = fs.create_node(Node::MODE_DIR | 0o755, "refused", root, 0, 0).unwrap();
+    assert!(convert_ext2(Cursor::new(large), &mut fs, refused.0).is_err());
+}
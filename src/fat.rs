@@ -0,0 +1,664 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use archive::{copy_from_node, set_attr, sorted_children};
+use disk::Disk;
+use error::Error;
+use filesystem::FileSystem;
+use node::Node;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Attributes of a long name entry
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// Flags in the reserved byte of a short entry, marking a lowercase base name or extension
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// FAT entries at or above this end a cluster chain
+const CLUSTER_END: u32 = 0x0FFFFFF8;
+const CLUSTER_MASK: u32 = 0x0FFFFFFF;
+/// Fewer clusters than this make a FAT16 filesystem, whatever the boot sector says
+const CLUSTERS_MIN: u64 = 65525;
+
+const SECTOR_SIZE: u64 = 512;
+const RESERVED_SECTORS: u64 = 32;
+const FATS: u64 = 2;
+const ROOT_CLUSTER: u32 = 2;
+
+/// Longest long name, in UTF-16 units
+const LONG_NAME_MAX: usize = 255;
+/// UTF-16 units stored in one long name entry
+const LONG_NAME_CHARS: usize = 13;
+/// Offsets of the UTF-16 units in a long name entry
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u16_at(data, offset) as u32 | (u16_at(data, offset + 2) as u32) << 16
+}
+
+fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset] = value as u8;
+    data[offset + 1] = (value >> 8) as u8;
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    put_u16(data, offset, value as u16);
+    put_u16(data, offset + 2, (value >> 16) as u16);
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2)/5 + day - 1;
+    let doe = yoe * 365 + yoe/4 - yoe/100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Date of a number of days since 1970-01-01, as year, month and day
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096)/365;
+    let doy = doe - (365 * yoe + yoe/4 - yoe/100);
+    let mp = (5 * doy + 2)/153;
+    let day = doy - (153 * mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Seconds since the epoch of a DOS date and time, which are taken to be UTC
+fn from_dos_time(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+    let days = days_from_civil(1980 + (date >> 9) as i64, ((date >> 5) & 0xF) as i64, (date & 0x1F) as i64);
+    let secs = (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3F) as i64 * 60 + (time & 0x1F) as i64 * 2;
+    cmp::max(days * 86400 + secs, 0) as u64
+}
+
+/// DOS date and time of seconds since the epoch, clamped to the years DOS can store
+fn to_dos_time(secs: u64) -> (u16, u16) {
+    let min = days_from_civil(1980, 1, 1) * 86400;
+    let max = days_from_civil(2108, 1, 1) * 86400 - 1;
+    let secs = cmp::max(cmp::min(secs as i64, max), min);
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let time = secs % 86400;
+    let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+    let time = ((time / 3600) << 11 | ((time / 60) % 60) << 5 | (time % 60)/2) as u16;
+    (date, time)
+}
+
+/// Checksum of a short name, stored in its long name entries
+fn short_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+/// A reader for FAT32 images
+struct Fat<R> {
+    image: R,
+    cluster_size: u64,
+    /// Byte offset of cluster 2
+    data_start: u64,
+    root_cluster: u32,
+    fat: Vec<u32>,
+}
+
+impl<R: Read + Seek> Fat<R> {
+    fn open(mut image: R) -> io::Result<Self> {
+        let mut boot = [0; 512];
+        image.seek(SeekFrom::Start(0))?;
+        image.read_exact(&mut boot)?;
+
+        if boot[510] != 0x55 || boot[511] != 0xAA {
+            return Err(invalid("not a FAT image"));
+        }
+
+        let sector_size = u16_at(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(&boot, 14) as u64;
+        let fats = boot[16] as u64;
+        if sector_size < 512 || ! sector_size.is_power_of_two() || sectors_per_cluster == 0 || fats == 0 {
+            return Err(invalid("invalid FAT boot sector"));
+        }
+        if u16_at(&boot, 22) != 0 || u16_at(&boot, 17) != 0 || u32_at(&boot, 36) == 0 {
+            return Err(invalid("not a FAT32 image, only FAT32 is supported"));
+        }
+
+        let total = u32_at(&boot, 32) as u64;
+        let fat_size = u32_at(&boot, 36) as u64;
+        let data_sectors = total.saturating_sub(reserved + fats * fat_size);
+        let clusters = cmp::min(data_sectors / sectors_per_cluster, (fat_size * sector_size)/4 - 2);
+
+        let mut data = vec![0; ((clusters + 2) * 4) as usize];
+        image.seek(SeekFrom::Start(reserved * sector_size))?;
+        image.read_exact(&mut data)?;
+
+        Ok(Fat {
+            image: image,
+            cluster_size: sectors_per_cluster * sector_size,
+            data_start: (reserved + fats * fat_size) * sector_size,
+            root_cluster: u32_at(&boot, 44),
+            fat: data.chunks(4).map(|entry| u32_at(entry, 0) & CLUSTER_MASK).collect(),
+        })
+    }
+
+    /// The clusters of a chain, which must not loop
+    fn chain(&self, first: u32) -> io::Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster < CLUSTER_END {
+            if cluster < 2 || cluster as usize >= self.fat.len() || clusters.len() >= self.fat.len() {
+                return Err(invalid("corrupt FAT cluster chain"));
+            }
+            clusters.push(cluster);
+            cluster = self.fat[cluster as usize];
+        }
+        Ok(clusters)
+    }
+
+    fn read_cluster(&mut self, cluster: u32, buf: &mut [u8]) -> io::Result<()> {
+        self.image.seek(SeekFrom::Start(self.data_start + (cluster as u64 - 2) * self.cluster_size))?;
+        self.image.read_exact(buf)
+    }
+
+    /// The entries of a directory, without "." and "..", as name and short entry
+    fn entries(&mut self, first: u32) -> io::Result<Vec<(String, [u8; 32])>> {
+        let mut data = vec![0; self.cluster_size as usize];
+        let mut entries = Vec::new();
+        let mut long = Vec::new();
+        let mut long_checksum = 0;
+        for cluster in self.chain(first)? {
+            self.read_cluster(cluster, &mut data)?;
+            for raw in data.chunks(32) {
+                if raw[0] == 0 {
+                    return Ok(entries);
+                }
+                if raw[0] == 0xE5 {
+                    long.clear();
+                    continue;
+                }
+
+                if raw[11] & 0x3F == ATTR_LONG_NAME {
+                    // Long name entries come last part first, before their short entry
+                    if raw[0] & 0x40 != 0 {
+                        long.clear();
+                        long_checksum = raw[13];
+                    }
+                    let mut part = Vec::new();
+                    for &offset in LONG_NAME_OFFSETS.iter() {
+                        part.push(u16_at(raw, offset));
+                    }
+                    long.splice(0..0, part);
+                    continue;
+                }
+
+                let mut short = [0; 11];
+                short.copy_from_slice(&raw[..11]);
+                let name = if ! long.is_empty() && long_checksum == short_checksum(&short) {
+                    let len = long.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(long.len());
+                    String::from_utf16_lossy(&long[..len])
+                } else {
+                    if short[0] == 0x05 {
+                        short[0] = 0xE5;
+                    }
+                    let mut base = String::from_utf8_lossy(&short[..8]).trim_end().to_string();
+                    let mut ext = String::from_utf8_lossy(&short[8..]).trim_end().to_string();
+                    if raw[12] & CASE_LOWER_BASE != 0 {
+                        base = base.to_lowercase();
+                    }
+                    if raw[12] & CASE_LOWER_EXT != 0 {
+                        ext = ext.to_lowercase();
+                    }
+                    if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+                };
+                long.clear();
+
+                if raw[11] & ATTR_VOLUME_ID != 0 || name == "." || name == ".." {
+                    continue;
+                }
+
+                let mut entry = [0; 32];
+                entry.copy_from_slice(raw);
+                entries.push((name, entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn archive_dir<D: Disk>(&mut self, first: u32, fs: &mut FileSystem<D>, parent_block: u64) -> io::Result<()> {
+        for (name, entry) in self.entries(first)? {
+            let dir = entry[11] & ATTR_DIRECTORY != 0;
+            let perm = match (dir, entry[11] & ATTR_READ_ONLY != 0) {
+                (true, false) => 0o755,
+                (true, true) => 0o555,
+                (false, false) => 0o644,
+                (false, true) => 0o444,
+            };
+            let mode = if dir { Node::MODE_DIR } else { Node::MODE_FILE } | perm;
+            let cluster = (u16_at(&entry, 20) as u32) << 16 | u16_at(&entry, 26) as u32;
+            let ctime = from_dos_time(u16_at(&entry, 16), u16_at(&entry, 14));
+            let mtime = from_dos_time(u16_at(&entry, 24), u16_at(&entry, 22));
+
+            let node = fs.create_node(mode, &name, parent_block, ctime, 0)?;
+            if dir {
+                self.archive_dir(cluster, fs, node.0)?;
+            } else {
+                let size = u32_at(&entry, 28) as u64;
+                let mut data = vec![0; self.cluster_size as usize];
+                let mut offset = 0;
+                let clusters = if size > 0 { self.chain(cluster)? } else { Vec::new() };
+                for cluster in clusters {
+                    if offset >= size {
+                        break;
+                    }
+                    let len = cmp::min(size - offset, self.cluster_size) as usize;
+                    self.read_cluster(cluster, &mut data)?;
+                    fs.write_node(node.0, offset, &data[..len], mtime, 0)?;
+                    offset += len as u64;
+                }
+                if offset < size {
+                    return Err(invalid("FAT cluster chain shorter than the file"));
+                }
+            }
+
+            set_attr(fs, node.0, mode, 0, 0, mtime, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy the files and directories of a FAT32 image, such as an EFI system partition, into a directory node
+///
+/// Files get permissions from the read-only attribute, owned by root. Times are taken to be UTC.
+pub fn archive_fat<D: Disk, R: Read + Seek>(fs: &mut FileSystem<D>, image: R, parent_block: u64) -> io::Result<()> {
+    let mut fat = Fat::open(image)?;
+    let root = fat.root_cluster;
+    fat.archive_dir(root, fs, parent_block)
+}
+
+/// Whether a character may appear in a short name
+fn short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// The short name of a name that fits in 8.3 form with a single case in each part, and its case flags
+fn short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (name.contains('.') && ext.is_empty()) {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    for &(part, offset, flag) in [(base, 0, CASE_LOWER_BASE), (ext, 8, CASE_LOWER_EXT)].iter() {
+        let upper = part.to_ascii_uppercase();
+        if part != upper {
+            if part != part.to_ascii_lowercase() {
+                return None;
+            }
+            case |= flag;
+        }
+        if ! upper.chars().all(short_char) {
+            return None;
+        }
+        short[offset..offset + part.len()].copy_from_slice(upper.as_bytes());
+    }
+    Some((short, case))
+}
+
+/// A numbered short name for a name that needs a long name, such as "LONGNA~1.TXT"
+fn short_alias(name: &str, used: &HashSet<[u8; 11]>) -> io::Result<[u8; 11]> {
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => (trimmed, ""),
+    };
+    let clean = |part: &str, len: usize| -> String {
+        part.to_ascii_uppercase().chars().filter(|&c| short_char(c)).take(len).collect()
+    };
+    let base = clean(base, 8);
+    let base = if base.is_empty() { "_".to_string() } else { base };
+    let ext = clean(ext, 3);
+
+    for n in 1..1000000 {
+        let tail = format!("~{}", n);
+        let mut short = [b' '; 11];
+        let len = cmp::min(base.len(), 8 - tail.len());
+        short[..len].copy_from_slice(&base.as_bytes()[..len]);
+        short[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+        if ! used.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(Error::NoSpace.into())
+}
+
+/// The directory entries of a name, long name entries first
+fn name_entries(name: &str, used: &mut HashSet<[u8; 11]>) -> io::Result<Vec<[u8; 32]>> {
+    let mut entries = Vec::new();
+    let short = match short_name(name) {
+        Some((short, case)) if ! used.contains(&short) => {
+            let mut entry = [0; 32];
+            entry[..11].copy_from_slice(&short);
+            entry[12] = case;
+            entries.push(entry);
+            short
+        },
+        _ => {
+            let units: Vec<u16> = name.encode_utf16().collect();
+            if units.len() > LONG_NAME_MAX {
+                return Err(Error::NameTooLong.into());
+            }
+            let short = short_alias(name, used)?;
+            let checksum = short_checksum(&short);
+            let parts = (units.len() + LONG_NAME_CHARS - 1)/LONG_NAME_CHARS;
+            for i in (0..parts).rev() {
+                let mut entry = [0; 32];
+                entry[0] = (i + 1) as u8 | if i + 1 == parts { 0x40 } else { 0 };
+                entry[11] = ATTR_LONG_NAME;
+                entry[13] = checksum;
+                for (j, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                    // The name ends with a null if there is room, then padding
+                    let k = i * LONG_NAME_CHARS + j;
+                    let unit = if k < units.len() { units[k] } else if k == units.len() { 0 } else { 0xFFFF };
+                    put_u16(&mut entry, offset, unit);
+                }
+                entries.push(entry);
+            }
+            let mut entry = [0; 32];
+            entry[..11].copy_from_slice(&short);
+            entries.push(entry);
+            short
+        }
+    };
+    used.insert(short);
+    Ok(entries)
+}
+
+/// Fill in the short entry of a file or directory
+fn short_entry(entry: &mut [u8], attr: u8, cluster: u32, size: u32, node: &Node) {
+    let (cdate, ctime) = to_dos_time(node.ctime);
+    let (mdate, mtime) = to_dos_time(node.mtime);
+    entry[11] = attr;
+    put_u16(entry, 14, ctime);
+    put_u16(entry, 16, cdate);
+    put_u16(entry, 18, mdate);
+    put_u16(entry, 20, (cluster >> 16) as u16);
+    put_u16(entry, 22, mtime);
+    put_u16(entry, 24, mdate);
+    put_u16(entry, 26, cluster as u16);
+    put_u32(entry, 28, size);
+}
+
+/// A writer for a new FAT32 filesystem, allocating clusters in order
+struct FatWriter<W> {
+    image: W,
+    cluster_size: u64,
+    /// Byte offset of cluster 2
+    data_start: u64,
+    fat: Vec<u32>,
+    /// Next free cluster
+    next: u32,
+}
+
+impl<W: Write + Seek> FatWriter<W> {
+    /// Allocate a chain of contiguous clusters, returning the first or 0 for none
+    fn alloc(&mut self, size: u64) -> io::Result<u32> {
+        let count = (size + self.cluster_size - 1)/self.cluster_size;
+        if count == 0 {
+            return Ok(0);
+        }
+        if self.next as u64 + count > self.fat.len() as u64 {
+            return Err(Error::NoSpace.into());
+        }
+
+        let first = self.next;
+        for i in 0..count as u32 {
+            self.fat[(first + i) as usize] = first + i + 1;
+        }
+        self.next += count as u32;
+        self.fat[self.next as usize - 1] = CLUSTER_MASK;
+        Ok(first)
+    }
+
+    fn seek_cluster(&mut self, cluster: u32) -> io::Result<()> {
+        self.image.seek(SeekFrom::Start(self.data_start + (cluster as u64 - 2) * self.cluster_size))?;
+        Ok(())
+    }
+
+    /// Write a directory node and the nodes below it, returning its first cluster
+    fn extract_dir<D: Disk>(&mut self, fs: &mut FileSystem<D>, block: u64, parent_cluster: Option<u32>) -> io::Result<u32> {
+        let children: Vec<(u64, Node)> = sorted_children(fs, block)?.into_iter()
+            .filter(|child| child.1.is_dir() || child.1.is_file())
+            .collect();
+
+        // Names are looked up ignoring case, so they have to differ in more than case
+        let mut used = HashSet::new();
+        let mut lower = HashSet::new();
+        let mut names = Vec::new();
+        for child in children.iter() {
+            let name = child.1.name().or(Err(Error::InvalidName))?;
+            if ! lower.insert(name.to_lowercase()) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("names differ only in case: {}", name)));
+            }
+            names.push(name_entries(name, &mut used)?);
+        }
+
+        let mut data = Vec::new();
+        if parent_cluster.is_some() {
+            data.resize(64, 0);
+        }
+        let count = data.len() + names.iter().map(|entries| entries.len() * 32).sum::<usize>();
+        let cluster = self.alloc(cmp::max(count as u64, 1))?;
+
+        if let Some(parent) = parent_cluster {
+            let node = fs.node(block)?.1;
+            data[..11].copy_from_slice(b".          ");
+            short_entry(&mut data[..32], ATTR_DIRECTORY, cluster, 0, &node);
+            data[32..43].copy_from_slice(b"..         ");
+            let parent = if parent == ROOT_CLUSTER { 0 } else { parent };
+            short_entry(&mut data[32..64], ATTR_DIRECTORY, parent, 0, &node);
+        }
+
+        for (child, mut entries) in children.into_iter().zip(names.into_iter()) {
+            let read_only = child.1.mode & (Node::MODE_WRITE << 6) == 0;
+            let mut attr = if read_only { ATTR_READ_ONLY } else { 0 };
+            let (first, size) = if child.1.is_dir() {
+                attr |= ATTR_DIRECTORY;
+                (self.extract_dir(fs, child.0, Some(cluster))?, 0)
+            } else {
+                let size = fs.node_len(child.0)?;
+                if size > u32::max_value() as u64 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "file too large for FAT32"));
+                }
+                attr |= ATTR_ARCHIVE;
+                let first = self.alloc(size)?;
+                if first != 0 {
+                    self.seek_cluster(first)?;
+                    copy_from_node(fs, child.0, &mut self.image)?;
+                }
+                (first, size as u32)
+            };
+
+            let last = entries.len() - 1;
+            short_entry(&mut entries[last], attr, first, size, &child.1);
+            for entry in entries.iter() {
+                data.extend_from_slice(entry);
+            }
+        }
+
+        // Unused entries are zero, which also marks the end of the directory
+        let len = (data.len() as u64 + self.cluster_size - 1)/self.cluster_size;
+        data.resize((cmp::max(len, 1) * self.cluster_size) as usize, 0);
+        self.seek_cluster(cluster)?;
+        self.image.write_all(&data)?;
+        Ok(cluster)
+    }
+}
+
+/// Sectors per cluster for a FAT32 filesystem of a number of sectors, as chosen by Windows
+fn sectors_per_cluster(total: u64) -> u64 {
+    let size = total * SECTOR_SIZE;
+    if size <= 260 * 1024 * 1024 {
+        1
+    } else if size <= 8 << 30 {
+        8
+    } else if size <= 16 << 30 {
+        16
+    } else if size <= 32 << 30 {
+        32
+    } else {
+        64
+    }
+}
+
+/// Create a FAT32 filesystem filling an image, such as an EFI system partition, holding a copy of a directory node
+///
+/// Symlinks are skipped, as FAT has no place for them. Files without write permission for
+/// their owner are marked read-only.
+pub fn extract_fat<D: Disk, W: Write + Seek>(fs: &mut FileSystem<D>, parent_block: u64, image: W) -> io::Result<()> {
+    let mut image = image;
+    let total = image.seek(SeekFrom::End(0))? / SECTOR_SIZE;
+    if total > u32::max_value() as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "image too large for FAT32"));
+    }
+
+    let spc = sectors_per_cluster(total);
+    let per_fat = (256 * spc + FATS)/2;
+    let fat_size = (total.saturating_sub(RESERVED_SECTORS) + per_fat - 1)/per_fat;
+    let clusters = total.saturating_sub(RESERVED_SECTORS + FATS * fat_size) / spc;
+    if clusters < CLUSTERS_MIN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "image too small for FAT32, which needs at least 33 MiB"));
+    }
+
+    let mut writer = FatWriter {
+        image: image,
+        cluster_size: spc * SECTOR_SIZE,
+        data_start: (RESERVED_SECTORS + FATS * fat_size) * SECTOR_SIZE,
+        fat: vec![0; (clusters + 2) as usize],
+        next: ROOT_CLUSTER,
+    };
+    writer.fat[0] = 0x0FFFFFF8;
+    writer.fat[1] = CLUSTER_MASK;
+    writer.extract_dir(fs, parent_block, None)?;
+
+    let mut fat = vec![0; (fat_size * SECTOR_SIZE) as usize];
+    for (i, &entry) in writer.fat.iter().enumerate() {
+        put_u32(&mut fat, i * 4, entry);
+    }
+    for i in 0..FATS {
+        writer.image.seek(SeekFrom::Start((RESERVED_SECTORS + i * fat_size) * SECTOR_SIZE))?;
+        writer.image.write_all(&fat)?;
+    }
+
+    let mut boot = [0; SECTOR_SIZE as usize];
+    boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot[3..11].copy_from_slice(b"REDOXFS ");
+    put_u16(&mut boot, 11, SECTOR_SIZE as u16);
+    boot[13] = spc as u8;
+    put_u16(&mut boot, 14, RESERVED_SECTORS as u16);
+    boot[16] = FATS as u8;
+    boot[21] = 0xF8;
+    put_u16(&mut boot, 24, 32);
+    put_u16(&mut boot, 26, 64);
+    put_u32(&mut boot, 32, total as u32);
+    put_u32(&mut boot, 36, fat_size as u32);
+    put_u32(&mut boot, 44, ROOT_CLUSTER);
+    put_u16(&mut boot, 48, 1);
+    put_u16(&mut boot, 50, 6);
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    boot[67..71].copy_from_slice(&fs.header.1.uuid[..4]);
+    boot[71..82].copy_from_slice(b"NO NAME    ");
+    boot[82..90].copy_from_slice(b"FAT32   ");
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+
+    let mut info = [0; SECTOR_SIZE as usize];
+    put_u32(&mut info, 0, 0x41615252);
+    put_u32(&mut info, 484, 0x61417272);
+    put_u32(&mut info, 488, (clusters + 2 - writer.next as u64) as u32);
+    put_u32(&mut info, 492, writer.next);
+    put_u32(&mut info, 508, 0xAA550000);
+
+    // The boot sector and the info sector, with backups at sector 6
+    for &start in [0, 6].iter() {
+        writer.image.seek(SeekFrom::Start(start * SECTOR_SIZE))?;
+        writer.image.write_all(&boot)?;
+        writer.image.write_all(&info)?;
+    }
+    writer.image.flush()
+}
+
+#[test]
+fn fat_test() {
+    use std::io::Cursor;
+    use archive::read_all;
+    use testing::memory_fs;
+
+    assert_eq!(to_dos_time(from_dos_time(0x5A2E, 0x6B3D)), (0x5A2E, 0x6B3D));
+    assert_eq!(from_dos_time(0x21, 0), days_from_civil(1980, 1, 1) as u64 * 86400);
+    assert_eq!(to_dos_time(0), (0x21, 0));
+
+    let mut filesystem = memory_fs(256);
+    let root = filesystem.header.1.root;
+
+    let mtime = days_from_civil(2020, 5, 17) as u64 * 86400 + 3600;
+    let efi = filesystem.create_node(Node::MODE_DIR | 0o755, "EFI", root, 0, 0).unwrap();
+    let boot = filesystem.create_node(Node::MODE_DIR | 0o755, "boot", efi.0, 0, 0).unwrap();
+    let loader = filesystem.create_node(Node::MODE_FILE | 0o444, "BOOTX64.EFI", boot.0, 0, 0).unwrap();
+    filesystem.write_node(loader.0, 0, &[3; 1000], mtime, 0).unwrap();
+    let long = filesystem.create_node(Node::MODE_FILE | 0o644, "A long name, in Ünicode.conf", root, 0, 0).unwrap();
+    filesystem.write_node(long.0, 0, b"timeout 5", mtime, 0).unwrap();
+    let similar = filesystem.create_node(Node::MODE_FILE | 0o644, "A long name 2.conf", root, 0, 0).unwrap();
+    filesystem.write_node(similar.0, 0, b"other", mtime, 0).unwrap();
+    filesystem.create_node(Node::MODE_FILE | 0o644, "empty", root, 0, 0).unwrap();
+    filesystem.create_node(Node::MODE_SYMLINK | 0o777, "link", root, 0, 0).unwrap();
+
+    let mut image = Cursor::new(vec![0; 40 * 1024 * 1024]);
+    extract_fat(&mut filesystem, root, &mut image).unwrap();
+
+    let dest = filesystem.create_node(Node::MODE_DIR | 0o755, "dest", root, 0, 0).unwrap();
+    image.set_position(0);
+    archive_fat(&mut filesystem, &mut image, dest.0).unwrap();
+
+    let mut names: Vec<String> = sorted_children(&mut filesystem, dest.0).unwrap().iter().map(|child| child.1.name().unwrap().to_string()).collect();
+    names.sort();
+    assert_eq!(names, ["A long name 2.conf", "A long name, in Ünicode.conf", "EFI", "empty"]);
+
+    let efi = filesystem.find_node("EFI", dest.0).unwrap();
+    let boot = filesystem.find_node("boot", efi.0).unwrap();
+    assert!(boot.1.is_dir());
+    let loader = filesystem.find_node("BOOTX64.EFI", boot.0).unwrap();
+    assert_eq!({ loader.1.mode }, Node::MODE_FILE | 0o444);
+    assert_eq!({ loader.1.mtime }, mtime);
+    assert_eq!(read_all(&mut filesystem, loader.0).unwrap(), vec![3; 1000]);
+    let long = filesystem.find_node("A long name, in Ünicode.conf", dest.0).unwrap();
+    assert_eq!({ long.1.mode }, Node::MODE_FILE | 0o644);
+    assert_eq!(read_all(&mut filesystem, long.0).unwrap(), b"timeout 5");
+    let similar = filesystem.find_node("A long name 2.conf", dest.0).unwrap();
+    assert_eq!(read_all(&mut filesystem, similar.0).unwrap(), b"other");
+    let empty = filesystem.find_node("empty", dest.0).unwrap();
+    assert_eq!(filesystem.node_len(empty.0).unwrap(), 0);
+}
@@ -0,0 +1,338 @@
+//! A C interface to the filesystem, for tools that are not written in Rust
+//!
+//! Functions returning a number give a negative errno on failure. Nodes are named by the
+//! number of their block, as in `ExStat::ino`. See `include/redoxfs.h`.
+//!
+//! A panic never unwinds into C: it fails the call with `-EIO`, or null, and the filesystem
+//! should then be closed, as what it holds in memory may be inconsistent.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{ptr, slice};
+
+use syscall::error::{EINVAL, EIO, ERANGE};
+
+use disk::{DiskCache, DiskFile};
+use error::{Error, Result};
+use ex_stat::ExStat;
+use filesystem::FileSystem;
+use node::Node;
+
+/// An open filesystem, opaque to C
+pub type RedoxFs = FileSystem<DiskCache<DiskFile>>;
+
+fn now() -> (u64, u32) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    (time.as_secs(), time.subsec_nanos())
+}
+
+unsafe fn c_str<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(Error::InvalidName);
+    }
+    CStr::from_ptr(s).to_str().or(Err(Error::InvalidName))
+}
+
+/// Run the body of an entry point, returning error if it panics
+fn guard<T, F: FnOnce() -> T>(error: T, f: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(error)
+}
+
+fn status(res: Result<()>) -> c_int {
+    match res {
+        Ok(()) => 0,
+        Err(err) => -err.errno(),
+    }
+}
+
+fn number(res: Result<u64>) -> i64 {
+    match res {
+        Ok(value) => value as i64,
+        Err(err) => -err.errno() as i64,
+    }
+}
+
+/// Open the filesystem on a disk or image, returning null on failure
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_open(path: *const c_char) -> *mut RedoxFs {
+    guard(ptr::null_mut(), || {
+        let path = match c_str(path) {
+            Ok(path) => path,
+            Err(_) => return ptr::null_mut(),
+        };
+        match DiskFile::open(path).map_err(|_| Error::Io(0)).and_then(|disk| FileSystem::open(DiskCache::new(disk))) {
+            Ok(fs) => Box::into_raw(Box::new(fs)),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Create an image of size bytes holding a new filesystem, returning null on failure
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_mkfs(path: *const c_char, size: u64) -> *mut RedoxFs {
+    guard(ptr::null_mut(), || {
+        let path = match c_str(path) {
+            Ok(path) => path,
+            Err(_) => return ptr::null_mut(),
+        };
+        let ctime = now();
+        match DiskFile::create(path, size).map_err(|_| Error::Io(0)).and_then(|disk| FileSystem::create(DiskCache::new(disk), ctime.0, ctime.1)) {
+            Ok(fs) => Box::into_raw(Box::new(fs)),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Write everything to the disk and free the filesystem, which is freed even on failure
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_close(fs: *mut RedoxFs) -> c_int {
+    guard(-EIO, || {
+        if fs.is_null() {
+            return -EINVAL;
+        }
+        let mut fs = Box::from_raw(fs);
+        status(fs.sync_all())
+    })
+}
+
+/// Write everything to the disk
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_sync(fs: *mut RedoxFs) -> c_int {
+    guard(-EIO, || {
+        match fs.as_mut() {
+            Some(fs) => status(fs.sync_all()),
+            None => -EINVAL,
+        }
+    })
+}
+
+/// The root directory node
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_root(fs: *const RedoxFs) -> u64 {
+    guard(0, || {
+        match fs.as_ref() {
+            Some(fs) => fs.root(),
+            None => 0,
+        }
+    })
+}
+
+/// Find the node at a path of names separated by slashes, starting at parent
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_lookup(fs: *mut RedoxFs, parent: u64, path: *const c_char) -> i64 {
+    guard(-EIO as i64, || {
+        let fs = match fs.as_mut() {
+            Some(fs) => fs,
+            None => return -EINVAL as i64,
+        };
+        number(c_str(path).and_then(|path| {
+            let mut block = parent;
+            for name in path.split('/').filter(|name| ! name.is_empty()) {
+                block = fs.find_node(name, block)?.0;
+            }
+            Ok(block)
+        }))
+    })
+}
+
+/// Fill stat with the metadata of a node
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_stat(fs: *mut RedoxFs, node: u64, stat: *mut ExStat) -> c_int {
+    guard(-EIO, || {
+        match (fs.as_mut(), stat.as_mut()) {
+            (Some(fs), Some(stat)) => status(fs.node_stat(node).map(|node_stat| *stat = node_stat)),
+            _ => -EINVAL,
+        }
+    })
+}
+
+/// Read up to len bytes at offset, returning the number read
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_read(fs: *mut RedoxFs, node: u64, offset: u64, buf: *mut u8, len: usize) -> i64 {
+    guard(-EIO as i64, || {
+        match fs.as_mut() {
+            Some(fs) if ! buf.is_null() || len == 0 => {
+                let buf = if len == 0 { &mut [][..] } else { slice::from_raw_parts_mut(buf, len) };
+                number(fs.read_node(node, offset, buf).map(|count| count as u64))
+            },
+            _ => -EINVAL as i64,
+        }
+    })
+}
+
+/// Write len bytes at offset, returning the number written
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_write(fs: *mut RedoxFs, node: u64, offset: u64, buf: *const u8, len: usize) -> i64 {
+    guard(-EIO as i64, || {
+        match fs.as_mut() {
+            Some(fs) if ! buf.is_null() || len == 0 => {
+                let buf = if len == 0 { &[][..] } else { slice::from_raw_parts(buf, len) };
+                let mtime = now();
+                number(fs.node_check_write(node, false).and_then(|_| fs.write_node(node, offset, buf, mtime.0, mtime.1)).map(|count| count as u64))
+            },
+            _ => -EINVAL as i64,
+        }
+    })
+}
+
+/// Change the length of a node
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_truncate(fs: *mut RedoxFs, node: u64, len: u64) -> c_int {
+    guard(-EIO, || {
+        match fs.as_mut() {
+            Some(fs) => status(fs.node_set_len(node, len)),
+            None => -EINVAL,
+        }
+    })
+}
+
+/// Copy the name of entry index of a directory to name, null terminated, returning its node
+///
+/// Returns 0 after the last entry, and -ERANGE if name_len is too short for the name.
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_readdir(fs: *mut RedoxFs, dir: u64, index: u64, name: *mut c_char, name_len: usize) -> i64 {
+    guard(-EIO as i64, || {
+        let fs = match fs.as_mut() {
+            Some(fs) if ! name.is_null() => fs,
+            _ => return -EINVAL as i64,
+        };
+
+        let node = match fs.node(dir) {
+            Ok(node) => node,
+            Err(err) => return -err.errno() as i64,
+        };
+        if ! node.1.is_dir() {
+            return -Error::NotDir.errno() as i64;
+        }
+
+        let block = match fs.child_blocks(dir).nth(index as usize) {
+            Some(Ok(block)) => block,
+            Some(Err(err)) => return -err.errno() as i64,
+            None => return 0,
+        };
+        let child = match fs.node(block) {
+            Ok(child) => child,
+            Err(err) => return -err.errno() as i64,
+        };
+
+        let bytes = match child.1.name() {
+            Ok(child_name) => child_name.as_bytes(),
+            Err(_) => return -Error::InvalidName.errno() as i64,
+        };
+        if bytes.len() >= name_len {
+            return -ERANGE as i64;
+        }
+        let name = slice::from_raw_parts_mut(name as *mut u8, name_len);
+        name[..bytes.len()].copy_from_slice(bytes);
+        name[bytes.len()] = 0;
+        child.0 as i64
+    })
+}
+
+/// Create a node with a mode such as 0o100644 or 0o40755 in a directory, returning it
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_create(fs: *mut RedoxFs, parent: u64, name: *const c_char, mode: u16) -> i64 {
+    guard(-EIO as i64, || {
+        let fs = match fs.as_mut() {
+            Some(fs) => fs,
+            None => return -EINVAL as i64,
+        };
+        let ctime = now();
+        number(c_str(name).and_then(|name| fs.create_node(mode, name, parent, ctime.0, ctime.1)).map(|node| node.0))
+    })
+}
+
+/// Remove a file, symlink, or empty directory
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_remove(fs: *mut RedoxFs, parent: u64, name: *const c_char) -> c_int {
+    guard(-EIO, || {
+        let fs = match fs.as_mut() {
+            Some(fs) => fs,
+            None => return -EINVAL,
+        };
+        status(c_str(name).and_then(|name| {
+            let node = fs.find_node(name, parent)?;
+            fs.remove_node(node.1.mode & Node::MODE_TYPE, name, parent)
+        }))
+    })
+}
+
+/// Move a node to a new name and directory, replacing the node with that name
+#[no_mangle]
+pub unsafe extern "C" fn redoxfs_rename(fs: *mut RedoxFs, parent: u64, name: *const c_char, new_parent: u64, new_name: *const c_char) -> c_int {
+    guard(-EIO, || {
+        let fs = match fs.as_mut() {
+            Some(fs) => fs,
+            None => return -EINVAL,
+        };
+        status(c_str(name).and_then(|name| {
+            let new_name = c_str(new_name)?;
+            match fs.rename_node(name, parent, new_name, new_parent)? {
+                Some(block) => fs.release_node(block),
+                None => Ok(())
+            }
+        }))
+    })
+}
+
+#[test]
+fn guard_test() {
+    assert_eq!(guard(-EIO, || -> c_int { panic!("guard_test") }), -EIO);
+    assert_eq!(guard(-EIO, || 0), 0);
+}
+
+#[test]
+fn ffi_test() {
+    use std::{env, fs};
+    use std::ffi::CString;
+
+    let path = env::temp_dir().join(format!("redoxfs_ffi_test_{}.img", ::std::process::id()));
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    let dir_name = CString::new("dir").unwrap();
+    let file_name = CString::new("file").unwrap();
+    let file_path = CString::new("/dir/file").unwrap();
+    let missing_path = CString::new("dir/missing").unwrap();
+    unsafe {
+        let fs = redoxfs_mkfs(c_path.as_ptr(), 1024 * 1024);
+        assert!(! fs.is_null());
+        let root = redoxfs_root(fs);
+
+        let dir = redoxfs_create(fs, root, dir_name.as_ptr(), Node::MODE_DIR | 0o755);
+        assert!(dir > 0);
+        let file = redoxfs_create(fs, dir as u64, file_name.as_ptr(), Node::MODE_FILE | 0o644);
+        assert!(file > 0);
+        assert_eq!(redoxfs_write(fs, file as u64, 0, b"hello".as_ptr(), 5), 5);
+        assert_eq!(redoxfs_close(fs), 0);
+
+        let fs = redoxfs_open(c_path.as_ptr());
+        assert!(! fs.is_null());
+        let root = redoxfs_root(fs);
+        let file = redoxfs_lookup(fs, root, file_path.as_ptr());
+        assert!(file > 0);
+        assert_eq!(redoxfs_lookup(fs, root, missing_path.as_ptr()), -Error::NotFound.errno() as i64);
+
+        let mut stat = ExStat::default();
+        assert_eq!(redoxfs_stat(fs, file as u64, &mut stat), 0);
+        assert_eq!({ stat.size }, 5);
+        let mut buf = [0; 16];
+        assert_eq!(redoxfs_read(fs, file as u64, 1, buf.as_mut_ptr(), buf.len()), 4);
+        assert_eq!(&buf[..4], b"ello");
+
+        let dir = redoxfs_lookup(fs, root, dir_name.as_ptr()) as u64;
+        let mut name = [0 as c_char; 8];
+        assert_eq!(redoxfs_readdir(fs, dir, 0, name.as_mut_ptr(), name.len()), file);
+        assert_eq!(CStr::from_ptr(name.as_ptr()).to_str(), Ok("file"));
+        assert_eq!(redoxfs_readdir(fs, dir, 0, name.as_mut_ptr(), 4), -ERANGE as i64);
+        assert_eq!(redoxfs_readdir(fs, dir, 1, name.as_mut_ptr(), name.len()), 0);
+
+        assert_eq!(redoxfs_remove(fs, root, dir_name.as_ptr()), -Error::NotEmpty.errno());
+        assert_eq!(redoxfs_rename(fs, dir, file_name.as_ptr(), root, file_name.as_ptr()), 0);
+        assert_eq!(redoxfs_lookup(fs, root, file_name.as_ptr()), file);
+        assert_eq!(redoxfs_remove(fs, root, file_name.as_ptr()), 0);
+        assert_eq!(redoxfs_remove(fs, root, dir_name.as_ptr()), 0);
+        assert_eq!(redoxfs_close(fs), 0);
+    }
+    fs::remove_file(&path).unwrap();
+}
@@ -1,39 +1,239 @@
+use std::borrow::Cow;
 use std::cmp::min;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+
+#[cfg(feature = "nfc")]
+use unicode_normalization::{self, UnicodeNormalization};
+
+use uuid::Uuid;
+
+use extent::BlockIter;
+use xattr::{self, XattrNamespace};
+use {BLOCK_SIZE, SIGNATURE, Disk, Error, ExNode, ExStat, Extent, Header, Node, Result};
+#[cfg(test)]
+use testing::{DiskMemory, DiskReorder, DiskSyncs, memory_fs};
+
+/// How names are stored when nodes are created, and compared when they are looked up
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NamePolicy {
+    /// Use names exactly as given
+    Preserve,
+    /// Normalize names to Unicode Normalization Form C, so composed and decomposed names are the same
+    #[cfg(feature = "nfc")]
+    Nfc,
+}
+
+/// Something a filesystem tells its user about, through the callback given to
+/// `FileSystem::set_notify`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Notice {
+    /// The physical sector size of the disk is larger than the block size, so writes will be
+    /// slow, with both sizes
+    SlowSectors(u64, u64),
+    /// Free space dropped below a low space threshold, with the free blocks and the threshold
+    LowSpace(u64, u64),
+    /// Free space rose above a low space threshold, with the free blocks
+    SpaceRecovered(u64),
+    /// A rename that was interrupted was finished when opening, with the block of the node
+    RenameFinished(u64),
+    /// A rename that was interrupted could not be finished, as the disk is read-only
+    RenameLeft(u64),
+    /// A node left in the orphan list was freed when opening, with its block
+    OrphanReclaimed(u64),
+    /// The nodes left in the orphan list could not be freed, as the disk is read-only
+    OrphansLeft,
+}
 
-use syscall::error::{Result, Error, EEXIST, EISDIR, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY};
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Notice::SlowSectors(physical, block_size) => write!(f, "physical sector size {} is larger than block size {}, writes will be slow", physical, block_size),
+            Notice::LowSpace(free, threshold) => write!(f, "free space is low, {} blocks are left, below {} blocks", free, threshold),
+            Notice::SpaceRecovered(free) => write!(f, "free space is no longer as low, {} blocks are free", free),
+            Notice::RenameFinished(block) => write!(f, "finished interrupted rename of node at block {}", block),
+            Notice::RenameLeft(block) => write!(f, "read-only disk, not finishing interrupted rename of node at block {}", block),
+            Notice::OrphanReclaimed(block) => write!(f, "reclaimed orphaned node at block {}", block),
+            Notice::OrphansLeft => write!(f, "read-only disk, not reclaiming orphaned nodes"),
+        }
+    }
+}
 
-use {BLOCK_SIZE, Disk, ExNode, Extent, Header, Node};
+/// Most lookups remembered by `find_node`
+const LOOKUP_CACHE_SIZE: usize = 65536;
 
 /// A file system
 pub struct FileSystem<D: Disk> {
     pub disk: D,
     pub block: u64,
-    pub header: (u64, Header)
+    pub header: (u64, Header),
+    /// Block of the root directory that frontends serve, the top level tree or a subvolume
+    root: u64,
+    /// Nodes whose data changed since the last sync
+    dirty: BTreeSet<u64>,
+    /// Blocks were freed since the last barrier, which must not be reused before the nodes
+    /// that let go of them are on the disk
+    freed: bool,
+    /// Look up names ignoring case
+    case_insensitive: bool,
+    name_policy: NamePolicy,
+    /// Overwrite the freed data of every node with zeros
+    secure_delete: bool,
+    /// Owner and group reported for every node instead of the stored ones
+    uid: Option<u32>,
+    gid: Option<u32>,
+    /// Permission bits cleared from every reported mode
+    umask: u16,
+    /// Allocations may use the blocks reserved for root
+    privileged: bool,
+    /// Frontends that can check permissions themselves do so, see `mount::permissions`
+    check_permissions: bool,
+    /// Blocks of nodes found by name, keyed by parent block and lookup name, with the number of
+    /// the lookup that found them
+    lookups: HashMap<(u64, String), (u64, u64)>,
+    /// Keys of the lookups, oldest first, with their numbers, so that a key that was forgotten
+    /// and found again is not evicted by its earlier entry
+    lookup_order: VecDeque<((u64, String), u64)>,
+    /// Number of the next lookup to be remembered
+    lookup_next: u64,
+    /// The nodes of the free list, in order, kept in memory so allocating does not read them
+    free_nodes: Vec<(u64, Node)>,
+    /// Numbers of free blocks below which free space is low, from the highest
+    low_space: Vec<u64>,
+    /// Number of the `low_space` thresholds the free blocks are below
+    low_space_level: usize,
+    /// The level changed since `take_low_space_change` was called
+    low_space_changed: bool,
+    notify: Box<FnMut(Notice) + Send>,
+}
+
+/// Check that a name can be stored in a node
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('/') || name.contains('\0') {
+        return Err(Error::InvalidName);
+    }
+    if name.len() > Node::NAME_MAX {
+        return Err(Error::NameTooLong);
+    }
+    Ok(())
 }
 
 impl<D: Disk> FileSystem<D> {
-    /// Open a file system on a disk
-    pub fn open(mut disk: D) -> Result<Self> {
+    /// Find the header on a disk, returning the block it was found at
+    pub fn probe(disk: &mut D) -> Result<(u64, Header)> {
         for block in 0..65536 {
-            let mut header = (0, Header::default());
-            disk.read_at(block + header.0, &mut header.1)?;
+            let mut header = Header::default();
+            disk.read_at(block, &mut header)?;
+
+            if header.valid() {
+                return Ok((block, header));
+            } else if &header.signature == SIGNATURE {
+                return Err(Error::Version(header.version));
+            }
+        }
+
+        Err(Error::NoHeader)
+    }
 
-            if header.1.valid() {
-                let mut root = (header.1.root, Node::default());
-                disk.read_at(block + root.0, &mut root.1)?;
+    /// Open a file system on a disk
+    pub fn open(disk: D) -> Result<Self> {
+        Self::open_notify(disk, Box::new(|_| ()))
+    }
+
+    /// Open a file system like `open`, calling notify with the notices of opening it, such as
+    /// an interrupted rename being finished, and later ones, see `set_notify`
+    pub fn open_notify(mut disk: D, mut notify: Box<FnMut(Notice) + Send>) -> Result<Self> {
+        let (block, header) = Self::probe(&mut disk)?;
+        let header = (0, header);
+
+        if header.1.spanned() && (header.1.span_index != 0 || disk.size()? < header.1.size) {
+            return Err(Error::Incomplete);
+        }
+
+        let block_size = header.1.block_size();
+        if block_size < BLOCK_SIZE || ! block_size.is_power_of_two() {
+            return Err(Error::BlockSize(block_size));
+        }
+
+        let (logical, physical) = disk.sector_size();
+        if logical > BLOCK_SIZE {
+            return Err(Error::BlockSize(logical));
+        }
+        if physical > block_size {
+            notify(Notice::SlowSectors(physical, block_size));
+        }
+
+        let blocks = header.1.size/BLOCK_SIZE;
+        if header.1.root >= blocks || header.1.free >= blocks || header.1.orphans >= blocks
+            || header.1.subvolumes >= blocks || header.1.default_subvolume >= blocks {
+            return Err(Error::Corrupt(block + header.0));
+        }
 
-                let mut free = (header.1.free, Node::default());
-                disk.read_at(block + free.0, &mut free.1)?;
+        let mut root = (header.1.root, Node::default());
+        disk.read_at(block + root.0, &mut root.1)?;
 
-                return Ok(FileSystem {
-                    disk: disk,
-                    block: block,
-                    header: header
-                });
+        // Load the whole free list, which is only changed through this filesystem from now on
+        let mut free_nodes = Vec::new();
+        let mut free_block = header.1.free;
+        while free_block != 0 {
+            if free_block >= blocks || free_nodes.len() as u64 >= blocks {
+                return Err(Error::Corrupt(block + free_block));
+            }
+            let mut free = (free_block, Node::default());
+            disk.read_at(block + free.0, &mut free.1)?;
+            free_block = free.1.next;
+            free_nodes.push(free);
+        }
+
+        let case_insensitive = header.1.flags & Header::FLAG_CASE_INSENSITIVE == Header::FLAG_CASE_INSENSITIVE;
+        let root = if header.1.default_subvolume != 0 {
+            header.1.default_subvolume
+        } else {
+            header.1.root
+        };
+        let mut fs = FileSystem {
+            disk: disk,
+            block: block,
+            header: header,
+            root: root,
+            dirty: BTreeSet::new(),
+            freed: false,
+            case_insensitive: case_insensitive,
+            name_policy: NamePolicy::Preserve,
+            secure_delete: false,
+            uid: None,
+            gid: None,
+            umask: 0,
+            privileged: true,
+            check_permissions: false,
+            lookups: HashMap::new(),
+            lookup_order: VecDeque::new(),
+            lookup_next: 0,
+            free_nodes: free_nodes,
+            low_space: Vec::new(),
+            low_space_level: 0,
+            low_space_changed: false,
+            notify: notify,
+        };
+
+        // A rename that was interrupted is finished before the node it replaced is freed
+        let rename = fs.header.1.rename;
+        if rename != 0 {
+            match fs.finish_rename() {
+                Ok(()) => (fs.notify)(Notice::RenameFinished(rename)),
+                Err(Error::ReadOnly) => (fs.notify)(Notice::RenameLeft(rename)),
+                Err(err) => return Err(err)
             }
         }
 
-        Err(Error::new(ENOENT))
+        // Nodes left in the orphan list were still open when the filesystem was last used
+        match fs.reclaim_orphans() {
+            Ok(()) => (),
+            Err(Error::ReadOnly) => (fs.notify)(Notice::OrphansLeft),
+            Err(err) => return Err(err)
+        }
+
+        Ok(fs)
     }
 
     /// Create a file system on a disk
@@ -43,19 +243,42 @@ impl<D: Disk> FileSystem<D> {
 
     /// Create a file system on a disk, with reserved data at the beginning
     /// Reserved data will be zero padded up to the nearest block
-    pub fn create_reserved(mut disk: D, reserved: &[u8], ctime: u64, ctime_nsec: u32) -> Result<Self> {
+    pub fn create_reserved(disk: D, reserved: &[u8], ctime: u64, ctime_nsec: u32) -> Result<Self> {
+        Self::create_block_size(disk, reserved, BLOCK_SIZE, ctime, ctime_nsec)
+    }
+
+    /// Create a file system on a disk, with reserved data at the beginning, allocating space
+    /// in units of block_size bytes
+    pub fn create_block_size(mut disk: D, reserved: &[u8], block_size: u64, ctime: u64, ctime_nsec: u32) -> Result<Self> {
+        if block_size < BLOCK_SIZE || ! block_size.is_power_of_two() {
+            return Err(Error::BlockSize(block_size));
+        }
+
+        // Refuse to create blocks smaller than what the device can write at once
+        let (logical, physical) = disk.sector_size();
+        if logical > BLOCK_SIZE || physical > block_size {
+            return Err(Error::BlockSize(block_size));
+        }
+
         let size = disk.size()?;
         let block_offset = (reserved.len() as u64 + BLOCK_SIZE - 1)/BLOCK_SIZE;
 
-        if size >= (block_offset + 4) * BLOCK_SIZE {
+        // Free space starts at the first allocation block after the header, root, and free nodes
+        let cluster = block_size/BLOCK_SIZE;
+        let free_start = ((4 + cluster - 1)/cluster) * cluster;
+
+        if size >= (block_offset + free_start) * BLOCK_SIZE {
+            let free_blocks = (size/BLOCK_SIZE - block_offset - free_start)/cluster * cluster;
             let mut free = (2, Node::new(Node::MODE_FILE, "free", 0, ctime, ctime_nsec));
-            free.1.extents[0] = Extent::new(4, size - (block_offset + 4) * BLOCK_SIZE);
+            free.1.extents_mut()[0] = Extent::new(free_start, free_blocks * BLOCK_SIZE);
             disk.write_at(block_offset + free.0, &free.1)?;
 
             let root = (1, Node::new(Node::MODE_DIR | 0o755, "root", 0, ctime, ctime_nsec));
             disk.write_at(block_offset + root.0, &root.1)?;
 
-            let header = (0, Header::new(size, root.0, free.0));
+            let mut header = (0, Header::new(size, root.0, free.0));
+            header.1.block_size = block_size;
+            disk.barrier()?;
             disk.write_at(block_offset + header.0, &header.1)?;
 
             for block in 0..block_offset as usize {
@@ -73,10 +296,29 @@ impl<D: Disk> FileSystem<D> {
             Ok(FileSystem {
                 disk: disk,
                 block: block_offset,
-                header: header
+                header: header,
+                root: root.0,
+                dirty: BTreeSet::new(),
+                freed: false,
+                case_insensitive: false,
+                name_policy: NamePolicy::Preserve,
+                secure_delete: false,
+                uid: None,
+                gid: None,
+                umask: 0,
+                privileged: true,
+                check_permissions: false,
+                lookups: HashMap::new(),
+                lookup_order: VecDeque::new(),
+                lookup_next: 0,
+                free_nodes: vec![free],
+                low_space: Vec::new(),
+                low_space_level: 0,
+                low_space_changed: false,
+                notify: Box::new(|_| ()),
             })
         } else {
-            Err(Error::new(ENOSPC))
+            Err(Error::NoSpace)
         }
     }
 
@@ -88,30 +330,117 @@ impl<D: Disk> FileSystem<D> {
         self.disk.write_at(self.block + block, buffer)
     }
 
+    /// Read consecutive blocks into several buffers with one request, see `Disk::read_vectored_at`
+    pub fn read_vectored_at(&mut self, block: u64, buffers: &mut [&mut [u8]]) -> Result<usize> {
+        self.disk.read_vectored_at(self.block + block, buffers)
+    }
+
+    /// Write several buffers to consecutive blocks with one request, see `Disk::write_vectored_at`
+    pub fn write_vectored_at(&mut self, block: u64, buffers: &[&[u8]]) -> Result<usize> {
+        self.disk.write_vectored_at(self.block + block, buffers)
+    }
+
+    /// Flush all data and metadata written so far to stable storage, like `fsync`
+    pub fn sync(&mut self) -> Result<()> {
+        self.disk.sync()?;
+        self.dirty.clear();
+        self.freed = false;
+        Ok(())
+    }
+
+    /// Flush the whole filesystem to stable storage, like `syncfs`
+    ///
+    /// Data, nodes and the free list reach the disk before the header is rewritten,
+    /// so the header never refers to anything that could still be lost.
+    pub fn sync_all(&mut self) -> Result<()> {
+        match self.write_header() {
+            Ok(()) | Err(Error::ReadOnly) => self.sync(),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Make sure everything written so far reaches the disk before anything written after,
+    /// see `Disk::barrier`
+    pub fn barrier(&mut self) -> Result<()> {
+        self.disk.barrier()?;
+        self.freed = false;
+        Ok(())
+    }
+
+    /// Write the header, behind a barrier so it never reaches the disk before the nodes it
+    /// refers to
+    pub fn write_header(&mut self) -> Result<()> {
+        self.barrier()?;
+        self.disk.write_at(self.block + self.header.0, &self.header.1)?;
+        Ok(())
+    }
+
+    /// Flush the data of a node to stable storage, like `fdatasync`
+    ///
+    /// The barrier is skipped if the contents and length of the node have not changed since the last sync.
+    pub fn sync_data(&mut self, block: u64) -> Result<()> {
+        if self.dirty.contains(&block) {
+            self.sync()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Number of BLOCK_SIZE blocks in an allocation block
+    fn cluster(&self) -> u64 {
+        self.header.1.block_size()/BLOCK_SIZE
+    }
+
+    /// Round a length in bytes up to a whole number of allocation blocks
+    fn round_up(&self, length: u64) -> u64 {
+        let block_size = self.header.1.block_size();
+        ((length + block_size - 1)/block_size) * block_size
+    }
+
+    /// Allocate length blocks, rounded up to whole allocation blocks
     pub fn allocate(&mut self, length: u64) -> Result<u64> {
-        //TODO: traverse next pointer
-        let free_block = self.header.1.free;
-        let mut free = self.node(free_block)?;
-        let mut block_option = None;
-        for extent in free.1.extents.iter_mut() {
-            if extent.length/BLOCK_SIZE >= length {
-                block_option = Some(extent.block);
-                extent.length -= length * BLOCK_SIZE;
-                extent.block += length;
-                break;
+        let cluster = self.cluster();
+        let length = ((length + cluster - 1)/cluster) * cluster;
+        if ! self.privileged && self.header.1.root_reserved > 0 && self.available_blocks() < length {
+            return Err(Error::NoSpace);
+        }
+
+        let mut found = None;
+        'nodes: for (i, free) in self.free_nodes.iter_mut().enumerate() {
+            for extent in free.1.extents_mut().iter_mut() {
+                if extent.length/BLOCK_SIZE >= length {
+                    found = Some((i, extent.block));
+                    extent.length -= length * BLOCK_SIZE;
+                    extent.block += length;
+                    break 'nodes;
+                }
             }
         }
-        if let Some(block) = block_option {
+        if let Some((i, block)) = found {
+            if self.freed {
+                self.barrier()?;
+            }
+            let free = self.free_nodes[i];
             self.write_at(free.0, &free.1)?;
+            self.update_low_space();
             Ok(block)
         } else {
-            Err(Error::new(ENOSPC))
+            Err(Error::NoSpace)
         }
     }
 
+    /// Deallocate length bytes starting at block, rounded up to whole allocation blocks
     pub fn deallocate(&mut self, block: u64, length: u64) -> Result<()> {
+        let length = self.round_up(length);
         let free_block = self.header.1.free;
-        self.insert_blocks(block, length, free_block)
+        // Growing the free list may take a block, which must not fail for lack of reserved space
+        let privileged = self.privileged;
+        self.privileged = true;
+        let res = self.insert_blocks(block, length, free_block);
+        self.privileged = privileged;
+        self.freed = true;
+        self.update_low_space();
+        res
     }
 
     pub fn node(&mut self, block: u64) -> Result<(u64, Node)> {
@@ -127,420 +456,1392 @@ impl<D: Disk> FileSystem<D> {
     }
 
     pub fn child_nodes(&mut self, children: &mut Vec<(u64, Node)>, parent_block: u64) -> Result<()> {
-        if parent_block == 0 {
-            return Ok(());
+        for child in self.child_iter(parent_block) {
+            children.push(child?);
         }
+        Ok(())
+    }
 
-        let parent = self.node(parent_block)?;
-        for extent in parent.1.extents.iter() {
-            for (block, size) in extent.blocks() {
-                if size >= BLOCK_SIZE {
-                    children.push(self.node(block)?);
-                }
-            }
+    /// Iterate over the blocks of the children of a directory, without reading the children
+    pub fn child_blocks<'a>(&'a mut self, parent_block: u64) -> ChildBlocks<'a, D> {
+        ChildBlocks {
+            fs: self,
+            next: parent_block,
+            list: None,
+            extent: 0,
+            blocks: Extent::default().blocks(),
         }
+    }
 
-        self.child_nodes(children, parent.1.next)
+    /// Iterate over the children of a directory, reading one node at a time
+    pub fn child_iter<'a>(&'a mut self, parent_block: u64) -> ChildNodes<'a, D> {
+        self.child_blocks(parent_block).nodes()
     }
 
-    pub fn find_node(&mut self, name: &str, parent_block: u64) -> Result<(u64, Node)> {
-        if parent_block == 0 {
-            return Err(Error::new(ENOENT));
-        }
+    /// True if names are looked up ignoring case
+    pub fn case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
 
-        let parent = self.node(parent_block)?;
-        for extent in parent.1.extents.iter() {
-            for (block, size) in extent.blocks() {
-                if size >= BLOCK_SIZE {
-                    let child = self.node(block)?;
+    /// Look up names ignoring case for this mount, without changing the flags on disk
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+        self.lookups.clear();
+        self.lookup_order.clear();
+    }
 
-                    let mut matches = false;
-                    if let Ok(child_name) = child.1.name() {
-                        if child_name == name {
-                            matches = true;
-                        }
-                    }
+    pub fn name_policy(&self) -> NamePolicy {
+        self.name_policy
+    }
+
+    /// Choose how names are stored and looked up for this mount
+    pub fn set_name_policy(&mut self, name_policy: NamePolicy) {
+        self.name_policy = name_policy;
+        self.lookups.clear();
+        self.lookup_order.clear();
+    }
+
+    pub fn secure_delete(&self) -> bool {
+        self.secure_delete
+    }
+
+    /// Overwrite freed data with zeros for this mount, as if every node had `Node::FLAG_SECURE_DELETE`
+    pub fn set_secure_delete(&mut self, secure_delete: bool) {
+        self.secure_delete = secure_delete;
+    }
+
+    /// Whether frontends that are not required to check the permissions of the caller, like FUSE, do
+    pub fn check_permissions(&self) -> bool {
+        self.check_permissions
+    }
+
+    /// Make frontends like FUSE check the permissions of the caller, as the Redox scheme always does
+    pub fn set_check_permissions(&mut self, check_permissions: bool) {
+        self.check_permissions = check_permissions;
+    }
+
+    /// Allow the following allocations to use the blocks reserved for root, which frontends
+    /// set for each request by whether it comes from root
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.privileged = privileged;
+    }
 
-                    if matches {
-                        return Ok(child);
+    /// Reserve a number of free blocks for root, so a full filesystem can still be used to recover
+    pub fn set_root_reserved(&mut self, blocks: u64) -> Result<()> {
+        self.header.1.root_reserved = blocks;
+        self.write_header()?;
+        Ok(())
+    }
+
+    /// Set the feature flags, see `Header::FLAG_*`, which mounts elsewhere would not notice.
+    /// Turning on `Header::FLAG_CASE_INSENSITIVE` fails with `Error::Exists` if a directory has
+    /// names that only differ in case.
+    pub fn set_flags(&mut self, flags: u64) -> Result<()> {
+        let case_insensitive = flags & Header::FLAG_CASE_INSENSITIVE == Header::FLAG_CASE_INSENSITIVE;
+        if case_insensitive && self.header.1.flags & Header::FLAG_CASE_INSENSITIVE == 0 {
+            let mut dirs = vec![self.header.1.root];
+            for subvolume in self.subvolumes()? {
+                dirs.push(subvolume.0);
+            }
+            let block = self.block;
+            while let Some(dir) = dirs.pop() {
+                let mut names = BTreeSet::new();
+                for child in self.child_iter(dir) {
+                    let child = child?;
+                    let name = child.1.name().or(Err(Error::Corrupt(block + child.0)))?.to_lowercase();
+                    if ! names.insert(name) {
+                        return Err(Error::Exists);
+                    }
+                    if child.1.is_dir() {
+                        dirs.push(child.0);
                     }
                 }
             }
         }
 
-        self.find_node(name, parent.1.next)
+        self.header.1.flags = flags;
+        self.write_header()?;
+        self.set_case_insensitive(case_insensitive);
+        Ok(())
     }
 
-    fn insert_blocks(&mut self, block: u64, length: u64, parent_block: u64) -> Result<()> {
-        if parent_block == 0 {
-            return Err(Error::new(ENOSPC));
+    /// Name the filesystem, with a label of at most `Header::LABEL_MAX` bytes, or an empty one
+    /// to remove it
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        if label.len() > Header::LABEL_MAX {
+            return Err(Error::NameTooLong);
         }
+        self.header.1.label = [0; Header::LABEL_MAX];
+        self.header.1.label[..label.len()].copy_from_slice(label.as_bytes());
+        self.header.1.label_len = label.len() as u16;
+        self.write_header()?;
+        Ok(())
+    }
 
-        let mut inserted = false;
-        let mut parent = self.node(parent_block)?;
-        for extent in parent.1.extents.iter_mut() {
-            if extent.length == 0 {
-                //New extent
-                inserted = true;
-                extent.block = block;
-                extent.length = length;
-                break;
-            } else if length % BLOCK_SIZE == 0 && extent.block == block + length/BLOCK_SIZE {
-                //At beginning
-                inserted = true;
-                extent.block = block;
-                extent.length += length;
-                break;
-            } else if extent.length % BLOCK_SIZE == 0 && extent.block + extent.length/BLOCK_SIZE == block {
-                //At end
-                inserted = true;
-                extent.length += length;
-                break;
-            }
+    /// Store options for mounts to apply before their own, at most `Header::MOUNT_OPTIONS_MAX`
+    /// bytes of them, which are only checked when mounting
+    pub fn set_mount_options(&mut self, options: &str) -> Result<()> {
+        if options.len() > Header::MOUNT_OPTIONS_MAX {
+            return Err(Error::NameTooLong);
         }
+        self.header.1.mount_options = [0; Header::MOUNT_OPTIONS_MAX];
+        self.header.1.mount_options[..options.len()].copy_from_slice(options.as_bytes());
+        self.header.1.mount_options_len = options.len() as u16;
+        self.write_header()?;
+        Ok(())
+    }
 
-        if inserted {
-            self.write_at(parent.0, &parent.1)?;
-            Ok(())
-        } else {
-            if parent.1.next == 0 {
-                let next = self.allocate(1)?;
-                // Could be mutated by self.allocate if free block
-                if parent.0 == self.header.1.free {
-                    self.read_at(parent.0, &mut parent.1)?;
-                }
-                parent.1.next = next;
-                self.write_at(parent.0, &parent.1)?;
-                self.write_at(parent.1.next, &Node::default())?;
-            }
+    /// Number of free blocks, from the free list kept in memory
+    pub fn free_blocks(&self) -> u64 {
+        self.free_nodes.iter().map(|free| {
+            free.1.extents().iter().map(|extent| extent.length/BLOCK_SIZE).sum::<u64>()
+        }).sum()
+    }
 
-            self.insert_blocks(block, length, parent.1.next)
-        }
+    /// Call notify with the notices of this filesystem, such as low free space, for frontends
+    /// to log them
+    pub fn set_notify(&mut self, notify: Box<FnMut(Notice) + Send>) {
+        self.notify = notify;
     }
 
-    pub fn create_node(&mut self, mode: u16, name: &str, parent_block: u64, ctime: u64, ctime_nsec: u32) -> Result<(u64, Node)> {
-        if self.find_node(name, parent_block).is_ok() {
-            Err(Error::new(EEXIST))
-        } else {
-            let node = (self.allocate(1)?, Node::new(mode, name, parent_block, ctime, ctime_nsec));
-            self.write_at(node.0, &node.1)?;
+    /// Warn when the free blocks drop below each of thresholds, a number of blocks, so daemons
+    /// can react before allocations fail, or none if it is empty
+    ///
+    /// The callback of `set_notify` gets a notice when the level changes.
+    pub fn set_low_space(&mut self, mut thresholds: Vec<u64>) {
+        thresholds.sort_by(|a, b| b.cmp(a));
+        self.low_space = thresholds;
+        self.low_space_level = 0;
+        self.update_low_space();
+    }
 
-            self.insert_blocks(node.0, BLOCK_SIZE, parent_block)?;
+    /// Number of the low space thresholds that the free blocks are below, 0 if there is enough
+    pub fn low_space_level(&self) -> usize {
+        self.low_space_level
+    }
 
-            Ok(node)
-        }
+    /// True if the low space level changed since the last call, for frontends to tell watchers
+    pub fn take_low_space_change(&mut self) -> bool {
+        let changed = self.low_space_changed;
+        self.low_space_changed = false;
+        changed
     }
 
-    fn remove_blocks(&mut self, block: u64, length: u64, parent_block: u64) -> Result<()> {
-        if parent_block == 0 {
-            return Err(Error::new(ENOENT));
+    /// Find the low space level after the free blocks changed, with a notice when it does
+    fn update_low_space(&mut self) {
+        if self.low_space.is_empty() {
+            return;
         }
 
-        let mut removed = false;
-        let mut replace_option = None;
-        let mut parent = self.node(parent_block)?;
-        for extent in parent.1.extents.iter_mut() {
-            if block >= extent.block && block + length <= extent.block + extent.length/BLOCK_SIZE {
-                //Inside
-                removed = true;
-
-                let left = Extent::new(extent.block, (block - extent.block) * BLOCK_SIZE);
-                let right = Extent::new(block + length, ((extent.block + extent.length/BLOCK_SIZE) - (block + length)) * BLOCK_SIZE);
+        let free = self.free_blocks();
+        let level = self.low_space.iter().filter(|&&threshold| free < threshold).count();
+        if level > self.low_space_level {
+            (self.notify)(Notice::LowSpace(free, self.low_space[level - 1]));
+        } else if level < self.low_space_level {
+            (self.notify)(Notice::SpaceRecovered(free));
+        }
+        if level != self.low_space_level {
+            self.low_space_level = level;
+            self.low_space_changed = true;
+        }
+    }
 
-                if left.length > 0 {
-                    *extent = left;
+    /// Number of free blocks that can be allocated by requests that are not privileged
+    pub fn available_blocks(&self) -> u64 {
+        self.free_blocks().saturating_sub(self.header.1.root_reserved)
+    }
 
-                    if right.length > 0 {
-                        replace_option = Some(right);
-                    }
-                } else if right.length > 0 {
-                    *extent = right;
-                } else {
-                    *extent = Extent::default();
+    /// The block after the last one in use, relative to the header, so the free space from there
+    /// to the end of the disk can be cut off
+    fn used_end(&self) -> u64 {
+        let mut free = Vec::new();
+        for node in self.free_nodes.iter() {
+            for extent in node.1.extents().iter() {
+                if extent.length > 0 {
+                    free.push((extent.block, extent.length/BLOCK_SIZE));
                 }
-
-                break;
             }
         }
+        free.sort_by(|a, b| b.cmp(a));
 
-        if removed {
-            self.write_at(parent.0, &parent.1)?;
-
-            if let Some(replace) = replace_option {
-                self.insert_blocks(replace.block, replace.length, parent_block)?;
+        let mut end = self.header.1.size/BLOCK_SIZE - self.block;
+        for &(block, length) in free.iter() {
+            if block + length >= end && block < end {
+                end = block;
             }
+        }
+        end
+    }
 
-            self.deallocate(block, BLOCK_SIZE)?;
+    /// Bytes of the disk the filesystem needs, up to the end of the last block in use
+    pub fn used_size(&self) -> u64 {
+        (self.block + self.used_end()) * BLOCK_SIZE
+    }
 
-            Ok(())
-        } else {
-            self.remove_blocks(block, length, parent.1.next)
+    /// Remove the free space after the last block in use from the filesystem, so the disk can be
+    /// truncated to the returned size in bytes
+    pub fn shrink(&mut self) -> Result<u64> {
+        if self.header.1.spanned() {
+            return Err(Error::Unsupported);
         }
-    }
 
-    pub fn remove_node(&mut self, mode: u16, name: &str, parent_block: u64) -> Result<()> {
-        let node = self.find_node(name, parent_block)?;
-        if node.1.mode & Node::MODE_TYPE == mode {
-            if node.1.is_dir() {
-                let mut children = Vec::new();
-                self.child_nodes(&mut children, node.0)?;
-                if ! children.is_empty() {
-                    return Err(Error::new(ENOTEMPTY));
+        let end = self.used_end();
+        for i in 0..self.free_nodes.len() {
+            let mut free = self.free_nodes[i];
+            let mut changed = false;
+            for extent in free.1.extents_mut().iter_mut() {
+                if extent.length > 0 && extent.block >= end {
+                    *extent = Extent::default();
+                    changed = true;
                 }
             }
+            if changed {
+                self.write_list_node(&free)?;
+            }
+        }
 
-            self.node_set_len(node.0, 0)?;
-            self.remove_blocks(node.0, 1, parent_block)?;
-            self.write_at(node.0, &Node::default())?;
+        self.header.1.size = (self.block + end) * BLOCK_SIZE;
+        self.write_header()?;
+        Ok(self.header.1.size)
+    }
 
-            Ok(())
-        } else if node.1.is_dir() {
-            Err(Error::new(EISDIR))
-        } else {
-            Err(Error::new(ENOTDIR))
+    /// Report every node as owned by uid and gid if given, with the bits of umask cleared from
+    /// its permissions, for mounting images whose owners do not exist on this system
+    ///
+    /// Only `node_stat` and `mapped_node`, which frontends check permissions against, are
+    /// affected, the stored nodes keep their owners and modes.
+    pub fn set_ownership(&mut self, uid: Option<u32>, gid: Option<u32>, umask: u16) {
+        self.uid = uid;
+        self.gid = gid;
+        self.umask = umask & Node::MODE_PERM;
+    }
+
+    /// A copy of node with the owner, group and mode it is reported with, see `set_ownership`
+    pub fn mapped_node(&self, node: &Node) -> Node {
+        let mut mapped = *node;
+        mapped.mode = node.mode & ! self.umask;
+        mapped.uid = self.uid.unwrap_or(node.uid);
+        mapped.gid = self.gid.unwrap_or(node.gid);
+        mapped
+    }
+
+    /// Block of the root directory that frontends serve
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// The roots of the named subvolumes
+    pub fn subvolumes(&mut self) -> Result<Vec<(u64, Node)>> {
+        let mut subvolumes = Vec::new();
+        let block = self.header.1.subvolumes;
+        if block != 0 {
+            self.child_nodes(&mut subvolumes, block)?;
         }
+        Ok(subvolumes)
     }
 
-    // TODO: modification time
-    fn node_ensure_len(&mut self, block: u64, mut length: u64) -> Result<()> {
+    /// Find the root of the subvolume with this name
+    pub fn find_subvolume(&mut self, name: &str) -> Result<(u64, Node)> {
+        let block = self.header.1.subvolumes;
         if block == 0 {
-            return Err(Error::new(ENOENT));
+            return Err(Error::NotFound);
         }
+        self.find_node(name, block)
+    }
 
-        let mut changed = false;
-
-        let mut node = self.node(block)?;
-        for extent in node.1.extents.iter_mut() {
-            if extent.length >= length {
-                length = 0;
-                break;
-            } else {
-                changed = true;
-                let allocated = ((extent.length + BLOCK_SIZE - 1)/BLOCK_SIZE) * BLOCK_SIZE;
-                if allocated >= length {
-                    extent.length = length;
-                    length = 0;
-                    break;
-                } else {
-                    extent.length = allocated;
-                    length -= allocated;
-                }
-            }
+    /// Create a subvolume, an empty tree of its own that shares the free space with the others
+    pub fn create_subvolume(&mut self, name: &str, ctime: u64, ctime_nsec: u32) -> Result<(u64, Node)> {
+        if self.header.1.subvolumes == 0 {
+            let subvolumes = (self.allocate(1)?, Node::new(Node::MODE_DIR | 0o755, "subvolumes", 0, ctime, ctime_nsec));
+            self.write_at(subvolumes.0, &subvolumes.1)?;
+            self.header.1.subvolumes = subvolumes.0;
+            self.write_header()?;
         }
 
-        if changed {
-            self.write_at(node.0, &node.1)?;
-        }
+        let subvolumes = self.header.1.subvolumes;
+        self.create_node(Node::MODE_DIR | 0o755, name, subvolumes, ctime, ctime_nsec)
+    }
 
-        if length > 0 {
-            if node.1.next > 0 {
-                self.node_ensure_len(node.1.next, length)
-            } else {
-                let new_block = self.allocate((length + BLOCK_SIZE - 1)/BLOCK_SIZE)?;
-                self.insert_blocks(new_block, length, block)?;
-                Ok(())
-            }
+    /// Serve the subvolume with this name, or the top level tree if name is None
+    pub fn set_subvolume(&mut self, name: Option<&str>) -> Result<()> {
+        self.root = match name {
+            Some(name) => self.find_subvolume(name)?.0,
+            None => self.header.1.root
+        };
+        Ok(())
+    }
+
+    /// Choose the subvolume served when a mount does not name one, or the top level tree if name is None
+    pub fn set_default_subvolume(&mut self, name: Option<&str>) -> Result<()> {
+        self.header.1.default_subvolume = match name {
+            Some(name) => self.find_subvolume(name)?.0,
+            None => 0
+        };
+        self.write_header()?;
+        Ok(())
+    }
+
+    /// Mark the filesystem as mounted, or as cleanly unmounted once everything is on the disk
+    pub fn set_mounted(&mut self, mounted: bool) -> Result<()> {
+        if mounted {
+            self.header.1.state |= Header::STATE_MOUNTED;
         } else {
-            Ok(())
+            self.header.1.state &= ! Header::STATE_MOUNTED;
         }
+        self.write_header()?;
+        self.disk.sync()
     }
 
-    //TODO: modification time
-    pub fn node_set_len(&mut self, block: u64, mut length: u64) -> Result<()> {
-        if block == 0 {
-            return Err(Error::new(ENOENT));
+    /// Quickly check the structures everything else is found from, so corruption is found when
+    /// mounting rather than by a request failing later. The root, subvolume, and orphan nodes
+    /// have to be readable, and all but the orphan node directories. The extents of the free list have to lie
+    /// within the disk, apart from each other and from those nodes and the free list itself.
+    ///
+    /// Fails with `Error::Corrupt` at the first problem, unless repair is true, in which case the
+    /// bad extents are removed from the free list, losing the space they describe but never
+    /// handing out a block in use, and their number is returned. Problems with the nodes cannot
+    /// be repaired.
+    pub fn quick_check(&mut self, repair: bool) -> Result<u64> {
+        let end = self.header.1.size/BLOCK_SIZE - self.block;
+
+        // Blocks that must not be free
+        let mut used = vec![self.header.0];
+        used.extend(self.free_nodes.iter().map(|free| free.0));
+
+        let mut dirs = vec![self.header.1.root];
+        if self.header.1.subvolumes != 0 {
+            dirs.push(self.header.1.subvolumes);
         }
+        for pass in 0..2 {
+            // The subvolumes are only listed once their directory is known to be one
+            if pass == 1 {
+                dirs = self.subvolumes()?.iter().map(|subvolume| subvolume.0).collect();
+            }
+            for &dir in dirs.iter() {
+                if dir >= end || ! self.node(dir)?.1.is_dir() {
+                    return Err(Error::Corrupt(self.block + dir));
+                }
+            }
+            used.extend(dirs.iter().cloned());
+        }
+        let orphans = self.header.1.orphans;
+        if orphans != 0 {
+            self.node(orphans)?;
+            used.push(orphans);
+        }
+        used.sort();
 
-        let mut changed = false;
-
-        let mut node = self.node(block)?;
-        for extent in node.1.extents.iter_mut() {
-            if extent.length > length {
-                let start = (length + BLOCK_SIZE - 1)/BLOCK_SIZE;
-                let end = (extent.length + BLOCK_SIZE - 1)/BLOCK_SIZE;
-                if end > start {
-                    self.deallocate(extent.block + start, (end - start) * BLOCK_SIZE)?;
+        // Every extent of the free list, by its start, and where it is listed
+        let mut extents = Vec::new();
+        for (i, free) in self.free_nodes.iter().enumerate() {
+            for (j, extent) in free.1.extents().iter().enumerate() {
+                if extent.length > 0 {
+                    extents.push((extent.block, extent.length, i, j));
+                }
+            }
+        }
+        extents.sort();
+
+        let mut bad = Vec::new();
+        let mut free_end = 0;
+        for &(block, length, i, j) in extents.iter() {
+            let blocks = length/BLOCK_SIZE;
+            let first_used = match used.binary_search(&block) {
+                Ok(k) | Err(k) => k
+            };
+            if length % BLOCK_SIZE != 0 || block < free_end || block.checked_add(blocks).map_or(true, |block_end| block_end > end)
+                || used.get(first_used).map_or(false, |&used_block| used_block < block + blocks) {
+                if ! repair {
+                    return Err(Error::Corrupt(self.block + self.free_nodes[i].0));
                 }
-                extent.length = length;
-                changed = true;
-                length = 0;
+                bad.push((i, j));
             } else {
-                length -= extent.length;
+                free_end = block + blocks;
             }
         }
 
-        if changed {
-            self.write_at(node.0, &node.1)?;
+        for &(i, j) in bad.iter() {
+            let mut free = self.free_nodes[i];
+            free.1.extents_mut()[j] = Extent::default();
+            self.write_list_node(&free)?;
+        }
+        Ok(bad.len() as u64)
+    }
+
+    /// Apply the name policy to a name
+    fn normalize<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match self.name_policy {
+            NamePolicy::Preserve => Cow::Borrowed(name),
+            #[cfg(feature = "nfc")]
+            NamePolicy::Nfc => if unicode_normalization::is_nfc(name) {
+                Cow::Borrowed(name)
+            } else {
+                Cow::Owned(name.nfc().collect())
+            },
         }
+    }
 
-        if node.1.next > 0 {
-            self.node_set_len(node.1.next, length)
+    fn name_matches(&self, child_name: &str, name: &str) -> bool {
+        if self.case_insensitive {
+            child_name == name || child_name.to_lowercase() == name.to_lowercase()
         } else {
-            Ok(())
+            child_name == name
         }
     }
 
-    fn node_extents(&mut self, block: u64, mut offset: u64, mut len: usize, extents: &mut Vec<Extent>) -> Result<()> {
-        if block == 0 {
-            return Ok(());
+    /// The key of a name in the lookup cache, which ignores case if lookups do
+    fn lookup_key(&self, name: &str, parent_block: u64) -> (u64, String) {
+        if self.case_insensitive {
+            (parent_block, name.to_lowercase())
+        } else {
+            (parent_block, name.to_string())
         }
+    }
 
-        let node = self.node(block)?;
-        for extent in node.1.extents.iter() {
-            let mut push_extent = Extent::default();
-            for (block, size) in extent.blocks() {
-                if offset == 0 {
-                    if push_extent.block == 0 {
-                        push_extent.block = block;
-                    }
-                    if len as u64 >= size {
-                        push_extent.length += size;
-                        len -= size as usize;
-                    } else if len > 0 {
-                        push_extent.length += len as u64;
-                        len = 0;
-                        break;
-                    } else {
-                        break;
-                    }
-                } else {
-                    offset -= 1;
-                }
-            }
-            if push_extent.length > 0 {
-                extents.push(push_extent);
-            }
-            if len == 0 {
-                break;
+    fn forget_lookup(&mut self, name: &str, parent_block: u64) {
+        let name = self.normalize(name).into_owned();
+        let key = self.lookup_key(&name, parent_block);
+        self.lookups.remove(&key);
+    }
+
+    pub fn find_node(&mut self, name: &str, parent_block: u64) -> Result<(u64, Node)> {
+        let name = self.normalize(name).into_owned();
+        let key = self.lookup_key(&name, parent_block);
+
+        // A remembered node is used if it still has this parent and name, which a removed node
+        // does not, so stale entries only cost one read
+        if let Some(&(block, _)) = self.lookups.get(&key) {
+            let node = self.node(block)?;
+            let matches = node.1.parent == parent_block && match node.1.name() {
+                Ok(child_name) => self.name_matches(child_name, &name),
+                Err(_) => false
+            };
+            if matches {
+                return Ok(node);
             }
+            self.lookups.remove(&key);
         }
 
-        if len > 0 {
-            self.node_extents(node.1.next, offset, len, extents)
-        } else {
-            Ok(())
+        let node = self.find_child(&name, parent_block)?;
+        while self.lookup_order.len() >= LOOKUP_CACHE_SIZE {
+            let (removed, number) = self.lookup_order.pop_front().unwrap();
+            // Entries that were forgotten, or found again since, are left alone
+            if self.lookups.get(&removed).map_or(false, |lookup| lookup.1 == number) {
+                self.lookups.remove(&removed);
+            }
         }
+        let number = self.lookup_next;
+        self.lookup_next += 1;
+        self.lookups.insert(key.clone(), (node.0, number));
+        self.lookup_order.push_back((key, number));
+        Ok(node)
     }
 
-    pub fn read_node(&mut self, block: u64, offset: u64, buf: &mut [u8]) -> Result<usize> {
-        let block_offset = offset / BLOCK_SIZE;
-        let mut byte_offset = (offset % BLOCK_SIZE) as usize;
-
-        let mut extents = Vec::new();
-        self.node_extents(block, block_offset, byte_offset + buf.len(), &mut extents)?;
-
-        let mut i = 0;
-        for extent in extents.iter() {
-            let mut block = extent.block;
-            let mut length = extent.length;
+    fn find_child(&mut self, name: &str, mut parent_block: u64) -> Result<(u64, Node)> {
+        // Walks the nodes the children continue in with a loop, like `insert_blocks`
+        loop {
+            if parent_block == 0 {
+                return Err(Error::NotFound);
+            }
 
-            if byte_offset > 0 && length > 0 {
-                let mut sector = [0; BLOCK_SIZE as usize];
-                self.read_at(block, &mut sector)?;
+            let parent = self.node(parent_block)?;
+            for extent in parent.1.extents().iter() {
+                for (block, size) in extent.blocks() {
+                    if size >= BLOCK_SIZE {
+                        let child = self.node(block)?;
+
+                        let mut matches = false;
+                        if let Ok(child_name) = child.1.name() {
+                            if self.name_matches(child_name, name) {
+                                matches = true;
+                            }
+                        }
 
-                let sector_size = min(sector.len() as u64, length) as usize;
-                for (s_b, b) in sector[byte_offset..sector_size].iter().zip(buf[i..].iter_mut()) {
-                    *b = *s_b;
-                    i += 1;
+                        if matches {
+                            return Ok(child);
+                        }
+                    }
                 }
+            }
 
-                block += 1;
-                length -= sector_size as u64;
+            parent_block = parent.1.next;
+        }
+    }
 
-                byte_offset = 0;
-            }
+    /// Read a node, or its copy in memory if it is part of the free list
+    fn list_node(&mut self, block: u64) -> Result<(u64, Node)> {
+        match self.free_nodes.iter().find(|free| free.0 == block) {
+            Some(free) => Ok(*free),
+            None => self.node(block)
+        }
+    }
 
-            let length_aligned = ((min(length, (buf.len() - i) as u64)/BLOCK_SIZE) * BLOCK_SIZE) as usize;
+    /// Write a node that lists extents, updating the copy in memory if it is part of the free list
+    fn write_list_node(&mut self, node: &(u64, Node)) -> Result<()> {
+        self.write_at(node.0, &node.1)?;
+        if let Some(i) = self.free_nodes.iter().position(|free| free.0 == node.0) {
+            self.free_nodes[i] = *node;
+        } else if self.free_nodes.last().map_or(false, |free| free.1.next == node.0) {
+            // A new node at the end of the free list
+            self.free_nodes.push(*node);
+        }
+        Ok(())
+    }
 
-            if length_aligned > 0 {
-                let extent_buf = &mut buf[i..i + length_aligned];
-                self.read_at(block, extent_buf)?;
-                i += length_aligned;
-                block += (length_aligned as u64)/BLOCK_SIZE;
-                length -= length_aligned as u64;
+    fn insert_blocks(&mut self, block: u64, length: u64, mut parent_block: u64) -> Result<()> {
+        // Walks the nodes the extents continue in with a loop, as a long chain could overflow the stack
+        loop {
+            if parent_block == 0 {
+                return Err(Error::NoSpace);
             }
 
-            if length > 0 {
-                let mut sector = [0; BLOCK_SIZE as usize];
-                self.read_at(block, &mut sector)?;
-
-                let sector_size = min(sector.len() as u64, length) as usize;
-                for (s_b, b) in sector[..sector_size].iter().zip(buf[i..].iter_mut()) {
-                    *b = *s_b;
-                    i += 1;
+            let mut inserted = false;
+            let mut parent = self.list_node(parent_block)?;
+            for extent in parent.1.extents_mut().iter_mut() {
+                if extent.length == 0 {
+                    //New extent
+                    inserted = true;
+                    extent.block = block;
+                    extent.length = length;
+                    break;
+                } else if length % BLOCK_SIZE == 0 && extent.block == block + length/BLOCK_SIZE {
+                    //At beginning
+                    inserted = true;
+                    extent.block = block;
+                    extent.length += length;
+                    break;
+                } else if extent.length % BLOCK_SIZE == 0 && extent.block + extent.length/BLOCK_SIZE == block {
+                    //At end
+                    inserted = true;
+                    extent.length += length;
+                    break;
                 }
+            }
 
-                block += 1;
-                length -= sector_size as u64;
+            if inserted {
+                return self.write_list_node(&parent);
             }
 
-            assert_eq!(length, 0);
-            assert_eq!(block, extent.block + (extent.length + BLOCK_SIZE - 1)/BLOCK_SIZE);
-        }
+            if parent.1.next == 0 {
+                let next = self.allocate(1)?;
+                // Could be mutated by self.allocate if part of the free list
+                parent = self.list_node(parent.0)?;
+                parent.1.next = next;
+                self.write_list_node(&parent)?;
+                self.write_list_node(&(next, Node::default()))?;
+            }
 
-        Ok(i)
+            parent_block = parent.1.next;
+        }
     }
 
-    pub fn write_node(&mut self, block: u64, offset: u64, buf: &[u8], mtime: u64, mtime_nsec: u32) -> Result<usize> {
-        let block_offset = offset / BLOCK_SIZE;
-        let mut byte_offset = (offset % BLOCK_SIZE) as usize;
+    /// Create a node, failing with `Exists` if the parent already has a child with this name
+    ///
+    /// The node gets the `Node::FLAG_INHERITED` flags of the parent, and loses the permissions in
+    /// its `xattr::DEFAULT_UMASK` attribute, which a directory also gets.
+    pub fn create_node(&mut self, mode: u16, name: &str, parent_block: u64, ctime: u64, ctime_nsec: u32) -> Result<(u64, Node)> {
+        let name = self.normalize(name);
+        check_name(&name)?;
+
+        // Only a missing name allows creation, other lookup errors must not lead to a duplicate
+        match self.find_child(&name, parent_block) {
+            Ok(_) => Err(Error::Exists),
+            Err(Error::NotFound) => {
+                let mut flags = 0;
+                let mut default_umask = None;
+                if parent_block > 0 {
+                    let parent = self.node(parent_block)?;
+                    if parent.1.is_immutable() {
+                        return Err(Error::NotPermitted);
+                    }
+                    flags = parent.1.flags & Node::FLAG_INHERITED;
+                    default_umask = match self.get_xattr(parent_block, xattr::DEFAULT_UMASK) {
+                        Ok(value) => xattr::parse_umask(&value).map(|umask| (value, umask)),
+                        Err(Error::NoAttribute) => None,
+                        Err(err) => return Err(err)
+                    };
+                }
 
-        self.node_ensure_len(block, block_offset as u64 * BLOCK_SIZE + (byte_offset + buf.len()) as u64)?;
+                let mode = match default_umask {
+                    Some((_, umask)) => mode & ! umask,
+                    None => mode
+                };
+                let mut node = (self.allocate(1)?, Node::new(mode, &name, parent_block, ctime, ctime_nsec));
+                let random = *Uuid::new_v4().as_bytes();
+                node.1.generation = (random[0] as u32) | (random[1] as u32) << 8 | (random[2] as u32) << 16 | (random[3] as u32) << 24;
+                node.1.flags = flags;
+                // The block of a node that could not be added to its parent is given back
+                let res = self.write_at(node.0, &node.1).and_then(|_| self.insert_blocks(node.0, BLOCK_SIZE, parent_block));
+                if let Err(err) = res {
+                    self.deallocate(node.0, BLOCK_SIZE)?;
+                    return Err(err);
+                }
 
-        let mut extents = Vec::new();
-        self.node_extents(block, block_offset, byte_offset + buf.len(), &mut extents)?;
+                if let Some((value, _)) = default_umask {
+                    if node.1.is_dir() {
+                        self.set_xattr(node.0, xattr::DEFAULT_UMASK, &value, false, false)?;
+                        node = self.node(node.0)?;
+                    }
+                }
 
-        let mut i = 0;
-        for extent in extents.iter() {
-            let mut block = extent.block;
-            let mut length = extent.length;
+                Ok(node)
+            },
+            Err(err) => Err(err)
+        }
+    }
 
-            if byte_offset > 0 && length > 0 {
-                let mut sector = [0; BLOCK_SIZE as usize];
-                self.read_at(block, &mut sector)?;
+    fn remove_blocks(&mut self, block: u64, length: u64, parent_block: u64) -> Result<()> {
+        self.unlink_blocks(block, length, parent_block)?;
+        self.deallocate(block, BLOCK_SIZE)
+    }
 
-                let sector_size = min(sector.len() as u64, length) as usize;
-                for (s_b, b) in sector[byte_offset..sector_size].iter_mut().zip(buf[i..].iter()) {
-                    *s_b = *b;
-                    i += 1;
+    /// Remove blocks from the extents of a node, without deallocating them
+    fn unlink_blocks(&mut self, block: u64, length: u64, mut parent_block: u64) -> Result<()> {
+        // Walks the nodes the extents continue in with a loop, like `insert_blocks`
+        loop {
+            if parent_block == 0 {
+                return Err(Error::NotFound);
+            }
+
+            let mut removed = false;
+            let mut replace_option = None;
+            let mut parent = self.node(parent_block)?;
+            for extent in parent.1.extents_mut().iter_mut() {
+                if block >= extent.block && block + length <= extent.block + extent.length/BLOCK_SIZE {
+                    //Inside
+                    removed = true;
+
+                    let left = Extent::new(extent.block, (block - extent.block) * BLOCK_SIZE);
+                    let right = Extent::new(block + length, ((extent.block + extent.length/BLOCK_SIZE) - (block + length)) * BLOCK_SIZE);
+
+                    if left.length > 0 {
+                        *extent = left;
+
+                        if right.length > 0 {
+                            replace_option = Some(right);
+                        }
+                    } else if right.length > 0 {
+                        *extent = right;
+                    } else {
+                        *extent = Extent::default();
+                    }
+
+                    break;
                 }
+            }
 
-                self.write_at(block, &sector)?;
+            if removed {
+                self.write_at(parent.0, &parent.1)?;
 
-                block += 1;
-                length -= sector_size as u64;
+                if let Some(replace) = replace_option {
+                    self.insert_blocks(replace.block, replace.length, parent_block)?;
+                }
 
-                byte_offset = 0;
+                return Ok(());
             }
 
-            let length_aligned = ((min(length, (buf.len() - i) as u64)/BLOCK_SIZE) * BLOCK_SIZE) as usize;
+            parent_block = parent.1.next;
+        }
+    }
+
+    /// Find a node that can be removed, checking its type and that it has no children
+    fn removable_node(&mut self, mode: u16, name: &str, parent_block: u64) -> Result<(u64, Node)> {
+        let node = self.find_node(name, parent_block)?;
+        let parent = self.node(parent_block)?;
+        if node.1.is_immutable() || node.1.is_append() || parent.1.is_immutable() || parent.1.is_append() {
+            return Err(Error::NotPermitted);
+        }
 
-            if length_aligned > 0 {
-                let extent_buf = &buf[i..i + length_aligned];
-                self.write_at(block, extent_buf)?;
-                i += length_aligned;
-                block += (length_aligned as u64)/BLOCK_SIZE;
-                length -= length_aligned as u64;
+        if node.1.mode & Node::MODE_TYPE == mode {
+            if node.1.is_dir() {
+                if let Some(child) = self.child_blocks(node.0).next() {
+                    child?;
+                    return Err(Error::NotEmpty);
+                }
             }
 
-            if length > 0 {
-                let mut sector = [0; BLOCK_SIZE as usize];
-                self.read_at(block, &mut sector)?;
+            Ok(node)
+        } else if node.1.is_dir() {
+            Err(Error::IsDir)
+        } else {
+            Err(Error::NotDir)
+        }
+    }
 
-                let sector_size = min(sector.len() as u64, length) as usize;
-                for (s_b, b) in sector[..sector_size].iter_mut().zip(buf[i..].iter()) {
-                    *s_b = *b;
-                    i += 1;
+    pub fn remove_node(&mut self, mode: u16, name: &str, parent_block: u64) -> Result<()> {
+        let node = self.removable_node(mode, name, parent_block)?;
+        self.forget_lookup(name, parent_block);
+
+        self.release_xattrs(node.0)?;
+        self.set_len(node.0, 0)?;
+        self.remove_blocks(node.0, 1, parent_block)?;
+        self.write_at(node.0, &Node::default())?;
+
+        Ok(())
+    }
+
+    /// The block of the orphan list, creating it if there is none
+    fn orphans(&mut self) -> Result<u64> {
+        if self.header.1.orphans == 0 {
+            let orphans = (self.allocate(1)?, Node::new(Node::MODE_FILE, "orphans", 0, 0, 0));
+            self.write_at(orphans.0, &orphans.1)?;
+
+            self.header.1.orphans = orphans.0;
+            self.write_header()?;
+        }
+        Ok(self.header.1.orphans)
+    }
+
+    /// Unlink a node that is still open, moving it to the orphan list until release_node is called
+    pub fn orphan_node(&mut self, mode: u16, name: &str, parent_block: u64) -> Result<u64> {
+        let mut node = self.removable_node(mode, name, parent_block)?;
+        self.forget_lookup(name, parent_block);
+
+        let orphans = self.orphans()?;
+        self.unlink_blocks(node.0, 1, parent_block)?;
+        self.insert_blocks(node.0, BLOCK_SIZE, orphans)?;
+
+        node.1.parent = 0;
+        self.write_at(node.0, &node.1)?;
+
+        Ok(node.0)
+    }
+
+    /// Give a node a new name and parent, replacing the node that had that name
+    ///
+    /// The replaced node is moved to the orphan list and returned, to be freed with release_node
+    /// once it is no longer open. The rename is recorded in the header until it is done, and is
+    /// finished when the filesystem is opened, so after a crash the new name refers to either
+    /// the replaced node or the renamed one.
+    pub fn rename_node(&mut self, orig_name: &str, orig_parent: u64, new_name: &str, new_parent: u64) -> Result<Option<u64>> {
+        let new_name = self.normalize(new_name).into_owned();
+        check_name(&new_name)?;
+
+        let node = self.find_node(orig_name, orig_parent)?;
+        let orig = self.node(orig_parent)?;
+        let new = self.node(new_parent)?;
+        if ! new.1.is_dir() {
+            return Err(Error::NotDir);
+        }
+        if node.1.is_immutable() || node.1.is_append() || orig.1.is_immutable() || orig.1.is_append() || new.1.is_immutable() {
+            return Err(Error::NotPermitted);
+        }
+
+        if node.1.is_dir() {
+            let mut block = new_parent;
+            while block != 0 {
+                if block == node.0 {
+                    return Err(Error::InvalidMove);
+                }
+                block = self.node(block)?.1.parent;
+            }
+        }
+
+        let replace = match self.find_node(&new_name, new_parent) {
+            Ok(ref existing) if existing.0 == node.0 => {
+                if self.normalize(orig_name) == new_name {
+                    return Ok(None);
+                }
+                // Only the case of the name changes
+                None
+            },
+            Ok(existing) => {
+                if existing.1.is_immutable() || existing.1.is_append() || new.1.is_append() {
+                    return Err(Error::NotPermitted);
+                }
+                if node.1.is_dir() {
+                    if ! existing.1.is_dir() {
+                        return Err(Error::NotDir);
+                    }
+                    if let Some(child) = self.child_blocks(existing.0).next() {
+                        child?;
+                        return Err(Error::NotEmpty);
+                    }
+                } else if existing.1.is_dir() {
+                    return Err(Error::IsDir);
+                }
+                Some(existing.0)
+            },
+            Err(Error::NotFound) => None,
+            Err(err) => return Err(err)
+        };
+
+        self.forget_lookup(orig_name, orig_parent);
+        self.forget_lookup(&new_name, new_parent);
+        if replace.is_some() {
+            self.orphans()?;
+        }
+        self.make_name_room(node.0, new_name.len())?;
+
+        self.record_rename(node.0, orig_parent, new_parent, replace.unwrap_or(0), &new_name)?;
+        self.finish_rename()?;
+        Ok(replace)
+    }
+
+    /// Record a rename in the header, after everything written before it and before anything
+    /// written after it
+    fn record_rename(&mut self, block: u64, from: u64, to: u64, replace: u64, name: &str) -> Result<()> {
+        self.header.1.rename = block;
+        self.header.1.rename_from = from;
+        self.header.1.rename_to = to;
+        self.header.1.rename_replace = replace;
+        self.header.1.rename_name = [0; Node::NAME_MAX];
+        self.header.1.rename_name[..name.len()].copy_from_slice(name.as_bytes());
+        self.header.1.rename_name_len = name.len() as u16;
+        self.write_header()?;
+        self.barrier()
+    }
+
+    /// Carry out the rename recorded in the header, skipping the steps that were already done
+    fn finish_rename(&mut self) -> Result<()> {
+        let block = self.header.1.rename;
+        let from = self.header.1.rename_from;
+        let to = self.header.1.rename_to;
+        let replace = self.header.1.rename_replace;
+        let name = self.header.1.rename_name().or(Err(Error::Corrupt(self.block + self.header.0)))?.to_string();
+
+        // The replaced node goes to the orphan list before it leaves the directory, so it is
+        // freed when the filesystem is opened if it was not yet
+        if replace != 0 {
+            let orphans = self.orphans()?;
+            if ! self.has_child(orphans, replace)? {
+                self.insert_blocks(replace, BLOCK_SIZE, orphans)?;
+            }
+            if self.has_child(to, replace)? {
+                self.unlink_blocks(replace, 1, to)?;
+                let mut replaced = self.node(replace)?;
+                replaced.1.parent = 0;
+                self.write_at(replaced.0, &replaced.1)?;
+            }
+        }
+
+        if to != from && ! self.has_child(to, block)? {
+            self.insert_blocks(block, BLOCK_SIZE, to)?;
+        }
+
+        self.make_name_room(block, name.len())?;
+        let mut node = self.node(block)?;
+        if ! node.1.set_name(&name) {
+            return Err(Error::NameTooLong);
+        }
+        node.1.parent = to;
+        self.write_at(node.0, &node.1)?;
+
+        if to != from && self.has_child(from, block)? {
+            self.unlink_blocks(block, 1, from)?;
+        }
+
+        // The header is only cleared once every step is on the disk, and cleared on the disk
+        // before the replaced node can be freed and reused
+        self.header.1.rename = 0;
+        self.header.1.rename_from = 0;
+        self.header.1.rename_to = 0;
+        self.header.1.rename_replace = 0;
+        self.header.1.rename_name_len = 0;
+        self.header.1.rename_name = [0; Node::NAME_MAX];
+        self.write_header()?;
+        self.barrier()
+    }
+
+    /// Move the extents of a node that a name of name_len bytes would leave no room for into a
+    /// new node that its extents continue in, so `Node::set_name` can take the name
+    fn make_name_room(&mut self, block: u64, name_len: usize) -> Result<()> {
+        let mut node = self.node(block)?;
+        let mut room = Node::default();
+        room.name_len = name_len as u16;
+        let count = room.extents().len();
+
+        let mut moved = node.1.extents()[min(count, node.1.extents().len())..].to_vec();
+        while moved.last().map_or(false, |extent| extent.block == 0 && extent.length == 0) {
+            moved.pop();
+        }
+        if moved.is_empty() {
+            return Ok(());
+        }
+
+        // The new node reaches the disk before the node that refers to it stops listing its extents
+        let mut next = (self.allocate(1)?, Node::default());
+        next.1.extents_mut()[..moved.len()].copy_from_slice(&moved);
+        next.1.next = node.1.next;
+        self.write_at(next.0, &next.1)?;
+        self.barrier()?;
+
+        for extent in node.1.extents_mut()[count..].iter_mut() {
+            *extent = Extent::default();
+        }
+        node.1.next = next.0;
+        self.write_at(node.0, &node.1)?;
+        Ok(())
+    }
+
+    /// True if block is one of the children of a directory
+    fn has_child(&mut self, parent_block: u64, block: u64) -> Result<bool> {
+        for child in self.child_blocks(parent_block) {
+            if child? == block {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Free an orphaned node and its data, once it is no longer open
+    pub fn release_node(&mut self, block: u64) -> Result<()> {
+        let orphans = self.header.1.orphans;
+
+        self.release_xattrs(block)?;
+        self.set_len(block, 0)?;
+        self.remove_blocks(block, 1, orphans)?;
+        self.write_at(block, &Node::default())?;
+
+        Ok(())
+    }
+
+    /// Free every node in the orphan list
+    fn reclaim_orphans(&mut self) -> Result<()> {
+        let orphans = self.header.1.orphans;
+        let mut children = Vec::new();
+        self.child_nodes(&mut children, orphans)?;
+        for child in children.iter() {
+            self.release_node(child.0)?;
+            (self.notify)(Notice::OrphanReclaimed(child.0));
+        }
+        Ok(())
+    }
+
+    // TODO: modification time
+    fn node_ensure_len(&mut self, block: u64, mut length: u64) -> Result<()> {
+        if block == 0 {
+            return Err(Error::NotFound);
+        }
+
+        self.dirty.insert(block);
+
+        let block_size = self.header.1.block_size();
+        let mut next = block;
+        loop {
+            let mut changed = false;
+
+            let mut node = self.node(next)?;
+            for extent in node.1.extents_mut().iter_mut() {
+                if extent.length >= length {
+                    length = 0;
+                    break;
+                } else {
+                    changed = true;
+                    let allocated = ((extent.length + block_size - 1)/block_size) * block_size;
+                    if allocated >= length {
+                        extent.length = length;
+                        length = 0;
+                        break;
+                    } else {
+                        extent.length = allocated;
+                        length -= allocated;
+                    }
                 }
+            }
 
-                self.write_at(block, &sector)?;
+            if changed {
+                self.write_at(node.0, &node.1)?;
+            }
 
-                block += 1;
-                length -= sector_size as u64;
+            if length == 0 {
+                return Ok(());
+            } else if node.1.next > 0 {
+                next = node.1.next;
+            } else {
+                let new_block = self.allocate((length + BLOCK_SIZE - 1)/BLOCK_SIZE)?;
+                if let Err(err) = self.insert_blocks(new_block, length, next) {
+                    // No block was left for a node to continue the extents in
+                    self.deallocate(new_block, length)?;
+                    return Err(err);
+                }
+                return Ok(());
             }
+        }
+    }
 
-            assert_eq!(length, 0);
-            assert_eq!(block, extent.block + (extent.length + BLOCK_SIZE - 1)/BLOCK_SIZE);
+    /// Fail with `NoSpace` before a node is changed if extending it to length bytes needs more
+    /// blocks than this request may allocate
+    fn node_check_space(&mut self, block: u64, length: u64) -> Result<()> {
+        let allocated = self.node_allocated(block)? - self.node_count(block)? * BLOCK_SIZE;
+        if length > allocated {
+            let needed = self.round_up(length - allocated)/BLOCK_SIZE;
+            let free = if self.privileged { self.free_blocks() } else { self.available_blocks() };
+            if needed > free {
+                return Err(Error::NoSpace);
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of nodes holding the extents of a node, itself and the nodes its extents continue in
+    fn node_count(&mut self, block: u64) -> Result<u64> {
+        let mut count = 0;
+        let mut next = block;
+        while next > 0 {
+            count += 1;
+            next = self.node(next)?.1.next;
+        }
+        Ok(count)
+    }
+
+    /// Check that the flags of a node allow it to be written, only at its end if append is true
+    pub fn node_check_write(&mut self, block: u64, append: bool) -> Result<()> {
+        let node = self.node(block)?;
+        if node.1.is_immutable() || (node.1.is_append() && ! append) {
+            Err(Error::NotPermitted)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Change the attribute flags of a node
+    pub fn node_set_flags(&mut self, block: u64, flags: u32) -> Result<()> {
+        if flags & ! Node::FLAG_MASK != 0 {
+            return Err(Error::NotPermitted);
+        }
+
+        let mut node = self.node(block)?;
+        node.1.flags = flags;
+        self.write_at(node.0, &node.1)?;
+        Ok(())
+    }
+
+    /// The extended attributes of a node, see `xattr`
+    pub fn node_xattrs(&mut self, block: u64) -> Result<Vec<(String, Vec<u8>)>> {
+        let xattrs = self.node(block)?.1.xattrs;
+        if xattrs == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut data = vec![0; self.node_len(xattrs)? as usize];
+        let count = self.read_node(xattrs, 0, &mut data)?;
+        xattr::decode(&data[..count], xattrs)
+    }
+
+    /// The value of the extended attribute of a node called name
+    pub fn get_xattr(&mut self, block: u64, name: &str) -> Result<Vec<u8>> {
+        self.node_xattrs(block)?.into_iter().find(|xattr| xattr.0 == name).map(|xattr| xattr.1).ok_or(Error::NoAttribute)
+    }
+
+    /// Set the extended attribute of a node called name, which has to be in a namespace other than
+    /// `system`. With create, it fails with `Exists` if the attribute is set, and with replace,
+    /// with `NoAttribute` if it is not.
+    pub fn set_xattr(&mut self, block: u64, name: &str, value: &[u8], create: bool, replace: bool) -> Result<()> {
+        if XattrNamespace::of(name)? == XattrNamespace::System {
+            return Err(Error::Unsupported);
+        }
+        if name.len() > xattr::NAME_MAX {
+            return Err(Error::NameTooLong);
+        }
+        if value.len() > xattr::VALUE_MAX {
+            return Err(Error::TooLarge);
+        }
+
+        let node = self.node(block)?;
+        if node.1.is_immutable() {
+            return Err(Error::NotPermitted);
+        }
+        if name == xattr::DEFAULT_UMASK && (! node.1.is_dir() || xattr::parse_umask(value).is_none()) {
+            return Err(Error::InvalidValue);
+        }
+
+        let mut xattrs = self.node_xattrs(block)?;
+        match xattrs.iter().position(|xattr| xattr.0 == name) {
+            Some(i) => if create {
+                return Err(Error::Exists);
+            } else {
+                xattrs[i].1 = value.to_vec();
+            },
+            None => if replace {
+                return Err(Error::NoAttribute);
+            } else {
+                xattrs.push((name.to_string(), value.to_vec()));
+            }
+        }
+        self.write_xattrs(node, &xattrs)
+    }
+
+    /// Remove the extended attribute of a node called name
+    pub fn remove_xattr(&mut self, block: u64, name: &str) -> Result<()> {
+        let node = self.node(block)?;
+        if node.1.is_immutable() {
+            return Err(Error::NotPermitted);
+        }
+
+        let mut xattrs = self.node_xattrs(block)?;
+        let i = xattrs.iter().position(|xattr| xattr.0 == name).ok_or(Error::NoAttribute)?;
+        xattrs.remove(i);
+        self.write_xattrs(node, &xattrs)
+    }
+
+    /// Replace the extended attributes of a node, allocating the node holding them if there was
+    /// none, and freeing it if there are none left
+    fn write_xattrs(&mut self, mut node: (u64, Node), xattrs: &[(String, Vec<u8>)]) -> Result<()> {
+        if xattrs.is_empty() {
+            return self.release_xattrs(node.0);
+        }
+
+        if node.1.xattrs == 0 {
+            let xattrs_node = (self.allocate(1)?, Node::new(Node::MODE_FILE, "xattrs", node.0, 0, 0));
+            self.write_at(xattrs_node.0, &xattrs_node.1)?;
+            node.1.xattrs = xattrs_node.0;
+            self.write_at(node.0, &node.1)?;
+        }
+
+        let data = xattr::encode(xattrs);
+        self.write_node(node.1.xattrs, 0, &data, 0, 0)?;
+        self.set_len(node.1.xattrs, data.len() as u64)
+    }
+
+    /// Free the extended attributes of a node
+    fn release_xattrs(&mut self, block: u64) -> Result<()> {
+        let mut node = self.node(block)?;
+        if node.1.xattrs != 0 {
+            self.set_len(node.1.xattrs, 0)?;
+            self.deallocate(node.1.xattrs, BLOCK_SIZE)?;
+            node.1.xattrs = 0;
+            self.write_at(node.0, &node.1)?;
+        }
+        Ok(())
+    }
+
+    //TODO: modification time
+    pub fn node_set_len(&mut self, block: u64, length: u64) -> Result<()> {
+        self.node_check_write(block, false)?;
+        self.set_len(block, length)
+    }
+
+    /// Write zeros to count blocks starting at block
+    fn zero_blocks(&mut self, block: u64, count: u64) -> Result<()> {
+        let zeros = vec![0; 256 * BLOCK_SIZE as usize];
+        let mut i = 0;
+        while i < count {
+            let len = min(count - i, 256);
+            self.write_at(block + i, &zeros[..(len * BLOCK_SIZE) as usize])?;
+            i += len;
+        }
+        Ok(())
+    }
+
+    /// Set the length of a node, ignoring its flags
+    fn set_len(&mut self, block: u64, length: u64) -> Result<()> {
+        if block == 0 {
+            return Err(Error::NotFound);
+        }
+
+        let secure = self.secure_delete || self.node(block)?.1.is_secure_delete();
+        self.set_len_secure(block, length, secure)
+    }
+
+    /// Set the length of a node and the nodes its extents continue in, zeroing freed data if secure is true
+    fn set_len_secure(&mut self, block: u64, mut length: u64, secure: bool) -> Result<()> {
+        let block_size = self.header.1.block_size();
+        let cluster = self.cluster();
+        let mut next = block;
+        while next > 0 {
+            let mut changed = false;
+
+            let mut node = self.node(next)?;
+            for extent in node.1.extents_mut().iter_mut() {
+                if extent.length > length {
+                    let start = (length + block_size - 1)/block_size * cluster;
+                    let end = (extent.length + block_size - 1)/block_size * cluster;
+                    if secure {
+                        // The rest of the last block that is kept could be exposed again by extending the node
+                        let kept = length/BLOCK_SIZE;
+                        if length % BLOCK_SIZE != 0 {
+                            let mut sector = [0; BLOCK_SIZE as usize];
+                            self.read_at(extent.block + kept, &mut sector)?;
+                            for b in sector[(length % BLOCK_SIZE) as usize..].iter_mut() {
+                                *b = 0;
+                            }
+                            self.write_at(extent.block + kept, &sector)?;
+                        }
+                        let zero_start = (length + BLOCK_SIZE - 1)/BLOCK_SIZE;
+                        if end > zero_start {
+                            self.zero_blocks(extent.block + zero_start, end - zero_start)?;
+                        }
+                    }
+                    if end > start {
+                        self.deallocate(extent.block + start, (end - start) * BLOCK_SIZE)?;
+                    }
+                    extent.length = length;
+                    changed = true;
+                    length = 0;
+                } else {
+                    length -= extent.length;
+                }
+            }
+
+            if changed {
+                self.write_at(node.0, &node.1)?;
+            }
+
+            next = node.1.next;
+        }
+        Ok(())
+    }
+
+    fn node_extents(&mut self, block: u64, mut offset: u64, mut len: usize, extents: &mut Vec<Extent>) -> Result<()> {
+        let mut next = block;
+        while next > 0 && len > 0 {
+            let node = self.node(next)?;
+            for extent in node.1.extents().iter() {
+                // Found by arithmetic rather than by visiting each block, so seeking far into a large node is quick
+                let blocks = (extent.length + BLOCK_SIZE - 1)/BLOCK_SIZE;
+                if offset >= blocks {
+                    offset -= blocks;
+                    continue;
+                }
+
+                let length = min(extent.length - offset * BLOCK_SIZE, len as u64);
+                extents.push(Extent::new(extent.block + offset, length));
+                offset = 0;
+                len -= length as usize;
+                if len == 0 {
+                    break;
+                }
+            }
+
+            next = node.1.next;
+        }
+        Ok(())
+    }
+
+    pub fn read_node(&mut self, block: u64, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        // Nothing is read at or past the end, even inside the last block
+        if offset >= self.node_len(block)? {
+            return Ok(0);
+        }
+
+        let block_offset = offset / BLOCK_SIZE;
+        let mut byte_offset = (offset % BLOCK_SIZE) as usize;
+
+        let mut extents = Vec::new();
+        self.node_extents(block, block_offset, byte_offset + buf.len(), &mut extents)?;
+
+        let mut i = 0;
+        for extent in extents.iter() {
+            // The partial blocks at either end are read into sectors and the whole ones in between
+            // straight into buf, all with one request
+            let head = if byte_offset > 0 && extent.length > 0 { min(BLOCK_SIZE, extent.length) as usize } else { 0 };
+            let head_count = if head > 0 { min(head - byte_offset, buf.len() - i) } else { 0 };
+            let rest = extent.length - head as u64;
+            let middle = i + head_count;
+            let aligned = ((min(rest, (buf.len() - middle) as u64)/BLOCK_SIZE) * BLOCK_SIZE) as usize;
+            let tail = (rest - aligned as u64) as usize;
+            assert!(tail < BLOCK_SIZE as usize);
+
+            let mut head_sector = [0; BLOCK_SIZE as usize];
+            let mut tail_sector = [0; BLOCK_SIZE as usize];
+            {
+                let mut buffers: Vec<&mut [u8]> = Vec::with_capacity(3);
+                if head > 0 {
+                    buffers.push(&mut head_sector);
+                }
+                if aligned > 0 {
+                    buffers.push(&mut buf[middle..middle + aligned]);
+                }
+                if tail > 0 {
+                    buffers.push(&mut tail_sector);
+                }
+                if ! buffers.is_empty() {
+                    self.read_vectored_at(extent.block, &mut buffers)?;
+                }
+            }
+
+            if head > 0 {
+                buf[i..i + head_count].copy_from_slice(&head_sector[byte_offset..byte_offset + head_count]);
+                i += head_count;
+                byte_offset = 0;
+            }
+            i += aligned;
+            if tail > 0 {
+                let count = min(tail, buf.len() - i);
+                buf[i..i + count].copy_from_slice(&tail_sector[..count]);
+                i += count;
+            }
+        }
+
+        Ok(i)
+    }
+
+    pub fn write_node(&mut self, block: u64, offset: u64, buf: &[u8], mtime: u64, mtime_nsec: u32) -> Result<usize> {
+        let block_offset = offset / BLOCK_SIZE;
+        let mut byte_offset = (offset % BLOCK_SIZE) as usize;
+
+        let node = self.node(block)?;
+        let old_len = self.node_len(block)?;
+        if node.1.is_immutable() || (node.1.is_append() && offset < old_len) {
+            return Err(Error::NotPermitted);
+        }
+
+        let len = match offset.checked_add(buf.len() as u64) {
+            Some(len) if len <= Node::LEN_MAX => len,
+            _ => return Err(Error::TooLarge)
+        };
+        if len > old_len {
+            self.node_check_space(block, len)?;
+        }
+
+        self.dirty.insert(block);
+        if let Err(err) = self.node_ensure_len(block, len) {
+            // Free what was allocated before the disk filled, so the node keeps its old length
+            if len > old_len {
+                self.set_len(block, old_len)?;
+            }
+            return Err(err);
+        }
+
+        let mut extents = Vec::new();
+        self.node_extents(block, block_offset, byte_offset + buf.len(), &mut extents)?;
+
+        let mut i = 0;
+        for extent in extents.iter() {
+            // The partial blocks at either end are read into sectors to be changed, and written
+            // with the whole ones in between in one request
+            let head = if byte_offset > 0 && extent.length > 0 { min(BLOCK_SIZE, extent.length) as usize } else { 0 };
+            let head_count = if head > 0 { min(head - byte_offset, buf.len() - i) } else { 0 };
+            let rest = extent.length - head as u64;
+            let aligned = ((min(rest, (buf.len() - i - head_count) as u64)/BLOCK_SIZE) * BLOCK_SIZE) as usize;
+            let tail = (rest - aligned as u64) as usize;
+            assert!(tail < BLOCK_SIZE as usize);
+
+            let mut head_sector = [0; BLOCK_SIZE as usize];
+            if head > 0 {
+                self.read_at(extent.block, &mut head_sector)?;
+                head_sector[byte_offset..byte_offset + head_count].copy_from_slice(&buf[i..i + head_count]);
+                i += head_count;
+                byte_offset = 0;
+            }
+
+            let middle = &buf[i..i + aligned];
+            i += aligned;
+
+            let mut tail_sector = [0; BLOCK_SIZE as usize];
+            if tail > 0 {
+                let head_blocks = if head > 0 { 1 } else { 0 };
+                let tail_block = extent.block + head_blocks + (aligned as u64)/BLOCK_SIZE;
+                self.read_at(tail_block, &mut tail_sector)?;
+                let count = min(tail, buf.len() - i);
+                tail_sector[..count].copy_from_slice(&buf[i..i + count]);
+                i += count;
+            }
+
+            let mut buffers: Vec<&[u8]> = Vec::with_capacity(3);
+            if head > 0 {
+                buffers.push(&head_sector);
+            }
+            if aligned > 0 {
+                buffers.push(middle);
+            }
+            if tail > 0 {
+                buffers.push(&tail_sector);
+            }
+            if ! buffers.is_empty() {
+                self.write_vectored_at(extent.block, &buffers)?;
+            }
         }
 
         if i > 0 {
@@ -555,23 +1856,951 @@ impl<D: Disk> FileSystem<D> {
         Ok(i)
     }
 
+    /// Write to the end of a node, returning the offset written at and the number of bytes written
+    pub fn append_node(&mut self, block: u64, buf: &[u8], mtime: u64, mtime_nsec: u32) -> Result<(u64, usize)> {
+        let offset = self.node_len(block)?;
+        let count = self.write_node(block, offset, buf, mtime, mtime_nsec)?;
+        Ok((offset, count))
+    }
+
+    /// Bytes allocated to a node, its data, and the nodes its extents continue in
+    fn node_allocated(&mut self, block: u64) -> Result<u64> {
+        let block_size = self.header.1.block_size();
+
+        let mut size = 0;
+        let mut next = block;
+        while next > 0 {
+            let node = self.node(next)?;
+            size += BLOCK_SIZE;
+            for extent in node.1.extents().iter() {
+                size += (extent.length + block_size - 1)/block_size * block_size;
+            }
+            next = node.1.next;
+        }
+        Ok(size)
+    }
+
+    /// Collect the extended metadata of a node
+    pub fn node_stat(&mut self, block: u64) -> Result<ExStat> {
+        let node = self.node(block)?;
+        let mapped = self.mapped_node(&node.1);
+        Ok(ExStat {
+            ino: node.0,
+            mode: mapped.mode,
+            uid: mapped.uid,
+            gid: mapped.gid,
+            flags: node.1.flags,
+            generation: node.1.generation,
+            size: self.node_len(block)?,
+            blocks: self.node_allocated(block)?/512,
+            blksize: self.header.1.block_size() as u32,
+            mtime: node.1.mtime,
+            mtime_nsec: node.1.mtime_nsec,
+            ctime: node.1.ctime,
+            ctime_nsec: node.1.ctime_nsec,
+            crtime: node.1.crtime,
+            crtime_nsec: node.1.crtime_nsec,
+        })
+    }
+
     pub fn node_len(&mut self, block: u64) -> Result<u64> {
         if block == 0 {
-            return Err(Error::new(ENOENT));
+            return Err(Error::NotFound);
         }
 
         let mut size = 0;
+        let mut next = block;
+        while next > 0 {
+            let node = self.node(next)?;
+            for extent in node.1.extents().iter() {
+                size += extent.length;
+            }
+            next = node.1.next;
+        }
+        Ok(size)
+    }
+}
 
-        let node = self.node(block)?;
-        for extent in node.1.extents.iter() {
-            size += extent.length;
+/// The blocks of the children of a directory, from `FileSystem::child_blocks`
+pub struct ChildBlocks<'a, D: Disk + 'a> {
+    fs: &'a mut FileSystem<D>,
+    /// Next node of the directory's list of extents, or 0 at the end
+    next: u64,
+    list: Option<Node>,
+    extent: usize,
+    blocks: BlockIter,
+}
+
+impl<'a, D: Disk> ChildBlocks<'a, D> {
+    /// Read the node of each remaining child, such as after skipping some by block
+    pub fn nodes(self) -> ChildNodes<'a, D> {
+        ChildNodes {
+            blocks: self,
+        }
+    }
+}
+
+impl<'a, D: Disk> Iterator for ChildBlocks<'a, D> {
+    type Item = Result<u64>;
+
+    fn next(&mut self) -> Option<Result<u64>> {
+        loop {
+            while let Some((block, size)) = self.blocks.next() {
+                if size >= BLOCK_SIZE {
+                    return Some(Ok(block));
+                }
+            }
+
+            let extent = self.list.as_ref().and_then(|list| list.extents().get(self.extent).cloned());
+            match extent {
+                Some(extent) => {
+                    self.extent += 1;
+                    self.blocks = extent.blocks();
+                },
+                None => {
+                    if self.next == 0 {
+                        return None;
+                    }
+                    match self.fs.node(self.next) {
+                        Ok(list) => {
+                            self.next = list.1.next;
+                            self.list = Some(list.1);
+                            self.extent = 0;
+                        },
+                        Err(err) => {
+                            self.next = 0;
+                            self.list = None;
+                            return Some(Err(err));
+                        }
+                    }
+                }
+            }
         }
+    }
+}
+
+/// The children of a directory, from `FileSystem::child_iter`
+pub struct ChildNodes<'a, D: Disk + 'a> {
+    blocks: ChildBlocks<'a, D>,
+}
+
+impl<'a, D: Disk> Iterator for ChildNodes<'a, D> {
+    type Item = Result<(u64, Node)>;
+
+    fn next(&mut self) -> Option<Result<(u64, Node)>> {
+        match self.blocks.next() {
+            Some(Ok(block)) => Some(self.blocks.fs.node(block)),
+            Some(Err(err)) => Some(Err(err)),
+            None => None,
+        }
+    }
+}
+
+#[test]
+fn unaligned_io_test() {
+    use disk::DiskCache;
+
+    let mut fs = FileSystem::create(DiskCache::new(DiskMemory::new(64)), 0, 0).unwrap();
+    let root = fs.header.1.root;
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+
+    let mut expected = vec![0; 5 * BLOCK_SIZE as usize];
+    for &(offset, len) in [(0, 3 * BLOCK_SIZE as usize), (100, 2 * BLOCK_SIZE as usize), (BLOCK_SIZE as usize - 1, 2), (4000, 5000), (10, 10)].iter() {
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 + offset) as u8).collect();
+        assert_eq!(fs.write_node(file.0, offset as u64, &data, 0, 0), Ok(len));
+        expected[offset..offset + len].copy_from_slice(&data);
+    }
+    let len = fs.node_len(file.0).unwrap() as usize;
+    expected.truncate(len);
+
+    for offset in [0, 1, 100, BLOCK_SIZE as usize - 1, BLOCK_SIZE as usize, 5000].iter().cloned() {
+        let mut buf = vec![0; len - offset];
+        assert_eq!(fs.read_node(file.0, offset as u64, &mut buf), Ok(len - offset));
+        assert_eq!(buf, &expected[offset..]);
+    }
+}
+
+#[test]
+fn read_past_end_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+    let small = fs.create_node(Node::MODE_FILE, "small", root, 0, 0).unwrap();
+    fs.write_node(small.0, 0, &[1; 100], 0, 0).unwrap();
+    let large = fs.create_node(Node::MODE_FILE, "large", root, 0, 0).unwrap();
+    fs.write_node(large.0, 0, &[2; 5000], 0, 0).unwrap();
+
+    let mut buf = [0; 100];
+    assert_eq!(fs.read_node(small.0, 100, &mut buf), Ok(0));
+    assert_eq!(fs.read_node(small.0, 150, &mut buf), Ok(0));
+    assert_eq!(fs.read_node(large.0, 6000, &mut buf), Ok(0));
+    assert_eq!(fs.read_node(large.0, 10 * BLOCK_SIZE, &mut buf), Ok(0));
+    assert_eq!(fs.read_node(large.0, 4950, &mut buf), Ok(50));
+    assert_eq!(&buf[..50], &[2; 50][..]);
+}
 
-        if node.1.next > 0 {
-            size += self.node_len(node.1.next)?;
-            Ok(size)
+#[test]
+fn block_size_test() {
+    let disk = DiskMemory::new(64);
+    assert!(FileSystem::create_block_size(DiskMemory(Vec::new()), &[], 512, 0, 0).is_err());
+
+    let mut fs = FileSystem::create_block_size(disk, &[], 2 * BLOCK_SIZE, 0, 0).unwrap();
+    let free = fs.header.1.free;
+    let root = fs.header.1.root;
+    let free_size = fs.node_len(free).unwrap();
+    assert_eq!(free_size % (2 * BLOCK_SIZE), 0);
+
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    assert_eq!(file.0 % 2, 0);
+
+    let data = [1; 10000];
+    assert_eq!(fs.write_node(file.0, 0, &data, 0, 0), Ok(data.len()));
+    assert_eq!(fs.node_len(free).unwrap(), free_size - 6 * BLOCK_SIZE);
+
+    fs.node_set_len(file.0, 100).unwrap();
+    assert_eq!(fs.node_len(free).unwrap(), free_size - 4 * BLOCK_SIZE);
+
+    fs.remove_node(Node::MODE_FILE, "file", root).unwrap();
+    assert_eq!(fs.node_len(free).unwrap(), free_size);
+}
+
+#[test]
+fn create_exists_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+
+    let file = fs.create_node(Node::MODE_FILE, "lock", root, 0, 0).unwrap();
+    assert_eq!(fs.create_node(Node::MODE_FILE, "lock", root, 0, 0).map(|node| node.0), Err(Error::Exists));
+    assert_eq!(fs.create_node(Node::MODE_DIR, "lock", root, 0, 0).map(|node| node.0), Err(Error::Exists));
+
+    let mut children = Vec::new();
+    fs.child_nodes(&mut children, root).unwrap();
+    assert_eq!(children.iter().map(|child| child.0).collect::<Vec<u64>>(), vec![file.0]);
+}
+
+#[test]
+fn child_iter_test() {
+    let mut fs = memory_fs(1024);
+    let root = fs.header.1.root;
+
+    // Data between the nodes gives every child its own extent, overflowing the root's list
+    let mut files = Vec::new();
+    for i in 0..300 {
+        let file = fs.create_node(Node::MODE_FILE, &format!("file{}", i), root, 0, 0).unwrap();
+        fs.write_node(file.0, 0, &[1], 0, 0).unwrap();
+        files.push(file.0);
+    }
+    assert!(fs.node(root).unwrap().1.next != 0);
+
+    let mut children = Vec::new();
+    fs.child_nodes(&mut children, root).unwrap();
+    assert_eq!(children.iter().map(|child| child.0).collect::<Vec<u64>>(), files);
+    assert_eq!(fs.child_blocks(root).map(|block| block.unwrap()).collect::<Vec<u64>>(), files);
+
+    let mut blocks = fs.child_blocks(root);
+    assert_eq!(blocks.nth(249).unwrap().unwrap(), files[249]);
+    let rest: Vec<String> = blocks.nodes().map(|child| child.unwrap().1.name().unwrap().to_string()).collect();
+    assert_eq!(rest.len(), 50);
+    assert_eq!(rest[0], "file250");
+}
+
+#[test]
+fn lookup_cache_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    assert_eq!(fs.find_node("file", root).map(|node| node.0), Ok(file.0));
+    assert_eq!(fs.lookups.get(&(root, "file".to_string())).map(|lookup| lookup.0), Some(file.0));
+    assert_eq!(fs.find_node("file", root).map(|node| node.0), Ok(file.0));
+
+    fs.remove_node(Node::MODE_FILE, "file", root).unwrap();
+    assert!(fs.lookups.is_empty());
+    assert_eq!(fs.find_node("file", root).map(|node| node.0), Err(Error::NotFound));
+
+    // A stale entry is noticed when the node no longer has the name
+    let dir = fs.create_node(Node::MODE_DIR, "dir", root, 0, 0).unwrap();
+    let file = fs.create_node(Node::MODE_FILE, "file", dir.0, 0, 0).unwrap();
+    fs.lookups.insert((root, "file".to_string()), (file.0, 0));
+    assert_eq!(fs.find_node("file", root).map(|node| node.0), Err(Error::NotFound));
+    assert_eq!(fs.find_node("file", dir.0).map(|node| node.0), Ok(file.0));
+
+    fs.set_case_insensitive(true);
+    assert_eq!(fs.find_node("FILE", dir.0).map(|node| node.0), Ok(file.0));
+    assert_eq!(fs.lookups.get(&(dir.0, "file".to_string())).map(|lookup| lookup.0), Some(file.0));
+
+    // The entry of a name that was forgotten and found again outlives the earlier one
+    fs.set_case_insensitive(false);
+    assert_eq!(fs.find_node("file", dir.0).map(|node| node.0), Ok(file.0));
+    fs.forget_lookup("file", dir.0);
+    assert_eq!(fs.find_node("file", dir.0).map(|node| node.0), Ok(file.0));
+    assert_eq!(fs.lookup_order.len(), 2);
+    for i in 0..LOOKUP_CACHE_SIZE - 2 {
+        fs.lookup_order.push_back(((0, i.to_string()), u64::max_value()));
+    }
+    let other = fs.create_node(Node::MODE_FILE, "other", dir.0, 0, 0).unwrap();
+    assert_eq!(fs.find_node("other", dir.0).map(|node| node.0), Ok(other.0));
+    assert!(fs.lookups.contains_key(&(dir.0, "file".to_string())));
+    assert_eq!(fs.lookup_order.len(), LOOKUP_CACHE_SIZE);
+}
+
+#[test]
+fn free_list_test() {
+    let mut fs = memory_fs(2048);
+    let root = fs.header.1.root;
+
+    // Freeing every other file leaves more free extents than one node can list
+    for i in 0..600 {
+        let file = fs.create_node(Node::MODE_FILE, &format!("file{}", i), root, 0, 0).unwrap();
+        fs.write_node(file.0, 0, &[1], 0, 0).unwrap();
+    }
+    for i in (0..600).step_by(2) {
+        fs.remove_node(Node::MODE_FILE, &format!("file{}", i), root).unwrap();
+    }
+    assert!(fs.free_nodes.len() > 1);
+
+    let free_nodes = fs.free_nodes.clone();
+    let mut fs = FileSystem::open(fs.disk).unwrap();
+    assert_eq!(fs.free_nodes.len(), free_nodes.len());
+    for (free, saved) in fs.free_nodes.iter().zip(free_nodes.iter()) {
+        assert_eq!(free.0, saved.0);
+        assert_eq!(&free.1.data[..], &saved.1.data[..]);
+        assert_eq!({ free.1.next }, { saved.1.next });
+    }
+
+    // Once the free extents at the start of the list are used, the rest of the list is searched
+    while fs.allocate(2).is_ok() {}
+    for free in fs.free_nodes.iter_mut() {
+        assert!(free.1.extents_mut().iter().all(|extent| extent.length < 2 * BLOCK_SIZE));
+    }
+}
+
+#[test]
+fn case_insensitive_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+
+    let file = fs.create_node(Node::MODE_FILE, "ReadMe", root, 0, 0).unwrap();
+    assert_eq!(fs.find_node("readme", root).map(|node| node.0), Err(Error::NotFound));
+
+    fs.set_case_insensitive(true);
+    let found = fs.find_node("README", root).unwrap();
+    assert_eq!(found.0, file.0);
+    assert_eq!(found.1.name(), Ok("ReadMe"));
+    assert_eq!(fs.create_node(Node::MODE_FILE, "readme", root, 0, 0).map(|node| node.0), Err(Error::Exists));
+}
+
+#[test]
+fn name_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+
+    assert_eq!(fs.create_node(Node::MODE_FILE, "", root, 0, 0).map(|node| node.0), Err(Error::InvalidName));
+    assert_eq!(fs.create_node(Node::MODE_FILE, "a/b", root, 0, 0).map(|node| node.0), Err(Error::InvalidName));
+    assert_eq!(fs.create_node(Node::MODE_FILE, &"a".repeat(Node::NAME_MAX + 1), root, 0, 0).map(|node| node.0), Err(Error::NameTooLong));
+    assert!(fs.create_node(Node::MODE_FILE, &"a".repeat(Node::NAME_MAX), root, 0, 0).is_ok());
+}
+
+#[cfg(feature = "nfc")]
+#[test]
+fn nfc_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+    fs.set_name_policy(NamePolicy::Nfc);
+
+    // "é" written as "e" followed by a combining acute accent, as macOS does
+    let file = fs.create_node(Node::MODE_FILE, "caf\u{65}\u{301}", root, 0, 0).unwrap();
+    assert_eq!(file.1.name(), Ok("caf\u{e9}"));
+    assert_eq!(fs.find_node("caf\u{65}\u{301}", root).map(|node| node.0), Ok(file.0));
+    assert_eq!(fs.create_node(Node::MODE_FILE, "caf\u{e9}", root, 0, 0).map(|node| node.0), Err(Error::Exists));
+}
+
+#[test]
+fn flags_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+
+    let log = fs.create_node(Node::MODE_FILE, "log", root, 0, 0).unwrap();
+    fs.write_node(log.0, 0, &[1; 100], 0, 0).unwrap();
+    fs.node_set_flags(log.0, Node::FLAG_APPEND).unwrap();
+    assert_eq!(fs.write_node(log.0, 0, &[2; 10], 0, 0), Err(Error::NotPermitted));
+    assert_eq!(fs.append_node(log.0, &[2; 10], 0, 0), Ok((100, 10)));
+    assert_eq!(fs.node_set_len(log.0, 0), Err(Error::NotPermitted));
+    assert_eq!(fs.remove_node(Node::MODE_FILE, "log", root), Err(Error::NotPermitted));
+
+    let dir = fs.create_node(Node::MODE_DIR, "dir", root, 0, 0).unwrap();
+    fs.create_node(Node::MODE_FILE, "file", dir.0, 0, 0).unwrap();
+    fs.node_set_flags(dir.0, Node::FLAG_IMMUTABLE).unwrap();
+    assert_eq!(fs.create_node(Node::MODE_FILE, "new", dir.0, 0, 0).map(|node| node.0), Err(Error::NotPermitted));
+    assert_eq!(fs.remove_node(Node::MODE_FILE, "file", dir.0), Err(Error::NotPermitted));
+
+    fs.node_set_flags(dir.0, 0).unwrap();
+    assert_eq!(fs.remove_node(Node::MODE_FILE, "file", dir.0), Ok(()));
+}
+
+#[test]
+fn inherit_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+
+    let log = fs.create_node(Node::MODE_DIR | 0o755, "log", root, 0, 0).unwrap();
+    fs.node_set_flags(log.0, Node::FLAG_NODUMP | Node::FLAG_APPEND).unwrap();
+    fs.set_xattr(log.0, xattr::DEFAULT_UMASK, b"027", false, false).unwrap();
+    assert_eq!(fs.set_xattr(log.0, xattr::DEFAULT_UMASK, b"1000", false, false), Err(Error::InvalidValue));
+    assert_eq!(fs.set_xattr(log.0, xattr::DEFAULT_UMASK, b"", false, false), Err(Error::InvalidValue));
+
+    let file = fs.create_node(Node::MODE_FILE | 0o666, "file", log.0, 0, 0).unwrap();
+    assert_eq!({ file.1.mode }, Node::MODE_FILE | 0o640);
+    assert_eq!({ file.1.flags }, Node::FLAG_NODUMP);
+    assert_eq!(fs.set_xattr(file.0, xattr::DEFAULT_UMASK, b"027", false, false), Err(Error::InvalidValue));
+
+    // Directories pass the umask on to everything created below them
+    let dir = fs.create_node(Node::MODE_DIR | 0o777, "dir", log.0, 0, 0).unwrap();
+    assert_eq!({ dir.1.mode }, Node::MODE_DIR | 0o750);
+    assert_eq!({ dir.1.flags }, Node::FLAG_NODUMP);
+    assert_eq!(fs.get_xattr(dir.0, xattr::DEFAULT_UMASK), Ok(b"027".to_vec()));
+    let nested = fs.create_node(Node::MODE_FILE | 0o666, "nested", dir.0, 0, 0).unwrap();
+    assert_eq!({ nested.1.mode }, Node::MODE_FILE | 0o640);
+
+    let other = fs.create_node(Node::MODE_FILE | 0o666, "other", root, 0, 0).unwrap();
+    assert_eq!({ other.1.mode }, Node::MODE_FILE | 0o666);
+    assert_eq!({ other.1.flags }, 0);
+}
+
+#[test]
+fn secure_delete_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+
+    let secret = fs.create_node(Node::MODE_FILE, "secret", root, 0, 0).unwrap();
+    fs.write_node(secret.0, 0, &[1; 3 * BLOCK_SIZE as usize], 0, 0).unwrap();
+    fs.node_set_flags(secret.0, Node::FLAG_SECURE_DELETE).unwrap();
+    let data = fs.node(secret.0).unwrap().1.extents()[0].block;
+
+    fs.node_set_len(secret.0, 100).unwrap();
+    let mut buf = vec![0; 3 * BLOCK_SIZE as usize];
+    fs.read_at(data, &mut buf).unwrap();
+    assert!(buf[..100].iter().all(|&b| b == 1));
+    assert!(buf[100..].iter().all(|&b| b == 0));
+
+    fs.remove_node(Node::MODE_FILE, "secret", root).unwrap();
+    fs.read_at(data, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0));
+
+    // Without the flag or the mount option, freed data is left in place
+    let plain = fs.create_node(Node::MODE_FILE, "plain", root, 0, 0).unwrap();
+    fs.write_node(plain.0, 0, &[2; BLOCK_SIZE as usize], 0, 0).unwrap();
+    let data = fs.node(plain.0).unwrap().1.extents()[0].block;
+    fs.remove_node(Node::MODE_FILE, "plain", root).unwrap();
+    fs.read_at(data, &mut buf[..BLOCK_SIZE as usize]).unwrap();
+    assert!(buf[..BLOCK_SIZE as usize].iter().all(|&b| b == 2));
+
+    fs.set_secure_delete(true);
+    let plain = fs.create_node(Node::MODE_FILE, "plain", root, 0, 0).unwrap();
+    fs.write_node(plain.0, 0, &[2; BLOCK_SIZE as usize], 0, 0).unwrap();
+    let data = fs.node(plain.0).unwrap().1.extents()[0].block;
+    fs.remove_node(Node::MODE_FILE, "plain", root).unwrap();
+    fs.read_at(data, &mut buf[..BLOCK_SIZE as usize]).unwrap();
+    assert!(buf[..BLOCK_SIZE as usize].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn node_stat_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 5, 6).unwrap();
+    fs.write_node(file.0, 0, &[1; 10000], 7, 8).unwrap();
+
+    let stat = fs.node_stat(file.0).unwrap();
+    assert_eq!((stat.ino, stat.size, stat.blocks, stat.blksize), (file.0, 10000, 4 * BLOCK_SIZE/512, BLOCK_SIZE as u32));
+    assert_eq!((stat.crtime, stat.crtime_nsec, stat.mtime, stat.mtime_nsec), (5, 6, 7, 8));
+    assert_eq!({ stat.generation }, { file.1.generation });
+}
+
+#[test]
+fn root_reserved_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+    let free = fs.free_blocks();
+    fs.set_root_reserved(free - 2).unwrap();
+    assert_eq!(fs.available_blocks(), 2);
+
+    // Others can use the blocks that are not reserved, root can use the rest
+    fs.set_privileged(false);
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    assert_eq!(fs.write_node(file.0, 0, &[1; BLOCK_SIZE as usize], 0, 0), Ok(BLOCK_SIZE as usize));
+    assert_eq!(fs.write_node(file.0, BLOCK_SIZE, &[1; BLOCK_SIZE as usize], 0, 0), Err(Error::NoSpace));
+    fs.set_privileged(true);
+    assert_eq!(fs.write_node(file.0, BLOCK_SIZE, &[1; BLOCK_SIZE as usize], 0, 0), Ok(BLOCK_SIZE as usize));
+    assert_eq!(fs.free_blocks(), free - 3);
+
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert_eq!({ fs.header.1.root_reserved }, free - 2);
+}
+
+#[test]
+fn no_space_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    fs.write_node(file.0, 0, &[1; 10], 0, 0).unwrap();
+
+    // Leave a hole in the free space, so it is not all contiguous
+    let hole = fs.create_node(Node::MODE_FILE, "hole", root, 0, 0).unwrap();
+    fs.write_node(hole.0, 0, &[1; 2 * BLOCK_SIZE as usize], 0, 0).unwrap();
+    fs.create_node(Node::MODE_FILE, "after", root, 0, 0).unwrap();
+    fs.remove_node(Node::MODE_FILE, "hole", root).unwrap();
+    let free = fs.free_blocks();
+
+    // More than is free fails before anything is allocated
+    let buf = vec![2; (free as usize + 2) * BLOCK_SIZE as usize];
+    assert_eq!(fs.write_node(file.0, 0, &buf, 0, 0), Err(Error::NoSpace));
+    assert_eq!(fs.node_len(file.0), Ok(10));
+    assert_eq!(fs.free_blocks(), free);
+
+    // Enough free blocks that are not contiguous fail to allocate, and are given back
+    assert_eq!(fs.write_node(file.0, 0, &buf[..(free as usize + 1) * BLOCK_SIZE as usize], 0, 0), Err(Error::NoSpace));
+    assert_eq!(fs.node_len(file.0), Ok(10));
+    assert_eq!(fs.free_blocks(), free);
+
+    let mut data = [0; 10];
+    assert_eq!(fs.read_node(file.0, 0, &mut data), Ok(10));
+    assert_eq!(data, [1; 10]);
+}
+
+#[test]
+fn create_fail_test() {
+    use disk::DiskFaulty;
+    use testing::DiskMemory;
+
+    let mut fs = FileSystem::create(DiskFaulty::new(DiskMemory::new(64)), 0, 0).unwrap();
+    let root = fs.header.1.root;
+    let free = fs.free_blocks();
+
+    // A node that cannot be added to its parent does not keep its block
+    fs.disk.fail_write(root);
+    assert_eq!(fs.create_node(Node::MODE_FILE, "file", root, 0, 0).map(|node| node.0), Err(Error::Io(root)));
+    assert_eq!(fs.free_blocks(), free);
+    assert_eq!(fs.find_node("file", root).map(|node| node.0), Err(Error::NotFound));
+
+    fs.disk.clear();
+    fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    assert_eq!(fs.free_blocks(), free - 1);
+}
+
+#[test]
+fn large_file_test() {
+    let mut fs = memory_fs(1024);
+    let root = fs.header.1.root;
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    let other = fs.create_node(Node::MODE_FILE, "other", root, 0, 0).unwrap();
+
+    // Interleaved writes keep the extents from merging, so they continue in more nodes
+    for i in 0..400 {
+        fs.write_node(file.0, i * BLOCK_SIZE, &[i as u8; BLOCK_SIZE as usize], 0, 0).unwrap();
+        fs.write_node(other.0, i * BLOCK_SIZE, &[0; BLOCK_SIZE as usize], 0, 0).unwrap();
+    }
+    assert!(fs.node(file.0).unwrap().1.next > 0);
+    assert_eq!(fs.node_len(file.0), Ok(400 * BLOCK_SIZE));
+
+    let mut buf = vec![0; 2 * BLOCK_SIZE as usize];
+    assert_eq!(fs.read_node(file.0, 398 * BLOCK_SIZE, &mut buf), Ok(2 * BLOCK_SIZE as usize));
+    assert_eq!((buf[0], buf[BLOCK_SIZE as usize]), (398u64 as u8, 399u64 as u8));
+
+    fs.node_set_len(file.0, 10 * BLOCK_SIZE).unwrap();
+    assert_eq!(fs.node_len(file.0), Ok(10 * BLOCK_SIZE));
+    assert_eq!(fs.read_node(file.0, 9 * BLOCK_SIZE, &mut buf), Ok(BLOCK_SIZE as usize));
+    assert_eq!(buf[0], 9);
+
+    assert_eq!(fs.write_node(file.0, Node::LEN_MAX, &[1], 0, 0), Err(Error::TooLarge));
+    assert_eq!(fs.write_node(file.0, u64::MAX, &[1], 0, 0), Err(Error::TooLarge));
+    assert_eq!(fs.node_len(file.0), Ok(10 * BLOCK_SIZE));
+}
+
+#[test]
+fn long_dir_test() {
+    let mut fs = memory_fs(1024);
+    let root = fs.header.1.root;
+    let filler = fs.create_node(Node::MODE_FILE, "filler", root, 0, 0).unwrap();
+
+    // Data written between the children keeps their blocks from merging into one extent
+    for i in 0..300 {
+        fs.create_node(Node::MODE_FILE, &format!("{}", i), root, 0, 0).unwrap();
+        fs.write_node(filler.0, i * BLOCK_SIZE, &[0; BLOCK_SIZE as usize], 0, 0).unwrap();
+    }
+    assert!(fs.node(root).unwrap().1.next > 0);
+
+    let last = fs.find_child("299", root).unwrap();
+    fs.remove_node(Node::MODE_FILE, "299", root).unwrap();
+    assert_eq!(fs.find_child("299", root).map(|node| node.0), Err(Error::NotFound));
+    assert_eq!(fs.unlink_blocks(last.0, 1, root), Err(Error::NotFound));
+    fs.find_child("298", root).unwrap();
+}
+
+#[test]
+fn subvolume_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+    assert_eq!(fs.root(), root);
+    assert_eq!(fs.set_subvolume(Some("home")), Err(Error::NotFound));
+
+    let home = fs.create_subvolume("home", 0, 0).unwrap();
+    assert_eq!(fs.create_subvolume("home", 0, 0).map(|node| node.0), Err(Error::Exists));
+    fs.create_node(Node::MODE_FILE, "file", home.0, 0, 0).unwrap();
+    let names: Vec<String> = fs.subvolumes().unwrap().iter().map(|node| node.1.name().unwrap().to_string()).collect();
+    assert_eq!(names, ["home"]);
+
+    // Each tree has its own children
+    fs.set_subvolume(Some("home")).unwrap();
+    assert_eq!(fs.root(), home.0);
+    assert!(fs.find_node("file", home.0).is_ok());
+    assert_eq!(fs.find_node("file", root).map(|node| node.0), Err(Error::NotFound));
+
+    fs.set_default_subvolume(Some("home")).unwrap();
+    let mut fs = FileSystem::open(fs.disk).unwrap();
+    assert_eq!(fs.root(), home.0);
+    fs.set_subvolume(None).unwrap();
+    assert_eq!(fs.root(), root);
+}
+
+#[test]
+fn xattr_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    assert_eq!(fs.node_xattrs(file.0), Ok(Vec::new()));
+    assert_eq!(fs.get_xattr(file.0, "user.a"), Err(Error::NoAttribute));
+
+    fs.set_xattr(file.0, "user.a", b"1", false, false).unwrap();
+    fs.set_xattr(file.0, "security.capability", b"cap", true, false).unwrap();
+    assert_eq!(fs.set_xattr(file.0, "user.a", b"2", true, false), Err(Error::Exists));
+    assert_eq!(fs.set_xattr(file.0, "user.b", b"2", false, true), Err(Error::NoAttribute));
+    assert_eq!(fs.set_xattr(file.0, "system.posix_acl_access", b"", false, false), Err(Error::Unsupported));
+    assert_eq!(fs.set_xattr(file.0, "a", b"", false, false), Err(Error::Unsupported));
+    fs.set_xattr(file.0, "user.a", b"22", false, true).unwrap();
+    assert_eq!(fs.get_xattr(file.0, "user.a"), Ok(b"22".to_vec()));
+    assert_eq!(fs.node_xattrs(file.0).unwrap().len(), 2);
+
+    // The attributes are freed with the last one or the node
+    let free = fs.free_blocks();
+    fs.remove_xattr(file.0, "user.a").unwrap();
+    assert_eq!(fs.remove_xattr(file.0, "user.a"), Err(Error::NoAttribute));
+    assert_eq!(fs.free_blocks(), free);
+    fs.remove_xattr(file.0, "security.capability").unwrap();
+    assert_eq!({ fs.node(file.0).unwrap().1.xattrs }, 0);
+    assert!(fs.free_blocks() > free);
+
+    fs.set_xattr(file.0, "trusted.a", b"1", false, false).unwrap();
+    let free = fs.free_blocks();
+    fs.remove_node(Node::MODE_FILE, "file", root).unwrap();
+    assert!(fs.free_blocks() >= free + 2);
+}
+
+#[test]
+fn ownership_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+    let mut file = fs.create_node(Node::MODE_FILE | 0o664, "file", root, 0, 0).unwrap();
+    file.1.uid = 1000;
+    file.1.gid = 1000;
+    fs.write_at(file.0, &file.1).unwrap();
+
+    fs.set_ownership(Some(0), None, 0o022);
+    let stat = fs.node_stat(file.0).unwrap();
+    assert_eq!(({ stat.uid }, { stat.gid }, { stat.mode }), (0, 1000, Node::MODE_FILE | 0o644));
+    assert_eq!({ fs.node(file.0).unwrap().1.mode }, Node::MODE_FILE | 0o664);
+
+    // Permissions are checked against the same owner that is reported
+    fs.set_ownership(Some(2000), None, 0o002);
+    let node = fs.node(file.0).unwrap().1;
+    assert!(! node.permission(2000, 2000, Node::MODE_WRITE));
+    let mapped = fs.mapped_node(&node);
+    assert!(mapped.permission(2000, 2000, Node::MODE_WRITE));
+    assert!(! mapped.permission(3000, 3000, Node::MODE_WRITE));
+}
+
+#[test]
+fn orphan_test() {
+    use std::sync::{Arc, Mutex};
+
+    let mut fs = memory_fs(64);
+    let free = fs.header.1.free;
+    let root = fs.header.1.root;
+
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    fs.write_node(file.0, 0, &[1; 10000], 0, 0).unwrap();
+    let free_size = fs.node_len(free).unwrap();
+
+    assert_eq!(fs.orphan_node(Node::MODE_FILE, "file", root), Ok(file.0));
+    assert_eq!(fs.find_node("file", root).map(|node| node.0), Err(Error::NotFound));
+    assert_eq!(fs.read_node(file.0, 0, &mut [0; 100]), Ok(100));
+
+    // Reopening reclaims the node and its three data blocks, while the orphan list itself stays allocated
+    let notices = Arc::new(Mutex::new(Vec::new()));
+    let sink = notices.clone();
+    let mut fs = FileSystem::open_notify(fs.disk, Box::new(move |notice| sink.lock().unwrap().push(notice))).unwrap();
+    assert_eq!(fs.node_len(free).unwrap(), free_size + 3 * BLOCK_SIZE);
+    assert_eq!(*notices.lock().unwrap(), vec![Notice::OrphanReclaimed(file.0)]);
+}
+
+#[test]
+fn rename_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+
+    let dir = fs.create_node(Node::MODE_DIR, "dir", root, 0, 0).unwrap();
+    let sub = fs.create_node(Node::MODE_DIR, "sub", dir.0, 0, 0).unwrap();
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    fs.write_node(file.0, 0, b"new", 0, 0).unwrap();
+    let old = fs.create_node(Node::MODE_FILE, "old", dir.0, 0, 0).unwrap();
+
+    assert_eq!(fs.rename_node("dir", root, "dir", sub.0), Err(Error::InvalidMove));
+    assert_eq!(fs.rename_node("file", root, "sub", dir.0), Err(Error::IsDir));
+    assert_eq!(fs.rename_node("sub", dir.0, "old", dir.0), Err(Error::NotDir));
+    assert_eq!(fs.rename_node("missing", root, "file", root), Err(Error::NotFound));
+
+    assert_eq!(fs.rename_node("file", root, "old", dir.0), Ok(Some(old.0)));
+    assert_eq!(fs.find_node("file", root).map(|node| node.0), Err(Error::NotFound));
+    let renamed = fs.find_node("old", dir.0).unwrap();
+    assert_eq!(renamed.0, file.0);
+    assert_eq!({ renamed.1.parent }, dir.0);
+    fs.release_node(old.0).unwrap();
+
+    assert_eq!(fs.rename_node("sub", dir.0, "moved", root), Ok(None));
+    assert_eq!(fs.find_node("moved", root).map(|node| node.0), Ok(sub.0));
+    assert_eq!(fs.child_blocks(dir.0).map(|block| block.unwrap()).collect::<Vec<u64>>(), vec![file.0]);
+}
+
+#[test]
+fn rename_long_name_test() {
+    let mut fs = memory_fs(1024);
+    let root = fs.header.1.root;
+    let file = fs.create_node(Node::MODE_FILE, "a", root, 0, 0).unwrap();
+    let other = fs.create_node(Node::MODE_FILE, "b", root, 0, 0).unwrap();
+
+    // Appending to both in turn gives the file an extent for every block, more than fit beside a long name
+    let mut expected = Vec::new();
+    for i in 0..240 {
+        let data = [i as u8; BLOCK_SIZE as usize];
+        fs.append_node(file.0, &data, 0, 0).unwrap();
+        fs.append_node(other.0, &data, 0, 0).unwrap();
+        expected.extend_from_slice(&data);
+    }
+    assert_eq!({ fs.node(file.0).unwrap().1.next }, 0);
+
+    let name = "x".repeat(Node::NAME_MAX);
+    assert_eq!(fs.rename_node("a", root, &name, root), Ok(None));
+    assert_eq!(fs.rename_node("b", root, &"y".repeat(Node::NAME_MAX + 1), root), Err(Error::NameTooLong));
+
+    let mut fs = FileSystem::open(fs.disk).unwrap();
+    let renamed = fs.find_node(&name, root).unwrap();
+    assert_eq!(renamed.0, file.0);
+    assert!({ renamed.1.next } != 0);
+    let mut data = vec![0; expected.len()];
+    assert_eq!(fs.read_node(renamed.0, 0, &mut data), Ok(expected.len()));
+    assert!(data == expected);
+}
+
+#[test]
+fn rename_crash_test() {
+    use std::sync::{Arc, Mutex};
+    use disk::DiskFaulty;
+
+    let mut finished = false;
+    for writes in 0.. {
+        let mut fs = FileSystem::create(DiskFaulty::new(DiskMemory::new(64)), 0, 0).unwrap();
+        let root = fs.header.1.root;
+        let dir = fs.create_node(Node::MODE_DIR, "dir", root, 0, 0).unwrap();
+        let target = fs.create_node(Node::MODE_FILE, "target", root, 0, 0).unwrap();
+        fs.write_node(target.0, 0, b"old", 0, 0).unwrap();
+        let tmp = fs.create_node(Node::MODE_FILE, "tmp", dir.0, 0, 0).unwrap();
+        fs.write_node(tmp.0, 0, b"new", 0, 0).unwrap();
+
+        fs.disk.power_loss_after(writes);
+        let res = fs.rename_node("tmp", dir.0, "target", root);
+        fs.disk.clear();
+
+        let notices = Arc::new(Mutex::new(Vec::new()));
+        let sink = notices.clone();
+        let mut fs = FileSystem::open_notify(fs.disk, Box::new(move |notice| sink.lock().unwrap().push(notice))).unwrap();
+        assert_eq!({ fs.header.1.rename }, 0);
+        for notice in notices.lock().unwrap().iter() {
+            if let Notice::RenameFinished(block) = *notice {
+                assert_eq!(block, tmp.0);
+                finished = true;
+            }
+        }
+        let node = fs.find_node("target", root).unwrap();
+        let mut data = [0; 3];
+        fs.read_node(node.0, 0, &mut data).unwrap();
+        if node.0 == target.0 {
+            assert!(res.is_err());
+            assert_eq!(&data, b"old");
+            assert_eq!(fs.find_node("tmp", dir.0).map(|node| node.0), Ok(tmp.0));
         } else {
-            Ok(size)
+            assert_eq!(node.0, tmp.0);
+            assert_eq!(&data, b"new");
+            assert_eq!(fs.find_node("tmp", dir.0).map(|node| node.0), Err(Error::NotFound));
+            assert_eq!(fs.child_blocks(root).filter(|block| *block.as_ref().unwrap() == tmp.0).count(), 1);
+        }
+
+        if res.is_ok() {
+            break;
+        }
+    }
+    assert!(finished);
+}
+
+#[test]
+fn rename_reorder_test() {
+    use std::usize;
+
+    for writes in 0.. {
+        let mut fs = FileSystem::create(DiskReorder(DiskMemory::new(64), Vec::new(), usize::MAX), 0, 0).unwrap();
+        let root = fs.header.1.root;
+        let target = fs.create_node(Node::MODE_FILE, "target", root, 0, 0).unwrap();
+        fs.write_node(target.0, 0, b"old", 0, 0).unwrap();
+        let tmp = fs.create_node(Node::MODE_FILE, "tmp", root, 0, 0).unwrap();
+        fs.write_node(tmp.0, 0, b"new", 0, 0).unwrap();
+        fs.sync().unwrap();
+
+        fs.disk.2 = writes;
+        let res = fs.rename_node("tmp", root, "target", root);
+        let image = fs.disk.0 .0.clone();
+        let held = fs.disk.1.clone();
+
+        for lost in 0..held.len() + 1 {
+            let mut disk = DiskReorder(DiskMemory(image.clone()), held.clone(), usize::MAX);
+            disk.crash(lost);
+
+            let mut fs = FileSystem::open(disk).unwrap();
+            let node = fs.find_node("target", root).unwrap();
+            let mut data = [0; 3];
+            fs.read_node(node.0, 0, &mut data).unwrap();
+            if node.0 == target.0 {
+                assert_eq!(&data, b"old");
+                assert_eq!(fs.find_node("tmp", root).map(|node| node.0), Ok(tmp.0));
+            } else {
+                assert_eq!(node.0, tmp.0);
+                assert_eq!(&data, b"new");
+                assert_eq!(fs.find_node("tmp", root).map(|node| node.0), Err(Error::NotFound));
+            }
+        }
+
+        if res.is_ok() {
+            break;
         }
     }
 }
+
+#[test]
+fn mounted_test() {
+    let mut fs = memory_fs(64);
+    assert!(! fs.header.1.mounted());
+
+    // A filesystem that was never unmounted is still marked when it is opened again
+    fs.set_mounted(true).unwrap();
+    let mut fs = FileSystem::open(fs.disk).unwrap();
+    assert!(fs.header.1.mounted());
+
+    fs.set_mounted(false).unwrap();
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert!(! fs.header.1.mounted());
+}
+
+#[test]
+fn tune_test() {
+    let mut fs = memory_fs(64);
+    let root = fs.header.1.root;
+    let dir = fs.create_node(Node::MODE_DIR | 0o755, "dir", root, 0, 0).unwrap();
+    fs.create_node(Node::MODE_FILE | 0o644, "README", dir.0, 0, 0).unwrap();
+    fs.create_node(Node::MODE_FILE | 0o644, "readme", dir.0, 0, 0).unwrap();
+
+    // Names that would collide keep lookups from ignoring case
+    assert_eq!(fs.set_flags(Header::FLAG_CASE_INSENSITIVE), Err(Error::Exists));
+    fs.remove_node(Node::MODE_FILE, "readme", dir.0).unwrap();
+    fs.set_flags(Header::FLAG_CASE_INSENSITIVE).unwrap();
+    assert!(fs.find_node("readme", dir.0).is_ok());
+
+    assert_eq!(fs.set_label(&"x".repeat(Header::LABEL_MAX + 1)), Err(Error::NameTooLong));
+    fs.set_label("boot").unwrap();
+    fs.set_mount_options("secure_delete,umask=022").unwrap();
+
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert!(fs.case_insensitive());
+    assert_eq!(fs.header.1.label(), Ok("boot"));
+    assert_eq!(fs.header.1.mount_options(), Ok("secure_delete,umask=022"));
+}
+
+#[test]
+fn quick_check_test() {
+    let mut fs = memory_fs(64);
+    fs.create_subvolume("sub", 0, 0).unwrap();
+    assert_eq!(fs.quick_check(false), Ok(0));
+
+    // A free extent over the root directory would hand it out again
+    let root = fs.header.1.root;
+    let mut free = fs.free_nodes[0];
+    free.1.extents_mut()[1] = Extent::new(root, BLOCK_SIZE);
+    free.1.extents_mut()[2] = Extent::new(60, 8 * BLOCK_SIZE);
+    fs.write_list_node(&free).unwrap();
+    let free_blocks = fs.free_blocks();
+    assert_eq!(fs.quick_check(false), Err(Error::Corrupt(free.0)));
+
+    assert_eq!(fs.quick_check(true), Ok(2));
+    assert_eq!(fs.free_blocks(), free_blocks - 9);
+    let mut fs = FileSystem::open(fs.disk).unwrap();
+    assert_eq!(fs.quick_check(false), Ok(0));
+}
+
+#[test]
+fn low_space_test() {
+    use std::sync::{Arc, Mutex};
+
+    let mut fs = memory_fs(64);
+    let notices = Arc::new(Mutex::new(Vec::new()));
+    let sink = notices.clone();
+    fs.set_notify(Box::new(move |notice| sink.lock().unwrap().push(notice)));
+    let free = fs.free_blocks();
+    fs.set_low_space(vec![free - 20, free - 10]);
+    assert_eq!(fs.low_space_level(), 0);
+    assert!(! fs.take_low_space_change());
+
+    let block = fs.allocate(15).unwrap();
+    assert_eq!(fs.low_space_level(), 1);
+    assert!(fs.take_low_space_change());
+    assert!(! fs.take_low_space_change());
+
+    let other = fs.allocate(10).unwrap();
+    assert_eq!(fs.low_space_level(), 2);
+
+    fs.deallocate(other, 10 * BLOCK_SIZE).unwrap();
+    fs.deallocate(block, 15 * BLOCK_SIZE).unwrap();
+    assert_eq!(fs.low_space_level(), 0);
+    assert!(fs.take_low_space_change());
+    assert_eq!(*notices.lock().unwrap(), vec![
+        Notice::LowSpace(free - 15, free - 10),
+        Notice::LowSpace(free - 25, free - 20),
+        Notice::SpaceRecovered(free - 15),
+        Notice::SpaceRecovered(free),
+    ]);
+}
+
+#[test]
+fn sync_test() {
+    let mut fs = FileSystem::create(DiskSyncs(DiskMemory::new(64), 0), 0, 0).unwrap();
+    let root = fs.header.1.root;
+
+    let file = fs.create_node(Node::MODE_FILE, "file", root, 0, 0).unwrap();
+    assert_eq!(fs.sync_data(file.0), Ok(()));
+    assert_eq!(fs.disk.1, 0);
+
+    fs.write_node(file.0, 0, &[1; 100], 0, 0).unwrap();
+    assert_eq!(fs.sync_data(file.0), Ok(()));
+    assert_eq!(fs.disk.1, 1);
+    assert_eq!(fs.sync_data(file.0), Ok(()));
+    assert_eq!(fs.disk.1, 1);
+
+    assert_eq!(fs.sync(), Ok(()));
+    assert_eq!(fs.disk.1, 2);
+}
@@ -1,9 +1,10 @@
-use std::{fmt, mem, slice};
+use std::{cmp, fmt, mem, slice, str};
 use std::ops::{Deref, DerefMut};
 
 use uuid::Uuid;
 
 use {BLOCK_SIZE, SIGNATURE, VERSION};
+use node::Node;
 
 /// The header of the filesystem
 #[repr(packed)]
@@ -20,11 +21,66 @@ pub struct Header {
     pub root: u64,
     /// Block of free space node
     pub free: u64,
+    /// Index of the disk holding this header, when spanning several disks
+    pub span_index: u64,
+    /// Number of disks the filesystem spans, 0 or 1 if it uses a single disk
+    pub span_count: u64,
+    /// Number of blocks contributed by each disk of the span
+    pub span_sizes: [u64; 16],
+    /// Allocation block size in bytes, a power of two multiple of BLOCK_SIZE, or 0 for BLOCK_SIZE
+    pub block_size: u64,
+    /// Block of orphan node, listing nodes that were unlinked while still open, or 0 if there is none
+    pub orphans: u64,
+    /// Feature flags, see `Header::FLAG_*`
+    pub flags: u64,
+    /// State flags, see `Header::STATE_*`
+    pub state: u64,
+    /// Number of free blocks that only root may allocate
+    pub root_reserved: u64,
+    /// Block of the directory whose children are the roots of named subvolumes, or 0 if there are none
+    pub subvolumes: u64,
+    /// Block of the root mounted when no subvolume is chosen, or 0 for `root`
+    pub default_subvolume: u64,
+    /// Block of a node that is being renamed, or 0 if no rename is in progress
+    pub rename: u64,
+    /// Directory the renamed node is moved from
+    pub rename_from: u64,
+    /// Directory the renamed node is moved to
+    pub rename_to: u64,
+    /// Block of the node that the renamed node replaces, or 0 if there is none
+    pub rename_replace: u64,
+    /// Length of the new name in bytes
+    pub rename_name_len: u16,
+    /// The new name of the renamed node
+    pub rename_name: [u8; Node::NAME_MAX],
+    /// Length of the label in bytes
+    pub label_len: u16,
+    /// Name of the filesystem chosen by its owner, which does not have to be unique
+    pub label: [u8; Header::LABEL_MAX],
+    /// Length of the default mount options in bytes
+    pub mount_options_len: u16,
+    /// Comma separated options that mounts apply before the ones they are given
+    pub mount_options: [u8; Header::MOUNT_OPTIONS_MAX],
     /// Padding
-    pub padding: [u8; BLOCK_SIZE as usize - 56]
+    pub padding: [u8; BLOCK_SIZE as usize - 294 - Header::LABEL_MAX - Header::MOUNT_OPTIONS_MAX - Node::NAME_MAX]
 }
 
 impl Header {
+    /// Names are looked up ignoring case, but stored as given
+    pub const FLAG_CASE_INSENSITIVE: u64 = 1;
+
+    /// The filesystem is mounted, or was not cleanly unmounted
+    pub const STATE_MOUNTED: u64 = 1;
+
+    /// Maximum number of disks a filesystem can span
+    pub const SPAN_MAX: usize = 16;
+
+    /// Maximum length of the label in bytes
+    pub const LABEL_MAX: usize = 64;
+
+    /// Maximum length of the default mount options in bytes
+    pub const MOUNT_OPTIONS_MAX: usize = 256;
+
     pub fn default() -> Header {
         Header {
             signature: [0; 8],
@@ -33,7 +89,27 @@ impl Header {
             size: 0,
             root: 0,
             free: 0,
-            padding: [0; BLOCK_SIZE as usize - 56]
+            span_index: 0,
+            span_count: 0,
+            span_sizes: [0; 16],
+            block_size: 0,
+            orphans: 0,
+            flags: 0,
+            state: 0,
+            root_reserved: 0,
+            subvolumes: 0,
+            default_subvolume: 0,
+            rename: 0,
+            rename_from: 0,
+            rename_to: 0,
+            rename_replace: 0,
+            rename_name_len: 0,
+            rename_name: [0; Node::NAME_MAX],
+            label_len: 0,
+            label: [0; Header::LABEL_MAX],
+            mount_options_len: 0,
+            mount_options: [0; Header::MOUNT_OPTIONS_MAX],
+            padding: [0; BLOCK_SIZE as usize - 294 - Header::LABEL_MAX - Header::MOUNT_OPTIONS_MAX - Node::NAME_MAX]
         }
     }
 
@@ -46,13 +122,70 @@ impl Header {
             size: size,
             root: root,
             free: free,
-            padding: [0; BLOCK_SIZE as usize - 56]
+            span_index: 0,
+            span_count: 0,
+            span_sizes: [0; 16],
+            block_size: BLOCK_SIZE,
+            orphans: 0,
+            flags: 0,
+            state: 0,
+            root_reserved: 0,
+            subvolumes: 0,
+            default_subvolume: 0,
+            rename: 0,
+            rename_from: 0,
+            rename_to: 0,
+            rename_replace: 0,
+            rename_name_len: 0,
+            rename_name: [0; Node::NAME_MAX],
+            label_len: 0,
+            label: [0; Header::LABEL_MAX],
+            mount_options_len: 0,
+            mount_options: [0; Header::MOUNT_OPTIONS_MAX],
+            padding: [0; BLOCK_SIZE as usize - 294 - Header::LABEL_MAX - Header::MOUNT_OPTIONS_MAX - Node::NAME_MAX]
         }
     }
 
     pub fn valid(&self) -> bool {
         &self.signature == SIGNATURE && self.version == VERSION
     }
+
+    /// True if the filesystem is mounted, or was not cleanly unmounted
+    pub fn mounted(&self) -> bool {
+        self.state & Header::STATE_MOUNTED == Header::STATE_MOUNTED
+    }
+
+    /// True if the filesystem spans several disks
+    pub fn spanned(&self) -> bool {
+        self.span_count > 1
+    }
+
+    /// The new name of the node being renamed
+    pub fn rename_name(&self) -> Result<&str, str::Utf8Error> {
+        let len = cmp::min(self.rename_name_len as usize, Node::NAME_MAX);
+        str::from_utf8(&self.rename_name[..len])
+    }
+
+    /// The label, which is empty if none was set
+    pub fn label(&self) -> Result<&str, str::Utf8Error> {
+        let len = cmp::min(self.label_len as usize, Header::LABEL_MAX);
+        str::from_utf8(&self.label[..len])
+    }
+
+    /// The default mount options, which are empty if none were set
+    pub fn mount_options(&self) -> Result<&str, str::Utf8Error> {
+        let len = cmp::min(self.mount_options_len as usize, Header::MOUNT_OPTIONS_MAX);
+        str::from_utf8(&self.mount_options[..len])
+    }
+
+    /// Allocation block size in bytes
+    pub fn block_size(&self) -> u64 {
+        if self.block_size == 0 {
+            BLOCK_SIZE
+        } else {
+            self.block_size
+        }
+    }
 }
 
 impl fmt::Debug for Header {
@@ -64,6 +197,16 @@ impl fmt::Debug for Header {
             .field("size", &self.size)
             .field("root", &self.root)
             .field("free", &self.free)
+            .field("span_index", &self.span_index)
+            .field("span_count", &self.span_count)
+            .field("block_size", &self.block_size)
+            .field("orphans", &self.orphans)
+            .field("flags", &self.flags)
+            .field("state", &self.state)
+            .field("root_reserved", &self.root_reserved)
+            .field("subvolumes", &self.subvolumes)
+            .field("default_subvolume", &self.default_subvolume)
+            .field("rename", &self.rename)
             .finish()
     }
 }
@@ -4,24 +4,63 @@
 #![deny(warnings)]
 
 extern crate syscall;
+#[cfg(feature = "nfc")]
+extern crate unicode_normalization;
 extern crate uuid;
 
 pub const BLOCK_SIZE: u64 = 4096;
 pub const SIGNATURE: &'static [u8; 8] = b"RedoxFS\0";
-pub const VERSION: u64 = 3;
+pub const VERSION: u64 = 4;
 
-pub use self::disk::{Disk, DiskCache, DiskFile};
+#[cfg(unix)]
+pub use self::archive::{Difference, archive_at, archive_at_progress, archive_tar, archive_tar_progress, compare_at, extract_at, extract_tar};
+#[cfg(feature = "async")]
+pub use self::async_fs::{AsyncFileSystem, FsFuture};
+pub use self::compact::{compact, compact_progress};
+pub use self::disk::{CachePool, Disk, DiskCache, DiskFaulty, DiskFile, DiskHttp, DiskMirror, DiskNbd, DiskPartition, DiskQcow2, DiskS3, DiskSpan, DiskVerity, GptEntry, verity_data_blocks};
+#[cfg(unix)]
+pub use self::disk::DiskMmap;
+#[cfg(feature = "async")]
+pub use self::disk::{AsyncDisk, DiskBlocking, DiskFuture, block_on};
+pub use self::error::{Error, Result};
 pub use self::ex_node::ExNode;
+pub use self::ex_stat::ExStat;
+#[cfg(unix)]
+pub use self::ext2::convert_ext2;
 pub use self::extent::Extent;
-pub use self::filesystem::FileSystem;
+#[cfg(unix)]
+pub use self::fat::{archive_fat, extract_fat};
+pub use self::filesystem::{ChildBlocks, ChildNodes, FileSystem, NamePolicy, Notice};
 pub use self::header::Header;
-pub use self::mount::mount;
+pub use self::mount::{mount, mount_all};
 pub use self::node::Node;
+#[cfg(unix)]
+pub use self::send::{receive, send};
+pub use self::xattr::XattrNamespace;
 
+#[cfg(unix)]
+mod archive;
+#[cfg(feature = "async")]
+mod async_fs;
+mod compact;
 mod disk;
+mod error;
 mod ex_node;
+mod ex_stat;
+#[cfg(unix)]
+mod ext2;
 mod extent;
+mod ffi;
+#[cfg(unix)]
+mod fat;
 mod filesystem;
 mod header;
 mod mount;
 mod node;
+mod progress;
+#[cfg(unix)]
+mod send;
+mod sha256;
+#[cfg(test)]
+mod testing;
+mod xattr;
@@ -1,110 +1,372 @@
 extern crate fuse;
+extern crate libc;
 extern crate time;
 
 use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::thread;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use BLOCK_SIZE;
 use disk::Disk;
+use error::{Error, Result as FsResult};
+use ex_stat::ExStat;
 use filesystem;
+use mount::permissions;
 use node::Node;
+use xattr::XattrNamespace;
 
-use self::fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyCreate, ReplyDirectory, ReplyEmpty, ReplyStatfs, ReplyWrite, Session};
+use self::fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyCreate, ReplyDirectory, ReplyEmpty, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Session};
+#[cfg(target_os = "macos")]
+use self::fuse::ReplyXTimes;
 use self::time::Timespec;
 
+/// How long the kernel may cache attributes and names
+///
+/// The fuse crate cannot send invalidation notifications, so this bounds how long the kernel
+/// shows stale data. Nothing else can change the filesystem while it is mounted, as the disk
+/// is locked and the mount owns the only `FileSystem`.
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };                 // 1 second
 
 const NULL_TIME: Timespec = Timespec { sec: 0, nsec: 0 };
 
 pub fn mount<D: Disk, P: AsRef<Path>, F: FnMut()>(filesystem: filesystem::FileSystem<D>, mountpoint: &P, mut callback: F, options: &[&OsStr]) -> io::Result<()> {
-    let mut session = Session::new(Fuse {
-        fs: filesystem
-    }, mountpoint.as_ref(), options)?;
+    let mut session = Session::new(Fuse::new(filesystem), mountpoint.as_ref(), options)?;
+    session.filesystem.mount()?;
 
     callback();
 
-    session.run()
+    session.run()?;
+    session.filesystem.unmount()
+}
+
+/// Mount several filesystems, serving each one from its own thread
+pub fn mount_all<D: Disk + Send + 'static, P: AsRef<Path>, F: FnMut(usize)>(filesystems: Vec<(filesystem::FileSystem<D>, P)>, mut callback: F, options: &[&OsStr]) -> io::Result<()> {
+    let mut sessions = Vec::new();
+    for (i, (filesystem, mountpoint)) in filesystems.into_iter().enumerate() {
+        let mut session = Session::new(Fuse::new(filesystem), mountpoint.as_ref(), options)?;
+        session.filesystem.mount()?;
+        sessions.push(session);
+
+        callback(i);
+    }
+
+    let threads: Vec<_> = sessions.into_iter().map(|mut session| {
+        thread::spawn(move || {
+            session.run()?;
+            session.filesystem.unmount()
+        })
+    }).collect();
+
+    let mut res = Ok(());
+    for thread in threads {
+        match thread.join() {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => res = Err(err),
+            Err(_) => res = Err(io::Error::new(io::ErrorKind::Other, "filesystem thread panicked"))
+        }
+    }
+    res
 }
 
 pub struct Fuse<D: Disk> {
     pub fs: filesystem::FileSystem<D>,
+    /// Number of open handles for each node
+    open: BTreeMap<u64, usize>,
+    /// Nodes that were unlinked while open, to be released when their last handle is closed
+    orphans: BTreeSet<u64>,
+    /// Buffer file data is read into for replies, kept so it is not allocated and zeroed for
+    /// every read
+    read_buf: Vec<u8>,
 }
 
-fn node_attr(node: &(u64, Node)) -> FileAttr {
+impl<D: Disk> Fuse<D> {
+    pub fn new(fs: filesystem::FileSystem<D>) -> Fuse<D> {
+        Fuse {
+            fs: fs,
+            open: BTreeMap::new(),
+            orphans: BTreeSet::new(),
+            read_buf: Vec::new(),
+        }
+    }
+
+    /// Mark the filesystem as mounted, unless the disk is read-only
+    fn mount(&mut self) -> io::Result<()> {
+        match self.fs.set_mounted(true) {
+            Ok(()) | Err(Error::ReadOnly) => Ok(()),
+            Err(err) => Err(err.into())
+        }
+    }
+
+    /// Mark the filesystem as cleanly unmounted, after the kernel is done with it
+    fn unmount(&mut self) -> io::Result<()> {
+        match self.fs.set_mounted(false) {
+            Ok(()) | Err(Error::ReadOnly) => Ok(()),
+            Err(err) => Err(err.into())
+        }
+    }
+
+    /// The inode of a block, see `swap_root`
+    fn ino(&self, block: u64) -> u64 {
+        swap_root(block, self.fs.root())
+    }
+
+    /// The block of an inode, which the same swap gives back
+    fn block(&self, ino: u64) -> u64 {
+        self.ino(ino)
+    }
+
+    /// The attributes of a node, with its inode
+    fn attr(&self, stat: &ExStat) -> FileAttr {
+        let mut attr = node_attr(stat);
+        attr.ino = self.ino(stat.ino);
+        attr
+    }
+
+    /// Check the permissions of the caller on the node at block, if they are checked at all, with
+    /// the errno to fail with if they do not allow the operation
+    fn check<F: FnOnce(&Node) -> bool>(&mut self, block: u64, check: F, denied: i32) -> Result<(), i32> {
+        if ! self.fs.check_permissions() {
+            return Ok(());
+        }
+
+        match self.fs.node(block) {
+            Ok(node) => if check(&self.fs.mapped_node(&node.1)) {
+                Ok(())
+            } else {
+                Err(denied)
+            },
+            Err(err) => Err(errno(err))
+        }
+    }
+
+    /// Check that the caller may remove the child called name from the directory at parent_block,
+    /// if permissions are checked, as a sticky directory only lets the owners do so. A missing
+    /// child is left for the operation to report, or to create in the case of a rename target
+    fn check_remove(&mut self, parent_block: u64, name: &OsStr, uid: u32) -> Result<(), i32> {
+        if ! self.fs.check_permissions() {
+            return Ok(());
+        }
+
+        let nodes = self.fs.node(parent_block).and_then(|parent| {
+            let child = name_str(name).and_then(|name| self.fs.find_node(name, parent_block))?;
+            Ok((parent, child))
+        });
+        match nodes {
+            Ok((parent, child)) => if permissions::remove(&self.fs.mapped_node(&parent.1), &self.fs.mapped_node(&child.1), uid) {
+                Ok(())
+            } else {
+                Err(libc::EPERM)
+            },
+            Err(Error::NotFound) => Ok(()),
+            Err(err) => Err(errno(err))
+        }
+    }
+
+    /// Check that the caller may get or set, as given by op, the extended attribute called name of
+    /// the node at block, if permissions are checked
+    fn check_xattr(&mut self, block: u64, name: &str, req: &Request, op: u16) -> Result<(), i32> {
+        let namespace = match XattrNamespace::of(name) {
+            Ok(namespace) => namespace,
+            // Attributes outside the known namespaces can never have been set
+            Err(_) if op == Node::MODE_READ => return Err(errno(Error::NoAttribute)),
+            Err(err) => return Err(errno(err))
+        };
+        let denied = if namespace == XattrNamespace::User { libc::EACCES } else { libc::EPERM };
+        self.check(block, |node| permissions::xattr(node, req.uid(), req.gid(), namespace, op), denied)
+    }
+
+    /// Give a new node in the directory at parent_block to the caller, if permissions are checked,
+    /// as no one else could change it
+    fn own(&mut self, parent_block: u64, node: &mut (u64, Node), req: &Request) -> FsResult<()> {
+        if self.fs.check_permissions() {
+            let parent = self.fs.node(parent_block)?;
+            permissions::create(&parent.1, &mut node.1, req.uid(), req.gid());
+            self.fs.write_at(node.0, &node.1)?;
+        }
+        Ok(())
+    }
+
+    /// Clear the setuid and setgid bits of the node at block before the caller changes its data,
+    /// if permissions are checked
+    fn clear_setid(&mut self, block: u64, req: &Request) -> FsResult<()> {
+        if self.fs.check_permissions() {
+            let mut node = self.fs.node(block)?;
+            if permissions::clear_setid(&mut node.1, req.uid()) {
+                self.fs.write_at(node.0, &node.1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a node, or orphan it if it is still open
+    fn remove_node(&mut self, mode: u16, name: &str, parent_block: u64) -> FsResult<()> {
+        let block = self.fs.find_node(name, parent_block)?.0;
+        if self.open.contains_key(&block) {
+            let block = self.fs.orphan_node(mode, name, parent_block)?;
+            self.orphans.insert(block);
+            Ok(())
+        } else {
+            self.fs.remove_node(mode, name, parent_block)
+        }
+    }
+}
+
+/// The errno of an error in the numbering of the host, which is not the Redox one on macOS
+fn errno(err: Error) -> i32 {
+    match err {
+        Error::NoHeader | Error::NotFound => libc::ENOENT,
+        Error::Version(_) | Error::Corrupt(_) | Error::Io(_) => libc::EIO,
+        Error::NoSpace => libc::ENOSPC,
+        Error::NotDir => libc::ENOTDIR,
+        Error::IsDir => libc::EISDIR,
+        Error::NotEmpty => libc::ENOTEMPTY,
+        Error::Exists => libc::EEXIST,
+        Error::ReadOnly => libc::EROFS,
+        Error::Incomplete => libc::ENXIO,
+        Error::BlockSize(_) => libc::EINVAL,
+        Error::InvalidName => libc::EILSEQ,
+        Error::NameTooLong => libc::ENAMETOOLONG,
+        Error::NotPermitted => libc::EPERM,
+        Error::InvalidMove => libc::EINVAL,
+        Error::TooLarge => libc::EFBIG,
+        #[cfg(target_os = "macos")]
+        Error::NoAttribute => libc::ENOATTR,
+        #[cfg(not(target_os = "macos"))]
+        Error::NoAttribute => libc::ENODATA,
+        Error::InvalidValue => libc::EINVAL,
+        Error::Unsupported => libc::ENOTSUP,
+    }
+}
+
+/// Swap the root that is served with block 1, as FUSE always uses inode 1 for the root, while
+/// a subvolume has its root elsewhere
+fn swap_root(block: u64, root: u64) -> u64 {
+    if block == root {
+        1
+    } else if block == 1 {
+        root
+    } else {
+        block
+    }
+}
+
+/// Answer an extended attribute call with data, or only its length if size is 0
+fn xattr_reply(data: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// Names that are not valid UTF-8 cannot be stored
+fn name_str(name: &OsStr) -> FsResult<&str> {
+    name.to_str().ok_or(Error::InvalidName)
+}
+
+fn node_attr(stat: &ExStat) -> FileAttr {
     FileAttr {
-        ino: node.0,
-        size: node.1.extents[0].length,
-        blocks: (node.1.extents[0].length + BLOCK_SIZE - 1)/BLOCK_SIZE,
+        ino: stat.ino,
+        size: stat.size,
+        blocks: stat.blocks,
         atime: NULL_TIME,
         mtime: Timespec {
-            sec: node.1.mtime as i64,
-            nsec: node.1.mtime_nsec as i32,
+            sec: stat.mtime as i64,
+            nsec: stat.mtime_nsec as i32,
         },
         ctime: Timespec {
-            sec: node.1.ctime as i64,
-            nsec: node.1.ctime_nsec as i32,
+            sec: stat.ctime as i64,
+            nsec: stat.ctime_nsec as i32,
         },
-        crtime: NULL_TIME,
-        kind: if node.1.is_dir() {
-            FileType::Directory
-        } else if node.1.is_symlink() {
-            FileType::Symlink
-        } else {
-            FileType::RegularFile
+        crtime: Timespec {
+            sec: stat.crtime as i64,
+            nsec: stat.crtime_nsec as i32,
+        },
+        kind: match stat.mode & Node::MODE_TYPE {
+            Node::MODE_DIR => FileType::Directory,
+            Node::MODE_SYMLINK => FileType::Symlink,
+            _ => FileType::RegularFile
         },
-        perm: node.1.mode & Node::MODE_PERM,
+        perm: stat.mode & Node::MODE_PERM,
         nlink: 1,
-        uid: node.1.uid,
-        gid: node.1.gid,
+        uid: stat.uid,
+        gid: stat.gid,
         rdev: 0,
         flags: 0,
     }
 }
 
 impl<D: Disk> Filesystem for Fuse<D> {
-    fn lookup(&mut self, _req: &Request, parent_block: u64, name: &OsStr, reply: ReplyEntry) {
-        match self.fs.find_node(name.to_str().unwrap(), parent_block) {
-            Ok(node) => {
-                reply.entry(&TTL, &node_attr(&node), 0);
+    fn lookup(&mut self, req: &Request, parent_block: u64, name: &OsStr, reply: ReplyEntry) {
+        let parent_block = self.block(parent_block);
+        if let Err(err) = self.check(parent_block, |node| permissions::access(node, req.uid(), req.gid(), Node::MODE_EXEC), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
+        match name_str(name).and_then(|name| self.fs.find_node(name, parent_block)).and_then(|node| self.fs.node_stat(node.0)) {
+            Ok(stat) => {
+                reply.entry(&TTL, &self.attr(&stat), 0);
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(errno(err));
             }
         }
     }
 
     fn getattr(&mut self, _req: &Request, block: u64, reply: ReplyAttr) {
-        match self.fs.node(block) {
-            Ok(node) => {
-                reply.attr(&TTL, &node_attr(&node));
+        let block = self.block(block);
+        match self.fs.node_stat(block) {
+            Ok(stat) => {
+                reply.attr(&TTL, &self.attr(&stat));
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(errno(err));
             }
         }
     }
 
-    fn setattr(&mut self, _req: &Request, block: u64, mode: Option<u32>,
+    fn setattr(&mut self, req: &Request, block: u64, mode: Option<u32>,
                 uid: Option<u32>, gid: Option<u32>, size: Option<u64>,
                 _atime: Option<Timespec>, mtime: Option<Timespec>, _fh: Option<u64>,
                 _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>,
                 _flags: Option<u32>, reply: ReplyAttr) {
+        let block = self.block(block);
+        self.fs.set_privileged(req.uid() == 0);
+        let (req_uid, req_gid) = (req.uid(), req.gid());
+        let mut allowed = Ok(());
+        if mode.is_some() || mtime.is_some() {
+            allowed = allowed.and(self.check(block, |node| permissions::owner(node, req_uid), libc::EPERM));
+        }
+        if uid.is_some() || gid.is_some() {
+            allowed = allowed.and(self.check(block, |node| permissions::chown(node, req_uid, req_gid, uid, gid), libc::EPERM));
+        }
+        if size.is_some() {
+            allowed = allowed.and(self.check(block, |node| permissions::access(node, req_uid, req_gid, Node::MODE_WRITE), libc::EACCES));
+        }
+        if let Err(err) = allowed {
+            reply.error(err);
+            return;
+        }
+
         if let Some(mode) = mode {
             match self.fs.node(block) {
                 Ok(mut node) => if node.1.mode & Node::MODE_PERM != mode as u16 & Node::MODE_PERM {
                     // println!("Chmod {:?}:{:o}:{:o}", node.1.name(), node.1.mode, mode);
                     node.1.mode = (node.1.mode & Node::MODE_TYPE) | (mode as u16 & Node::MODE_PERM);
                     if let Err(err) = self.fs.write_at(node.0, &node.1) {
-                        reply.error(err.errno as i32);
+                        reply.error(errno(err));
                         return;
                     }
                 },
                 Err(err) => {
-                    reply.error(err.errno as i32);
+                    reply.error(errno(err));
                     return;
                 }
             }
@@ -115,12 +377,12 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 Ok(mut node) => if node.1.uid != uid {
                     node.1.uid = uid;
                     if let Err(err) = self.fs.write_at(node.0, &node.1) {
-                        reply.error(err.errno as i32);
+                        reply.error(errno(err));
                         return;
                     }
                 },
                 Err(err) => {
-                    reply.error(err.errno as i32);
+                    reply.error(errno(err));
                     return;
                 }
             }
@@ -131,20 +393,20 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 Ok(mut node) => if node.1.gid != gid {
                     node.1.gid = gid;
                     if let Err(err) = self.fs.write_at(node.0, &node.1) {
-                        reply.error(err.errno as i32);
+                        reply.error(errno(err));
                         return;
                     }
                 },
                 Err(err) => {
-                    reply.error(err.errno as i32);
+                    reply.error(errno(err));
                     return;
                 }
             }
         }
 
         if let Some(size) = size {
-            if let Err(err) = self.fs.node_set_len(block, size) {
-                reply.error(err.errno as i32);
+            if let Err(err) = self.clear_setid(block, req).and_then(|()| self.fs.node_set_len(block, size)) {
+                reply.error(errno(err));
                 return;
             }
         }
@@ -155,47 +417,100 @@ impl<D: Disk> Filesystem for Fuse<D> {
                     node.1.mtime = mtime.sec as u64;
                     node.1.mtime_nsec = mtime.nsec as u32;
                     if let Err(err) = self.fs.write_at(node.0, &node.1) {
-                        reply.error(err.errno as i32);
+                        reply.error(errno(err));
                         return;
                     }
                 },
                 Err(err) => {
-                    reply.error(err.errno as i32);
+                    reply.error(errno(err));
                     return;
                 }
             }
         }
 
-        match self.fs.node(block) {
-            Ok(node) => {
-                reply.attr(&TTL, &node_attr(&node));
+        match self.fs.node_stat(block) {
+            Ok(stat) => {
+                reply.attr(&TTL, &self.attr(&stat));
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(errno(err));
+            }
+        }
+    }
+
+    fn open(&mut self, req: &Request, block: u64, flags: u32, reply: ReplyOpen) {
+        let block = self.block(block);
+        let flags_i = flags as i32;
+        let op = match flags_i & libc::O_ACCMODE {
+            libc::O_RDONLY => Node::MODE_READ,
+            libc::O_WRONLY => Node::MODE_WRITE,
+            _ => Node::MODE_READ | Node::MODE_WRITE
+        };
+        if let Err(err) = self.check(block, |node| permissions::access(node, req.uid(), req.gid(), op), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
+        if flags_i & libc::O_ACCMODE != libc::O_RDONLY {
+            if let Err(err) = self.fs.node_check_write(block, flags_i & libc::O_APPEND == libc::O_APPEND) {
+                reply.error(errno(err));
+                return;
             }
         }
+
+        *self.open.entry(block).or_insert(0) += 1;
+        // The reply takes FOPEN_* flags, not the O_* flags the file was opened with
+        reply.opened(0, 0);
+    }
+
+    fn release(&mut self, _req: &Request, block: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        let block = self.block(block);
+        let closed = match self.open.get_mut(&block) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false
+        };
+
+        if closed {
+            self.open.remove(&block);
+            if self.orphans.remove(&block) {
+                if let Err(err) = self.fs.release_node(block) {
+                    reply.error(errno(err));
+                    return;
+                }
+            }
+        }
+
+        reply.ok();
     }
 
     fn read(&mut self, _req: &Request, block: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
-        let mut data = vec![0; size as usize];
-        match self.fs.read_node(block, cmp::max(0, offset) as u64, &mut data) {
+        let block = self.block(block);
+        let size = size as usize;
+        if self.read_buf.len() < size {
+            self.read_buf.resize(size, 0);
+        }
+        match self.fs.read_node(block, cmp::max(0, offset) as u64, &mut self.read_buf[..size]) {
             Ok(count) => {
-                reply.data(&data[..count]);
+                reply.data(&self.read_buf[..count]);
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(errno(err));
             }
         }
     }
 
-    fn write(&mut self, _req: &Request, block: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+    fn write(&mut self, req: &Request, block: u64, _fh: u64, offset: i64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        let block = self.block(block);
+        self.fs.set_privileged(req.uid() == 0);
         let mtime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        match self.fs.write_node(block, cmp::max(0, offset) as u64, &data, mtime.as_secs(), mtime.subsec_nanos()) {
+        match self.clear_setid(block, req).and_then(|()| self.fs.write_node(block, cmp::max(0, offset) as u64, &data, mtime.as_secs(), mtime.subsec_nanos())) {
             Ok(count) => {
                 reply.written(count as u32);
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(errno(err));
             }
         }
     }
@@ -204,140 +519,374 @@ impl<D: Disk> Filesystem for Fuse<D> {
         reply.ok();
     }
 
-    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        reply.ok();
+    fn fsync(&mut self, _req: &Request, block: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let block = self.block(block);
+        let result = if datasync {
+            self.fs.sync_data(block)
+        } else {
+            self.fs.sync()
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err))
+        }
     }
 
-    fn readdir(&mut self, _req: &Request, parent_block: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        let mut children = Vec::new();
-        match self.fs.child_nodes(&mut children, parent_block) {
-            Ok(()) => {
-                let mut i;
-                let skip;
-                if offset == 0 {
-                    skip = 0;
-                    i = 0;
-                    reply.add(parent_block - self.fs.header.0, i, FileType::Directory, ".");
-                    i += 1;
-                    reply.add(parent_block - self.fs.header.0, i, FileType::Directory, "..");
-                    i += 1;
-                } else {
-                    i = offset + 1;
-                    skip = offset as usize - 1;
-                }
+    fn fsyncdir(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.fs.sync_all() {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err))
+        }
+    }
 
-                for child in children.iter().skip(skip) {
-                    let full = reply.add(child.0 - self.fs.header.0, i, if child.1.is_dir() {
-                        FileType::Directory
-                    } else {
-                        FileType::RegularFile
-                    }, child.1.name().unwrap());
+    fn readdir(&mut self, req: &Request, parent_block: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let parent_block = self.block(parent_block);
+        if let Err(err) = self.check(parent_block, |node| permissions::access(node, req.uid(), req.gid(), Node::MODE_READ), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
+        let root = self.fs.root();
+        let header_block = self.fs.header.0;
+        let mut i;
+        let skip;
+        if offset == 0 {
+            skip = 0;
+            i = 0;
+            reply.add(swap_root(parent_block, root) - header_block, i, FileType::Directory, ".");
+            i += 1;
+            reply.add(swap_root(parent_block, root) - header_block, i, FileType::Directory, "..");
+            i += 1;
+        } else {
+            i = offset + 1;
+            skip = offset as usize - 1;
+        }
 
-                    if full {
-                        break;
-                    }
+        // Entries before the offset were already returned, so their nodes are not read again
+        let mut blocks = self.fs.child_blocks(parent_block);
+        for _ in 0..skip {
+            match blocks.next() {
+                Some(Ok(_)) => (),
+                Some(Err(err)) => {
+                    reply.error(errno(err));
+                    return;
+                },
+                None => break
+            }
+        }
 
-                    i += 1;
+        for child in blocks.nodes() {
+            let child = match child {
+                Ok(child) => child,
+                Err(err) => {
+                    reply.error(errno(err));
+                    return;
                 }
-                reply.ok();
-            },
-            Err(err) => {
-                reply.error(err.errno as i32);
+            };
+
+            let full = reply.add(swap_root(child.0, root) - header_block, i, if child.1.is_dir() {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            }, child.1.name().unwrap());
+
+            if full {
+                break;
             }
+
+            i += 1;
         }
+        reply.ok();
     }
 
-    fn create(&mut self, _req: &Request, parent_block: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+    fn create(&mut self, req: &Request, parent_block: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+        let parent_block = self.block(parent_block);
+        self.fs.set_privileged(req.uid() == 0);
+        if let Err(err) = self.check(parent_block, |node| permissions::modify_dir(node, req.uid(), req.gid()), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
         let ctime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        match self.fs.create_node(Node::MODE_FILE | (mode as u16 & Node::MODE_PERM), name.to_str().unwrap(), parent_block, ctime.as_secs(), ctime.subsec_nanos()) {
-            Ok(node) => {
-                // println!("Create {:?}:{:o}:{:o}", node.1.name(), node.1.mode, mode);
-                reply.created(&TTL, &node_attr(&node), 0, 0, flags);
+        let mut result = name_str(name).and_then(|name| self.fs.create_node(Node::MODE_FILE | (mode as u16 & Node::MODE_PERM), name, parent_block, ctime.as_secs(), ctime.subsec_nanos()))
+            .and_then(|mut node| self.own(parent_block, &mut node, req).map(|()| node));
+        let exists = match result {
+            Err(Error::Exists) => true,
+            _ => false
+        };
+        if exists && flags as i32 & libc::O_EXCL != libc::O_EXCL {
+            // Without O_EXCL, a name created since the kernel's lookup is opened instead
+            result = name_str(name).and_then(|name| self.fs.find_node(name, parent_block)).and_then(|node| if node.1.is_dir() {
+                Err(Error::IsDir)
+            } else {
+                Ok(node)
+            });
+        }
+        match result.and_then(|node| self.fs.node_stat(node.0)) {
+            Ok(stat) => {
+                // println!("Create {:?}:{:o}:{:o}", stat.ino, stat.mode, mode);
+                *self.open.entry(stat.ino).or_insert(0) += 1;
+                // Like for open, the reply takes FOPEN_* flags rather than the O_* ones
+                reply.created(&TTL, &self.attr(&stat), 0, 0, 0);
             },
             Err(error) => {
-                reply.error(error.errno as i32);
+                reply.error(errno(error));
             }
         }
     }
 
-    fn mkdir(&mut self, _req: &Request, parent_block: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+    fn mkdir(&mut self, req: &Request, parent_block: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let parent_block = self.block(parent_block);
+        self.fs.set_privileged(req.uid() == 0);
+        if let Err(err) = self.check(parent_block, |node| permissions::modify_dir(node, req.uid(), req.gid()), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
         let ctime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        match self.fs.create_node(Node::MODE_DIR | (mode as u16 & Node::MODE_PERM), name.to_str().unwrap(), parent_block, ctime.as_secs(), ctime.subsec_nanos()) {
-            Ok(node) => {
-                // println!("Mkdir {:?}:{:o}:{:o}", node.1.name(), node.1.mode, mode);
-                reply.entry(&TTL, &node_attr(&node), 0);
+        match name_str(name).and_then(|name| self.fs.create_node(Node::MODE_DIR | (mode as u16 & Node::MODE_PERM), name, parent_block, ctime.as_secs(), ctime.subsec_nanos()))
+            .and_then(|mut node| self.own(parent_block, &mut node, req).map(|()| node)).and_then(|node| self.fs.node_stat(node.0)) {
+            Ok(stat) => {
+                // println!("Mkdir {:?}:{:o}:{:o}", stat.ino, stat.mode, mode);
+                reply.entry(&TTL, &self.attr(&stat), 0);
             },
             Err(error) => {
-                reply.error(error.errno as i32);
+                reply.error(errno(error));
             }
         }
     }
 
-    fn rmdir(&mut self, _req: &Request, parent_block: u64, name: &OsStr, reply: ReplyEmpty) {
-        match self.fs.remove_node(Node::MODE_DIR, name.to_str().unwrap(), parent_block) {
+    fn rmdir(&mut self, req: &Request, parent_block: u64, name: &OsStr, reply: ReplyEmpty) {
+        let parent_block = self.block(parent_block);
+        if let Err(err) = self.check(parent_block, |node| permissions::modify_dir(node, req.uid(), req.gid()), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
+        if let Err(err) = self.check_remove(parent_block, name, req.uid()) {
+            reply.error(err);
+            return;
+        }
+        match name_str(name).and_then(|name| self.remove_node(Node::MODE_DIR, name, parent_block)) {
             Ok(()) => {
                 reply.ok();
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(errno(err));
             }
         }
     }
 
-    fn unlink(&mut self, _req: &Request, parent_block: u64, name: &OsStr, reply: ReplyEmpty) {
-        match self.fs.remove_node(Node::MODE_FILE, name.to_str().unwrap(), parent_block) {
+    fn unlink(&mut self, req: &Request, parent_block: u64, name: &OsStr, reply: ReplyEmpty) {
+        let parent_block = self.block(parent_block);
+        if let Err(err) = self.check(parent_block, |node| permissions::modify_dir(node, req.uid(), req.gid()), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
+        if let Err(err) = self.check_remove(parent_block, name, req.uid()) {
+            reply.error(err);
+            return;
+        }
+        match name_str(name).and_then(|name| self.remove_node(Node::MODE_FILE, name, parent_block)) {
             Ok(()) => {
                 reply.ok();
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(errno(err));
             }
         }
     }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        let free = self.fs.header.1.free;
-        match self.fs.node_len(free) {
-            Ok(free_size) => {
-                let bsize = BLOCK_SIZE;
-                let blocks = self.fs.header.1.size/bsize;
-                let bfree = free_size/bsize;
-                reply.statfs(blocks, bfree, bfree, 0, 0, bsize as u32, 256, 0);
+    fn rename(&mut self, req: &Request, parent_block: u64, name: &OsStr, new_parent_block: u64, new_name: &OsStr, reply: ReplyEmpty) {
+        let parent_block = self.block(parent_block);
+        let new_parent_block = self.block(new_parent_block);
+        self.fs.set_privileged(req.uid() == 0);
+        if let Err(err) = self.check(parent_block, |node| permissions::modify_dir(node, req.uid(), req.gid()), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
+        if let Err(err) = self.check_remove(parent_block, name, req.uid()) {
+            reply.error(err);
+            return;
+        }
+        if let Err(err) = self.check(new_parent_block, |node| permissions::modify_dir(node, req.uid(), req.gid()), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
+        if let Err(err) = self.check_remove(new_parent_block, new_name, req.uid()) {
+            reply.error(err);
+            return;
+        }
+        let res = name_str(name).and_then(|name| {
+            let new_name = name_str(new_name)?;
+            match self.fs.rename_node(name, parent_block, new_name, new_parent_block)? {
+                // The replaced node stays in the orphan list while it is open
+                Some(block) => if self.open.contains_key(&block) {
+                    self.orphans.insert(block);
+                    Ok(())
+                } else {
+                    self.fs.release_node(block)
+                },
+                None => Ok(())
+            }
+        });
+        match res {
+            Ok(()) => {
+                reply.ok();
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(errno(err));
             }
         }
     }
 
-    fn symlink(&mut self, _req: &Request, parent_block: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let bsize = self.fs.header.1.block_size();
+        let blocks = self.fs.header.1.size/bsize;
+        let bfree = self.fs.free_blocks() * BLOCK_SIZE/bsize;
+        let bavail = self.fs.available_blocks() * BLOCK_SIZE/bsize;
+        reply.statfs(blocks, bfree, bavail, 0, 0, bsize as u32, 256, 0);
+    }
+
+    fn symlink(&mut self, req: &Request, parent_block: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let parent_block = self.block(parent_block);
+        self.fs.set_privileged(req.uid() == 0);
+        if let Err(err) = self.check(parent_block, |node| permissions::modify_dir(node, req.uid(), req.gid()), libc::EACCES) {
+            reply.error(err);
+            return;
+        }
         let ctime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        match self.fs.create_node(Node::MODE_SYMLINK | 0o777, name.to_str().unwrap(), parent_block, ctime.as_secs(), ctime.subsec_nanos()) {
+        match name_str(name).and_then(|name| self.fs.create_node(Node::MODE_SYMLINK | 0o777, name, parent_block, ctime.as_secs(), ctime.subsec_nanos()))
+            .and_then(|mut node| self.own(parent_block, &mut node, req).map(|()| node)) {
             Ok(node) => {
                 let mtime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                match self.fs.write_node(node.0, 0, link.as_os_str().as_bytes(), mtime.as_secs(), mtime.subsec_nanos()) {
-                    Ok(_count) => {
-                        reply.entry(&TTL, &node_attr(&node), 0);
+                match self.fs.write_node(node.0, 0, link.as_os_str().as_bytes(), mtime.as_secs(), mtime.subsec_nanos()).and_then(|_count| self.fs.node_stat(node.0)) {
+                    Ok(stat) => {
+                        reply.entry(&TTL, &self.attr(&stat), 0);
                     },
                     Err(err) => {
-                        reply.error(err.errno as i32);
+                        reply.error(errno(err));
                     }
                 }
             },
             Err(error) => {
-                reply.error(error.errno as i32);
+                reply.error(errno(error));
             }
         }
     }
 
-    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+    fn readlink(&mut self, _req: &Request, block: u64, reply: ReplyData) {
+        let block = self.block(block);
         let mut data = vec![0; 4096];
-        match self.fs.read_node(ino, 0, &mut data) {
+        match self.fs.read_node(block, 0, &mut data) {
             Ok(count) => {
                 reply.data(&data[..count]);
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(errno(err));
+            }
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request, block: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let block = self.block(block);
+        let name = match name_str(name) {
+            Ok(name) => name,
+            Err(err) => {
+                reply.error(errno(err));
+                return;
+            }
+        };
+        if let Err(err) = self.check_xattr(block, name, req, Node::MODE_READ) {
+            reply.error(err);
+            return;
+        }
+        match self.fs.get_xattr(block, name) {
+            Ok(value) => xattr_reply(&value, size, reply),
+            Err(err) => {
+                reply.error(errno(err));
+            }
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request, block: u64, size: u32, reply: ReplyXattr) {
+        let block = self.block(block);
+        let xattrs = match self.fs.node_xattrs(block) {
+            Ok(xattrs) => xattrs,
+            Err(err) => {
+                reply.error(errno(err));
+                return;
+            }
+        };
+        // Names the caller could not get are left out, like trusted ones for everyone but root
+        let mut names = Vec::new();
+        for (name, _value) in xattrs {
+            if self.check_xattr(block, &name, req, Node::MODE_READ).is_ok() {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+        xattr_reply(&names, size, reply);
+    }
+
+    fn setxattr(&mut self, req: &Request, block: u64, name: &OsStr, value: &[u8], flags: u32, _position: u32, reply: ReplyEmpty) {
+        let block = self.block(block);
+        self.fs.set_privileged(req.uid() == 0);
+        let name = match name_str(name) {
+            Ok(name) => name,
+            Err(err) => {
+                reply.error(errno(err));
+                return;
+            }
+        };
+        if let Err(err) = self.check_xattr(block, name, req, Node::MODE_WRITE) {
+            reply.error(err);
+            return;
+        }
+        let create = flags as i32 & libc::XATTR_CREATE == libc::XATTR_CREATE;
+        let replace = flags as i32 & libc::XATTR_REPLACE == libc::XATTR_REPLACE;
+        match self.fs.set_xattr(block, name, value, create, replace) {
+            Ok(()) => {
+                reply.ok();
+            },
+            Err(err) => {
+                reply.error(errno(err));
+            }
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request, block: u64, name: &OsStr, reply: ReplyEmpty) {
+        let block = self.block(block);
+        let name = match name_str(name) {
+            Ok(name) => name,
+            Err(err) => {
+                reply.error(errno(err));
+                return;
+            }
+        };
+        if let Err(err) = self.check_xattr(block, name, req, Node::MODE_WRITE) {
+            reply.error(err);
+            return;
+        }
+        match self.fs.remove_xattr(block, name) {
+            Ok(()) => {
+                reply.ok();
+            },
+            Err(err) => {
+                reply.error(errno(err));
+            }
+        }
+    }
+
+    /// Creation time for the Finder, which has no backup time to go with it
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, _req: &Request, block: u64, reply: ReplyXTimes) {
+        let block = self.block(block);
+        match self.fs.node_stat(block) {
+            Ok(stat) => {
+                reply.xtimes(NULL_TIME, Timespec {
+                    sec: stat.crtime as i64,
+                    nsec: stat.crtime_nsec as i32,
+                });
+            },
+            Err(err) => {
+                reply.error(errno(err));
             }
         }
     }
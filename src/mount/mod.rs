@@ -7,11 +7,14 @@ use filesystem::FileSystem;
 #[cfg(unix)]
 mod fuse;
 
+#[cfg(any(unix, target_os = "redox"))]
+mod permissions;
+
 #[cfg(target_os = "redox")]
 mod redox;
 
 #[cfg(all(unix, target_os = "macos"))]
-pub fn mount<D: Disk, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, callback: F) -> io::Result<()> {
+pub fn mount<D: Disk + Send + 'static, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, callback: F) -> io::Result<()> {
     use std::ffi::OsStr;
 
     fuse::mount(filesystem, mountpoint, callback, &[
@@ -21,15 +24,44 @@ pub fn mount<D: Disk, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mou
         // be `root`, thus that we need to allow `root` to have access.
         OsStr::new("-o"),
         OsStr::new("defer_permissions"),
+        // Without extended attributes, Finder would otherwise store its metadata in
+        // "._" AppleDouble files next to every file it touches
+        OsStr::new("-o"),
+        OsStr::new("noappledouble"),
     ])
 }
 
 #[cfg(all(unix, not(target_os = "macos")))]
-pub fn mount<D: Disk, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, callback: F) -> io::Result<()> {
+pub fn mount<D: Disk + Send + 'static, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, callback: F) -> io::Result<()> {
     fuse::mount(filesystem, mountpoint, callback, &[])
 }
 
 #[cfg(target_os = "redox")]
-pub fn mount<D: Disk, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, callback: F) -> io::Result<()> {
+pub fn mount<D: Disk + Send + 'static, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, callback: F) -> io::Result<()> {
     redox::mount(filesystem, mountpoint, callback)
 }
+
+/// Mount several filesystems at once, calling callback with the index of each one as it is mounted
+#[cfg(all(unix, target_os = "macos"))]
+pub fn mount_all<D: Disk + Send + 'static, P: AsRef<Path>, F: FnMut(usize)>(filesystems: Vec<(FileSystem<D>, P)>, callback: F) -> io::Result<()> {
+    use std::ffi::OsStr;
+
+    fuse::mount_all(filesystems, callback, &[
+        OsStr::new("-o"),
+        OsStr::new("defer_permissions"),
+        OsStr::new("-o"),
+        OsStr::new("noappledouble"),
+    ])
+}
+
+/// Mount several filesystems at once, calling callback with the index of each one as it is mounted
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn mount_all<D: Disk + Send + 'static, P: AsRef<Path>, F: FnMut(usize)>(filesystems: Vec<(FileSystem<D>, P)>, callback: F) -> io::Result<()> {
+    fuse::mount_all(filesystems, callback, &[])
+}
+
+/// Mount several filesystems at once, calling callback with the index of each one as it is mounted
+#[cfg(target_os = "redox")]
+pub fn mount_all<D: Disk + Send + 'static, P: AsRef<Path>, F: FnMut(usize)>(filesystems: Vec<(FileSystem<D>, P)>, callback: F) -> io::Result<()> {
+    redox::mount_all(filesystems, callback)
+}
@@ -0,0 +1,154 @@
+//! Checks of what the caller of an operation may do, shared by the mount frontends
+//!
+//! Root may do everything. The `access` checks fail with `EACCES`, the ownership checks with `EPERM`.
+
+use node::Node;
+use xattr::XattrNamespace;
+
+/// The caller may read, write, or search the node, as given by the `Node::MODE_*` bits of op
+pub fn access(node: &Node, uid: u32, gid: u32, op: u16) -> bool {
+    node.permission(uid, gid, op)
+}
+
+/// The caller may create, remove, or rename children of the directory
+pub fn modify_dir(dir: &Node, uid: u32, gid: u32) -> bool {
+    access(dir, uid, gid, Node::MODE_WRITE | Node::MODE_EXEC)
+}
+
+/// The caller may remove or rename the child out of the directory, which it can also modify
+///
+/// In a sticky directory, only the owners of the directory or the child may do so.
+pub fn remove(dir: &Node, child: &Node, uid: u32) -> bool {
+    dir.mode & Node::MODE_STICKY == 0 || owner(dir, uid) || owner(child, uid)
+}
+
+/// Give a node created by the caller in parent to them
+///
+/// Children of a setgid directory get its group instead, and new directories in it are setgid too.
+pub fn create(parent: &Node, node: &mut Node, uid: u32, gid: u32) {
+    node.uid = uid;
+    if parent.mode & Node::MODE_SETGID == Node::MODE_SETGID {
+        node.gid = parent.gid;
+        if node.is_dir() {
+            node.mode |= Node::MODE_SETGID;
+        }
+    } else {
+        node.gid = gid;
+    }
+}
+
+/// Clear the setuid and setgid bits of a node the caller is about to change, unless they own it,
+/// returning whether the node has to be written back
+pub fn clear_setid(node: &mut Node, uid: u32) -> bool {
+    if node.mode & (Node::MODE_SETUID | Node::MODE_SETGID) != 0 && ! owner(node, uid) {
+        node.mode &= ! (Node::MODE_SETUID | Node::MODE_SETGID);
+        true
+    } else {
+        false
+    }
+}
+
+/// The caller owns the node, so it may change its mode and times
+pub fn owner(node: &Node, uid: u32) -> bool {
+    uid == 0 || node.uid == uid
+}
+
+/// The caller may change the owner and group of the node to new_uid and new_gid, if they are given
+///
+/// Only root can give a node to another user, while the owner can change its group to their own.
+pub fn chown(node: &Node, uid: u32, gid: u32, new_uid: Option<u32>, new_gid: Option<u32>) -> bool {
+    if uid == 0 {
+        return true;
+    }
+
+    node.uid == uid
+        && new_uid.map_or(true, |new_uid| new_uid == node.uid)
+        && new_gid.map_or(true, |new_gid| new_gid == node.gid || new_gid == gid)
+}
+
+/// The caller may get or set extended attributes in the namespace on the node, as given by
+/// `Node::MODE_READ` or `Node::MODE_WRITE` in op
+///
+/// User attributes follow the permissions of the data of files and directories, and other nodes
+/// have none. Only root may set the others, or read trusted ones.
+pub fn xattr(node: &Node, uid: u32, gid: u32, namespace: XattrNamespace, op: u16) -> bool {
+    match namespace {
+        XattrNamespace::User => (node.is_file() || node.is_dir()) && access(node, uid, gid, op),
+        XattrNamespace::Security | XattrNamespace::System => op == Node::MODE_READ || uid == 0,
+        XattrNamespace::Trusted => uid == 0,
+    }
+}
+
+#[test]
+fn permissions_test() {
+    let mut node = Node::new(Node::MODE_FILE | 0o640, "file", 0, 0, 0);
+    node.uid = 1000;
+    node.gid = 100;
+
+    assert!(access(&node, 1000, 1000, Node::MODE_READ | Node::MODE_WRITE));
+    assert!(access(&node, 1001, 100, Node::MODE_READ));
+    assert!(! access(&node, 1001, 100, Node::MODE_WRITE));
+    assert!(! access(&node, 1001, 1001, Node::MODE_READ));
+    assert!(access(&node, 0, 0, Node::MODE_WRITE));
+
+    let mut dir = Node::new(Node::MODE_DIR | 0o755, "dir", 0, 0, 0);
+    dir.uid = 1000;
+    assert!(modify_dir(&dir, 1000, 1000));
+    assert!(! modify_dir(&dir, 1001, 1001));
+
+    assert!(owner(&node, 1000));
+    assert!(owner(&node, 0));
+    assert!(! owner(&node, 1001));
+
+    assert!(chown(&node, 1000, 1000, None, Some(1000)));
+    assert!(chown(&node, 1000, 1000, Some(1000), Some(100)));
+    assert!(! chown(&node, 1000, 1000, Some(1001), None));
+    assert!(! chown(&node, 1000, 1000, None, Some(0)));
+    assert!(! chown(&node, 1001, 100, None, Some(100)));
+    assert!(chown(&node, 0, 0, Some(1001), Some(0)));
+}
+
+#[test]
+fn special_bits_test() {
+    let mut dir = Node::new(Node::MODE_DIR | Node::MODE_SETGID | Node::MODE_STICKY | 0o777, "dir", 0, 0, 0);
+    dir.uid = 1000;
+    dir.gid = 100;
+
+    let mut child = Node::new(Node::MODE_DIR | 0o755, "child", 0, 0, 0);
+    create(&dir, &mut child, 1001, 1001);
+    assert_eq!(child.uid, 1001);
+    assert_eq!(child.gid, 100);
+    assert_eq!(child.mode & Node::MODE_SETGID, Node::MODE_SETGID);
+
+    assert!(remove(&dir, &child, 1001));
+    assert!(remove(&dir, &child, 1000));
+    assert!(remove(&dir, &child, 0));
+    assert!(! remove(&dir, &child, 1002));
+
+    let mut file = Node::new(Node::MODE_FILE | Node::MODE_SETUID | Node::MODE_SETGID | 0o777, "file", 0, 0, 0);
+    file.uid = 1000;
+    assert!(! clear_setid(&mut file, 1000));
+    assert!(! clear_setid(&mut file, 0));
+    assert!(clear_setid(&mut file, 1001));
+    assert_eq!(file.mode & Node::MODE_PERM, 0o777);
+    assert!(! clear_setid(&mut file, 1001));
+}
+
+#[test]
+fn xattr_test() {
+    let mut file = Node::new(Node::MODE_FILE | 0o644, "file", 0, 0, 0);
+    file.uid = 1000;
+    let link = Node::new(Node::MODE_SYMLINK | 0o777, "link", 0, 0, 0);
+
+    assert!(xattr(&file, 1000, 1000, XattrNamespace::User, Node::MODE_WRITE));
+    assert!(xattr(&file, 1001, 1001, XattrNamespace::User, Node::MODE_READ));
+    assert!(! xattr(&file, 1001, 1001, XattrNamespace::User, Node::MODE_WRITE));
+    assert!(! xattr(&link, 0, 0, XattrNamespace::User, Node::MODE_READ));
+
+    assert!(xattr(&file, 1001, 1001, XattrNamespace::Security, Node::MODE_READ));
+    assert!(! xattr(&file, 1000, 1000, XattrNamespace::Security, Node::MODE_WRITE));
+    assert!(xattr(&file, 0, 0, XattrNamespace::Security, Node::MODE_WRITE));
+
+    assert!(! xattr(&file, 1000, 1000, XattrNamespace::Trusted, Node::MODE_READ));
+    assert!(xattr(&file, 0, 0, XattrNamespace::Trusted, Node::MODE_WRITE));
+}
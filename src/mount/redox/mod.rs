@@ -1,11 +1,16 @@
 extern crate spin;
 
-use syscall::{Packet, Scheme};
+use syscall::{Error, Packet, Scheme, EIO, SYS_FEVENT};
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::{mem, slice, thread};
 
 use disk::Disk;
+use error::Error as FsError;
 use filesystem::FileSystem;
 
 use self::scheme::FileScheme;
@@ -13,17 +18,117 @@ use self::scheme::FileScheme;
 pub mod resource;
 pub mod scheme;
 
-pub fn mount<D: Disk, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, mut callback: F) -> io::Result<()> {
+/// Packets a worker reads from the scheme socket at once, to answer them all with one write
+const BATCH: usize = 8;
+
+pub fn mount<D: Disk + Send + 'static, P: AsRef<Path>, F: FnMut()>(mut filesystem: FileSystem<D>, mountpoint: &P, mut callback: F) -> io::Result<()> {
     let mountpoint = mountpoint.as_ref();
-    let mut socket = File::create(format!(":{}", mountpoint.display()))?;
+    let socket = File::create(format!(":{}", mountpoint.display()))?;
+    set_mounted(&mut filesystem)?;
 
     callback();
 
     let scheme = FileScheme::new(format!("{}", mountpoint.display()), filesystem);
+    serve(vec![(socket, Arc::new(scheme))])
+}
+
+/// Mark the filesystem as mounted, unless the disk is read-only
+fn set_mounted<D: Disk>(filesystem: &mut FileSystem<D>) -> io::Result<()> {
+    match filesystem.set_mounted(true) {
+        Ok(()) | Err(FsError::ReadOnly) => Ok(()),
+        Err(err) => Err(err.into())
+    }
+}
+
+/// Tell the kernel about changes to watched handles, after the requests that made them
+fn push_events<D: Disk>(packets: &mut Vec<Packet>, scheme: &FileScheme<D>) {
+    for (id, flags) in scheme.take_events() {
+        packets.push(Packet {
+            id: 0,
+            pid: 0,
+            uid: 0,
+            gid: 0,
+            a: SYS_FEVENT,
+            b: id,
+            c: flags,
+            d: 0
+        });
+    }
+}
+
+/// The bytes of packets, as they are read from and written to the scheme socket
+fn packet_bytes(packets: &mut [Packet]) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(packets.as_mut_ptr() as *mut u8, packets.len() * mem::size_of::<Packet>()) }
+}
+
+/// Handle requests from a scheme socket, until it is closed or reading or writing it fails
+///
+/// Every read takes up to `BATCH` packets that are waiting, and their replies are written
+/// together, followed by the events they caused. A request that panics fails with EIO, and
+/// the worker goes on with the next one, while the locks it held stay poisoned.
+fn work<D: Disk>(mut socket: File, scheme: &FileScheme<D>) -> io::Result<()> {
+    let mut packets = [Packet::default(); BATCH];
+    let mut replies = Vec::with_capacity(BATCH);
     loop {
-        let mut packet = Packet::default();
-        socket.read(&mut packet).unwrap();
-        scheme.handle(&mut packet);
-        socket.write(&packet).unwrap();
+        let count = socket.read(packet_bytes(&mut packets))?/mem::size_of::<Packet>();
+        if count == 0 {
+            return Ok(());
+        }
+
+        replies.clear();
+        for packet in packets[..count].iter_mut() {
+            if panic::catch_unwind(AssertUnwindSafe(|| scheme.handle(packet))).is_err() {
+                packet.a = Error::mux(Err(Error::new(EIO)));
+            }
+            replies.push(*packet);
+        }
+        push_events(&mut replies, scheme);
+
+        socket.write(packet_bytes(&mut replies))?;
+    }
+}
+
+/// Handle the requests to each scheme on a thread of its own, until all of them are unmounted
+///
+/// Every request holds the lock of the whole filesystem, so more threads for one scheme would
+/// only take turns with it. A filesystem is marked as cleanly unmounted once its scheme socket
+/// is closed. The first error is returned without waiting for the other schemes, which may
+/// still be mounted.
+fn serve<D: Disk + Send + 'static>(mounts: Vec<(File, Arc<FileScheme<D>>)>) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    for (socket, scheme) in mounts {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                work(socket, &scheme)?;
+                scheme.unmount().map_err(|err| io::Error::from_raw_os_error(err.errno))
+            }));
+            let _ = sender.send(res.unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::Other, "scheme worker panicked"))
+            }));
+        });
+    }
+    drop(sender);
+
+    for res in receiver.iter() {
+        res?;
     }
+    Ok(())
+}
+
+/// Mount several filesystems, each served by its own thread
+pub fn mount_all<D: Disk + Send + 'static, P: AsRef<Path>, F: FnMut(usize)>(filesystems: Vec<(FileSystem<D>, P)>, mut callback: F) -> io::Result<()> {
+    let mut mounts = Vec::new();
+    for (i, (mut filesystem, mountpoint)) in filesystems.into_iter().enumerate() {
+        let mountpoint = mountpoint.as_ref();
+        let socket = File::create(format!(":{}", mountpoint.display()))?;
+        set_mounted(&mut filesystem)?;
+
+        callback(i);
+
+        let scheme = FileScheme::new(format!("{}", mountpoint.display()), filesystem);
+        mounts.push((socket, Arc::new(scheme)));
+    }
+
+    serve(mounts)
 }
@@ -3,23 +3,78 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use syscall::data::TimeSpec;
 use syscall::error::{Error, Result, EBADF, EINVAL, EISDIR, EPERM};
-use syscall::flag::{O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, F_GETFL, F_SETFL, MODE_PERM};
+use syscall::flag::{O_ACCMODE, O_APPEND, O_RDONLY, O_WRONLY, O_RDWR, F_GETFL, F_SETFL, MODE_PERM};
 use syscall::{Stat, SEEK_SET, SEEK_CUR, SEEK_END};
 
 use disk::Disk;
 use filesystem::FileSystem;
+use mount::permissions;
+use node::Node;
+
+/// fcntl command returning the attribute flags of a node, see `Node::FLAG_*`
+///
+/// The command numbers match the FS_IOC_GETFLAGS and FS_IOC_SETFLAGS ioctls of Linux, which use the same flags.
+pub const F_GETATTR: usize = 0x8008_6601;
+/// fcntl command setting the attribute flags of a node to arg
+pub const F_SETATTR: usize = 0x4008_6602;
+/// fcntl command returning the seconds of the creation time of a node, which Stat has no field for
+pub const F_GETCRTIME: usize = 0x5246_0001;
+/// fcntl command returning the nanoseconds of the creation time of a node
+pub const F_GETCRTIME_NSEC: usize = 0x5246_0002;
+/// fcntl command returning how many of the low space thresholds the free space of the
+/// filesystem is below, on any handle, see `FileSystem::set_low_space`
+pub const F_GETLOWSPACE: usize = 0x5246_0003;
+
+/// Clear the setuid and setgid bits of the node before uid changes its data, see `permissions::clear_setid`
+fn clear_setid<D: Disk>(block: u64, uid: u32, fs: &mut FileSystem<D>) -> Result<()> {
+    let mut node = fs.node(block)?;
+    if permissions::clear_setid(&mut node.1, uid) {
+        fs.write_at(node.0, &node.1)?;
+    }
+    Ok(())
+}
+
+/// Handle the fcntl commands on the node itself, on behalf of uid
+fn fcntl_node<D: Disk>(block: u64, uid: u32, cmd: usize, arg: usize, fs: &mut FileSystem<D>) -> Result<usize> {
+    let node = fs.node(block)?;
+    match cmd {
+        F_GETCRTIME => Ok(node.1.crtime as usize),
+        F_GETCRTIME_NSEC => Ok(node.1.crtime_nsec as usize),
+        F_GETATTR => Ok(node.1.flags as usize),
+        F_SETATTR => {
+            if arg & ! (Node::FLAG_MASK as usize) != 0 {
+                return Err(Error::new(EINVAL));
+            }
 
-pub trait Resource<D: Disk> {
+            // Only root may protect a node or lift its protection
+            let changed = arg as u32 ^ node.1.flags;
+            if uid != 0 && (node.1.uid != uid || changed & (Node::FLAG_IMMUTABLE | Node::FLAG_APPEND) != 0) {
+                return Err(Error::new(EPERM));
+            }
+
+            fs.node_set_flags(block, arg as u32)?;
+            Ok(0)
+        },
+        _ => Err(Error::new(EINVAL))
+    }
+}
+
+/// An open handle, used by whichever thread handles a request for it
+pub trait Resource<D: Disk>: Send {
+    fn block(&self) -> u64;
+    fn uid(&self) -> u32;
+    fn gid(&self) -> u32;
+    /// A resource for the same node with its own copy of the offset and flags
     fn dup(&self) -> Result<Box<Resource<D>>>;
     fn read(&mut self, buf: &mut [u8], fs: &mut FileSystem<D>) -> Result<usize>;
     fn write(&mut self, buf: &[u8], fs: &mut FileSystem<D>) -> Result<usize>;
     fn seek(&mut self, offset: usize, whence: usize, fs: &mut FileSystem<D>) -> Result<usize>;
     fn fchmod(&mut self, mode: u16, fs: &mut FileSystem<D>) -> Result<usize>;
     fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FileSystem<D>) -> Result<usize>;
-    fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize>;
+    fn fcntl(&mut self, cmd: usize, arg: usize, fs: &mut FileSystem<D>) -> Result<usize>;
     fn path(&self, buf: &mut [u8]) -> Result<usize>;
     fn stat(&self, _stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize>;
-    fn sync(&mut self) -> Result<usize>;
+    fn sync(&mut self, fs: &mut FileSystem<D>) -> Result<usize>;
     fn truncate(&mut self, len: usize, fs: &mut FileSystem<D>) -> Result<usize>;
     fn utimens(&mut self, times: &[TimeSpec], fs: &mut FileSystem<D>) -> Result<usize>;
 }
@@ -30,28 +85,43 @@ pub struct DirResource {
     data: Option<Vec<u8>>,
     seek: usize,
     uid: u32,
+    gid: u32,
 }
 
 impl DirResource {
-    pub fn new(path: String, block: u64, data: Option<Vec<u8>>, uid: u32) -> DirResource {
+    pub fn new(path: String, block: u64, data: Option<Vec<u8>>, uid: u32, gid: u32) -> DirResource {
         DirResource {
             path: path,
             block: block,
             data: data,
             seek: 0,
             uid: uid,
+            gid: gid,
         }
     }
 }
 
 impl<D: Disk> Resource<D> for DirResource {
+    fn block(&self) -> u64 {
+        self.block
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+
     fn dup(&self) -> Result<Box<Resource<D>>> {
         Ok(Box::new(DirResource {
             path: self.path.clone(),
             block: self.block,
             data: self.data.clone(),
             seek: self.seek,
-            uid: self.uid
+            uid: self.uid,
+            gid: self.gid
         }))
     }
 
@@ -85,7 +155,7 @@ impl<D: Disk> Resource<D> for DirResource {
     fn fchmod(&mut self, mode: u16, fs: &mut FileSystem<D>) -> Result<usize> {
         let mut node = fs.node(self.block)?;
 
-        if node.1.uid == self.uid || self.uid == 0 {
+        if permissions::owner(&fs.mapped_node(&node.1), self.uid) {
             node.1.mode = (node.1.mode & ! MODE_PERM) | (mode & MODE_PERM);
 
             fs.write_at(node.0, &node.1)?;
@@ -99,12 +169,14 @@ impl<D: Disk> Resource<D> for DirResource {
     fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FileSystem<D>) -> Result<usize> {
         let mut node = fs.node(self.block)?;
 
-        if node.1.uid == self.uid || self.uid == 0 {
-            if uid as i32 != -1 {
+        let new_uid = if uid as i32 != -1 { Some(uid) } else { None };
+        let new_gid = if gid as i32 != -1 { Some(gid) } else { None };
+        if permissions::chown(&fs.mapped_node(&node.1), self.uid, self.gid, new_uid, new_gid) {
+            if let Some(uid) = new_uid {
                 node.1.uid = uid;
             }
 
-            if gid as i32 != -1 {
+            if let Some(gid) = new_gid {
                 node.1.gid = gid;
             }
 
@@ -116,8 +188,11 @@ impl<D: Disk> Resource<D> for DirResource {
         }
     }
 
-    fn fcntl(&mut self, _cmd: usize, _arg: usize) -> Result<usize> {
-        Err(Error::new(EBADF))
+    fn fcntl(&mut self, cmd: usize, arg: usize, fs: &mut FileSystem<D>) -> Result<usize> {
+        match cmd {
+            F_GETATTR | F_SETATTR | F_GETCRTIME | F_GETCRTIME_NSEC => fcntl_node(self.block, self.uid, cmd, arg, fs),
+            _ => Err(Error::new(EBADF))
+        }
     }
 
     fn path(&self, buf: &mut [u8]) -> Result<usize> {
@@ -133,27 +208,29 @@ impl<D: Disk> Resource<D> for DirResource {
     }
 
     fn stat(&self, stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize> {
-        let node = fs.node(self.block)?;
+        let node = fs.node_stat(self.block)?;
 
         *stat = Stat {
             st_dev: 0, // TODO
-            st_ino: node.0,
-            st_mode: node.1.mode,
+            st_ino: node.ino,
+            st_mode: node.mode,
             st_nlink: 1,
-            st_uid: node.1.uid,
-            st_gid: node.1.gid,
-            st_size: fs.node_len(self.block)?,
-            st_mtime: node.1.mtime,
-            st_mtime_nsec: node.1.mtime_nsec,
-            st_ctime: node.1.ctime,
-            st_ctime_nsec: node.1.ctime_nsec,
+            st_uid: node.uid,
+            st_gid: node.gid,
+            st_size: node.size,
+            st_blksize: node.blksize,
+            st_blocks: node.blocks,
+            st_mtime: node.mtime,
+            st_mtime_nsec: node.mtime_nsec,
+            st_ctime: node.ctime,
+            st_ctime_nsec: node.ctime_nsec,
             ..Default::default()
         };
 
         Ok(0)
     }
 
-    fn sync(&mut self) -> Result<usize> {
+    fn sync(&mut self, _fs: &mut FileSystem<D>) -> Result<usize> {
         Err(Error::new(EBADF))
     }
 
@@ -172,21 +249,35 @@ pub struct FileResource {
     flags: usize,
     seek: u64,
     uid: u32,
+    gid: u32,
 }
 
 impl FileResource {
-    pub fn new(path: String, block: u64, flags: usize, seek: u64, uid: u32) -> FileResource {
+    pub fn new(path: String, block: u64, flags: usize, seek: u64, uid: u32, gid: u32) -> FileResource {
         FileResource {
             path: path,
             block: block,
             flags: flags,
             seek: seek,
             uid: uid,
+            gid: gid,
         }
     }
 }
 
 impl<D: Disk> Resource<D> for FileResource {
+    fn block(&self) -> u64 {
+        self.block
+    }
+
+    fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    fn gid(&self) -> u32 {
+        self.gid
+    }
+
     fn dup(&self) -> Result<Box<Resource<D>>> {
         Ok(Box::new(FileResource {
             path: self.path.clone(),
@@ -194,6 +285,7 @@ impl<D: Disk> Resource<D> for FileResource {
             flags: self.flags,
             seek: self.seek,
             uid: self.uid,
+            gid: self.gid,
         }))
     }
 
@@ -209,10 +301,18 @@ impl<D: Disk> Resource<D> for FileResource {
 
     fn write(&mut self, buf: &[u8], fs: &mut FileSystem<D>) -> Result<usize> {
         if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
+            clear_setid(self.block, self.uid, fs)?;
             let mtime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-            let count = fs.write_node(self.block, self.seek, buf, mtime.as_secs(), mtime.subsec_nanos())?;
-            self.seek += count as u64;
-            Ok(count)
+            if self.flags & O_APPEND == O_APPEND {
+                // The end of the node is found while holding the filesystem, so appends cannot interleave
+                let (offset, count) = fs.append_node(self.block, buf, mtime.as_secs(), mtime.subsec_nanos())?;
+                self.seek = offset + count as u64;
+                Ok(count)
+            } else {
+                let count = fs.write_node(self.block, self.seek, buf, mtime.as_secs(), mtime.subsec_nanos())?;
+                self.seek += count as u64;
+                Ok(count)
+            }
         } else {
             Err(Error::new(EBADF))
         }
@@ -234,7 +334,7 @@ impl<D: Disk> Resource<D> for FileResource {
     fn fchmod(&mut self, mode: u16, fs: &mut FileSystem<D>) -> Result<usize> {
         let mut node = fs.node(self.block)?;
 
-        if node.1.uid == self.uid || self.uid == 0 {
+        if permissions::owner(&fs.mapped_node(&node.1), self.uid) {
             node.1.mode = (node.1.mode & ! MODE_PERM) | (mode & MODE_PERM);
 
             fs.write_at(node.0, &node.1)?;
@@ -248,12 +348,14 @@ impl<D: Disk> Resource<D> for FileResource {
     fn fchown(&mut self, uid: u32, gid: u32, fs: &mut FileSystem<D>) -> Result<usize> {
         let mut node = fs.node(self.block)?;
 
-        if node.1.uid == self.uid || self.uid == 0 {
-            if uid as i32 != -1 {
+        let new_uid = if uid as i32 != -1 { Some(uid) } else { None };
+        let new_gid = if gid as i32 != -1 { Some(gid) } else { None };
+        if permissions::chown(&fs.mapped_node(&node.1), self.uid, self.gid, new_uid, new_gid) {
+            if let Some(uid) = new_uid {
                 node.1.uid = uid;
             }
 
-            if gid as i32 != -1 {
+            if let Some(gid) = new_gid {
                 node.1.gid = gid;
             }
 
@@ -265,13 +367,14 @@ impl<D: Disk> Resource<D> for FileResource {
         }
     }
 
-    fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize> {
+    fn fcntl(&mut self, cmd: usize, arg: usize, fs: &mut FileSystem<D>) -> Result<usize> {
         match cmd {
             F_GETFL => Ok(self.flags),
             F_SETFL => {
                 self.flags = (self.flags & O_ACCMODE) | (arg & ! O_ACCMODE);
                 Ok(0)
             },
+            F_GETATTR | F_SETATTR | F_GETCRTIME | F_GETCRTIME_NSEC => fcntl_node(self.block, self.uid, cmd, arg, fs),
             _ => Err(Error::new(EINVAL))
         }
     }
@@ -289,32 +392,36 @@ impl<D: Disk> Resource<D> for FileResource {
     }
 
     fn stat(&self, stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize> {
-        let node = fs.node(self.block)?;
+        let node = fs.node_stat(self.block)?;
 
         *stat = Stat {
             st_dev: 0, // TODO
-            st_ino: node.0,
-            st_mode: node.1.mode,
+            st_ino: node.ino,
+            st_mode: node.mode,
             st_nlink: 1,
-            st_uid: node.1.uid,
-            st_gid: node.1.gid,
-            st_size: fs.node_len(self.block)?,
-            st_mtime: node.1.mtime,
-            st_mtime_nsec: node.1.mtime_nsec,
-            st_ctime: node.1.ctime,
-            st_ctime_nsec: node.1.ctime_nsec,
+            st_uid: node.uid,
+            st_gid: node.gid,
+            st_size: node.size,
+            st_blksize: node.blksize,
+            st_blocks: node.blocks,
+            st_mtime: node.mtime,
+            st_mtime_nsec: node.mtime_nsec,
+            st_ctime: node.ctime,
+            st_ctime_nsec: node.ctime_nsec,
             ..Default::default()
         };
 
         Ok(0)
     }
 
-    fn sync(&mut self) -> Result<usize> {
+    fn sync(&mut self, fs: &mut FileSystem<D>) -> Result<usize> {
+        fs.sync()?;
         Ok(0)
     }
 
     fn truncate(&mut self, len: usize, fs: &mut FileSystem<D>) -> Result<usize> {
         if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
+            clear_setid(self.block, self.uid, fs)?;
             fs.node_set_len(self.block, len as u64)?;
             Ok(0)
         } else {
@@ -325,7 +432,7 @@ impl<D: Disk> Resource<D> for FileResource {
     fn utimens(&mut self, times: &[TimeSpec], fs: &mut FileSystem<D>) -> Result<usize> {
         let mut node = fs.node(self.block)?;
 
-        if node.1.uid == self.uid || self.uid == 0 {
+        if permissions::owner(&fs.mapped_node(&node.1), self.uid) {
             if let Some(mtime) = times.get(1) {
 
                 node.1.mtime = mtime.tv_sec as u64;
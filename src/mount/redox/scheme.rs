@@ -1,39 +1,172 @@
-use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::str;
+use std::sync::{Arc, Mutex as BlockingMutex, MutexGuard};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use syscall::data::{Stat, StatVfs, TimeSpec};
-use syscall::error::{Error, Result, EACCES, EEXIST, EISDIR, ENOTDIR, EPERM, ENOENT, EBADF, ELOOP, EINVAL};
-use syscall::flag::{O_APPEND, O_CREAT, O_DIRECTORY, O_STAT, O_EXCL, O_TRUNC, O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, MODE_PERM, O_SYMLINK, O_NOFOLLOW};
+use syscall::error::{Error, Result, EACCES, EEXIST, EILSEQ, EISDIR, ENOTDIR, EPERM, ENOENT, EBADF, ELOOP, EINVAL, EIO};
+use syscall::flag::{O_APPEND, O_CREAT, O_DIRECTORY, O_STAT, O_EXCL, O_TRUNC, O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, MODE_PERM, O_SYMLINK, O_NOFOLLOW, EVENT_READ};
 use syscall::scheme::Scheme;
 
 use BLOCK_SIZE;
 use disk::Disk;
+use error::Error as FsError;
 use filesystem::FileSystem;
+use mount::permissions;
 use node::Node;
 
-use super::resource::{Resource, DirResource, FileResource};
+use super::resource::{Resource, DirResource, FileResource, F_GETLOWSPACE};
 use super::spin::Mutex;
 
+/// An open handle, with the block of its node kept outside of the lock of its resource, so the
+/// handles of a node can be found while one of them is in use
+struct Handle<D: Disk> {
+    block: u64,
+    resource: Arc<BlockingMutex<Box<Resource<D>>>>,
+}
+
+/// The scheme of a filesystem, which can handle requests from several threads at once
+///
+/// Every request that reads or changes nodes holds the filesystem lock for all of its disk
+/// I/O, so requests to different files still take turns rather than running in parallel.
+///
+/// The resource of a handle is locked before the filesystem, which is locked before the other
+/// fields. Those, being held only briefly, are spin locks, while a thread waiting for the
+/// resource or the filesystem sleeps, as their holder may be waiting for the disk. Neither is
+/// locked while holding `files`, which is locked before `open` and `orphans`.
+///
+/// Handles made by dup share the resource, and so the offset and flags, of the handle they were
+/// made from, like dup does on other systems, unless dup is asked for an `unshared` one.
 pub struct FileScheme<D: Disk> {
     name: String,
-    fs: RefCell<FileSystem<D>>,
+    fs: BlockingMutex<FileSystem<D>>,
     next_id: AtomicUsize,
-    files: Mutex<BTreeMap<usize, Box<Resource<D>>>>
+    files: Mutex<BTreeMap<usize, Handle<D>>>,
+    /// Number of handles of each node that has any
+    open: Mutex<BTreeMap<u64, usize>>,
+    /// Nodes that were unlinked while open, to be released when their last handle is closed
+    orphans: Mutex<BTreeSet<u64>>,
+    /// Event flags requested for each handle with fevent
+    watches: Mutex<BTreeMap<usize, usize>>,
+    /// Events to send to the kernel after the current request, as handle and flags
+    events: Mutex<Vec<(usize, usize)>>
 }
 
 impl<D: Disk> FileScheme<D> {
     pub fn new(name: String, fs: FileSystem<D>) -> FileScheme<D> {
         FileScheme {
             name: name,
-            fs: RefCell::new(fs),
+            fs: BlockingMutex::new(fs),
             next_id: AtomicUsize::new(1),
-            files: Mutex::new(BTreeMap::new())
+            files: Mutex::new(BTreeMap::new()),
+            open: Mutex::new(BTreeMap::new()),
+            orphans: Mutex::new(BTreeSet::new()),
+            watches: Mutex::new(BTreeMap::new()),
+            events: Mutex::new(Vec::new())
+        }
+    }
+
+    /// Mark the filesystem as cleanly unmounted, once the kernel is done with the scheme
+    pub fn unmount(&self) -> Result<()> {
+        match self.fs()?.set_mounted(false) {
+            Ok(()) | Err(FsError::ReadOnly) => Ok(()),
+            Err(err) => Err(err.into())
+        }
+    }
+
+    fn fs(&self) -> Result<MutexGuard<FileSystem<D>>> {
+        lock(&self.fs)
+    }
+
+    /// The resource of a handle, to be locked without holding `files`
+    fn resource(&self, id: usize) -> Result<Arc<BlockingMutex<Box<Resource<D>>>>> {
+        self.files.lock().get(&id).map(|handle| handle.resource.clone()).ok_or(Error::new(EBADF))
+    }
+
+    /// Add a handle for a resource
+    ///
+    /// This is done before the filesystem is unlocked, so the node cannot be removed before it
+    /// counts as open.
+    fn insert(&self, resource: Box<Resource<D>>) -> usize {
+        let block = resource.block();
+        self.add(Handle {
+            block: block,
+            resource: Arc::new(BlockingMutex::new(resource)),
+        })
+    }
+
+    /// Add a handle sharing the resource of another
+    fn share(&self, id: usize) -> Result<usize> {
+        let handle = {
+            let files = self.files.lock();
+            let handle = files.get(&id).ok_or(Error::new(EBADF))?;
+            Handle {
+                block: handle.block,
+                resource: handle.resource.clone(),
+            }
+        };
+        Ok(self.add(handle))
+    }
+
+    /// Add a handle, counting it as open for its node
+    fn add(&self, handle: Handle<D>) -> usize {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut files = self.files.lock();
+        *self.open.lock().entry(handle.block).or_insert(0) += 1;
+        files.insert(id, handle);
+        id
+    }
+
+    /// Queue an event for every watched handle of a node, which changed or, if it is a
+    /// directory, had a child created or removed
+    fn notify(&self, block: u64) {
+        let files = self.files.lock();
+        let watches = self.watches.lock();
+        let mut events = self.events.lock();
+        for (&id, &flags) in watches.iter() {
+            if flags & EVENT_READ == EVENT_READ && files.get(&id).map_or(false, |handle| handle.block == block) {
+                events.push((id, EVENT_READ));
+            }
         }
     }
 
+    /// Take the events queued since the last call, to be written to the scheme socket
+    ///
+    /// When free space crossed a low space threshold, handles watching the root directory get
+    /// an event too. This does not wait for the filesystem if another request is using it, but
+    /// leaves the event to the next call.
+    pub fn take_events(&self) -> Vec<(usize, usize)> {
+        let low_space_root = match self.fs.try_lock() {
+            Ok(mut fs) => if fs.take_low_space_change() {
+                Some(fs.root())
+            } else {
+                None
+            },
+            Err(_) => None
+        };
+        if let Some(root) = low_space_root {
+            self.notify(root);
+        }
+
+        let mut events = self.events.lock();
+        events.drain(..).collect()
+    }
+
+    /// Remove a node, or orphan it if it is still open
+    fn remove_node(&self, fs: &mut FileSystem<D>, mode: u16, name: &str, parent_block: u64, block: u64) -> Result<usize> {
+        let open = self.open.lock().contains_key(&block);
+        if open {
+            let block = fs.orphan_node(mode, name, parent_block)?;
+            self.orphans.lock().insert(block);
+        } else {
+            fs.remove_node(mode, name, parent_block)?;
+        }
+        self.notify(parent_block);
+        self.notify(block);
+        Ok(0)
+    }
+
     fn resolve_symlink(&self, fs: &mut FileSystem<D>, uid: u32, gid: u32, url: &[u8], node: (u64, Node), nodes: &mut Vec<(u64, Node)>) -> Result<Vec<u8>> {
         let mut node = node;
         for _ in 1..10 { // XXX What should the limit be?
@@ -64,7 +197,7 @@ impl<D: Disk> FileScheme<D> {
     fn path_nodes(&self, fs: &mut FileSystem<D>, path: &str, uid: u32, gid: u32, nodes: &mut Vec<(u64, Node)>) -> Result<Option<(u64, Node)>> {
         let mut parts = path.split('/').filter(|part| ! part.is_empty());
         let mut part_opt = None;
-        let mut block = fs.header.1.root;
+        let mut block = fs.root();
         loop {
             let node_res = match part_opt {
                 None => fs.node(block),
@@ -74,7 +207,7 @@ impl<D: Disk> FileScheme<D> {
             part_opt = parts.next();
             if part_opt.is_some() {
                 let node = node_res?;
-                if ! node.1.permission(uid, gid, Node::MODE_EXEC) {
+                if ! permissions::access(&fs.mapped_node(&node.1), uid, gid, Node::MODE_EXEC) {
                     return Err(Error::new(EACCES));
                 }
                 if node.1.is_symlink() {
@@ -83,7 +216,7 @@ impl<D: Disk> FileScheme<D> {
                     url.push(b':');
                     for i in nodes.iter() {
                         url.push(b'/');
-                        url.extend_from_slice(&i.1.name);
+                        url.extend_from_slice(i.1.name().unwrap_or("").as_bytes());
                     }
                     self.resolve_symlink(fs, uid, gid, &url, node, nodes)?;
                     block = nodes.last().unwrap().0;
@@ -96,9 +229,9 @@ impl<D: Disk> FileScheme<D> {
             } else {
                 match node_res {
                     Ok(node) => return Ok(Some(node)),
-                    Err(err) => match err.errno {
-                        ENOENT => return Ok(None),
-                        _ => return Err(err)
+                    Err(err) => match err {
+                        FsError::NotFound => return Ok(None),
+                        _ => return Err(err.into())
                     }
                 }
             }
@@ -106,6 +239,36 @@ impl<D: Disk> FileScheme<D> {
     }
 }
 
+/// The path of a node from the root, found through its parents so that it follows renames, or
+/// None if the node was unlinked
+fn node_path<D: Disk>(fs: &mut FileSystem<D>, block: u64) -> Result<Option<String>> {
+    let root = fs.root();
+    let mut names = Vec::new();
+    let mut block = block;
+    while block != root {
+        let node = fs.node(block)?;
+        if node.1.parent == 0 {
+            return Ok(None);
+        }
+        // A parent chain longer than any path could be is a loop
+        if names.len() > 4096 {
+            return Err(FsError::Corrupt(block).into());
+        }
+        names.push(node.1.name().or(Err(Error::new(EILSEQ)))?.to_string());
+        block = node.1.parent;
+    }
+    names.reverse();
+    Ok(Some(names.join("/")))
+}
+
+/// Lock a resource or the filesystem, failing with EIO if a request panicked while holding it
+///
+/// The request may have left it half changed, so once the filesystem lock is poisoned, every
+/// request to the scheme fails rather than reading or writing the disk any further.
+fn lock<T>(mutex: &BlockingMutex<T>) -> Result<MutexGuard<T>> {
+    mutex.lock().map_err(|_| Error::new(EIO))
+}
+
 /// Make a relative path absolute
 /// Given a cwd of "scheme:/path"
 /// This function will turn "foo" into "scheme:/path/foo"
@@ -179,11 +342,12 @@ pub fn canonicalize(current: &[u8], path: &[u8]) -> Vec<u8> {
 
 impl<D: Disk> Scheme for FileScheme<D> {
     fn open(&self, url: &[u8], flags: usize, uid: u32, gid: u32) -> Result<usize> {
-        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+        let path = str::from_utf8(url).or(Err(Error::new(EILSEQ)))?.trim_matches('/');
 
         // println!("Open '{}' {:X}", path, flags);
 
-        let mut fs = self.fs.borrow_mut();
+        let mut fs = self.fs()?;
+        fs.set_privileged(uid == 0);
 
         let mut nodes = Vec::new();
         let node_opt = self.path_nodes(&mut fs, path, uid, gid, &mut nodes)?;
@@ -192,16 +356,14 @@ impl<D: Disk> Scheme for FileScheme<D> {
                 return Err(Error::new(EEXIST));
             } else if node.1.is_dir() {
                 if flags & O_ACCMODE == O_RDONLY {
-                    if ! node.1.permission(uid, gid, Node::MODE_READ) {
+                    if ! permissions::access(&fs.mapped_node(&node.1), uid, gid, Node::MODE_READ) {
                         // println!("dir not readable {:o}", node.1.mode);
                         return Err(Error::new(EACCES));
                     }
 
-                    let mut children = Vec::new();
-                    fs.child_nodes(&mut children, node.0)?;
-
                     let mut data = Vec::new();
-                    for child in children.iter() {
+                    for child in fs.child_iter(node.0) {
+                        let child = child?;
                         if let Ok(name) = child.1.name() {
                             if ! data.is_empty() {
                                 data.push(b'\n');
@@ -210,12 +372,12 @@ impl<D: Disk> Scheme for FileScheme<D> {
                         }
                     }
 
-                    Box::new(DirResource::new(path.to_string(), node.0, Some(data), uid))
+                    Box::new(DirResource::new(path.to_string(), node.0, Some(data), uid, gid))
                 } else if flags & O_WRONLY == O_WRONLY {
                     // println!("{:X} & {:X}: EISDIR {}", flags, O_DIRECTORY, path);
                     return Err(Error::new(EISDIR));
                 } else {
-                    Box::new(DirResource::new(path.to_string(), node.0, None, uid))
+                    Box::new(DirResource::new(path.to_string(), node.0, None, uid, gid))
                 }
             } else if node.1.is_symlink() && !(flags & O_STAT == O_STAT && flags  & O_NOFOLLOW == O_NOFOLLOW) && flags & O_SYMLINK != O_SYMLINK {
                 let mut resolve_nodes = Vec::new();
@@ -230,23 +392,28 @@ impl<D: Disk> Scheme for FileScheme<D> {
                     return Err(Error::new(ENOTDIR));
                 }
 
-                if (flags & O_ACCMODE == O_RDONLY || flags & O_ACCMODE == O_RDWR) && ! node.1.permission(uid, gid, Node::MODE_READ) {
+                if (flags & O_ACCMODE == O_RDONLY || flags & O_ACCMODE == O_RDWR) && ! permissions::access(&fs.mapped_node(&node.1), uid, gid, Node::MODE_READ) {
                     // println!("file not readable {:o}", node.1.mode);
                     return Err(Error::new(EACCES));
                 }
 
-                if (flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR) && ! node.1.permission(uid, gid, Node::MODE_WRITE) {
+                if (flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR) && ! permissions::access(&fs.mapped_node(&node.1), uid, gid, Node::MODE_WRITE) {
                     // println!("file not writable {:o}", node.1.mode);
                     return Err(Error::new(EACCES));
                 }
 
+                if flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR {
+                    fs.node_check_write(node.0, flags & O_APPEND == O_APPEND)?;
+                }
+
                 if flags & O_TRUNC == O_TRUNC {
-                    if ! node.1.permission(uid, gid, Node::MODE_WRITE) {
+                    if ! permissions::access(&fs.mapped_node(&node.1), uid, gid, Node::MODE_WRITE) {
                         // println!("file not writable {:o}", node.1.mode);
                         return Err(Error::new(EACCES));
                     }
 
                     fs.node_set_len(node.0, 0)?;
+                    self.notify(node.0);
                 }
 
                 let seek = if flags & O_APPEND == O_APPEND {
@@ -255,7 +422,7 @@ impl<D: Disk> Scheme for FileScheme<D> {
                     0
                 };
 
-                Box::new(FileResource::new(path.to_string(), node.0, flags, seek, uid))
+                Box::new(FileResource::new(path.to_string(), node.0, flags, seek, uid, gid))
             },
             None => if flags & O_CREAT == O_CREAT {
                 let mut last_part = String::new();
@@ -266,7 +433,7 @@ impl<D: Disk> Scheme for FileScheme<D> {
                 }
                 if ! last_part.is_empty() {
                     if let Some(parent) = nodes.last() {
-                        if ! parent.1.permission(uid, gid, Node::MODE_WRITE) {
+                        if ! permissions::modify_dir(&fs.mapped_node(&parent.1), uid, gid) {
                             // println!("dir not writable {:o}", parent.1.mode);
                             return Err(Error::new(EACCES));
                         }
@@ -282,12 +449,12 @@ impl<D: Disk> Scheme for FileScheme<D> {
 
                         let ctime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
                         let mut node = fs.create_node(mode_type | (flags as u16 & Node::MODE_PERM), &last_part, parent.0, ctime.as_secs(), ctime.subsec_nanos())?;
-                        node.1.uid = uid;
-                        node.1.gid = gid;
+                        permissions::create(&parent.1, &mut node.1, uid, gid);
                         fs.write_at(node.0, &node.1)?;
+                        self.notify(parent.0);
 
                         if dir {
-                            Box::new(DirResource::new(path.to_string(), node.0, None, uid))
+                            Box::new(DirResource::new(path.to_string(), node.0, None, uid, gid))
                         } else {
                             let seek = if flags & O_APPEND == O_APPEND {
                                 fs.node_len(node.0)?
@@ -295,7 +462,7 @@ impl<D: Disk> Scheme for FileScheme<D> {
                                 0
                             };
 
-                            Box::new(FileResource::new(path.to_string(), node.0, flags, seek, uid))
+                            Box::new(FileResource::new(path.to_string(), node.0, flags, seek, uid, gid))
                         }
                     } else {
                         return Err(Error::new(EPERM));
@@ -308,22 +475,21 @@ impl<D: Disk> Scheme for FileScheme<D> {
             }
         };
 
-        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-        self.files.lock().insert(id, resource);
-
+        let id = self.insert(resource);
+        drop(fs);
         Ok(id)
     }
 
     fn chmod(&self, url: &[u8], mode: u16, uid: u32, gid: u32) -> Result<usize> {
-        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+        let path = str::from_utf8(url).or(Err(Error::new(EILSEQ)))?.trim_matches('/');
 
         // println!("Chmod '{}'", path);
 
-        let mut fs = self.fs.borrow_mut();
+        let mut fs = self.fs()?;
 
         let mut nodes = Vec::new();
         if let Some(mut node) = self.path_nodes(&mut fs, path, uid, gid, &mut nodes)? {
-            if node.1.uid == uid || uid == 0 {
+            if permissions::owner(&fs.mapped_node(&node.1), uid) {
                 node.1.mode = (node.1.mode & ! MODE_PERM) | (mode & MODE_PERM);
                 fs.write_at(node.0, &node.1)?;
                 Ok(0)
@@ -336,28 +502,27 @@ impl<D: Disk> Scheme for FileScheme<D> {
     }
 
     fn rmdir(&self, url: &[u8], uid: u32, gid: u32) -> Result<usize> {
-        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+        let path = str::from_utf8(url).or(Err(Error::new(EILSEQ)))?.trim_matches('/');
 
         // println!("Rmdir '{}'", path);
 
-        let mut fs = self.fs.borrow_mut();
+        let mut fs = self.fs()?;
 
         let mut nodes = Vec::new();
         if let Some(child) = self.path_nodes(&mut fs, path, uid, gid, &mut nodes)? {
             if let Some(parent) = nodes.last() {
-                if ! parent.1.permission(uid, gid, Node::MODE_WRITE) {
+                if ! permissions::modify_dir(&fs.mapped_node(&parent.1), uid, gid) {
                     // println!("dir not writable {:o}", parent.1.mode);
                     return Err(Error::new(EACCES));
                 }
 
-                if child.1.is_dir() {
-                    if ! child.1.permission(uid, gid, Node::MODE_WRITE) {
-                        // println!("dir not writable {:o}", parent.1.mode);
-                        return Err(Error::new(EACCES));
-                    }
+                if ! permissions::remove(&fs.mapped_node(&parent.1), &fs.mapped_node(&child.1), uid) {
+                    return Err(Error::new(EPERM));
+                }
 
+                if child.1.is_dir() {
                     if let Ok(child_name) = child.1.name() {
-                        fs.remove_node(Node::MODE_DIR, child_name, parent.0).and(Ok(0))
+                        self.remove_node(&mut fs, Node::MODE_DIR, child_name, parent.0, child.0)
                     } else {
                         Err(Error::new(ENOENT))
                     }
@@ -373,31 +538,30 @@ impl<D: Disk> Scheme for FileScheme<D> {
     }
 
     fn unlink(&self, url: &[u8], uid: u32, gid: u32) -> Result<usize> {
-        let path = str::from_utf8(url).unwrap_or("").trim_matches('/');
+        let path = str::from_utf8(url).or(Err(Error::new(EILSEQ)))?.trim_matches('/');
 
         // println!("Unlink '{}'", path);
 
-        let mut fs = self.fs.borrow_mut();
+        let mut fs = self.fs()?;
 
         let mut nodes = Vec::new();
         if let Some(child) = self.path_nodes(&mut fs, path, uid, gid, &mut nodes)? {
             if let Some(parent) = nodes.last() {
-                if ! parent.1.permission(uid, gid, Node::MODE_WRITE) {
+                if ! permissions::modify_dir(&fs.mapped_node(&parent.1), uid, gid) {
                     // println!("dir not writable {:o}", parent.1.mode);
                     return Err(Error::new(EACCES));
                 }
 
-                if ! child.1.is_dir() {
-                    if child.1.uid != uid {
-                        // println!("file not owned by current user {}", parent.1.uid);
-                        return Err(Error::new(EACCES));
-                    }
+                if ! permissions::remove(&fs.mapped_node(&parent.1), &fs.mapped_node(&child.1), uid) {
+                    return Err(Error::new(EPERM));
+                }
 
+                if ! child.1.is_dir() {
                     if let Ok(child_name) = child.1.name() {
                         if child.1.is_symlink() {
-                            fs.remove_node(Node::MODE_SYMLINK, child_name, parent.0).and(Ok(0))
+                            self.remove_node(&mut fs, Node::MODE_SYMLINK, child_name, parent.0, child.0)
                         } else {
-                            fs.remove_node(Node::MODE_FILE, child_name, parent.0).and(Ok(0))
+                            self.remove_node(&mut fs, Node::MODE_FILE, child_name, parent.0, child.0)
                         }
                     } else {
                         Err(Error::new(ENOENT))
@@ -418,173 +582,203 @@ impl<D: Disk> Scheme for FileScheme<D> {
     fn dup(&self, old_id: usize, buf: &[u8]) -> Result<usize> {
         // println!("Dup {}", old_id);
 
-        if ! buf.is_empty() {
-            return Err(Error::new(EINVAL));
+        let resource = self.resource(old_id)?;
+        let file = lock(&resource)?;
+        match buf {
+            b"" => (),
+            // Return a handle with its own offset and flags, starting from those of this one
+            b"unshared" => {
+                let dup = file.dup()?;
+                return Ok(self.insert(dup));
+            },
+            // Flush the whole filesystem, returning a duplicate handle once the disk has acknowledged it
+            b"syncfs" => {
+                self.fs()?.sync_all()?;
+            },
+            // Return a handle that reads an ExStat of the node
+            b"statx" => {
+                let stat = self.fs()?.node_stat(file.block())?;
+                return Ok(self.insert(Box::new(DirResource::new(String::new(), file.block(), Some(stat.to_vec()), file.uid(), file.gid()))));
+            },
+            _ => return Err(Error::new(EINVAL))
         }
 
-        let mut files = self.files.lock();
-        let resource = if let Some(old_resource) = files.get(&old_id) {
-            old_resource.dup()?
-        } else {
-            return Err(Error::new(EBADF));
-        };
-
-        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-        files.insert(id, resource);
-
-        Ok(id)
+        drop(file);
+        self.share(old_id)
     }
 
     #[allow(unused_variables)]
     fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
         // println!("Read {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.read(buf, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let resource = self.resource(id)?;
+        let mut file = lock(&resource)?;
+        file.read(buf, &mut self.fs()?)
     }
 
     fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
         // println!("Write {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.write(buf, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
+        let resource = self.resource(id)?;
+        let mut file = lock(&resource)?;
+        let count = {
+            let mut fs = self.fs()?;
+            fs.set_privileged(file.uid() == 0);
+            file.write(buf, &mut fs)?
+        };
+        if count > 0 {
+            self.notify(file.block());
         }
+        Ok(count)
     }
 
     fn seek(&self, id: usize, pos: usize, whence: usize) -> Result<usize> {
         // println!("Seek {}, {} {}", id, pos, whence);
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.seek(pos, whence, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let resource = self.resource(id)?;
+        let mut file = lock(&resource)?;
+        file.seek(pos, whence, &mut self.fs()?)
     }
 
     fn fchmod(&self, id: usize, mode: u16) -> Result<usize> {
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.fchmod(mode, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let resource = self.resource(id)?;
+        let mut file = lock(&resource)?;
+        file.fchmod(mode, &mut self.fs()?)
     }
 
     fn fchown(&self, id: usize, uid: u32, gid: u32) -> Result<usize> {
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.fchown(uid, gid, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let resource = self.resource(id)?;
+        let mut file = lock(&resource)?;
+        file.fchown(uid, gid, &mut self.fs()?)
     }
 
     fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.fcntl(cmd, arg)
+        let resource = self.resource(id)?;
+        if cmd == F_GETLOWSPACE {
+            return Ok(self.fs()?.low_space_level());
+        }
+        let mut file = lock(&resource)?;
+        file.fcntl(cmd, arg, &mut self.fs()?)
+    }
+
+    /// Watch a handle for changes to its node, or to the children of its directory
+    fn fevent(&self, id: usize, flags: usize) -> Result<usize> {
+        if ! self.files.lock().contains_key(&id) {
+            return Err(Error::new(EBADF));
+        }
+        let mut watches = self.watches.lock();
+        if flags == 0 {
+            watches.remove(&id);
         } else {
-            Err(Error::new(EBADF))
+            watches.insert(id, flags);
         }
+        Ok(0)
     }
 
     fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
         // println!("Fpath {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
-        let files = self.files.lock();
-        if let Some(file) = files.get(&id) {
-            let name = self.name.as_bytes();
-
-            let mut i = 0;
-            while i < buf.len() && i < name.len() {
-                buf[i] = name[i];
-                i += 1;
-            }
-            if i < buf.len() {
-                buf[i] = b':';
-                i += 1;
-            }
-            if i < buf.len() {
-                buf[i] = b'/';
-                i += 1;
-            }
+        let resource = self.resource(id)?;
+        let file = lock(&resource)?;
+        let name = self.name.as_bytes();
+
+        let mut i = 0;
+        while i < buf.len() && i < name.len() {
+            buf[i] = name[i];
+            i += 1;
+        }
+        if i < buf.len() {
+            buf[i] = b':';
+            i += 1;
+        }
+        if i < buf.len() {
+            buf[i] = b'/';
+            i += 1;
+        }
 
-            file.path(&mut buf[i..]).map(|count| i + count)
-        } else {
-            Err(Error::new(EBADF))
+        // An unlinked node keeps the path it was opened with
+        match node_path(&mut self.fs()?, file.block())? {
+            Some(path) => {
+                for &b in path.as_bytes() {
+                    if i >= buf.len() {
+                        break;
+                    }
+                    buf[i] = b;
+                    i += 1;
+                }
+                Ok(i)
+            },
+            None => file.path(&mut buf[i..]).map(|count| i + count)
         }
     }
 
     fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
         // println!("Fstat {}, {:X}", id, stat as *mut Stat as usize);
-        let files = self.files.lock();
-        if let Some(file) = files.get(&id) {
-            file.stat(stat, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let resource = self.resource(id)?;
+        let file = lock(&resource)?;
+        file.stat(stat, &mut self.fs()?)
     }
 
     fn fstatvfs(&self, id: usize, stat: &mut StatVfs) -> Result<usize> {
-        let files = self.files.lock();
-        if let Some(_file) = files.get(&id) {
-            let mut fs = self.fs.borrow_mut();
-
-            let free = fs.header.1.free;
-            let free_size = fs.node_len(free)?;
+        self.resource(id)?;
+        let fs = self.fs()?;
 
-            stat.f_bsize = BLOCK_SIZE as u32;
-            stat.f_blocks = fs.header.1.size/(stat.f_bsize as u64);
-            stat.f_bfree = free_size/(stat.f_bsize as u64);
-            stat.f_bavail = stat.f_bfree;
+        stat.f_bsize = fs.header.1.block_size() as u32;
+        stat.f_blocks = fs.header.1.size/(stat.f_bsize as u64);
+        stat.f_bfree = fs.free_blocks() * BLOCK_SIZE/(stat.f_bsize as u64);
+        stat.f_bavail = fs.available_blocks() * BLOCK_SIZE/(stat.f_bsize as u64);
 
-            Ok(0)
-        } else {
-            Err(Error::new(EBADF))
-        }
+        Ok(0)
     }
 
     fn fsync(&self, id: usize) -> Result<usize> {
         // println!("Fsync {}", id);
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.sync()
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let resource = self.resource(id)?;
+        let mut file = lock(&resource)?;
+        file.sync(&mut self.fs()?)
     }
 
     fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
         // println!("Ftruncate {}, {}", id, len);
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.truncate(len, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let resource = self.resource(id)?;
+        let mut file = lock(&resource)?;
+        let res = {
+            let mut fs = self.fs()?;
+            fs.set_privileged(file.uid() == 0);
+            file.truncate(len, &mut fs)?
+        };
+        self.notify(file.block());
+        Ok(res)
     }
 
     fn futimens(&self, id: usize, times: &[TimeSpec]) -> Result<usize> {
         // println!("Futimens {}, {}", id, times.len());
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.utimens(times, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let resource = self.resource(id)?;
+        let mut file = lock(&resource)?;
+        file.utimens(times, &mut self.fs()?)
     }
 
     fn close(&self, id: usize) -> Result<usize> {
         // println!("Close {}", id);
-        let mut files = self.files.lock();
-        if files.remove(&id).is_some() {
-            Ok(0)
-        } else {
-            Err(Error::new(EBADF))
+        let (handle, orphan) = {
+            let mut files = self.files.lock();
+            let handle = files.remove(&id).ok_or(Error::new(EBADF))?;
+            self.watches.lock().remove(&id);
+            let mut open = self.open.lock();
+            let last = match open.get_mut(&handle.block) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                },
+                _ => true
+            };
+            if last {
+                open.remove(&handle.block);
+            }
+            let orphan = last && self.orphans.lock().remove(&handle.block);
+            (handle, orphan)
+        };
+        if orphan {
+            // Requests still using the handle finish before its node is freed
+            let _file = lock(&handle.resource)?;
+            self.fs()?.release_node(handle.block)?;
         }
+        Ok(0)
     }
 }
@@ -1,9 +1,10 @@
-use std::{fmt, mem, ops, slice, str};
+use std::{cmp, fmt, mem, ops, slice, str};
 
 use BLOCK_SIZE;
 use super::Extent;
 
 /// A file/folder node
+#[derive(Clone, Copy)]
 #[repr(packed)]
 pub struct Node {
     pub mode: u16,
@@ -13,10 +14,21 @@ pub struct Node {
     pub ctime_nsec: u32,
     pub mtime: u64,
     pub mtime_nsec: u32,
-    pub name: [u8; 222],
     pub parent: u64,
     pub next: u64,
-    pub extents: [Extent; (BLOCK_SIZE as usize - 272)/16],
+    /// Length of the name in bytes
+    pub name_len: u16,
+    /// Attribute flags, see `Node::FLAG_*`
+    pub flags: u32,
+    /// Creation time
+    pub crtime: u64,
+    pub crtime_nsec: u32,
+    /// Chosen at random when the node is created, to tell it apart from earlier nodes in the same block
+    pub generation: u32,
+    /// Block of the node holding the extended attributes, see `xattr`, or 0 if there are none
+    pub xattrs: u64,
+    /// The name, padded to a multiple of 16 bytes, followed by as many extents as fit
+    pub data: [u8; BLOCK_SIZE as usize - 80],
 }
 
 impl Node {
@@ -29,6 +41,27 @@ impl Node {
     pub const MODE_EXEC: u16 = 0o1;
     pub const MODE_WRITE: u16 = 0o2;
     pub const MODE_READ: u16 = 0o4;
+    pub const MODE_STICKY: u16 = 0o1000;
+    pub const MODE_SETGID: u16 = 0o2000;
+    pub const MODE_SETUID: u16 = 0o4000;
+
+    /// Data freed by truncating or unlinking the node is overwritten with zeros
+    pub const FLAG_SECURE_DELETE: u32 = 0x1;
+    /// The node cannot be written, truncated, or unlinked, and no children can be added or removed
+    pub const FLAG_IMMUTABLE: u32 = 0x10;
+    /// The node can only be written at its end, and cannot be truncated or unlinked
+    pub const FLAG_APPEND: u32 = 0x20;
+    /// The node should be skipped by backups
+    pub const FLAG_NODUMP: u32 = 0x40;
+    /// Flags that may be set
+    pub const FLAG_MASK: u32 = Node::FLAG_SECURE_DELETE | Node::FLAG_IMMUTABLE | Node::FLAG_APPEND | Node::FLAG_NODUMP;
+    /// Flags of a directory that the nodes created in it get as well
+    pub const FLAG_INHERITED: u32 = Node::FLAG_SECURE_DELETE | Node::FLAG_NODUMP;
+
+    /// Longest name, in bytes, that fits in a node
+    pub const NAME_MAX: usize = 1024;
+    /// Longest length, in bytes, of the data of a node, the largest offset frontends can represent
+    pub const LEN_MAX: u64 = i64::MAX as u64;
 
     pub fn default() -> Node {
         Node {
@@ -39,18 +72,23 @@ impl Node {
             ctime_nsec: 0,
             mtime: 0,
             mtime_nsec: 0,
-            name: [0; 222],
             parent: 0,
             next: 0,
-            extents: [Extent::default(); (BLOCK_SIZE as usize - 272)/16],
+            name_len: 0,
+            flags: 0,
+            crtime: 0,
+            crtime_nsec: 0,
+            generation: 0,
+            xattrs: 0,
+            data: [0; BLOCK_SIZE as usize - 80],
         }
     }
 
+    /// Create a node created at ctime, with the name cut to NAME_MAX bytes
     pub fn new(mode: u16, name: &str, parent: u64, ctime: u64, ctime_nsec: u32) -> Node {
-        let mut bytes = [0; 222];
-        for (b, c) in bytes.iter_mut().zip(name.bytes()) {
-            *b = c;
-        }
+        let len = cmp::min(name.len(), Node::NAME_MAX);
+        let mut data = [0; BLOCK_SIZE as usize - 80];
+        data[..len].copy_from_slice(&name.as_bytes()[..len]);
 
         Node {
             mode: mode,
@@ -60,24 +98,66 @@ impl Node {
             ctime_nsec: ctime_nsec,
             mtime: ctime,
             mtime_nsec: ctime_nsec,
-            name: bytes,
             parent: parent,
             next: 0,
-            extents: [Extent::default(); (BLOCK_SIZE as usize - 272)/16],
+            name_len: len as u16,
+            flags: 0,
+            crtime: ctime,
+            crtime_nsec: ctime_nsec,
+            generation: 0,
+            xattrs: 0,
+            data: data,
         }
     }
 
+    fn name_len(&self) -> usize {
+        cmp::min(self.name_len as usize, Node::NAME_MAX)
+    }
+
     pub fn name(&self) -> Result<&str, str::Utf8Error> {
-        let mut len = 0;
+        str::from_utf8(&self.data[..self.name_len()])
+    }
 
-        for &b in self.name.iter() {
-            if b == 0 {
-                break;
-            }
-            len += 1;
+    /// Change the name, moving the extents after it
+    ///
+    /// Fails, leaving the node unchanged, if the name is longer than NAME_MAX or a longer name
+    /// would leave no room for extents that are in use.
+    pub fn set_name(&mut self, name: &str) -> bool {
+        if name.len() > Node::NAME_MAX {
+            return false;
         }
 
-        str::from_utf8(&self.name[..len])
+        let extents = self.extents().to_vec();
+        let offset = (name.len() + 15)/16 * 16;
+        let count = (self.data.len() - offset)/mem::size_of::<Extent>();
+        if extents.iter().skip(count).any(|extent| extent.block != 0 || extent.length != 0) {
+            return false;
+        }
+
+        self.data = [0; BLOCK_SIZE as usize - 80];
+        self.data[..name.len()].copy_from_slice(name.as_bytes());
+        self.name_len = name.len() as u16;
+        for (extent, old) in self.extents_mut().iter_mut().zip(extents.iter()) {
+            *extent = *old;
+        }
+        true
+    }
+
+    /// Offset of the first extent in data, after the name
+    fn extents_offset(&self) -> usize {
+        (self.name_len() + 15)/16 * 16
+    }
+
+    /// The extents of the node, fewer for longer names
+    pub fn extents(&self) -> &[Extent] {
+        let data = &self.data[self.extents_offset()..];
+        unsafe { slice::from_raw_parts(data.as_ptr() as *const Extent, data.len()/mem::size_of::<Extent>()) }
+    }
+
+    pub fn extents_mut(&mut self) -> &mut [Extent] {
+        let offset = self.extents_offset();
+        let data = &mut self.data[offset..];
+        unsafe { slice::from_raw_parts_mut(data.as_mut_ptr() as *mut Extent, data.len()/mem::size_of::<Extent>()) }
     }
 
     pub fn is_dir(&self) -> bool {
@@ -92,6 +172,18 @@ impl Node {
         self.mode & Node::MODE_TYPE == Node::MODE_SYMLINK
     }
 
+    pub fn is_immutable(&self) -> bool {
+        self.flags & Node::FLAG_IMMUTABLE == Node::FLAG_IMMUTABLE
+    }
+
+    pub fn is_append(&self) -> bool {
+        self.flags & Node::FLAG_APPEND == Node::FLAG_APPEND
+    }
+
+    pub fn is_secure_delete(&self) -> bool {
+        self.flags & Node::FLAG_SECURE_DELETE == Node::FLAG_SECURE_DELETE
+    }
+
     pub fn permission(&self, uid: u32, gid: u32, op: u16) -> bool {
         let mut perm = self.mode & 0o7;
         if self.uid == uid {
@@ -107,13 +199,13 @@ impl Node {
     }
 
     pub fn size(&self) -> u64 {
-        self.extents.iter().fold(0, |size, extent| size + extent.length)
+        self.extents().iter().fold(0, |size, extent| size + extent.length)
     }
 }
 
 impl fmt::Debug for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let extents: Vec<&Extent> = self.extents.iter().filter(|extent| -> bool { extent.length > 0 }).collect();
+        let extents: Vec<&Extent> = self.extents().iter().filter(|extent| -> bool { extent.length > 0 }).collect();
         f.debug_struct("Node")
             .field("mode", &self.mode)
             .field("uid", &self.uid)
@@ -122,8 +214,13 @@ impl fmt::Debug for Node {
             .field("ctime_nsec", &self.ctime_nsec)
             .field("mtime", &self.mtime)
             .field("mtime_nsec", &self.mtime_nsec)
+            .field("crtime", &self.crtime)
+            .field("crtime_nsec", &self.crtime_nsec)
+            .field("generation", &self.generation)
             .field("name", &self.name())
+            .field("flags", &self.flags)
             .field("next", &self.next)
+            .field("xattrs", &self.xattrs)
             .field("extents", &extents)
             .finish()
     }
@@ -150,3 +247,27 @@ impl ops::DerefMut for Node {
 fn node_size_test() {
     assert_eq!(mem::size_of::<Node>(), BLOCK_SIZE as usize);
 }
+
+#[test]
+fn node_name_test() {
+    let short = Node::new(Node::MODE_FILE, "a", 0, 0, 0);
+    assert_eq!(short.name(), Ok("a"));
+    assert_eq!(short.extents().len(), (BLOCK_SIZE as usize - 96)/16);
+
+    let name = "a".repeat(Node::NAME_MAX);
+    let long = Node::new(Node::MODE_FILE, &name, 0, 0, 0);
+    assert_eq!(long.name(), Ok(&name[..]));
+    assert_eq!(long.extents().len(), (BLOCK_SIZE as usize - 80 - Node::NAME_MAX)/16);
+
+    // Renaming keeps the extents, unless a longer name would cut off ones in use
+    let mut renamed = Node::new(Node::MODE_FILE, "a", 0, 0, 0);
+    renamed.extents_mut()[0] = Extent::new(5, BLOCK_SIZE);
+    assert!(renamed.set_name(&name));
+    assert_eq!(renamed.name(), Ok(&name[..]));
+    assert_eq!({ renamed.extents()[0].block }, 5);
+    assert!(renamed.set_name("b"));
+    let last = renamed.extents().len() - 1;
+    renamed.extents_mut()[last] = Extent::new(6, BLOCK_SIZE);
+    assert!(! renamed.set_name(&name));
+    assert_eq!(renamed.name(), Ok("b"));
+}
@@ -0,0 +1,56 @@
+//! Progress of long operations such as archiving, reported to a callback with the bytes done so
+//! far and the bytes in all, if they are known up front
+
+/// Bytes done between calls of the callback, so it is not called for every block
+const STEP: u64 = 1024 * 1024;
+
+/// Counts the bytes an operation is done with, and calls the callback every `STEP` of them
+pub struct Progress<'a> {
+    callback: &'a mut FnMut(u64, Option<u64>),
+    done: u64,
+    total: Option<u64>,
+    /// Bytes done at the last call of the callback
+    reported: u64,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(callback: &'a mut FnMut(u64, Option<u64>), total: Option<u64>) -> Progress<'a> {
+        Progress {
+            callback: callback,
+            done: 0,
+            total: total,
+            reported: 0,
+        }
+    }
+
+    /// Count count more bytes as done
+    pub fn add(&mut self, count: u64) {
+        self.done += count;
+        if self.done - self.reported >= STEP {
+            self.reported = self.done;
+            (self.callback)(self.done, self.total);
+        }
+    }
+
+    /// Call the callback a last time, with the bytes done as the total, which an estimated
+    /// or unknown one may not have been
+    pub fn finish(&mut self) {
+        self.reported = self.done;
+        (self.callback)(self.done, Some(self.done));
+    }
+}
+
+#[test]
+fn progress_test() {
+    let mut calls = Vec::new();
+    {
+        let mut callback = |done: u64, total: Option<u64>| calls.push((done, total));
+        let mut progress = Progress::new(&mut callback, Some(3 * STEP));
+        progress.add(STEP - 1);
+        progress.add(1);
+        progress.add(STEP/2);
+        progress.add(STEP);
+        progress.finish();
+    }
+    assert_eq!(calls, vec![(STEP, Some(3 * STEP)), (5 * STEP/2, Some(3 * STEP)), (5 * STEP/2, Some(5 * STEP/2))]);
+}
@@ -0,0 +1,380 @@
+use std::io::{self, Read, Write};
+
+use archive::{read_all, set_attr, sorted_children};
+use disk::Disk;
+use error::Error;
+use filesystem::FileSystem;
+use node::Node;
+
+const MAGIC: &'static [u8; 8] = b"RFSSend\0";
+const STREAM_VERSION: u8 = 1;
+
+const OP_END: u8 = 0;
+const OP_MKDIR: u8 = 1;
+const OP_CREATE: u8 = 2;
+const OP_SYMLINK: u8 = 3;
+const OP_REMOVE: u8 = 4;
+const OP_WRITE: u8 = 5;
+const OP_TRUNCATE: u8 = 6;
+const OP_ATTR: u8 = 7;
+
+/// Granularity at which file data is compared and sent
+const CHUNK: usize = 64 * 1024;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_uint<W: Write>(writer: &mut W, value: u64, len: usize) -> io::Result<()> {
+    let mut bytes = [0; 8];
+    for i in 0..len {
+        bytes[i] = (value >> (i * 8)) as u8;
+    }
+    writer.write_all(&bytes[..len])
+}
+
+fn read_uint<R: Read>(reader: &mut R, len: usize) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes[..len])?;
+    let mut value = 0;
+    for i in 0..len {
+        value |= (bytes[i] as u64) << (i * 8);
+    }
+    Ok(value)
+}
+
+/// The metadata of a node that is carried by the stream
+#[derive(Clone, Copy, PartialEq)]
+struct Attr {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    mtime_nsec: u32,
+}
+
+impl Attr {
+    fn from_node(node: &Node) -> Attr {
+        Attr {
+            mode: node.mode & Node::MODE_PERM,
+            uid: node.uid,
+            gid: node.gid,
+            mtime: node.mtime,
+            mtime_nsec: node.mtime_nsec,
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_uint(writer, self.mode as u64, 2)?;
+        write_uint(writer, self.uid as u64, 4)?;
+        write_uint(writer, self.gid as u64, 4)?;
+        write_uint(writer, self.mtime, 8)?;
+        write_uint(writer, self.mtime_nsec as u64, 4)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<Attr> {
+        Ok(Attr {
+            mode: read_uint(reader, 2)? as u16,
+            uid: read_uint(reader, 4)? as u32,
+            gid: read_uint(reader, 4)? as u32,
+            mtime: read_uint(reader, 8)?,
+            mtime_nsec: read_uint(reader, 4)? as u32,
+        })
+    }
+
+    fn apply<D: Disk>(&self, fs: &mut FileSystem<D>, block: u64) -> io::Result<()> {
+        set_attr(fs, block, self.mode, self.uid, self.gid, self.mtime, self.mtime_nsec)
+    }
+}
+
+fn write_op<W: Write>(writer: &mut W, op: u8, path: &str) -> io::Result<()> {
+    writer.write_all(&[op])?;
+    write_uint(writer, path.len() as u64, 4)?;
+    writer.write_all(path.as_bytes())
+}
+
+/// Send the chunks of a file that differ from the old version of it
+fn send_data<D: Disk, E: Disk, W: Write>(old: &mut FileSystem<D>, old_block: Option<u64>, new: &mut FileSystem<E>, new_block: u64, path: &str, writer: &mut W) -> io::Result<()> {
+    let new_len = new.node_len(new_block)?;
+    let old_len = match old_block {
+        Some(block) => old.node_len(block)?,
+        None => 0
+    };
+
+    if old_len > new_len {
+        write_op(writer, OP_TRUNCATE, path)?;
+        write_uint(writer, new_len, 8)?;
+    }
+
+    let mut new_buf = vec![0; CHUNK];
+    let mut old_buf = vec![0; CHUNK];
+    let mut offset = 0;
+    while offset < new_len {
+        let count = new.read_node(new_block, offset, &mut new_buf)?;
+        if count == 0 {
+            return Err(Error::Corrupt(new_block).into());
+        }
+
+        let same = match old_block {
+            Some(block) if offset < old_len => {
+                let old_count = old.read_node(block, offset, &mut old_buf[..count])?;
+                old_count == count && old_buf[..count] == new_buf[..count]
+            },
+            _ => false
+        };
+
+        if ! same {
+            write_op(writer, OP_WRITE, path)?;
+            write_uint(writer, offset, 8)?;
+            write_uint(writer, count as u64, 4)?;
+            writer.write_all(&new_buf[..count])?;
+        }
+
+        offset += count as u64;
+    }
+
+    Ok(())
+}
+
+fn send_dir<D: Disk, E: Disk, W: Write>(old: &mut FileSystem<D>, old_block: Option<u64>, new: &mut FileSystem<E>, new_block: u64, prefix: &str, writer: &mut W) -> io::Result<()> {
+    let old_children = match old_block {
+        Some(block) => sorted_children(old, block)?,
+        None => Vec::new()
+    };
+    let new_children = sorted_children(new, new_block)?;
+
+    for old_child in old_children.iter() {
+        let name = old_child.1.name().or(Err(Error::InvalidName))?;
+        if ! new_children.iter().any(|new_child| new_child.1.name().ok() == Some(name)) {
+            write_op(writer, OP_REMOVE, &format!("{}{}", prefix, name))?;
+        }
+    }
+
+    for new_child in new_children.iter() {
+        let name = new_child.1.name().or(Err(Error::InvalidName))?;
+        let path = format!("{}{}", prefix, name);
+        let attr = Attr::from_node(&new_child.1);
+
+        let old_child = old_children.iter().find(|old_child| old_child.1.name().ok() == Some(name));
+        let mut same = old_child.filter(|old_child| old_child.1.mode & Node::MODE_TYPE == new_child.1.mode & Node::MODE_TYPE);
+
+        let link = if new_child.1.is_symlink() {
+            let link = read_all(new, new_child.0)?;
+            if let Some(old_child) = same {
+                if read_all(old, old_child.0)? != link {
+                    same = None;
+                }
+            }
+            link
+        } else {
+            Vec::new()
+        };
+
+        if old_child.is_some() && same.is_none() {
+            write_op(writer, OP_REMOVE, &path)?;
+        }
+
+        match same {
+            Some(old_child) => {
+                if new_child.1.is_file() {
+                    send_data(old, Some(old_child.0), new, new_child.0, &path, writer)?;
+                } else if new_child.1.is_dir() {
+                    send_dir(old, Some(old_child.0), new, new_child.0, &format!("{}/", path), writer)?;
+                }
+
+                if Attr::from_node(&old_child.1) != attr {
+                    write_op(writer, OP_ATTR, &path)?;
+                    attr.write(writer)?;
+                }
+            },
+            None => if new_child.1.is_dir() {
+                write_op(writer, OP_MKDIR, &path)?;
+                attr.write(writer)?;
+                send_dir(old, None, new, new_child.0, &format!("{}/", path), writer)?;
+            } else if new_child.1.is_symlink() {
+                write_op(writer, OP_SYMLINK, &path)?;
+                attr.write(writer)?;
+                write_uint(writer, link.len() as u64, 4)?;
+                writer.write_all(&link)?;
+            } else {
+                write_op(writer, OP_CREATE, &path)?;
+                attr.write(writer)?;
+                send_data(old, None, new, new_child.0, &path, writer)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the changes that turn the tree of old into the tree of new
+///
+/// Applying the stream with `receive` to an image holding the same tree as old
+/// makes it match new. File data is compared in chunks, only those that differ are sent.
+pub fn send<D: Disk, E: Disk, W: Write>(old: &mut FileSystem<D>, new: &mut FileSystem<E>, writer: &mut W) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[STREAM_VERSION])?;
+
+    let old_root = old.header.1.root;
+    let new_root = new.header.1.root;
+    send_dir(old, Some(old_root), new, new_root, "", writer)?;
+
+    writer.write_all(&[OP_END])?;
+    writer.flush()
+}
+
+/// Find the parent directory node of a path in the stream, and the name in it
+fn receive_parent<'a, D: Disk>(fs: &mut FileSystem<D>, path: &'a str) -> io::Result<(u64, &'a str)> {
+    let mut names: Vec<&str> = path.split('/').collect();
+    let name = names.pop().unwrap_or("");
+    if name.is_empty() || names.iter().any(|name| name.is_empty() || *name == "." || *name == "..") {
+        return Err(invalid("invalid path in send stream"));
+    }
+
+    let mut block = fs.header.1.root;
+    for name in names {
+        block = fs.find_node(name, block)?.0;
+    }
+    Ok((block, name))
+}
+
+/// Remove a node, and everything below it if it is a directory
+fn remove_all<D: Disk>(fs: &mut FileSystem<D>, name: &str, parent_block: u64) -> io::Result<()> {
+    let node = fs.find_node(name, parent_block)?;
+    if node.1.is_dir() {
+        for child in sorted_children(fs, node.0)? {
+            remove_all(fs, child.1.name().or(Err(Error::InvalidName))?, node.0)?;
+        }
+    }
+    fs.remove_node(node.1.mode & Node::MODE_TYPE, name, parent_block)?;
+    Ok(())
+}
+
+/// Apply a stream written by `send`
+pub fn receive<D: Disk, R: Read>(fs: &mut FileSystem<D>, reader: &mut R) -> io::Result<()> {
+    let mut magic = [0; 9];
+    reader.read_exact(&mut magic)?;
+    if &magic[..8] != MAGIC {
+        return Err(invalid("not a send stream"));
+    }
+    if magic[8] != STREAM_VERSION {
+        return Err(invalid("unsupported send stream version"));
+    }
+
+    loop {
+        let op = read_uint(reader, 1)? as u8;
+        if op == OP_END {
+            return Ok(());
+        }
+
+        let path_len = read_uint(reader, 4)? as usize;
+        if path_len > 64 * Node::NAME_MAX {
+            return Err(invalid("path too long in send stream"));
+        }
+        let mut path = vec![0; path_len];
+        reader.read_exact(&mut path)?;
+        let path = String::from_utf8(path).or(Err(Error::InvalidName))?;
+        let (parent_block, name) = receive_parent(fs, &path)?;
+
+        match op {
+            OP_MKDIR | OP_CREATE | OP_SYMLINK => {
+                let attr = Attr::read(reader)?;
+                let mode_type = match op {
+                    OP_MKDIR => Node::MODE_DIR,
+                    OP_CREATE => Node::MODE_FILE,
+                    _ => Node::MODE_SYMLINK
+                };
+                let node = fs.create_node(mode_type | attr.mode, name, parent_block, attr.mtime, attr.mtime_nsec)?;
+                if op == OP_SYMLINK {
+                    let len = read_uint(reader, 4)? as usize;
+                    if len > CHUNK {
+                        return Err(invalid("symlink too long in send stream"));
+                    }
+                    let mut link = vec![0; len];
+                    reader.read_exact(&mut link)?;
+                    fs.write_node(node.0, 0, &link, attr.mtime, attr.mtime_nsec)?;
+                }
+                attr.apply(fs, node.0)?;
+            },
+            OP_REMOVE => {
+                remove_all(fs, name, parent_block)?;
+            },
+            OP_WRITE => {
+                let offset = read_uint(reader, 8)?;
+                let len = read_uint(reader, 4)? as usize;
+                if len > CHUNK {
+                    return Err(invalid("write too large in send stream"));
+                }
+                let mut data = vec![0; len];
+                reader.read_exact(&mut data)?;
+
+                // Times are restored by an attribute operation if they changed
+                let node = fs.find_node(name, parent_block)?;
+                fs.write_node(node.0, offset, &data, 0, 0)?;
+            },
+            OP_TRUNCATE => {
+                let len = read_uint(reader, 8)?;
+                let node = fs.find_node(name, parent_block)?;
+                fs.node_set_len(node.0, len)?;
+            },
+            OP_ATTR => {
+                let attr = Attr::read(reader)?;
+                let node = fs.find_node(name, parent_block)?;
+                attr.apply(fs, node.0)?;
+            },
+            _ => return Err(invalid("unknown operation in send stream"))
+        }
+    }
+}
+
+#[test]
+fn send_test() {
+    use archive::extract_tar;
+    use testing::memory_fs;
+
+    let image = || memory_fs(256);
+    let mut empty = image();
+    let mut old = image();
+    let mut new = image();
+
+    {
+        let root = old.header.1.root;
+        let file = old.create_node(Node::MODE_FILE | 0o644, "file", root, 0, 0).unwrap();
+        old.write_node(file.0, 0, &vec![1; 3 * CHUNK], 0, 0).unwrap();
+        let dir = old.create_node(Node::MODE_DIR | 0o755, "dir", root, 0, 0).unwrap();
+        old.create_node(Node::MODE_FILE | 0o644, "removed", dir.0, 0, 0).unwrap();
+        let link = old.create_node(Node::MODE_SYMLINK | 0o777, "link", root, 0, 0).unwrap();
+        old.write_node(link.0, 0, b"file", 0, 0).unwrap();
+        old.create_node(Node::MODE_FILE | 0o644, "shrunk", root, 0, 0).and_then(|node| old.write_node(node.0, 0, &[2; 100], 0, 0)).unwrap();
+    }
+
+    // A full send from an empty image copies the tree
+    let mut full = Vec::new();
+    send(&mut empty, &mut old, &mut full).unwrap();
+    receive(&mut new, &mut &full[..]).unwrap();
+
+    {
+        let root = new.header.1.root;
+        let file = new.find_node("file", root).unwrap();
+        new.write_node(file.0, CHUNK as u64, &[3; 10], 5, 0).unwrap();
+        let dir = new.find_node("dir", root).unwrap();
+        new.remove_node(Node::MODE_FILE, "removed", dir.0).unwrap();
+        new.create_node(Node::MODE_FILE | 0o600, "added", dir.0, 0, 0).unwrap();
+        new.remove_node(Node::MODE_SYMLINK, "link", root).unwrap();
+        let link = new.create_node(Node::MODE_SYMLINK | 0o777, "link", root, 0, 0).unwrap();
+        new.write_node(link.0, 0, b"dir", 0, 0).unwrap();
+        let shrunk = new.find_node("shrunk", root).unwrap();
+        new.node_set_len(shrunk.0, 10).unwrap();
+    }
+
+    let mut incremental = Vec::new();
+    send(&mut old, &mut new, &mut incremental).unwrap();
+    assert!(incremental.len() < 2 * CHUNK);
+    receive(&mut old, &mut &incremental[..]).unwrap();
+
+    let (mut old_tar, mut new_tar) = (Vec::new(), Vec::new());
+    let (old_root, new_root) = (old.header.1.root, new.header.1.root);
+    extract_tar(&mut old, old_root, &mut old_tar).unwrap();
+    extract_tar(&mut new, new_root, &mut new_tar).unwrap();
+    assert!(old_tar == new_tar);
+}
@@ -0,0 +1,189 @@
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Compress one 64 byte chunk into the state
+fn compress_chunk(state: &mut [u32; 8], chunk: &[u8]) {
+    let mut w = [0u32; 64];
+    for i in 0..16 {
+        w[i] = (chunk[i * 4] as u32) << 24 | (chunk[i * 4 + 1] as u32) << 16 | (chunk[i * 4 + 2] as u32) << 8 | chunk[i * 4 + 3] as u32;
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+    }
+
+    let mut h = *state;
+    for i in 0..64 {
+        let s1 = h[4].rotate_right(6) ^ h[4].rotate_right(11) ^ h[4].rotate_right(25);
+        let ch = (h[4] & h[5]) ^ (! h[4] & h[6]);
+        let t1 = h[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+        let s0 = h[0].rotate_right(2) ^ h[0].rotate_right(13) ^ h[0].rotate_right(22);
+        let maj = (h[0] & h[1]) ^ (h[0] & h[2]) ^ (h[1] & h[2]);
+        let t2 = s0.wrapping_add(maj);
+
+        h[7] = h[6];
+        h[6] = h[5];
+        h[5] = h[4];
+        h[4] = h[3].wrapping_add(t1);
+        h[3] = h[2];
+        h[2] = h[1];
+        h[1] = h[0];
+        h[0] = t1.wrapping_add(t2);
+    }
+
+    for i in 0..8 {
+        state[i] = state[i].wrapping_add(h[i]);
+    }
+}
+
+/// Compress whole 64 byte chunks into the state with the SHA extensions of x86 processors
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sha,ssse3,sse4.1")]
+unsafe fn compress_sha(state: &mut [u32; 8], chunks: &[u8]) {
+    use std::arch::x86_64::*;
+
+    // The rounds work on the state as ABEF and CDGH, and on big endian words
+    let mask = _mm_set_epi64x(0x0c0d0e0f08090a0b, 0x0405060700010203);
+    let dcba = _mm_shuffle_epi32(_mm_loadu_si128(state.as_ptr() as *const __m128i), 0xB1);
+    let efgh = _mm_shuffle_epi32(_mm_loadu_si128(state.as_ptr().offset(4) as *const __m128i), 0x1B);
+    let mut abef = _mm_alignr_epi8(dcba, efgh, 8);
+    let mut cdgh = _mm_blend_epi16(efgh, dcba, 0xF0);
+
+    for chunk in chunks.chunks(64) {
+        let (abef_save, cdgh_save) = (abef, cdgh);
+
+        let mut w = [_mm_setzero_si128(); 4];
+        for i in 0..16 {
+            if i < 4 {
+                w[i] = _mm_shuffle_epi8(_mm_loadu_si128(chunk.as_ptr().offset(i as isize * 16) as *const __m128i), mask);
+            } else {
+                // The next four words of the message schedule, from the four before them
+                let t = _mm_add_epi32(_mm_sha256msg1_epu32(w[i % 4], w[(i + 1) % 4]), _mm_alignr_epi8(w[(i + 3) % 4], w[(i + 2) % 4], 4));
+                w[i % 4] = _mm_sha256msg2_epu32(t, w[(i + 3) % 4]);
+            }
+
+            let msg = _mm_add_epi32(w[i % 4], _mm_loadu_si128(K.as_ptr().offset(i as isize * 4) as *const __m128i));
+            cdgh = _mm_sha256rnds2_epu32(cdgh, abef, msg);
+            abef = _mm_sha256rnds2_epu32(abef, cdgh, _mm_shuffle_epi32(msg, 0x0E));
+        }
+
+        abef = _mm_add_epi32(abef, abef_save);
+        cdgh = _mm_add_epi32(cdgh, cdgh_save);
+    }
+
+    let feba = _mm_shuffle_epi32(abef, 0x1B);
+    let dchg = _mm_shuffle_epi32(cdgh, 0xB1);
+    _mm_storeu_si128(state.as_mut_ptr() as *mut __m128i, _mm_blend_epi16(feba, dchg, 0xF0));
+    _mm_storeu_si128(state.as_mut_ptr().offset(4) as *mut __m128i, _mm_alignr_epi8(dchg, feba, 8));
+}
+
+/// Compress whole 64 byte chunks into the state, with the SHA instructions of the processor if
+/// it has them, as hashing every block read from a verity disk would otherwise take most of the time
+fn compress(state: &mut [u32; 8], chunks: &[u8]) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sha") && is_x86_feature_detected!("ssse3") && is_x86_feature_detected!("sse4.1") {
+            return unsafe { compress_sha(state, chunks) };
+        }
+    }
+
+    for chunk in chunks.chunks(64) {
+        compress_chunk(state, chunk);
+    }
+}
+
+/// SHA-256 digest of data
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    let whole = data.len() - data.len() % 64;
+    compress(&mut state, &data[..whole]);
+    let last = &data[whole..];
+
+    // Padding: a one bit, zeros, and the length in bits, in one or two chunks
+    let mut tail = [0; 128];
+    tail[..last.len()].copy_from_slice(last);
+    tail[last.len()] = 0x80;
+    let tail_len = if last.len() < 56 { 64 } else { 128 };
+    let bits = (data.len() as u64) * 8;
+    for i in 0..8 {
+        tail[tail_len - 1 - i] = (bits >> (i * 8)) as u8;
+    }
+    compress(&mut state, &tail[..tail_len]);
+
+    let mut digest = [0; 32];
+    for i in 0..8 {
+        for j in 0..4 {
+            digest[i * 4 + j] = (state[i] >> (24 - j * 8)) as u8;
+        }
+    }
+    digest
+}
+
+/// HMAC-SHA-256 of data with key, as used to sign requests
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block_key = [0; 64];
+    if key.len() > 64 {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Vec::with_capacity(64 + data.len());
+    inner.extend(block_key.iter().map(|b| b ^ 0x36));
+    inner.extend_from_slice(data);
+    let mut outer = Vec::with_capacity(64 + 32);
+    outer.extend(block_key.iter().map(|b| b ^ 0x5c));
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+#[test]
+fn sha256_test() {
+    fn hex(digest: [u8; 32]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    assert_eq!(hex(sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_eq!(hex(sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(hex(sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    assert_eq!(hex(sha256(&[b'a'; 64])), "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb");
+    assert_eq!(hex(sha256(&[b'a'; 1000])), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+}
+
+#[test]
+fn compress_test() {
+    // Whichever implementation compress picks has to match the portable one
+    let data: Vec<u8> = (0..64 * 20).map(|i| (i * 7 + i / 64) as u8).collect();
+    for count in 0..20 {
+        let mut state = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut portable = state;
+        compress(&mut state, &data[..count * 64]);
+        for chunk in data[..count * 64].chunks(64) {
+            compress_chunk(&mut portable, chunk);
+        }
+        assert_eq!(state, portable);
+    }
+}
+
+#[test]
+fn hmac_sha256_test() {
+    fn hex(digest: [u8; 32]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Test cases 2 and 6 of RFC 4231, the second with a key longer than a block
+    assert_eq!(hex(hmac_sha256(b"Jefe", b"what do ya want for nothing?")), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    assert_eq!(hex(hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+}
@@ -0,0 +1,121 @@
+//! Disks and filesystems shared by the tests of several modules
+
+use std::cmp::{max, min};
+
+use BLOCK_SIZE;
+use disk::Disk;
+use error::{Error, Result};
+use filesystem::FileSystem;
+
+/// A disk held in memory, which keeps its size
+pub struct DiskMemory(pub Vec<u8>);
+
+impl DiskMemory {
+    /// A disk of blocks zeroed blocks
+    pub fn new(blocks: u64) -> DiskMemory {
+        DiskMemory(vec![0; (blocks * BLOCK_SIZE) as usize])
+    }
+}
+
+impl Disk for DiskMemory {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let start = min((block * BLOCK_SIZE) as usize, self.0.len());
+        let end = min(start + buffer.len(), self.0.len());
+        buffer[..end - start].copy_from_slice(&self.0[start..end]);
+        Ok(end - start)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let start = min((block * BLOCK_SIZE) as usize, self.0.len());
+        let end = min(start + buffer.len(), self.0.len());
+        self.0[start..end].copy_from_slice(&buffer[..end - start]);
+        Ok(end - start)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.0.len() as u64)
+    }
+}
+
+/// A new filesystem on a disk of blocks in memory
+pub fn memory_fs(blocks: u64) -> FileSystem<DiskMemory> {
+    FileSystem::create(DiskMemory::new(blocks), 0, 0).unwrap()
+}
+
+/// A disk in memory that counts its syncs, but not the barriers that order header writes
+pub struct DiskSyncs(pub DiskMemory, pub usize);
+
+impl Disk for DiskSyncs {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        self.0.read_at(block, buffer)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        self.0.write_at(block, buffer)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        self.0.size()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.1 += 1;
+        Ok(())
+    }
+
+    fn barrier(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A disk in memory that holds writes back until a sync, like a device that reorders them
+///
+/// Writes fail once the count of writes left runs out, and `crash` loses the first of the
+/// ones held back.
+pub struct DiskReorder(pub DiskMemory, pub Vec<(u64, Vec<u8>)>, pub usize);
+
+impl DiskReorder {
+    /// Write the writes held back to the disk, except for the first lost ones
+    pub fn crash(&mut self, lost: usize) {
+        for write in self.1.drain(..).skip(lost) {
+            self.0.write_at(write.0, &write.1).unwrap();
+        }
+    }
+}
+
+impl Disk for DiskReorder {
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let count = self.0.read_at(block, buffer)?;
+        let start = block * BLOCK_SIZE;
+        let end = start + buffer.len() as u64;
+        for write in self.1.iter() {
+            let write_start = write.0 * BLOCK_SIZE;
+            let write_end = write_start + write.1.len() as u64;
+            if write_start < end && write_end > start {
+                let from = max(start, write_start);
+                let to = min(end, write_end);
+                buffer[(from - start) as usize..(to - start) as usize]
+                    .copy_from_slice(&write.1[(from - write_start) as usize..(to - write_start) as usize]);
+            }
+        }
+        Ok(count)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        if self.2 == 0 {
+            return Err(Error::Io(block));
+        }
+        self.2 -= 1;
+        self.1.push((block, buffer.to_vec()));
+        Ok(buffer.len())
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        self.0.size()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.crash(0);
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+//! Extended attributes, stored in the data of a node pointed to by `Node::xattrs`
+//!
+//! Each attribute is a byte with the length of its name, a little endian `u32` with the length of
+//! its value, the name, and the value.
+
+use error::{Error, Result};
+
+/// Longest name of an attribute, in bytes, including its namespace
+pub const NAME_MAX: usize = 255;
+/// Longest value of an attribute, in bytes
+pub const VALUE_MAX: usize = 65536;
+
+/// Attribute of a directory with an octal umask, such as `027`, that takes permissions away from
+/// the nodes created in it, and that directories created in it inherit
+pub const DEFAULT_UMASK: &'static str = "user.redoxfs.umask";
+
+/// The namespace of an attribute, the part of its name before the first `.`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum XattrNamespace {
+    /// Security labels and file capabilities, which anyone may read but only root may set
+    Security,
+    /// Attributes the filesystem implements itself, such as ACLs, which cannot be stored
+    System,
+    /// Attributes only root may read or set
+    Trusted,
+    /// Attributes of files and directories, with the permissions of their data
+    User,
+}
+
+impl XattrNamespace {
+    /// The namespace of an attribute name, which has to have one of the known ones
+    pub fn of(name: &str) -> Result<XattrNamespace> {
+        let prefix = name.split('.').next().unwrap_or("");
+        if prefix.len() == name.len() || prefix.len() + 1 == name.len() {
+            return Err(Error::Unsupported);
+        }
+        match prefix {
+            "security" => Ok(XattrNamespace::Security),
+            "system" => Ok(XattrNamespace::System),
+            "trusted" => Ok(XattrNamespace::Trusted),
+            "user" => Ok(XattrNamespace::User),
+            _ => Err(Error::Unsupported)
+        }
+    }
+}
+
+/// The umask in the value of a `DEFAULT_UMASK` attribute
+pub fn parse_umask(value: &[u8]) -> Option<u16> {
+    let value = ::std::str::from_utf8(value).ok()?.trim();
+    match u16::from_str_radix(value, 8) {
+        Ok(umask) if umask <= 0o777 && ! value.starts_with('+') => Some(umask),
+        _ => None
+    }
+}
+
+/// Split the data of an attribute node into its names and values
+pub fn decode(data: &[u8], block: u64) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        if i + 5 > data.len() {
+            return Err(Error::Corrupt(block));
+        }
+        let name_len = data[i] as usize;
+        let value_len = (data[i + 1] as usize) | (data[i + 2] as usize) << 8 | (data[i + 3] as usize) << 16 | (data[i + 4] as usize) << 24;
+        i += 5;
+        if i + name_len + value_len > data.len() {
+            return Err(Error::Corrupt(block));
+        }
+        let name = String::from_utf8(data[i..i + name_len].to_vec()).or(Err(Error::Corrupt(block)))?;
+        i += name_len;
+        xattrs.push((name, data[i..i + value_len].to_vec()));
+        i += value_len;
+    }
+    Ok(xattrs)
+}
+
+/// Join names and values into the data of an attribute node
+pub fn encode(xattrs: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for &(ref name, ref value) in xattrs.iter() {
+        let value_len = value.len() as u32;
+        data.push(name.len() as u8);
+        data.extend_from_slice(&[value_len as u8, (value_len >> 8) as u8, (value_len >> 16) as u8, (value_len >> 24) as u8]);
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(value);
+    }
+    data
+}
+
+#[test]
+fn xattr_test() {
+    assert_eq!(XattrNamespace::of("user.mime_type"), Ok(XattrNamespace::User));
+    assert_eq!(XattrNamespace::of("security.capability"), Ok(XattrNamespace::Security));
+    assert_eq!(XattrNamespace::of("trusted.overlay.opaque"), Ok(XattrNamespace::Trusted));
+    assert_eq!(XattrNamespace::of("system.posix_acl_access"), Ok(XattrNamespace::System));
+    assert_eq!(XattrNamespace::of("user."), Err(Error::Unsupported));
+    assert_eq!(XattrNamespace::of("user"), Err(Error::Unsupported));
+    assert_eq!(XattrNamespace::of("com.apple.FinderInfo"), Err(Error::Unsupported));
+
+    let xattrs = vec![("user.a".to_string(), vec![1, 2, 3]), ("security.b".to_string(), Vec::new())];
+    let data = encode(&xattrs);
+    assert_eq!(data.len(), 5 + 6 + 3 + 5 + 10);
+    assert_eq!(decode(&data, 0).unwrap(), xattrs);
+    assert_eq!(decode(&data[..data.len() - 1], 7), Err(Error::Corrupt(7)));
+}